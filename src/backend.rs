@@ -0,0 +1,216 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(feature = "opencl")]
+use crate::cl_part::{self, OpenClError};
+use crate::delimiters::{build_index, mask_delimiters, match_brackets, match_brackets_segments, Brackets};
+use crate::delimiters::{DelimiterError, DelimiterIndex};
+use crate::preprocessor::batch::{for_each_file, preprocess_packed, PackedSources, PreprocessedFile};
+use crate::preprocessor::parallel::{available_threads, preprocess_parallel, replay, segment_boundaries, write_result};
+use crate::preprocessor::{into_string, preprocess_with_options, Comment, PreprocessError, PreprocessOptions, Preprocessed};
+
+///Where the comments and literals are searched
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Backend {
+    //Sequentially on the current thread
+    Cpu,
+    //On up to `threads` threads, which scan segments of the text speculatively
+    CpuParallel { threads: usize },
+    //With the kernel in `cl_part` on the first OpenCL device, the result text is written on the CPU
+    OpenCl
+}
+
+///Why a backend was not used and the text was preprocessed by `Backend::Cpu` instead
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Fallback {
+    //The crate was built without the `opencl` feature
+    #[cfg_attr(feature = "opencl", allow(dead_code))]
+    FeatureDisabled,
+    //No OpenCL platform is installed, the message is the one of the OpenCL error
+    #[cfg_attr(not(feature = "opencl"), allow(dead_code))]
+    NoPlatform(String),
+    //The OpenCL platform has no device
+    #[cfg_attr(not(feature = "opencl"), allow(dead_code))]
+    NoDevice,
+    //Building or running the kernel failed
+    #[cfg_attr(not(feature = "opencl"), allow(dead_code))]
+    OpenCl(String)
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fallback::FeatureDisabled => write!(f, "built without the opencl feature"),
+            Fallback::NoPlatform(message) => write!(f, "no OpenCL platform found: {}", message),
+            Fallback::NoDevice => write!(f, "the OpenCL platform has no devices"),
+            Fallback::OpenCl(message) => write!(f, "OpenCL failed: {}", message)
+        }
+    }
+}
+
+///The backend which actually preprocessed a text
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BackendReport {
+    pub(crate) used: Backend,
+    //Set if the requested backend could not be used
+    pub(crate) fallback: Option<Fallback>
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct UnknownBackend(String);
+
+impl fmt::Display for UnknownBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown backend {:?}, expected cpu, cpu-parallel or opencl", self.0)
+    }
+}
+
+impl std::error::Error for UnknownBackend {}
+
+impl FromStr for Backend {
+    type Err = UnknownBackend;
+
+    ///Parses `cpu`, `cpu-parallel` with a thread per available core, `cpu-parallel=<threads>`
+    ///or `opencl`
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.trim().to_ascii_lowercase();
+        match name.as_str() {
+            "cpu" => Ok(Backend::Cpu),
+            "cpu-parallel" => Ok(Backend::CpuParallel { threads: available_threads() }),
+            "opencl" => Ok(Backend::OpenCl),
+            _ => name.strip_prefix("cpu-parallel=")
+                .and_then(|threads| threads.parse().ok())
+                .filter(|threads| *threads > 0)
+                .map(|threads| Backend::CpuParallel { threads })
+                .ok_or(UnknownBackend(name))
+        }
+    }
+}
+
+impl Backend {
+    ///Preprocesses the UTF-8 encoded text like `preprocess_with_options`. If the backend is not
+    ///available the text is preprocessed by `Backend::Cpu`, the report tells why
+    pub(crate) fn preprocess(&self, source_text: &str, result_text: &mut [u8], options: &PreprocessOptions)
+                             -> Result<(Preprocessed, BackendReport), PreprocessError> {
+        let source = source_text.as_bytes();
+        let report = |used| BackendReport { used, fallback: None };
+        match self {
+            Backend::Cpu => Ok((preprocess_with_options(source, result_text, options)?, report(Backend::Cpu))),
+            Backend::CpuParallel { threads } => {
+                Ok((preprocess_parallel(source, result_text, options, *threads)?, report(*self)))
+            }
+            Backend::OpenCl => match find_comments_opencl(source) {
+                Ok(comments) => {
+                    let boundaries = segment_boundaries(source.len(), available_threads());
+                    Ok((write_result(source, comments, &boundaries, result_text, options)?, report(Backend::OpenCl)))
+                }
+                Err(OpenClFailure::Preprocess(error)) => Err(error),
+                Err(OpenClFailure::Unavailable(fallback)) => {
+                    let preprocessed = preprocess_with_options(source, result_text, options)?;
+                    Ok((preprocessed, BackendReport { used: Backend::Cpu, fallback: Some(fallback) }))
+                }
+            }
+        }
+    }
+
+    ///Preprocesses every source on its own like `preprocess_batch_to_strings`, the OpenCL backend
+    ///finds the comments of all sources with one dispatch. `Backend::Cpu` takes one file after
+    ///another, `Backend::CpuParallel` scans several files at once
+    pub(crate) fn preprocess_batch(&self, sources: &[&str], options: &PreprocessOptions)
+                                   -> (Vec<PreprocessedFile>, BackendReport) {
+        let packed = PackedSources::pack(sources);
+        let report = |used| BackendReport { used, fallback: None };
+        match self {
+            Backend::Cpu => (preprocess_packed(&packed, options, 1), report(Backend::Cpu)),
+            Backend::CpuParallel { threads } => (preprocess_packed(&packed, options, *threads), report(*self)),
+            Backend::OpenCl => match find_comments_in_files_opencl(&packed) {
+                Ok(files) => {
+                    let preprocessed = for_each_file(&packed, available_threads(), |file| {
+                        files[file].clone().map(|comments| {
+                            let (result_text, preprocessed) = replay(packed.file(file), comments, options);
+                            (into_string(result_text), preprocessed)
+                        })
+                    });
+                    (preprocessed, report(Backend::OpenCl))
+                }
+                Err(fallback) => {
+                    let preprocessed = preprocess_packed(&packed, options, 1);
+                    (preprocessed, BackendReport { used: Backend::Cpu, fallback: Some(fallback) })
+                }
+            }
+        }
+    }
+
+    ///Matches the brackets of a text masked by `mask_delimiters` like `match_brackets`. If the
+    ///backend is not available they are matched by `Backend::Cpu`, the report tells why
+    pub(crate) fn match_brackets(&self, masked: &[u8]) -> (Brackets, BackendReport) {
+        let report = |used| BackendReport { used, fallback: None };
+        match self {
+            Backend::Cpu => (match_brackets(masked), report(Backend::Cpu)),
+            Backend::CpuParallel { threads } => {
+                (match_brackets_segments(masked, &segment_boundaries(masked.len(), *threads)), report(*self))
+            }
+            Backend::OpenCl => match match_brackets_opencl(masked) {
+                Ok(brackets) => (brackets, report(Backend::OpenCl)),
+                Err(fallback) => (match_brackets(masked), BackendReport { used: Backend::Cpu, fallback: Some(fallback) })
+            }
+        }
+    }
+
+    ///Indexes the delimiters of a preprocessed UTF-8 encoded text like `index_delimiters`, with
+    ///the brackets matched by the backend
+    pub(crate) fn index_delimiters(&self, source_text: &str, result_text: &[u8], preprocessed: &Preprocessed)
+                                   -> (Result<DelimiterIndex, DelimiterError>, BackendReport) {
+        let masked = mask_delimiters(result_text, preprocessed);
+        let (brackets, report) = self.match_brackets(&masked);
+        (build_index(source_text.as_bytes(), &masked, &brackets, &preprocessed.source_map), report)
+    }
+}
+
+#[cfg_attr(not(feature = "opencl"), allow(dead_code))]
+enum OpenClFailure {
+    Unavailable(Fallback),
+    Preprocess(PreprocessError)
+}
+
+#[cfg(feature = "opencl")]
+fn fallback(error: OpenClError) -> Fallback {
+    match error {
+        OpenClError::NoPlatform(error) => Fallback::NoPlatform(error.to_string()),
+        OpenClError::NoDevice => Fallback::NoDevice,
+        error => Fallback::OpenCl(error.to_string())
+    }
+}
+
+#[cfg(feature = "opencl")]
+fn find_comments_opencl(source: &[u8]) -> Result<Vec<Comment>, OpenClFailure> {
+    cl_part::find_comments(source).map_err(|error| match error {
+        OpenClError::Preprocess(error) => OpenClFailure::Preprocess(error),
+        error => OpenClFailure::Unavailable(fallback(error))
+    })
+}
+
+#[cfg(feature = "opencl")]
+fn find_comments_in_files_opencl(packed: &PackedSources) -> Result<Vec<Result<Vec<Comment>, PreprocessError>>, Fallback> {
+    cl_part::find_comments_in_files(packed).map_err(fallback)
+}
+
+#[cfg(not(feature = "opencl"))]
+fn find_comments_opencl(_source: &[u8]) -> Result<Vec<Comment>, OpenClFailure> {
+    Err(OpenClFailure::Unavailable(Fallback::FeatureDisabled))
+}
+
+#[cfg(not(feature = "opencl"))]
+fn find_comments_in_files_opencl(_packed: &PackedSources) -> Result<Vec<Result<Vec<Comment>, PreprocessError>>, Fallback> {
+    Err(Fallback::FeatureDisabled)
+}
+
+#[cfg(feature = "opencl")]
+fn match_brackets_opencl(masked: &[u8]) -> Result<Brackets, Fallback> {
+    cl_part::match_brackets(masked).map_err(fallback)
+}
+
+#[cfg(not(feature = "opencl"))]
+fn match_brackets_opencl(_masked: &[u8]) -> Result<Brackets, Fallback> {
+    Err(Fallback::FeatureDisabled)
+}
@@ -0,0 +1,107 @@
+use ocl::{Device, DeviceType, Platform};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+use super::OpenClError;
+
+///The environment variable `DeviceSelector::from_env` reads, e.g. `platform=pocl,type=cpu,index=1`
+pub(crate) const DEVICE_VARIABLE: &str = "WATERBOTTLE_OPENCL_DEVICE";
+
+///Chooses the OpenCL device to run on. Of the devices of the platforms whose name contains
+///`platform_name` and which are of `device_type`, the one at `index` is taken
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct DeviceSelector {
+    //Compared case-insensitively, any platform if it is None
+    pub(crate) platform_name: Option<String>,
+    //Any type if it is None
+    pub(crate) device_type: Option<DeviceType>,
+    //Counted over the matching devices of all matching platforms in order
+    pub(crate) index: usize
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct InvalidSelector(String);
+
+impl fmt::Display for InvalidSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid device selector {:?}, expected e.g. platform=<name>,type=gpu,index=0", self.0)
+    }
+}
+
+impl std::error::Error for InvalidSelector {}
+
+impl FromStr for DeviceSelector {
+    type Err = InvalidSelector;
+
+    ///Parses comma separated `platform=<name>`, `type=<cpu|gpu|accelerator|default|all>` and
+    ///`index=<n>` settings, any of which may be left out
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidSelector(text.to_string());
+        let mut selector = DeviceSelector::default();
+        for setting in text.split(',').map(str::trim).filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+            match key.trim().to_ascii_lowercase().as_str() {
+                "platform" => selector.platform_name = Some(value.trim().to_string()),
+                "type" => selector.device_type = Some(parse_device_type(value.trim()).ok_or_else(invalid)?),
+                "index" => selector.index = value.trim().parse().map_err(|_| invalid())?,
+                _ => return Err(invalid())
+            }
+        }
+        Ok(selector)
+    }
+}
+
+fn parse_device_type(name: &str) -> Option<DeviceType> {
+    match name.to_ascii_lowercase().as_str() {
+        "cpu" => Some(DeviceType::CPU),
+        "gpu" => Some(DeviceType::GPU),
+        "accelerator" => Some(DeviceType::ACCELERATOR),
+        "default" => Some(DeviceType::DEFAULT),
+        "all" => Some(DeviceType::ALL),
+        _ => None
+    }
+}
+
+impl DeviceSelector {
+    ///Reads the selector from `WATERBOTTLE_OPENCL_DEVICE`, the first device is taken if it is not set
+    pub(crate) fn from_env() -> Result<Self, InvalidSelector> {
+        match env::var(DEVICE_VARIABLE) {
+            Ok(text) => text.parse(),
+            Err(_) => Ok(DeviceSelector::default())
+        }
+    }
+
+    fn matches_platform(&self, platform: &Platform) -> bool {
+        self.platform_name.as_ref().is_none_or(|name| {
+            platform.name()
+                .map(|platform_name| platform_name.to_lowercase().contains(&name.to_lowercase()))
+                .unwrap_or(false)
+        })
+    }
+
+    ///Finds the selected device and its platform
+    pub(crate) fn select(&self) -> Result<(Platform, Device), OpenClError> {
+        let platforms = ocl::core::get_platform_ids()
+            .map_err(|error| OpenClError::NoPlatform(error.into()))?;
+        if platforms.is_empty() {
+            return Err(OpenClError::NoPlatform(ocl::Error::from("there are no OpenCL platforms")));
+        }
+        let platforms = Platform::list_from_core(platforms)
+            .into_iter()
+            .filter(|platform| self.matches_platform(platform))
+            .collect::<Vec<Platform>>();
+        if platforms.is_empty() {
+            let name = self.platform_name.as_deref().unwrap_or_default();
+            return Err(OpenClError::NoPlatform(ocl::Error::from(format!("no platform is named like {:?}", name))));
+        }
+
+        let mut devices = Vec::new();
+        for platform in platforms {
+            for device in Device::list(platform, Some(self.device_type.unwrap_or(DeviceType::ALL)))? {
+                devices.push((platform, device));
+            }
+        }
+        devices.into_iter().nth(self.index).ok_or(OpenClError::NoDevice)
+    }
+}
@@ -1,14 +1,23 @@
-/*
-use ocl::{Platform, DeviceType, Buffer, Queue, Context, Program, flags, Kernel};
-use ocl::builders::{ContextBuilder};
+use ocl::{Buffer, Queue, Program, flags, Kernel};
+use ocl::builders::ContextBuilder;
 use ocl::enums::DeviceSpecifier;
-use ocl::Device;
-use std::ffi::CString;
-use ocl::core::KernelWorkGroupInfo;
-use std::fmt::Debug;
-use std::convert::TryInto;
-use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::OnceLock;
 
+use crate::delimiters::{stitch_brackets, Brackets, Unmatched};
+use crate::preprocessor::batch::PackedSources;
+use crate::preprocessor::parallel::{stitch, Speculation};
+use crate::preprocessor::{Comment, CommentType, PreprocessError, PreprocessOptions};
+
+pub(crate) mod device;
+pub(crate) mod program;
+
+pub(crate) use self::device::{DeviceSelector, InvalidSelector};
+pub(crate) use self::program::{BuildDiagnostics, ProgramCache};
+
+///The amount of bytes every work item of `find_pairs` and `match_brackets` scans
+const SEGMENT_LENGTH: usize = 4096;
 
 #[derive(Debug)]
 pub struct CommentPair {
@@ -17,138 +26,561 @@ pub struct CommentPair {
     pub(crate) t: u8
 }
 
-pub(crate) fn run_opencl_part(text: &[u8]) -> ocl::Result<Vec<CommentPair>> {
-    let (platform, device, context) = find_default_opencl_requirements()?;
-    println!("Platform version: {}", platform.version().unwrap_or("Failed to get version".to_string()));
+///Set in `t` for literals containing escape sequences
+const HAS_ESCAPES: u8 = 0x80;
+
+impl CommentPair {
+    ///The type of the pair as numbered by the kernel
+    fn comment_type(&self, text: &[u8]) -> CommentType {
+        //The hashes of raw strings follow the r, which may be preceded by b or c
+        let hashes = |prefix: usize| text[(self.begin + prefix)..].iter().take_while(|c| **c == b'#').count();
+        match self.t & !HAS_ESCAPES {
+            0 => CommentType::DoubleSlash,
+            1 => CommentType::SlashStar,
+            2 => CommentType::OuterLineDoc,
+            3 => CommentType::OuterBlockDoc,
+            4 => CommentType::InnerLineDoc,
+            5 => CommentType::InnerBlockDoc,
+            6 => CommentType::String,
+            7 => CommentType::ByteString,
+            8 => CommentType::RawString { preceding_hashes: hashes(1) },
+            9 => CommentType::ByteRawString { preceding_hashes: hashes(2) },
+            10 => CommentType::CString,
+            11 => CommentType::RawCString { preceding_hashes: hashes(2) },
+            12 => CommentType::CharLiteral,
+            13 => CommentType::ByteCharLiteral,
+            14 => CommentType::Whitespace,
+            15 => CommentType::Shebang,
+            t => panic!("Unknown pair type {}", t)
+        }
+    }
+
+    fn to_comment(&self, text: &[u8]) -> Comment {
+        Comment {
+            begin: self.begin,
+            length: self.end - self.begin,
+            comment_type: self.comment_type(text),
+            has_escapes: self.t & HAS_ESCAPES != 0
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum OpenClError {
+    //No OpenCL platform is installed, e.g. because there is only the ICD loader
+    NoPlatform(ocl::Error),
+    //The platform has no device, or none which is selected
+    NoDevice,
+    InvalidSelector(InvalidSelector),
+    //The compiler rejected the program
+    Build(BuildDiagnostics),
+    Ocl(ocl::Error),
+    //The text itself cannot be preprocessed, e.g. because of an unterminated string
+    Preprocess(PreprocessError),
+    //An offset, length or capacity does not fit into the `int` arguments of the kernels
+    TooLarge(usize)
+}
+
+impl fmt::Display for OpenClError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenClError::NoPlatform(error) => write!(f, "no OpenCL platform found: {}", error),
+            OpenClError::NoDevice => write!(f, "the OpenCL platform has no devices"),
+            OpenClError::InvalidSelector(error) => error.fmt(f),
+            OpenClError::Build(diagnostics) => diagnostics.fmt(f),
+            OpenClError::Ocl(error) => write!(f, "OpenCL error: {}", error),
+            OpenClError::Preprocess(error) => error.fmt(f),
+            OpenClError::TooLarge(value) => write!(f, "{} does not fit into the 32-bit offsets of the kernels", value)
+        }
+    }
+}
+
+impl std::error::Error for OpenClError {}
+
+///Converts an offset, length or capacity to the `int` the kernels take
+fn kernel_int(value: usize) -> Result<i32, OpenClError> {
+    i32::try_from(value).map_err(|_| OpenClError::TooLarge(value))
+}
+
+///Checks that the offsets in a text of `length` bytes stay `int`s in the kernels, which add a
+///segment length or a few bytes of lookahead to them
+fn check_kernel_offsets(length: usize, segment_length: usize) -> Result<(), OpenClError> {
+    kernel_int(length.saturating_add(segment_length.max(4))).map(|_| ())
+}
+
+///Converts the offsets to the `int`s the kernels take
+fn kernel_ints(values: impl Iterator<Item=usize>) -> Result<Vec<i32>, OpenClError> {
+    values.map(kernel_int).collect()
+}
+
+impl From<InvalidSelector> for OpenClError {
+    fn from(error: InvalidSelector) -> Self {
+        OpenClError::InvalidSelector(error)
+    }
+}
+
+impl From<ocl::Error> for OpenClError {
+    fn from(error: ocl::Error) -> Self {
+        OpenClError::Ocl(error)
+    }
+}
+
+impl From<ocl::core::Error> for OpenClError {
+    fn from(error: ocl::core::Error) -> Self {
+        OpenClError::Ocl(error.into())
+    }
+}
+
+///Finds the comments and literals of the UTF-8 encoded text on the first OpenCL device
+pub(crate) fn find_comments(text: &[u8]) -> Result<Vec<Comment>, OpenClError> {
+    find_comments_opencl(text, SEGMENT_LENGTH, initial_capacity(SEGMENT_LENGTH))
+}
+
+///Finds the comments and literals of all packed files with one dispatch on the first OpenCL
+///device, the offsets of the comments are relative to the file they are in
+pub(crate) fn find_comments_in_files(packed: &PackedSources)
+                                     -> Result<Vec<Result<Vec<Comment>, PreprocessError>>, OpenClError> {
+    OpenClContext::shared()?.find_comments_in_files(packed.text(), packed.boundaries(), SEGMENT_LENGTH,
+                                                    initial_capacity(SEGMENT_LENGTH))
+}
+
+///Matches the brackets of the masked result text on the first OpenCL device, like `match_brackets`
+pub(crate) fn match_brackets(masked: &[u8]) -> Result<Brackets, OpenClError> {
+    OpenClContext::shared()?.match_brackets(masked, SEGMENT_LENGTH, initial_capacity(SEGMENT_LENGTH))
+}
+
+///The pairs every work item has room for at first. Every pair is at least a byte long, but
+///most code has a lot fewer pairs than bytes, segments with more let the kernel run again
+fn initial_capacity(segment_length: usize) -> usize {
+    (segment_length / 16).max(1)
+}
+
+///The pairs found by one run of `find_pairs`, `capacity` slots per segment
+struct KernelPairs {
+    capacity: usize,
+    begins: Vec<i32>,
+    ends: Vec<i32>,
+    types: Vec<u8>,
+    amounts: Vec<i32>,
+    stops: Vec<i32>
+}
+
+impl KernelPairs {
+    ///The capacity required to store the pairs of every segment, if some segment found more
+    ///pairs than it had room for
+    fn required_capacity(&self) -> Option<usize> {
+        self.amounts.iter()
+            .map(|amount| *amount as usize)
+            .max()
+            .filter(|amount| *amount > self.capacity)
+    }
+
+    ///The speculation of the segment beginning at `segment_begin`, with offsets relative to the
+    ///file it lies in
+    fn speculation(&self, text: &[u8], segment: usize, segment_begin: usize, file_begin: usize) -> Speculation {
+        let slots = (segment * self.capacity)..(segment * self.capacity + self.amounts[segment] as usize);
+        let comments = slots
+            .map(|slot| {
+                let comment = CommentPair {
+                    begin: self.begins[slot] as usize,
+                    end: self.ends[slot] as usize,
+                    t: self.types[slot]
+                }.to_comment(text);
+                Comment { begin: comment.begin - file_begin, ..comment }
+            })
+            .collect();
+        Speculation::from_comments(segment_begin - file_begin, comments, self.stops[segment] as usize - file_begin)
+    }
+}
+
+///Lets every work item scan a segment of `segment_length` bytes and stitches the segments
+///together. The device is the one selected by `WATERBOTTLE_OPENCL_DEVICE`
+pub(crate) fn find_comments_opencl(text: &[u8], segment_length: usize, capacity: usize)
+                                   -> Result<Vec<Comment>, OpenClError> {
+    OpenClContext::shared()?.find_comments(text, segment_length, capacity)
+}
+
+///The device and the built program, which can be used for any amount of texts
+pub(crate) struct OpenClContext {
+    queue: Queue,
+    program: Program,
+    diagnostics: BuildDiagnostics
+}
+
+impl OpenClContext {
+    ///Builds the program for the selected device, or takes the binary from the cache
+    pub(crate) fn new(selector: &DeviceSelector, cache: Option<&ProgramCache>) -> Result<Self, OpenClError> {
+        let (platform, device) = selector.select()?;
+        let context = ContextBuilder::new().platform(platform).devices(DeviceSpecifier::Single(device)).build()?;
+        let (program, diagnostics) = program::build_program(&platform, &device, &context, "", cache)?;
+        let queue = Queue::new(&context, device, None)?;
+        Ok(OpenClContext { queue, program, diagnostics })
+    }
+
+    ///Selects the device and the cache as configured by the environment variables
+    pub(crate) fn from_env() -> Result<Self, OpenClError> {
+        OpenClContext::new(&DeviceSelector::from_env()?, ProgramCache::from_env().as_ref())
+    }
+
+    ///The context `from_env` returns, which is only built by the first call that succeeds, so
+    ///that the program is compiled once per process
+    pub(crate) fn shared() -> Result<&'static OpenClContext, OpenClError> {
+        static SHARED: OnceLock<OpenClContext> = OnceLock::new();
+        if let Some(context) = SHARED.get() {
+            return Ok(context);
+        }
+        //Another thread may have built one meanwhile, then this one is dropped
+        let context = OpenClContext::from_env()?;
+        Ok(SHARED.get_or_init(|| context))
+    }
+
+    ///What the compiler reported when building the program, e.g. its warnings
+    pub(crate) fn diagnostics(&self) -> &BuildDiagnostics {
+        &self.diagnostics
+    }
+
+    ///Lets every work item scan a segment of `segment_length` bytes and stitches the segments
+    ///together. Every work item has room for `capacity` pairs, if that is too few for some
+    ///segment the kernel is run again with enough room
+    pub(crate) fn find_comments(&self, text: &[u8], segment_length: usize, capacity: usize)
+                                -> Result<Vec<Comment>, OpenClError> {
+        let mut files = self.find_comments_in_files(text, &[0, text.len()], segment_length, capacity)?;
+        files.pop().expect("The text is one file").map_err(OpenClError::Preprocess)
+    }
+
+    ///Like `find_comments` for every file of the text, file `i` spans `boundaries[i]..boundaries[i + 1]`.
+    ///The files are split into segments on their own and scanned with one dispatch, the offsets
+    ///of the comments are relative to the file they are in
+    pub(crate) fn find_comments_in_files(&self, text: &[u8], boundaries: &[usize], segment_length: usize,
+                                         capacity: usize) -> Result<Vec<Result<Vec<Comment>, PreprocessError>>, OpenClError> {
+        //The beginnings of the segments of every file
+        let segments = boundaries.windows(2)
+            .map(|file| (file[0]..file[1]).step_by(segment_length).collect::<Vec<usize>>())
+            .collect::<Vec<Vec<usize>>>();
+        //The file of every segment
+        let files = segments.iter()
+            .zip(boundaries.windows(2))
+            .flat_map(|(file_segments, file)| file_segments.iter().map(move |_| file))
+            .collect::<Vec<&[usize]>>();
+        let segment_count = segments.iter().map(Vec::len).sum::<usize>();
+        //Buffers cannot be empty, and files without segments have no comments
+        if segment_count == 0 {
+            return Ok(vec![Ok(Vec::new()); segments.len()]);
+        }
+
+        //The kernel takes `int` offsets, they are converted before anything is copied to the device
+        check_kernel_offsets(text.len(), segment_length)?;
+        let segment_begins = kernel_ints(segments.iter().flatten().copied())?;
+        let file_begins = kernel_ints(files.iter().map(|file| file[0]))?;
+        let file_ends = kernel_ints(files.iter().map(|file| file[1]))?;
+
+        let queue = &self.queue;
+        let buffers = SegmentBuffers {
+            text: Buffer::<u8>::builder()
+                .queue(queue.clone())
+                .flags(flags::MEM_READ_ONLY)
+                .len(text.len())
+                .copy_host_slice(text)
+                .build()?,
+            segment_begins: Buffer::<i32>::builder()
+                .queue(queue.clone())
+                .flags(flags::MEM_READ_ONLY)
+                .len(segment_count)
+                .copy_host_slice(&segment_begins)
+                .build()?,
+            file_begins: Buffer::<i32>::builder()
+                .queue(queue.clone())
+                .flags(flags::MEM_READ_ONLY)
+                .len(segment_count)
+                .copy_host_slice(&file_begins)
+                .build()?,
+            file_ends: Buffer::<i32>::builder()
+                .queue(queue.clone())
+                .flags(flags::MEM_READ_ONLY)
+                .len(segment_count)
+                .copy_host_slice(&file_ends)
+                .build()?,
+            segments: segment_count
+        };
+
+        let run = |capacity| find_pairs(&self.program, queue, &buffers, segment_length, capacity);
+        let mut pairs = run(capacity.max(1))?;
+        //The kernel counts the pairs it has no room for, so a second run always fits them
+        if let Some(capacity) = pairs.required_capacity() {
+            pairs = run(capacity)?;
+        }
+
+        let mut first_segment = 0;
+        let files = boundaries.windows(2)
+            .zip(&segments)
+            .map(|(file, file_segments)| {
+                let (file_begin, file_text) = (file[0], &text[file[0]..file[1]]);
+                if file_text.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let speculations = file_segments.iter()
+                    .enumerate()
+                    .map(|(i, begin)| pairs.speculation(text, first_segment + i, *begin, file_begin))
+                    .collect::<Vec<Speculation>>();
+                first_segment += file_segments.len();
+                let file_boundaries = file_segments.iter()
+                    .map(|begin| begin - file_begin)
+                    .chain(std::iter::once(file_text.len()))
+                    .collect::<Vec<usize>>();
+                stitch(file_text, &file_boundaries, &speculations, &PreprocessOptions::default())
+            })
+            .collect();
+        Ok(files)
+    }
+}
+
+impl OpenClContext {
+    ///Lets every work item match the brackets of a segment of `segment_length` bytes, what the
+    ///segments leave unmatched is matched on the host. Every work item has room for `capacity`
+    ///unmatched delimiters of either direction, the kernel is run again if that is too few
+    pub(crate) fn match_brackets(&self, masked: &[u8], segment_length: usize, capacity: usize)
+                                 -> Result<Brackets, OpenClError> {
+        //Buffers cannot be empty
+        if masked.is_empty() {
+            return Ok(Brackets::default());
+        }
+        //The kernels take `int` offsets, this is checked before anything is copied to the device
+        check_kernel_offsets(masked.len(), segment_length)?;
+        let length = kernel_int(masked.len())?;
+        let queue = &self.queue;
+        let segments = masked.len().div_ceil(segment_length);
+        let text = Buffer::<u8>::builder()
+            .queue(queue.clone())
+            .flags(flags::MEM_READ_ONLY)
+            .len(masked.len())
+            .copy_host_slice(masked)
+            .build()?;
+        let depth_buffer = Buffer::<i32>::builder()
+            .queue(queue.clone())
+            .flags(flags::MEM_READ_WRITE)
+            .len(masked.len())
+            .build()?;
+
+        let run = |capacity| segment_brackets(&self.program, queue, &text, masked.len(), &depth_buffer, segment_length,
+                                              segments, capacity);
+        let mut segment_matches = run(capacity.max(1))?;
+        //The kernel counts the delimiters it has no room for, so a second run always fits them
+        if let Some(capacity) = segment_matches.required_capacity() {
+            segment_matches = run(capacity)?;
+        }
+
+        let unmatched = (0..segments).map(|segment| segment_matches.unmatched(segment)).collect::<Vec<Unmatched>>();
+        let mut matches = segment_matches.matches.iter()
+            .map(|&open| if open < 0 { None } else { Some(open as usize) })
+            .collect::<Vec<Option<usize>>>();
+        let bases = stitch_brackets(&unmatched, &mut matches);
+
+        let base_buffer = Buffer::<i32>::builder()
+            .queue(queue.clone())
+            .flags(flags::MEM_READ_ONLY)
+            .len(segments)
+            .copy_host_slice(&bases)
+            .build()?;
+        let kernel = Kernel::builder()
+            .program(&self.program)
+            .name("add_depth_bases")
+            .queue(queue.clone())
+            .arg(&depth_buffer)
+            .arg(length)
+            .arg(kernel_int(segment_length)?)
+            .arg(&base_buffer)
+            .global_work_size(segments)
+            .build()?;
+        unsafe {
+            kernel.cmd()
+                .queue(queue)
+                .enq()?;
+        }
+        queue.finish()?;
+
+        let mut depths = vec![0i32; masked.len()];
+        depth_buffer.cmd().queue(queue).offset(0).read(&mut depths).enq()?;
+        Ok(Brackets { depths, matches })
+    }
+}
+
+///What `match_brackets` found for every segment, `capacity` slots per segment for the
+///unmatched delimiters
+struct SegmentMatches {
+    capacity: usize,
+    matches: Vec<i32>,
+    deltas: Vec<i32>,
+    closes: Vec<i32>,
+    close_amounts: Vec<i32>,
+    opens: Vec<i32>,
+    open_amounts: Vec<i32>
+}
+
+impl SegmentMatches {
+    ///The capacity required to store the unmatched delimiters of every segment, if some segment
+    ///left more unmatched than it had room for
+    fn required_capacity(&self) -> Option<usize> {
+        self.close_amounts.iter()
+            .chain(&self.open_amounts)
+            .map(|amount| *amount as usize)
+            .max()
+            .filter(|amount| *amount > self.capacity)
+    }
 
-    let program = make_program(&context, &device, "").expect("Unable to create program");
-    println!("Program status:");
-    println!("\tLog: {:?}", program.build_info(device, ocl::core::ProgramBuildInfo::BuildLog)?);
-    println!("\tBinType: {:?}", program.build_info(device, ocl::core::ProgramBuildInfo::BinaryType)?);
-    println!("\tStatus: {:?}", program.build_info(device, ocl::core::ProgramBuildInfo::BuildStatus)?);
-    println!("\tOptions: {:?}", program.build_info(device, ocl::core::ProgramBuildInfo::BuildOptions)?);
+    fn unmatched(&self, segment: usize) -> Unmatched {
+        let slots = |amounts: &[i32]| (segment * self.capacity)..(segment * self.capacity + amounts[segment] as usize);
+        Unmatched {
+            delta: self.deltas[segment],
+            closes: self.closes[slots(&self.close_amounts)].iter().map(|&close| close as usize).collect(),
+            opens: self.opens[slots(&self.open_amounts)].iter().map(|&open| open as usize).collect()
+        }
+    }
+}
 
-    let queue = Queue::new(&context, device, None).expect("Unable to create queue");
+///Runs `match_brackets` over the segments of the masked text with room for `capacity` unmatched
+///delimiters per segment, the depths relative to the segments are left in `depth_buffer`
+#[allow(clippy::too_many_arguments)]
+fn segment_brackets(program: &Program, queue: &Queue, text: &Buffer<u8>, length: usize, depth_buffer: &Buffer<i32>,
+                    segment_length: usize, segments: usize, capacity: usize) -> Result<SegmentMatches, OpenClError> {
+    //The arguments are checked before the buffers are allocated
+    let length_arg = kernel_int(length)?;
+    let (segment_length_arg, capacity_arg) = (kernel_int(segment_length)?, kernel_int(capacity)?);
+    let buffer = |len| Buffer::<i32>::builder()
+        .queue(queue.clone())
+        .flags(flags::MEM_READ_WRITE)
+        .len(len)
+        .build();
+    let match_buffer = buffer(length)?;
+    let delta_buffer = buffer(segments)?;
+    let close_buffer = buffer(segments * capacity)?;
+    let close_amount_buffer = buffer(segments)?;
+    let open_buffer = buffer(segments * capacity)?;
+    let open_amount_buffer = buffer(segments)?;
 
-    let text_buffer = Buffer::<u8>::builder()
+    let kernel = Kernel::builder()
+        .program(program)
+        .name("match_brackets")
         .queue(queue.clone())
-        .flags(flags::MEM_READ_ONLY)
-        .len(text.len())
-        .copy_host_slice(&text)
+        .arg(text)
+        .arg(length_arg)
+        .arg(segment_length_arg)
+        .arg(capacity_arg)
+        .arg(depth_buffer)
+        .arg(&match_buffer)
+        .arg(&delta_buffer)
+        .arg(&close_buffer)
+        .arg(&close_amount_buffer)
+        .arg(&open_buffer)
+        .arg(&open_amount_buffer)
+        .global_work_size(segments)
         .build()?;
 
-    const BUFFER_SIZE: i32 = 1024;
+    unsafe {
+        kernel.cmd()
+            .queue(queue)
+            .enq()?;
+    }
+
+    queue.finish()?;
+
+    let mut matches = SegmentMatches {
+        capacity,
+        matches: vec![0i32; length],
+        deltas: vec![0i32; segments],
+        closes: vec![0i32; segments * capacity],
+        close_amounts: vec![0i32; segments],
+        opens: vec![0i32; segments * capacity],
+        open_amounts: vec![0i32; segments]
+    };
+    match_buffer.cmd().queue(queue).offset(0).read(&mut matches.matches).enq()?;
+    delta_buffer.cmd().queue(queue).offset(0).read(&mut matches.deltas).enq()?;
+    close_buffer.cmd().queue(queue).offset(0).read(&mut matches.closes).enq()?;
+    close_amount_buffer.cmd().queue(queue).offset(0).read(&mut matches.close_amounts).enq()?;
+    open_buffer.cmd().queue(queue).offset(0).read(&mut matches.opens).enq()?;
+    open_amount_buffer.cmd().queue(queue).offset(0).read(&mut matches.open_amounts).enq()?;
+    Ok(matches)
+}
 
+///The input of `find_pairs`
+struct SegmentBuffers {
+    text: Buffer<u8>,
+    segment_begins: Buffer<i32>,
+    //The beginning and end of the file of every segment
+    file_begins: Buffer<i32>,
+    file_ends: Buffer<i32>,
+    segments: usize
+}
+
+///Runs `find_pairs` over the segments of the text with room for `capacity` pairs per segment
+fn find_pairs(program: &Program, queue: &Queue, buffers: &SegmentBuffers, segment_length: usize, capacity: usize)
+              -> Result<KernelPairs, OpenClError> {
+    //The arguments are checked before the buffers are allocated
+    let (segment_length_arg, capacity_arg) = (kernel_int(segment_length)?, kernel_int(capacity)?);
+    let segments = buffers.segments;
     let begin_buffer = Buffer::<i32>::builder()
         .queue(queue.clone())
         .flags(flags::MEM_READ_WRITE)
-        .len(BUFFER_SIZE)
+        .len(segments * capacity)
         .build()?;
     let end_buffer = Buffer::<i32>::builder()
         .queue(queue.clone())
         .flags(flags::MEM_READ_WRITE)
-        .len(BUFFER_SIZE)
+        .len(segments * capacity)
         .build()?;
     let type_buffer = Buffer::<u8>::builder()
         .queue(queue.clone())
         .flags(flags::MEM_READ_WRITE)
-        .len(BUFFER_SIZE)
+        .len(segments * capacity)
         .build()?;
     let amount_buffer = Buffer::<i32>::builder()
         .queue(queue.clone())
         .flags(flags::MEM_READ_WRITE)
-        .len(1)
+        .len(segments)
+        .build()?;
+    let stop_buffer = Buffer::<i32>::builder()
+        .queue(queue.clone())
+        .flags(flags::MEM_READ_WRITE)
+        .len(segments)
         .build()?;
 
     let kernel = Kernel::builder()
-        .program(&program)
+        .program(program)
         .name("find_pairs")
         .queue(queue.clone())
-        .arg(&text_buffer)
+        .arg(&buffers.text)
+        .arg(&buffers.segment_begins)
+        .arg(&buffers.file_begins)
+        .arg(&buffers.file_ends)
+        .arg(segment_length_arg)
+        .arg(capacity_arg)
         .arg(&begin_buffer)
         .arg(&end_buffer)
         .arg(&type_buffer)
         .arg(&amount_buffer)
-        .global_work_size(1)
-        .local_work_size(1)
+        .arg(&stop_buffer)
+        .global_work_size(segments)
         .build()?;
 
-    let mut work_items= kernel.wg_info(device, KernelWorkGroupInfo::PreferredWorkGroupSizeMultiple)?;
-    //work_items.into()
-    println!("{:?}", work_items);
-
-    let tmp;
     unsafe {
-
-        tmp = kernel.cmd()
-            .queue(&queue)
-            .enq();
+        kernel.cmd()
+            .queue(queue)
+            .enq()?;
     }
-    tmp?;
 
     queue.finish()?;
 
-    let mut begins = vec![0i32; BUFFER_SIZE as usize];
-    let mut ends = vec![0i32; BUFFER_SIZE as usize];
-    let mut types = vec![0u8; BUFFER_SIZE as usize];
-    let mut amount = vec![0; 1];
-    begin_buffer.cmd().queue(&queue).offset(0).
-        read(&mut begins).
-        enq()?;
-    end_buffer.cmd().queue(&queue).offset(0).
-        read(&mut ends).
-        enq()?;
-    type_buffer.cmd().queue(&queue).offset(0).
-        read(&mut types).
-        enq()?;
-    amount_buffer.cmd().queue(&queue).offset(0).
-        read(&mut amount).
-        enq()?;
-
-    let mut ret = Vec::with_capacity(amount[0] as usize);
-
-    for i in 0..(amount[0] as usize) {
-        ret.push(CommentPair {
-            begin: begins[i] as usize,
-            end: ends[i] as usize,
-            t: types[i]
-        });
-    }
-
-    Ok(ret)
-}
-
-fn find_default_opencl_requirements() -> ocl::Result<(Platform, Device, Context)> {
-    let platform = Platform::first()?;
-
-    //Fetch first device
-    let device = Device::list(platform, Option::Some(DeviceType::ALL)).expect("Unable to fetch devices").pop().expect("No devices available");
-    let context = ContextBuilder::new().platform(platform).devices(DeviceSpecifier::Single(device)).build().expect("Unable to build context");
-
-    Ok((platform, device, context))
-}
-
-fn make_program(context: &Context, device: &Device, compiler_options: &str) -> ocl::Result<Program> {
-    //Leave empty for now
-    let input_headers_map = std::collections::HashMap::<&str, &ocl::core::Program>::new();
-
-    let mut input_header_names = Vec::<CString>::with_capacity(input_headers_map.len());
-    let mut input_header_contents= Vec::<&ocl::core::Program>::with_capacity(input_headers_map.len());
-
-    for (header_name, header_content) in input_headers_map {
-        input_header_names.push(CString::new(header_name).expect("Unable to parse name"));
-        input_header_contents.push(header_content);
-    }
-
-    let source_as_c_string = CString::new(include_str!("./resources/test.cl"))?;
-
-    let program =ocl::core::create_program_with_source(context, &[source_as_c_string])?;
-    let compiler_options_as_c_string = CString::new(compiler_options)?;
-
-        //ocl::core::compile_program(&program, Option::Some(&[device]), &compiler_options_as_c_string, input_header_contents.as_slice(), input_header_names.as_slice(), None, None, None)?;
-        //ocl::core::link_program(context, Some(&[device]), &compiler_options_as_c_string, &[&program], None, None, None)?;
-
-    ocl::core::build_program(&program, Some(&[device]), &compiler_options_as_c_string, None, None)?;
-    Ok(ocl::Program::from(program))
+    let mut pairs = KernelPairs {
+        capacity,
+        begins: vec![0i32; segments * capacity],
+        ends: vec![0i32; segments * capacity],
+        types: vec![0u8; segments * capacity],
+        amounts: vec![0i32; segments],
+        stops: vec![0i32; segments]
+    };
+    begin_buffer.cmd().queue(queue).offset(0).read(&mut pairs.begins).enq()?;
+    end_buffer.cmd().queue(queue).offset(0).read(&mut pairs.ends).enq()?;
+    type_buffer.cmd().queue(queue).offset(0).read(&mut pairs.types).enq()?;
+    amount_buffer.cmd().queue(queue).offset(0).read(&mut pairs.amounts).enq()?;
+    stop_buffer.cmd().queue(queue).offset(0).read(&mut pairs.stops).enq()?;
+    Ok(pairs)
 }
-*/
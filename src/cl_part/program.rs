@@ -0,0 +1,222 @@
+use ocl::core::{ProgramBuildInfo, ProgramBuildInfoResult, ProgramInfo, ProgramInfoResult};
+use ocl::{Context, Device, Platform, Program};
+use std::env;
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use super::OpenClError;
+
+///The source of the kernels
+pub(crate) const PROGRAM_SOURCE: &str = include_str!("./resources/test.cl");
+
+///The environment variable naming the directory of the `ProgramCache`, which is off unless it is set
+pub(crate) const CACHE_VARIABLE: &str = "WATERBOTTLE_OPENCL_CACHE";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+    Note
+}
+
+///A message of the compiler, located in the program source if the compiler names the location
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BuildMessage {
+    pub(crate) severity: Severity,
+    pub(crate) line: Option<usize>,
+    pub(crate) column: Option<usize>,
+    pub(crate) message: String
+}
+
+///What the OpenCL compiler reported when building the program for a device
+#[derive(Clone, Debug)]
+pub(crate) struct BuildDiagnostics {
+    //The program was built from a binary in the `ProgramCache`
+    pub(crate) from_cache: bool,
+    pub(crate) messages: Vec<BuildMessage>,
+    //The complete build log, which also holds the lines no message was parsed from
+    pub(crate) log: String
+}
+
+impl BuildDiagnostics {
+    pub(crate) fn errors(&self) -> impl Iterator<Item = &BuildMessage> {
+        self.messages.iter().filter(|message| message.severity == Severity::Error)
+    }
+
+    pub(crate) fn warnings(&self) -> impl Iterator<Item = &BuildMessage> {
+        self.messages.iter().filter(|message| message.severity == Severity::Warning)
+    }
+}
+
+impl fmt::Display for BuildMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}: {}", line, column, self.message),
+            _ => write!(f, "{}", self.message)
+        }
+    }
+}
+
+impl fmt::Display for BuildDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "building the OpenCL program failed")?;
+        if self.errors().next().is_none() {
+            //The compiler does not report its messages in the format `parse_build_log` knows
+            return write!(f, "\n{}", self.log.trim_end());
+        }
+        for message in self.errors() {
+            write!(f, "\n{}", message)?;
+        }
+        Ok(())
+    }
+}
+
+const SEVERITIES: [(Severity, &str); 3] = [(Severity::Error, "error: "), (Severity::Warning, "warning: "),
+                                          (Severity::Note, "note: ")];
+
+///Parses the messages of a build log. Clang based compilers, which most implementations are,
+///report them as `<file>:<line>:<column>: <severity>: <message>`, or without the location, and
+///follow them by lines quoting the source which are skipped
+pub(crate) fn parse_build_log(log: &str) -> Vec<BuildMessage> {
+    log.lines()
+        .filter_map(|text| {
+            let (severity, location, message) = SEVERITIES.iter().find_map(|(severity, marker)| {
+                match text.strip_prefix(marker) {
+                    Some(message) => Some((*severity, "", message)),
+                    None => text.find(&format!(": {}", marker))
+                        .map(|position| (*severity, &text[..position], &text[(position + marker.len() + 2)..]))
+                }
+            })?;
+            //The location ends with the line and column, file names may contain colons
+            let mut numbers = location.rsplit(':').map(|part| part.trim().parse::<usize>().ok());
+            let (column, line) = match (numbers.next().flatten(), numbers.next().flatten()) {
+                (Some(column), Some(line)) => (Some(column), Some(line)),
+                _ => (None, None)
+            };
+            Some(BuildMessage { severity, line, column, message: message.trim().to_string() })
+        })
+        .collect()
+}
+
+///Stores the binaries of built programs, keyed by the device and a hash of the source and
+///compiler options, so that repeated runs don't have to compile the program again
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ProgramCache {
+    pub(crate) directory: PathBuf
+}
+
+impl ProgramCache {
+    ///The directory in `WATERBOTTLE_OPENCL_CACHE`, None if the variable is not set or empty.
+    ///The binaries found in the directory are run on the device, so there is no default one, a
+    ///shared directory like the temporary one would let other users plant binaries
+    pub(crate) fn from_env() -> Option<ProgramCache> {
+        ProgramCache::from_value(env::var_os(CACHE_VARIABLE).as_deref())
+    }
+
+    ///The cache for the value of `WATERBOTTLE_OPENCL_CACHE`, like `from_env`
+    pub(crate) fn from_value(directory: Option<&OsStr>) -> Option<ProgramCache> {
+        directory
+            .filter(|directory| !directory.is_empty())
+            .map(|directory| ProgramCache { directory: PathBuf::from(directory) })
+    }
+
+    ///The file the binary of the program is stored in
+    pub(crate) fn path(&self, platform: &Platform, device: &Device, compiler_options: &str) -> PathBuf {
+        //Another driver version may compile differently or not load the binary at all
+        let driver = device.info(ocl::enums::DeviceInfo::DriverVersion)
+            .map(|version| version.to_string())
+            .unwrap_or_default();
+        let key = [
+            platform.name().unwrap_or_default(),
+            platform.version().unwrap_or_default(),
+            device.name().unwrap_or_default(),
+            device.vendor().unwrap_or_default(),
+            driver,
+            compiler_options.to_string(),
+            PROGRAM_SOURCE.to_string()
+        ];
+        self.directory.join(format!("{:016x}.bin", fnv1a(&key)))
+    }
+
+    fn load(&self, path: &Path) -> Option<Vec<u8>> {
+        fs::read(path).ok().filter(|binary| !binary.is_empty())
+    }
+
+    ///Stores the binary, failing to is no reason to fail the build
+    fn store(&self, path: &Path, binary: &[u8]) {
+        //Other processes may read the cache meanwhile, so the file only appears once it is complete
+        let partial = path.with_extension(format!("{}.partial", process::id()));
+        let stored = fs::create_dir_all(&self.directory)
+            .and_then(|_| fs::write(&partial, binary))
+            .and_then(|_| fs::rename(&partial, path));
+        if stored.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+    }
+}
+
+///The 64 bit FNV-1a hash of the parts, unlike the hasher of the standard library it is the same
+///for every build
+fn fnv1a(parts: &[String]) -> u64 {
+    parts.iter()
+        .flat_map(|part| part.bytes().chain(std::iter::once(0)))
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+///Builds the program for the device, from the binary in the cache if there is one
+pub(crate) fn build_program(platform: &Platform, device: &Device, context: &Context, compiler_options: &str,
+                            cache: Option<&ProgramCache>) -> Result<(Program, BuildDiagnostics), OpenClError> {
+    let options = CString::new(compiler_options).map_err(ocl::Error::from)?;
+    let cached = cache.map(|cache| (cache, cache.path(platform, device, compiler_options)));
+
+    if let Some((cache, path)) = &cached {
+        if let Some(binary) = cache.load(path) {
+            let built = ocl::core::create_program_with_binary(context, &[device], &[&binary])
+                .and_then(|program| ocl::core::build_program(&program, Some(&[device]), &options, None, None)
+                    .map(|_| program));
+            match built {
+                Ok(program) => {
+                    let diagnostics = diagnostics(&program, device, true);
+                    return Ok((Program::from(program), diagnostics));
+                }
+                //The binary is stale or broken, it is replaced by a new one
+                Err(_) => {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+
+    let source = CString::new(PROGRAM_SOURCE).map_err(ocl::Error::from)?;
+    let program = ocl::core::create_program_with_source(context, &[source])?;
+    let built = ocl::core::build_program(&program, Some(&[device]), &options, None, None);
+    let diagnostics = diagnostics(&program, device, false);
+    if built.is_err() {
+        return Err(OpenClError::Build(diagnostics));
+    }
+
+    if let Some((cache, path)) = &cached {
+        if let Ok(ProgramInfoResult::Binaries(binaries)) = ocl::core::get_program_info(&program, ProgramInfo::Binaries) {
+            if let Some(binary) = binaries.first() {
+                cache.store(path, binary);
+            }
+        }
+    }
+    Ok((Program::from(program), diagnostics))
+}
+
+fn diagnostics(program: &ocl::core::Program, device: &Device, from_cache: bool) -> BuildDiagnostics {
+    let info = |request| ocl::core::get_program_build_info(program, device, request).ok();
+    let log = match info(ProgramBuildInfo::BuildLog) {
+        Some(ProgramBuildInfoResult::BuildLog(log)) => log.trim_end_matches('\0').to_string(),
+        _ => String::new()
+    };
+    BuildDiagnostics {
+        from_cache,
+        messages: parse_build_log(&log),
+        log
+    }
+}
@@ -1,20 +1,486 @@
-
 __kernel void test(__global uchar* ret) {
     printf("Global work item %li with local id %li calls in\n", get_global_id(0), get_local_id(0));
 }
 
+//The types of the pairs, the host maps them back to the CommentType with the same number
+#define DOUBLE_SLASH 0
+#define SLASH_STAR 1
+#define OUTER_LINE_DOC 2
+#define OUTER_BLOCK_DOC 3
+#define INNER_LINE_DOC 4
+#define INNER_BLOCK_DOC 5
+#define STRING 6
+#define BYTE_STRING 7
+#define RAW_STRING 8
+#define BYTE_RAW_STRING 9
+#define C_STRING 10
+#define RAW_C_STRING 11
+#define CHAR_LITERAL 12
+#define BYTE_CHAR_LITERAL 13
+#define WHITESPACE 14
+#define SHEBANG 15
+//Set in the type of literals containing escape sequences
+#define HAS_ESCAPES 0x80
+
+//What is found at an index of the document
+#define FOUND_CHAR 0
+#define FOUND_PAIR 1
+//Either the pair is unterminated, or deciding requires decoding Unicode. Either way the host
+//scans sequentially from there, which also reports the errors
+#define FOUND_UNDECIDED 2
+
 struct pair_t {
     int begin;
     int end;
     int type;
 };
 
+uchar char_at(__global const uchar* document, int length, int index) {
+    //No character compared with is a 0, so it stands in for the end of the document
+    return index < length ? document[index] : 0;
+}
+
+bool is_identifier_char(uchar c) {
+    return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == '_';
+}
+
+//Returns the length in bytes of the whitespace character at `index`, or 0 if there is none. Besides
+//the ASCII ones, Rust also takes U+0085, U+200E, U+200F, U+2028 and U+2029 as whitespace
+int whitespace_length(__global const uchar* document, int length, int index) {
+    uchar c = char_at(document, length, index);
+    uchar second = char_at(document, length, index + 1);
+    uchar third = char_at(document, length, index + 2);
+    if (c == ' ' || c == '\t' || c == '\n' || c == '\v' || c == '\f' || c == '\r') {
+        return 1;
+    }
+    if (c == 0xc2 && second == 0x85) {
+        return 2;
+    }
+    if (c == 0xe2 && second == 0x80 && (third == 0x8e || third == 0x8f || third == 0xa8 || third == 0xa9)) {
+        return 3;
+    }
+    return 0;
+}
+
+bool is_hex(uchar c) {
+    return (c >= '0' && c <= '9') || (c >= 'a' && c <= 'f') || (c >= 'A' && c <= 'F');
+}
+
+//Returns the amount of hashes if a raw string begins with the r at `r_index`, otherwise -1
+int raw_string_hashes(__global const uchar* document, int length, int r_index) {
+    int index = r_index + 1;
+    while (index < length && document[index] == '#') {
+        index++;
+    }
+    return char_at(document, length, index) == '"' ? index - r_index - 1 : -1;
+}
+
+//Returns the length of the escape sequence beginning with the backslash at `index`, like the
+//host does. Characters after the backslash which are not ASCII count as one byte, their
+//continuation bytes are skipped as content afterwards, which ends strings at the same place
+int escape_length(__global const uchar* document, int length, int index) {
+    uchar next = char_at(document, length, index + 1);
+    int tmp_index;
+    if (index + 1 >= length) {
+        return 1;
+    }
+    switch (next) {
+        case 'x':
+            tmp_index = index + 2;
+            while (tmp_index < index + 4 && is_hex(char_at(document, length, tmp_index))) {
+                tmp_index++;
+            }
+            return tmp_index - index;
+        case 'u':
+            tmp_index = index + 2;
+            if (char_at(document, length, tmp_index) != '{') {
+                return 2;
+            }
+            tmp_index++;
+            while (is_hex(char_at(document, length, tmp_index)) || char_at(document, length, tmp_index) == '_') {
+                tmp_index++;
+            }
+            if (char_at(document, length, tmp_index) == '}') {
+                tmp_index++;
+            }
+            return tmp_index - index;
+        case '\r':
+            return char_at(document, length, index + 2) == '\n' ? 3 : 2;
+        default:
+            return 2;
+    }
+}
+
+//Finds the type of the pair beginning at `index`, or -1 for none. The file spans `file_begin` to `length`
+int pair_type(__global const uchar* document, int file_begin, int length, int index, bool* undecided) {
+    uchar c = document[index];
+    uchar second = char_at(document, length, index + 1);
+    uchar third = char_at(document, length, index + 2);
+    uchar fourth = char_at(document, length, index + 3);
+    int type = -1;
+    switch (c) {
+        case 'r':
+            type = raw_string_hashes(document, length, index) >= 0 ? RAW_STRING : -1;
+            break;
+        case 'b':
+            if (second == '"') {
+                type = BYTE_STRING;
+            } else if (second == '\'') {
+                type = BYTE_CHAR_LITERAL;
+            } else if (second == 'r' && raw_string_hashes(document, length, index + 1) >= 0) {
+                type = BYTE_RAW_STRING;
+            }
+            break;
+        case 'c':
+            if (second == '"') {
+                type = C_STRING;
+            } else if (second == 'r' && raw_string_hashes(document, length, index + 1) >= 0) {
+                type = RAW_C_STRING;
+            }
+            break;
+        case '\'':
+            //'a' and '\n' are char literals, while 'a and 'static are lifetimes or labels
+            if (index + 1 >= length) {
+                return -1;
+            }
+            if (second == '\\') {
+                return CHAR_LITERAL;
+            }
+            if (second >= 0x80) {
+                *undecided = true;
+                return -1;
+            }
+            return second != '\'' && third == '\'' && index + 2 < length ? CHAR_LITERAL : -1;
+        case '"':
+            return STRING;
+        case '/':
+            if (second == '*') {
+                if (third == '!') {
+                    return INNER_BLOCK_DOC;
+                }
+                if (third == '*' && fourth != '*' && fourth != '/') {
+                    return OUTER_BLOCK_DOC;
+                }
+                return SLASH_STAR;
+            }
+            if (second == '/') {
+                if (third == '!') {
+                    return INNER_LINE_DOC;
+                }
+                if (third == '/' && fourth != '/') {
+                    return OUTER_LINE_DOC;
+                }
+                return DOUBLE_SLASH;
+            }
+            return -1;
+        default:
+            return whitespace_length(document, length, index) > 0 ? WHITESPACE : -1;
+    }
+
+    //Prefixed literals only start at a token boundary, whether a character before which is not
+    //ASCII belongs to an identifier needs Unicode tables, so the host decides
+    if (type >= 0 && index > file_begin) {
+        uchar before = document[index - 1];
+        if (before >= 0x80) {
+            *undecided = true;
+            return -1;
+        }
+        if (is_identifier_char(before)) {
+            return -1;
+        }
+    }
+    return type;
+}
+
+//Finds the end of the pair of the given type beginning at `index`, or -1 if it is unterminated
+int pair_end(__global const uchar* document, int length, int index, int type, bool* has_escapes, bool* undecided) {
+    int tmp_index;
+    int depth;
+    int hashes;
+    int prefix;
+    int found;
+    switch (type) {
+        case STRING:
+        case BYTE_STRING:
+        case C_STRING:
+            tmp_index = index + (type == STRING ? 1 : 2);
+            while (tmp_index < length) {
+                if (document[tmp_index] == '\\') {
+                    *has_escapes = true;
+                    tmp_index += escape_length(document, length, tmp_index);
+                } else if (document[tmp_index] == '"') {
+                    return tmp_index + 1;
+                } else {
+                    tmp_index++;
+                }
+            }
+            return -1;
+        case DOUBLE_SLASH:
+        case OUTER_LINE_DOC:
+        case INNER_LINE_DOC:
+            //The end of the file ends the comment as well
+            for (tmp_index = index + 2; tmp_index < length; tmp_index++) {
+                if (document[tmp_index] == '\n') {
+                    return tmp_index + 1;
+                }
+            }
+            return length;
+        case SLASH_STAR:
+        case OUTER_BLOCK_DOC:
+        case INNER_BLOCK_DOC:
+            //Block comments nest, so every /* has to be closed by its own */
+            depth = 1;
+            tmp_index = index + 2;
+            while (tmp_index + 1 < length) {
+                if (document[tmp_index] == '/' && document[tmp_index + 1] == '*') {
+                    depth++;
+                    tmp_index += 2;
+                } else if (document[tmp_index] == '*' && document[tmp_index + 1] == '/') {
+                    depth--;
+                    tmp_index += 2;
+                    if (depth == 0) {
+                        return tmp_index;
+                    }
+                } else {
+                    tmp_index++;
+                }
+            }
+            return -1;
+        case RAW_STRING:
+        case BYTE_RAW_STRING:
+        case RAW_C_STRING:
+            //Only a " followed by the same amount of hashes as the opening one ends the string
+            prefix = type == RAW_STRING ? 1 : 2;
+            hashes = raw_string_hashes(document, length, index + prefix - 1);
+            for (tmp_index = index + prefix + hashes + 1; tmp_index < length; tmp_index++) {
+                if (document[tmp_index] != '"') {
+                    continue;
+                }
+                found = 0;
+                while (found < hashes && char_at(document, length, tmp_index + 1 + found) == '#') {
+                    found++;
+                }
+                if (found == hashes) {
+                    return tmp_index + 1 + hashes;
+                }
+            }
+            return -1;
+        case CHAR_LITERAL:
+        case BYTE_CHAR_LITERAL:
+            tmp_index = index + (type == CHAR_LITERAL ? 1 : 2);
+            if (tmp_index >= length) {
+                return -1;
+            }
+            if (document[tmp_index] == '\\') {
+                //The closing quote is only found after escaped characters which are ASCII
+                if (char_at(document, length, tmp_index + 1) >= 0x80) {
+                    *undecided = true;
+                    return -1;
+                }
+                *has_escapes = true;
+                tmp_index += escape_length(document, length, tmp_index);
+            } else if (document[tmp_index] >= 0x80) {
+                *undecided = true;
+                return -1;
+            } else {
+                tmp_index++;
+            }
+            return char_at(document, length, tmp_index) == '\'' && tmp_index < length ? tmp_index + 1 : -1;
+        case WHITESPACE:
+            tmp_index = index;
+            while (whitespace_length(document, length, tmp_index) > 0) {
+                tmp_index += whitespace_length(document, length, tmp_index);
+            }
+            return tmp_index;
+        default:
+            return -1;
+    }
+}
+
+//Returns the end of the shebang like #!/usr/bin/env run-cargo-script beginning the file at `index`,
+//or -1 if there is none. Like rustc, #! followed by [ begins an inner attribute instead, also
+//after whitespace and comments. The line ending is not part of the shebang
+int shebang_end(__global const uchar* document, int length, int index, bool* undecided) {
+    int tmp_index = index + 2;
+    int type;
+    bool has_escapes = false;
+    if (char_at(document, length, index) != '#' || char_at(document, length, index + 1) != '!') {
+        return -1;
+    }
+    while (tmp_index < length) {
+        type = pair_type(document, index, length, tmp_index, undecided);
+        if (*undecided) {
+            return -1;
+        }
+        if (type != WHITESPACE && type != DOUBLE_SLASH && type != SLASH_STAR) {
+            break;
+        }
+        //An unterminated block comment reaches to the end of the file
+        tmp_index = pair_end(document, length, tmp_index, type, &has_escapes, undecided);
+        if (tmp_index < 0) {
+            tmp_index = length;
+        }
+    }
+    if (char_at(document, length, tmp_index) == '[') {
+        return -1;
+    }
+    for (tmp_index = index + 2; tmp_index < length; tmp_index++) {
+        if (document[tmp_index] == '\n') {
+            return document[tmp_index - 1] == '\r' ? tmp_index - 1 : tmp_index;
+        }
+    }
+    return length;
+}
+
+//Every work item scans one segment of the document as if it began in code, i.e. not within a
+//comment or literal. The document may consist of several files, every segment lies within one
+//and begins at `segment_begin`, the file it lies in spans `file_begin` to `file_end`. Pairs
+//beginning within the segment are scanned to their end, even beyond the segment but not beyond
+//the file. The host stitches the segments of every file together, taking the pairs of a segment
+//once its own scan reaches a place where the work item began a pair or character as well.
+//`amount` and `stop` receive the amount of pairs of every segment and where its scan stopped,
+//which is before the end of the segment if a pair is undecided.
+//Only `capacity` pairs are stored per segment, the ones beyond are still counted. An amount
+//above the capacity tells the host to run the kernel again with larger buffers
 __kernel void find_pairs(
-                    __global uchar* document,
+                    __global const uchar* document,
+                    __global const int* segment_begin,
+                    __global const int* file_begin,
+                    __global const int* file_end,
+                    const int segment_length,
+                    const int capacity,
                     __global int* begin,
                     __global int* end,
                     __global uchar* type,
-                    __global int* amount) {
-    printf("Global work item %li with local id %li calls in\n", get_global_id(0), get_local_id(0));
-    *amount = 1024;
+                    __global int* amount,
+                    __global int* stop) {
+    int segment = get_global_id(0);
+    int index = segment_begin[segment];
+    //Nothing looks before the beginning or beyond the length, so the scan stays within the file
+    int begin_of_file = file_begin[segment];
+    int length = file_end[segment];
+    int segment_end = min(index + segment_length, length);
+    int first_slot = segment * capacity;
+    int found = 0;
+
+    while (index < segment_end) {
+        bool undecided = false;
+        bool has_escapes = false;
+        int pair = -1;
+        int pair_end_index = index == begin_of_file ? shebang_end(document, length, index, &undecided) : -1;
+        if (pair_end_index >= 0) {
+            pair = SHEBANG;
+        } else if (!undecided) {
+            pair = pair_type(document, begin_of_file, length, index, &undecided);
+        }
+        if (undecided) {
+            break;
+        }
+        if (pair < 0) {
+            index++;
+            continue;
+        }
+        if (pair != SHEBANG) {
+            pair_end_index = pair_end(document, length, index, pair, &has_escapes, &undecided);
+        }
+        if (undecided || pair_end_index < 0) {
+            break;
+        }
+        if (found < capacity) {
+            begin[first_slot + found] = index;
+            end[first_slot + found] = pair_end_index;
+            type[first_slot + found] = (uchar)pair | (has_escapes ? HAS_ESCAPES : 0);
+        }
+        found++;
+        index = pair_end_index;
+    }
+    amount[segment] = found;
+    stop[segment] = index;
+}
+
+bool is_open_delimiter(uchar c) {
+    return c == '{' || c == '(' || c == '[';
+}
+
+bool is_close_delimiter(uchar c) {
+    return c == '}' || c == ')' || c == ']';
+}
+
+//Matches the brackets of a segment of the masked result text, the depths are relative to the
+//beginning of the segment. The open delimiters are linked through `match` while they are open,
+//so the stack needs no memory of its own. What the segment leaves unmatched is matched by the host
+__kernel void match_brackets(
+                    __global const uchar* text,
+                    const int length,
+                    const int segment_length,
+                    const int capacity,
+                    __global int* depth,
+                    __global int* match,
+                    __global int* delta,
+                    __global int* unmatched_closes,
+                    __global int* close_amount,
+                    __global int* unmatched_opens,
+                    __global int* open_amount) {
+    int segment = get_global_id(0);
+    int segment_begin = segment * segment_length;
+    int segment_end = min(segment_begin + segment_length, length);
+    int first_slot = segment * capacity;
+    int level = 0;
+    int top = -1;
+    int opens = 0;
+    int closes = 0;
+
+    for (int index = segment_begin; index < segment_end; index++) {
+        uchar c = text[index];
+        if (is_open_delimiter(c)) {
+            depth[index] = level++;
+            match[index] = top;
+            top = index;
+            opens++;
+        } else if (is_close_delimiter(c)) {
+            depth[index] = --level;
+            if (top >= 0) {
+                int open = top;
+                top = match[open];
+                match[open] = index;
+                match[index] = open;
+                opens--;
+            } else {
+                if (closes < capacity) {
+                    unmatched_closes[first_slot + closes] = index;
+                }
+                closes++;
+                match[index] = -1;
+            }
+        } else {
+            depth[index] = level;
+            match[index] = -1;
+        }
+    }
+
+    //The stack lists the open delimiters innermost first, they are stored in the order of the text
+    for (int slot = opens - 1; top >= 0; slot--) {
+        int next = match[top];
+        if (slot < capacity) {
+            unmatched_opens[first_slot + slot] = top;
+        }
+        match[top] = -1;
+        top = next;
+    }
+    delta[segment] = level;
+    close_amount[segment] = closes;
+    open_amount[segment] = opens;
+}
+
+//Adds the depth every segment begins at to the depths of its units
+__kernel void add_depth_bases(
+                    __global int* depth,
+                    const int length,
+                    const int segment_length,
+                    __global const int* base) {
+    int segment = get_global_id(0);
+    int segment_end = min((segment + 1) * segment_length, length);
+    for (int index = segment * segment_length; index < segment_end; index++) {
+        depth[index] += base[segment];
+    }
 }
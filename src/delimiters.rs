@@ -0,0 +1,360 @@
+use std::fmt;
+#[cfg(test)]
+use std::ops::RangeInclusive;
+use std::thread;
+
+use crate::preprocessor::{ErrorLocation, PreprocessError, Preprocessed, SourceMap, TextUnit};
+#[cfg(test)]
+use crate::preprocessor::{preprocess_to_string, PreprocessOptions};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DelimiterKind {
+    //`{` `}`
+    Brace,
+    //`(` `)`
+    Parenthesis,
+    //`[` `]`
+    Bracket
+}
+
+impl DelimiterKind {
+    fn of_open(c: char) -> Option<DelimiterKind> {
+        match c {
+            '{' => Some(DelimiterKind::Brace),
+            '(' => Some(DelimiterKind::Parenthesis),
+            '[' => Some(DelimiterKind::Bracket),
+            _ => None
+        }
+    }
+
+    fn of_close(c: char) -> Option<DelimiterKind> {
+        match c {
+            '}' => Some(DelimiterKind::Brace),
+            ')' => Some(DelimiterKind::Parenthesis),
+            ']' => Some(DelimiterKind::Bracket),
+            _ => None
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn open(&self) -> char {
+        match self {
+            DelimiterKind::Brace => '{',
+            DelimiterKind::Parenthesis => '(',
+            DelimiterKind::Bracket => '['
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn close(&self) -> char {
+        match self {
+            DelimiterKind::Brace => '}',
+            DelimiterKind::Parenthesis => ')',
+            DelimiterKind::Bracket => ']'
+        }
+    }
+}
+
+///A matched pair of delimiters, the offsets are in the source text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Delimiter {
+    pub(crate) kind: DelimiterKind,
+    pub(crate) open: usize,
+    pub(crate) close: usize,
+    //The number of delimiters enclosing this one, 0 at the top level
+    pub(crate) depth: usize,
+    //The index of the innermost enclosing delimiter
+    pub(crate) parent: Option<usize>
+}
+
+///A `;`, which ends a statement or item if its parent is a brace
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Semicolon {
+    pub(crate) offset: usize,
+    pub(crate) depth: usize,
+    pub(crate) parent: Option<usize>
+}
+
+///The delimiter tree of a text, with the delimiters ordered by their opening
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct DelimiterIndex {
+    pub(crate) delimiters: Vec<Delimiter>,
+    pub(crate) semicolons: Vec<Semicolon>
+}
+
+impl DelimiterIndex {
+    ///The index of the innermost delimiter whose contents include the offset, the delimiters
+    ///themselves are inside
+    pub(crate) fn enclosing(&self, offset: usize) -> Option<usize> {
+        //Delimiters opened later are nested deeper, so the last one containing the offset wins
+        let opened = self.delimiters.partition_point(|d| d.open <= offset);
+        self.delimiters[..opened].iter().rposition(|d| offset <= d.close)
+    }
+
+    ///The offset of the delimiter paired with the one at the offset
+    pub(crate) fn matching(&self, offset: usize) -> Option<usize> {
+        let delimiter = &self.delimiters[self.enclosing(offset)?];
+        if delimiter.open == offset {
+            Some(delimiter.close)
+        } else if delimiter.close == offset {
+            Some(delimiter.open)
+        } else {
+            None
+        }
+    }
+
+    ///The children of the delimiter, or the top level delimiters for `None`
+    #[cfg(test)]
+    pub(crate) fn children(&self, parent: Option<usize>) -> impl Iterator<Item=usize> + '_ {
+        let first = parent.map_or(0, |parent| parent + 1);
+        (first..self.delimiters.len())
+            .take_while(move |&i| parent.is_none_or(|parent| self.delimiters[i].close < self.delimiters[parent].close))
+            .filter(move |&i| self.delimiters[i].parent == parent)
+    }
+
+    ///The source lines of delimiters spanning multiple lines, from the opening to the closing line
+    #[cfg(test)]
+    pub(crate) fn fold_ranges(&self, source_map: &SourceMap) -> Vec<RangeInclusive<usize>> {
+        self.delimiters.iter()
+            .map(|d| source_map.source_line_column(d.open).line..=source_map.source_line_column(d.close).line)
+            .filter(|lines| lines.start() < lines.end())
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum DelimiterError {
+    Preprocess(PreprocessError),
+    //A closing delimiter of another kind than the innermost open one, boxed to keep the
+    //error small
+    Mismatched { open: Box<ErrorLocation>, close: Box<ErrorLocation> },
+    //A closing delimiter without an open one
+    Unopened(ErrorLocation),
+    //An open delimiter at the end of the text
+    Unclosed(ErrorLocation)
+}
+
+impl fmt::Display for DelimiterError {
+    ///Formats the error like rustc does, with a snippet marking the delimiter
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DelimiterError::Preprocess(error) => error.fmt(f),
+            DelimiterError::Mismatched { open, close } => {
+                close.fmt_error(f, &format!("mismatched closing delimiter: `{}`", close.delimiter))?;
+                writeln!(f)?;
+                open.fmt_note(f, "the delimiter was opened here")
+            }
+            DelimiterError::Unopened(location) => location.fmt_error(f, &format!("unexpected closing delimiter: `{}`", location.delimiter)),
+            DelimiterError::Unclosed(location) => location.fmt_error(f, "this file contains an unclosed delimiter")
+        }
+    }
+}
+
+impl std::error::Error for DelimiterError {}
+
+impl From<PreprocessError> for DelimiterError {
+    fn from(error: PreprocessError) -> Self {
+        DelimiterError::Preprocess(error)
+    }
+}
+
+///Preprocesses the string and indexes its delimiters, the offsets are byte offsets
+#[cfg(test)]
+pub(crate) fn index_str(source_text: &str, options: &PreprocessOptions) -> Result<DelimiterIndex, DelimiterError> {
+    let (result_text, preprocessed) = preprocess_to_string(source_text, options)?;
+    index_delimiters(source_text.as_bytes(), result_text.as_bytes(), &preprocessed)
+}
+
+///Matches the delimiters of the result text, in which strings and comments are already masked
+///out, except for doc comments which are skipped here
+#[cfg(test)]
+pub(crate) fn index_delimiters<T: TextUnit>(source_text: &[T], result_text: &[T], preprocessed: &Preprocessed)
+                                            -> Result<DelimiterIndex, DelimiterError> {
+    let masked = mask_delimiters(result_text, preprocessed);
+    build_index(source_text, &masked, &match_brackets(&masked), &preprocessed.source_map)
+}
+
+///The result text with only the delimiters and semicolons left, everything else including the
+///doc comments is a space. This is what the bracket matchers work on
+pub(crate) fn mask_delimiters<T: TextUnit>(result_text: &[T], preprocessed: &Preprocessed) -> Vec<u8> {
+    let source_map = &preprocessed.source_map;
+    let mut masked = result_text[..source_map.result_len()].iter()
+        .map(|unit| match unit.as_char() {
+            c @ ('{' | '}' | '(' | ')' | '[' | ']' | ';') => c as u8,
+            _ => b' '
+        })
+        .collect::<Vec<u8>>();
+    for comment in preprocessed.comments.iter().filter(|c| c.comment_type.is_doc_comment()) {
+        let begin = source_map.source_to_result(comment.begin);
+        let end = source_map.source_to_result(comment.begin + comment.length);
+        masked[begin..end].fill(b' ');
+    }
+    masked
+}
+
+///The nesting depth and the matching delimiter of every unit of a masked text
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Brackets {
+    //The number of open delimiters enclosing the unit, a delimiter does not enclose itself.
+    //Delimiters closed without being opened make it negative
+    pub(crate) depths: Vec<i32>,
+    //The offset of the delimiter paired with the one at the offset, regardless of their kinds
+    pub(crate) matches: Vec<Option<usize>>
+}
+
+fn is_open(c: u8) -> bool {
+    matches!(c, b'{' | b'(' | b'[')
+}
+
+fn is_close(c: u8) -> bool {
+    matches!(c, b'}' | b')' | b']')
+}
+
+///Matches the brackets of the masked text sequentially, the parallel matchers are checked
+///against this
+pub(crate) fn match_brackets(masked: &[u8]) -> Brackets {
+    let mut brackets = Brackets { depths: vec![0; masked.len()], matches: vec![None; masked.len()] };
+    let unmatched = match_segment(masked, 0, &mut brackets.depths, &mut brackets.matches);
+    stitch_brackets(&[unmatched], &mut brackets.matches);
+    brackets
+}
+
+///What a segment matched on its own cannot tell, with offsets in the whole text
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Unmatched {
+    //How much deeper the end of the segment is nested than its beginning
+    pub(crate) delta: i32,
+    //The closing delimiters without an open one in the segment, in order
+    pub(crate) closes: Vec<usize>,
+    //The open delimiters still open at the end of the segment, in order
+    pub(crate) opens: Vec<usize>
+}
+
+///Matches the brackets of the segment beginning at `begin`, the depths are relative to the
+///beginning of the segment
+fn match_segment(segment: &[u8], begin: usize, depths: &mut [i32], matches: &mut [Option<usize>]) -> Unmatched {
+    let mut unmatched = Unmatched::default();
+    for (i, &c) in segment.iter().enumerate() {
+        if is_open(c) {
+            depths[i] = unmatched.delta;
+            unmatched.delta += 1;
+            unmatched.opens.push(begin + i);
+        } else if is_close(c) {
+            unmatched.delta -= 1;
+            depths[i] = unmatched.delta;
+            match unmatched.opens.pop() {
+                Some(open) => {
+                    matches[open - begin] = Some(begin + i);
+                    matches[i] = Some(open);
+                }
+                None => unmatched.closes.push(begin + i)
+            }
+        } else {
+            depths[i] = unmatched.delta;
+        }
+    }
+    unmatched
+}
+
+///Matches what the segments left unmatched in order and returns the depth every segment
+///begins at
+pub(crate) fn stitch_brackets(segments: &[Unmatched], matches: &mut [Option<usize>]) -> Vec<i32> {
+    let mut opens: Vec<usize> = Vec::new();
+    let mut depth = 0;
+    segments.iter()
+        .map(|segment| {
+            for &close in &segment.closes {
+                if let Some(open) = opens.pop() {
+                    matches[open] = Some(close);
+                    matches[close] = Some(open);
+                }
+            }
+            opens.extend_from_slice(&segment.opens);
+            depth += segment.delta;
+            depth - segment.delta
+        })
+        .collect()
+}
+
+///Matches the brackets of the masked text split into segments at `boundaries`, which begin
+///with 0 and end with the length of the text. Every segment is matched on its own thread
+pub(crate) fn match_brackets_segments(masked: &[u8], boundaries: &[usize]) -> Brackets {
+    let mut brackets = Brackets { depths: vec![0; masked.len()], matches: vec![None; masked.len()] };
+    let unmatched = thread::scope(|scope| {
+        let handles = split_segments(&mut brackets, boundaries)
+            .map(|(begin, depths, matches)| {
+                let segment = &masked[begin..(begin + depths.len())];
+                scope.spawn(move || match_segment(segment, begin, depths, matches))
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().expect("Matching a segment panicked"))
+            .collect::<Vec<Unmatched>>()
+    });
+    let bases = stitch_brackets(&unmatched, &mut brackets.matches);
+    thread::scope(|scope| {
+        for ((_, depths, _), base) in split_segments(&mut brackets, boundaries).zip(bases) {
+            scope.spawn(move || depths.iter_mut().for_each(|depth| *depth += base));
+        }
+    });
+    brackets
+}
+
+///The beginning, depths and matches of every segment
+fn split_segments<'a>(brackets: &'a mut Brackets, boundaries: &'a [usize])
+                      -> impl Iterator<Item=(usize, &'a mut [i32], &'a mut [Option<usize>])> {
+    let mut depths = brackets.depths.as_mut_slice();
+    let mut matches = brackets.matches.as_mut_slice();
+    boundaries.windows(2).map(move |segment| {
+        let length = segment[1] - segment[0];
+        let (segment_depths, rest) = std::mem::take(&mut depths).split_at_mut(length);
+        depths = rest;
+        let (segment_matches, rest) = std::mem::take(&mut matches).split_at_mut(length);
+        matches = rest;
+        (segment[0], segment_depths, segment_matches)
+    })
+}
+
+///Builds the delimiter tree from the matched brackets, failing at the first closing delimiter
+///which does not close the innermost open one
+pub(crate) fn build_index<T: TextUnit>(source_text: &[T], masked: &[u8], brackets: &Brackets, source_map: &SourceMap)
+                                       -> Result<DelimiterIndex, DelimiterError> {
+    let location = |result_offset: usize| {
+        let offset = source_map.result_to_source(result_offset);
+        ErrorLocation::new(source_text, offset, &source_text[offset..offset + 1])
+    };
+    let mut index = DelimiterIndex::default();
+    //The open delimiters with their offset in the result text
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    for (offset, &c) in masked.iter().enumerate() {
+        if let Some(kind) = DelimiterKind::of_open(c as char) {
+            open.push((index.delimiters.len(), offset));
+            index.delimiters.push(Delimiter {
+                kind,
+                open: source_map.result_to_source(offset),
+                //Set when the delimiter is closed
+                close: 0,
+                depth: brackets.depths[offset] as usize,
+                parent: open.len().checked_sub(2).map(|i| open[i].0)
+            });
+        } else if let Some(kind) = DelimiterKind::of_close(c as char) {
+            let open_offset = brackets.matches[offset].ok_or_else(|| DelimiterError::Unopened(location(offset)))?;
+            let (i, _) = open.pop().filter(|&(_, innermost)| innermost == open_offset)
+                .expect("The brackets are matched innermost first");
+            if index.delimiters[i].kind != kind {
+                return Err(DelimiterError::Mismatched { open: Box::new(location(open_offset)), close: Box::new(location(offset)) });
+            }
+            index.delimiters[i].close = source_map.result_to_source(offset);
+        } else if c == b';' {
+            index.semicolons.push(Semicolon {
+                offset: source_map.result_to_source(offset),
+                depth: brackets.depths[offset] as usize,
+                parent: open.last().map(|&(i, _)| i)
+            });
+        }
+    }
+    match open.pop() {
+        Some((_, open_offset)) => Err(DelimiterError::Unclosed(location(open_offset))),
+        None => Ok(index)
+    }
+}
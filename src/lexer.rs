@@ -0,0 +1,389 @@
+use std::fmt;
+
+use unicode_xid::UnicodeXID;
+
+use crate::preprocessor::{is_whitespace, CommentType, ErrorLocation, PreprocessError, Preprocessed, SourceMap, TextUnit};
+#[cfg(test)]
+use crate::preprocessor::{preprocess_to_string, PreprocessOptions};
+
+///A part of the source text, in units of the text, i.e. bytes for UTF-8
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Span {
+    pub(crate) begin: usize,
+    pub(crate) length: usize
+}
+
+impl Span {
+    pub(crate) fn end(&self) -> usize {
+        self.begin + self.length
+    }
+
+    pub(crate) fn text<T: TextUnit>(&self, source_text: &[T]) -> String {
+        T::to_string(&source_text[self.begin..self.end()])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Token {
+    pub(crate) kind: TokenKind,
+    //Where the token is in the source text
+    pub(crate) span: Span
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TokenKind {
+    Identifier,
+    //An identifier like r#match, the span includes the r#
+    RawIdentifier,
+    Keyword(Keyword),
+    //Lifetimes and labels like 'a, 'static and 'outer, the span includes the '
+    Lifetime,
+    Literal {
+        kind: LiteralKind,
+        //An identifier following the literal, e.g. u8 in 1u8 or b"x"u8
+        suffix: Option<Span>
+    },
+    Punctuation(Punct),
+    //Doc comments are attributes, so they are tokens whether they are stripped, kept or rewritten
+    DocComment(CommentType)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LiteralKind {
+    Integer(Base),
+    //1.5, 1e10, 2. and 1f32
+    Float,
+    //Strings and chars as delimited by the preprocessor, which replaced them by "" or ''
+    Quoted(CommentType)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Base {
+    Binary,
+    Octal,
+    Decimal,
+    Hexadecimal
+}
+
+impl Base {
+    fn radix(&self) -> u32 {
+        match self {
+            Base::Binary => 2,
+            Base::Octal => 8,
+            Base::Decimal => 10,
+            Base::Hexadecimal => 16
+        }
+    }
+}
+
+///The strict and reserved keywords of the 2018 edition. Weak keywords like `union` and
+///`macro_rules` are identifiers, the parser decides by the context
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Keyword {
+    As, Break, Const, Continue, Crate, Else, Enum, Extern, False, Fn, For, If, Impl, In, Let, Loop,
+    Match, Mod, Move, Mut, Pub, Ref, Return, SelfValue, SelfType, Static, Struct, Super, Trait, True,
+    Type, Unsafe, Use, Where, While, Async, Await, Dyn,
+    //Reserved for future use
+    Abstract, Become, Box, Do, Final, Macro, Override, Priv, Typeof, Unsized, Virtual, Yield, Try
+}
+
+const KEYWORDS: [(&str, Keyword); 51] = [
+    ("as", Keyword::As), ("break", Keyword::Break), ("const", Keyword::Const), ("continue", Keyword::Continue),
+    ("crate", Keyword::Crate), ("else", Keyword::Else), ("enum", Keyword::Enum), ("extern", Keyword::Extern),
+    ("false", Keyword::False), ("fn", Keyword::Fn), ("for", Keyword::For), ("if", Keyword::If),
+    ("impl", Keyword::Impl), ("in", Keyword::In), ("let", Keyword::Let), ("loop", Keyword::Loop),
+    ("match", Keyword::Match), ("mod", Keyword::Mod), ("move", Keyword::Move), ("mut", Keyword::Mut),
+    ("pub", Keyword::Pub), ("ref", Keyword::Ref), ("return", Keyword::Return), ("self", Keyword::SelfValue),
+    ("Self", Keyword::SelfType), ("static", Keyword::Static), ("struct", Keyword::Struct), ("super", Keyword::Super),
+    ("trait", Keyword::Trait), ("true", Keyword::True), ("type", Keyword::Type), ("unsafe", Keyword::Unsafe),
+    ("use", Keyword::Use), ("where", Keyword::Where), ("while", Keyword::While), ("async", Keyword::Async),
+    ("await", Keyword::Await), ("dyn", Keyword::Dyn), ("abstract", Keyword::Abstract), ("become", Keyword::Become),
+    ("box", Keyword::Box), ("do", Keyword::Do), ("final", Keyword::Final), ("macro", Keyword::Macro),
+    ("override", Keyword::Override), ("priv", Keyword::Priv), ("typeof", Keyword::Typeof),
+    ("unsized", Keyword::Unsized), ("virtual", Keyword::Virtual), ("yield", Keyword::Yield), ("try", Keyword::Try)
+];
+
+impl Keyword {
+    pub(crate) fn from_identifier(identifier: &str) -> Option<Keyword> {
+        KEYWORDS.iter().find(|(name, _)| *name == identifier).map(|(_, keyword)| *keyword)
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        KEYWORDS.iter().find(|(_, keyword)| keyword == self).map(|(name, _)| *name).unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Punct {
+    Plus, Minus, Star, Slash, Percent, Caret, Not, And, Or, AndAnd, OrOr, Shl, Shr,
+    PlusEq, MinusEq, StarEq, SlashEq, PercentEq, CaretEq, AndEq, OrEq, ShlEq, ShrEq,
+    Eq, EqEq, Ne, Gt, Lt, Ge, Le, At, Underscore, Dot, DotDot, DotDotDot, DotDotEq,
+    Comma, Semi, Colon, PathSep, RArrow, FatArrow, Pound, Dollar, Question, Tilde,
+    OpenBrace, CloseBrace, OpenBracket, CloseBracket, OpenParen, CloseParen
+}
+
+//The longest punctuation is taken, so longer ones come first
+const PUNCTUATION: [(&str, Punct); 52] = [
+    ("<<=", Punct::ShlEq), (">>=", Punct::ShrEq), ("...", Punct::DotDotDot), ("..=", Punct::DotDotEq),
+    ("&&", Punct::AndAnd), ("||", Punct::OrOr), ("<<", Punct::Shl), (">>", Punct::Shr),
+    ("+=", Punct::PlusEq), ("-=", Punct::MinusEq), ("*=", Punct::StarEq), ("/=", Punct::SlashEq),
+    ("%=", Punct::PercentEq), ("^=", Punct::CaretEq), ("&=", Punct::AndEq), ("|=", Punct::OrEq),
+    ("==", Punct::EqEq), ("!=", Punct::Ne), (">=", Punct::Ge), ("<=", Punct::Le), ("..", Punct::DotDot),
+    ("::", Punct::PathSep), ("->", Punct::RArrow), ("=>", Punct::FatArrow),
+    ("+", Punct::Plus), ("-", Punct::Minus), ("*", Punct::Star), ("/", Punct::Slash), ("%", Punct::Percent),
+    ("^", Punct::Caret), ("!", Punct::Not), ("&", Punct::And), ("|", Punct::Or), ("=", Punct::Eq),
+    (">", Punct::Gt), ("<", Punct::Lt), ("@", Punct::At), ("_", Punct::Underscore), (".", Punct::Dot),
+    (",", Punct::Comma), (";", Punct::Semi), (":", Punct::Colon), ("#", Punct::Pound), ("$", Punct::Dollar),
+    ("?", Punct::Question), ("~", Punct::Tilde), ("{", Punct::OpenBrace), ("}", Punct::CloseBrace),
+    ("[", Punct::OpenBracket), ("]", Punct::CloseBracket), ("(", Punct::OpenParen), (")", Punct::CloseParen)
+];
+
+impl Punct {
+    pub(crate) fn from_text(text: &str) -> Option<Punct> {
+        PUNCTUATION.iter().find(|(punct_text, _)| *punct_text == text).map(|(_, punct)| *punct)
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        PUNCTUATION.iter().find(|(_, punct)| punct == self).map(|(text, _)| *text).unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum LexError {
+    Preprocess(PreprocessError),
+    //A character no token begins with, e.g. € or a ' which begins no lifetime
+    UnknownCharacter(ErrorLocation),
+    //A number like 0x without digits
+    MissingDigits(ErrorLocation),
+    //A digit the base does not have, e.g. 2 in 0b102
+    InvalidDigit(ErrorLocation),
+    //An exponent like the one of 1e without digits
+    MissingExponent(ErrorLocation)
+}
+
+impl fmt::Display for LexError {
+    ///Formats the error like rustc does, with a snippet marking the malformed token
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (location, message) = match self {
+            LexError::Preprocess(error) => return error.fmt(f),
+            LexError::UnknownCharacter(location) => (location, "unknown start of token"),
+            LexError::MissingDigits(location) => (location, "no valid digits found for number"),
+            LexError::InvalidDigit(location) => (location, "invalid digit for the base of the literal"),
+            LexError::MissingExponent(location) => (location, "expected at least one digit in exponent")
+        };
+        location.fmt_error(f, message)
+    }
+}
+
+impl std::error::Error for LexError {}
+
+impl From<PreprocessError> for LexError {
+    fn from(error: PreprocessError) -> Self {
+        LexError::Preprocess(error)
+    }
+}
+
+///Preprocesses the string and lexes it, the spans are byte offsets
+#[cfg(test)]
+pub(crate) fn tokenize(source_text: &str, options: &PreprocessOptions) -> Result<Vec<Token>, LexError> {
+    let (result_text, preprocessed) = preprocess_to_string(source_text, options)?;
+    lex(source_text.as_bytes(), result_text.as_bytes(), &preprocessed)
+}
+
+///Lexes the result text of `preprocess` into tokens. Literals and doc comments are taken from the
+///comments the preprocessor found, everything else from the result text, which has no whitespace
+///but the separators keeping tokens apart. The spans are mapped back to the source text
+pub(crate) fn lex<T: TextUnit>(source_text: &[T], result_text: &[T], preprocessed: &Preprocessed)
+                               -> Result<Vec<Token>, LexError> {
+    let lexer = Lexer {
+        source_text,
+        result_text: &result_text[..preprocessed.source_map.result_len()],
+        source_map: &preprocessed.source_map
+    };
+    let mut tokens = Vec::new();
+    let mut comments = preprocessed.comments.iter()
+        .filter(|c| c.comment_type.is_doc_comment() || is_literal(c.comment_type))
+        .peekable();
+    let mut index = 0;
+    loop {
+        let comment = comments.next_if(|c| lexer.source_map.source_to_result(c.begin) <= index);
+        if let Some(comment) = comment {
+            let span = Span { begin: comment.begin, length: comment.length };
+            index = lexer.source_map.source_to_result(span.end());
+            tokens.push(if comment.comment_type.is_doc_comment() {
+                Token { kind: TokenKind::DocComment(comment.comment_type), span }
+            } else {
+                let suffix_end = lexer.identifier_end(index);
+                let suffix = lexer.source_span(index, suffix_end);
+                index = suffix_end;
+                Token {
+                    kind: TokenKind::Literal { kind: LiteralKind::Quoted(comment.comment_type), suffix },
+                    span: Span { begin: span.begin, length: suffix.map_or(span.end(), |suffix| suffix.end()) - span.begin }
+                }
+            });
+            continue;
+        }
+        let (c, width) = match T::decode(lexer.result_text, index) {
+            Some(decoded) => decoded,
+            None if index >= lexer.result_text.len() => break,
+            None => return Err(LexError::UnknownCharacter(lexer.location(index, index + 1)))
+        };
+        if is_whitespace(c) {
+            index += width;
+            continue;
+        }
+        let (kind, end) = lexer.token(index, c)?;
+        tokens.push(Token { kind, span: lexer.source_span(index, end).expect("Tokens are not empty") });
+        index = end;
+    }
+    Ok(tokens)
+}
+
+fn is_literal(comment_type: CommentType) -> bool {
+    !comment_type.is_doc_comment() &&
+        !matches!(comment_type, CommentType::DoubleSlash | CommentType::SlashStar | CommentType::Whitespace | CommentType::Shebang)
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || c.is_xid_start()
+}
+
+struct Lexer<'a, T: TextUnit> {
+    source_text: &'a [T],
+    result_text: &'a [T],
+    source_map: &'a SourceMap
+}
+
+impl<'a, T: TextUnit> Lexer<'a, T> {
+    fn char_at(&self, index: usize) -> Option<char> {
+        T::decode(self.result_text, index).map(|(c, _)| c)
+    }
+
+    ///The span of the source text the result text from `begin` to `end` was copied from
+    fn source_span(&self, begin: usize, end: usize) -> Option<Span> {
+        if begin == end {
+            return None;
+        }
+        let source_begin = self.source_map.result_to_source(begin);
+        Some(Span { begin: source_begin, length: self.source_map.result_to_source(end - 1) + 1 - source_begin })
+    }
+
+    fn location(&self, begin: usize, end: usize) -> ErrorLocation {
+        let span = self.source_span(begin, end).expect("Errors are located at a character");
+        ErrorLocation::new(self.source_text, span.begin, &self.source_text[span.begin..span.end()])
+    }
+
+    ///Where the identifier beginning at `index` ends, `index` itself if none begins there
+    fn identifier_end(&self, index: usize) -> usize {
+        match T::decode(self.result_text, index) {
+            Some((c, width)) if is_identifier_start(c) => self.skip_while(index + width, |c| c.is_xid_continue()),
+            _ => index
+        }
+    }
+
+    fn skip_while(&self, mut index: usize, condition: impl Fn(char) -> bool) -> usize {
+        while let Some((_, width)) = T::decode(self.result_text, index).filter(|(c, _)| condition(*c)) {
+            index += width;
+        }
+        index
+    }
+
+    ///Lexes the token beginning with `c` at `index`, returning its kind and where it ends
+    fn token(&self, index: usize, c: char) -> Result<(TokenKind, usize), LexError> {
+        match c {
+            'r' if self.char_at(index + 1) == Some('#') && self.char_at(index + 2).is_some_and(is_identifier_start) => {
+                Ok((TokenKind::RawIdentifier, self.identifier_end(index + 2)))
+            }
+            '_' if !self.char_at(index + 1).is_some_and(|c| c.is_xid_continue()) => {
+                Ok((TokenKind::Punctuation(Punct::Underscore), index + 1))
+            }
+            c if is_identifier_start(c) => {
+                let end = self.identifier_end(index);
+                let identifier = T::to_string(&self.result_text[index..end]);
+                match Keyword::from_identifier(&identifier) {
+                    Some(keyword) => Ok((TokenKind::Keyword(keyword), end)),
+                    None => Ok((TokenKind::Identifier, end))
+                }
+            }
+            '\'' => {
+                //Char literals were found by the preprocessor, so this begins a lifetime or label,
+                //which may be raw like 'r#a
+                let raw = self.char_at(index + 1) == Some('r') && self.char_at(index + 2) == Some('#');
+                let name = if raw { index + 3 } else { index + 1 };
+                match self.char_at(name) {
+                    Some(c) if is_identifier_start(c) => Ok((TokenKind::Lifetime, self.identifier_end(name))),
+                    _ => Err(LexError::UnknownCharacter(self.location(index, index + 1)))
+                }
+            }
+            '0'..='9' => self.number(index),
+            _ => {
+                let punct = PUNCTUATION.iter().find(|(text, _)| {
+                    text.chars().enumerate().all(|(i, c)| self.result_text.get(index + i).is_some_and(|u| u.as_char() == c))
+                });
+                match punct {
+                    Some((text, punct)) => Ok((TokenKind::Punctuation(*punct), index + text.len())),
+                    None => {
+                        let width = T::decode(self.result_text, index).map_or(1, |(_, width)| width);
+                        Err(LexError::UnknownCharacter(self.location(index, index + width)))
+                    }
+                }
+            }
+        }
+    }
+
+    ///Lexes an integer or float literal with its suffix
+    fn number(&self, begin: usize) -> Result<(TokenKind, usize), LexError> {
+        let base = match (self.char_at(begin), self.char_at(begin + 1)) {
+            (Some('0'), Some('b')) => Base::Binary,
+            (Some('0'), Some('o')) => Base::Octal,
+            (Some('0'), Some('x')) => Base::Hexadecimal,
+            _ => Base::Decimal
+        };
+        let digits_begin = if base == Base::Decimal { begin } else { begin + 2 };
+        //Binary and octal literals are lexed with all decimal digits, which are reported if out of place
+        let digits_end = self.skip_while(digits_begin, |c| {
+            c == '_' || if base == Base::Hexadecimal { c.is_ascii_hexdigit() } else { c.is_ascii_digit() }
+        });
+        let digits = &self.result_text[digits_begin..digits_end];
+        if !digits.iter().any(|c| c.as_char() != '_') {
+            return Err(LexError::MissingDigits(self.location(begin, digits_end)));
+        }
+        if let Some(invalid) = digits.iter().position(|c| !c.as_char().is_digit(base.radix()) && c.as_char() != '_') {
+            let invalid = digits_begin + invalid;
+            return Err(LexError::InvalidDigit(self.location(invalid, invalid + 1)));
+        }
+
+        let mut end = digits_end;
+        let mut float = false;
+        if base == Base::Decimal {
+            //1. is a float, but not 1..2, 1.foo() or 1._x
+            let after_dot = self.char_at(end + 1);
+            if self.char_at(end) == Some('.') && after_dot != Some('.') && !after_dot.is_some_and(is_identifier_start) {
+                float = true;
+                end += 1;
+                if after_dot.is_some_and(|c| c.is_ascii_digit()) {
+                    end = self.skip_while(end, |c| c.is_ascii_digit() || c == '_');
+                }
+            }
+            if matches!(self.char_at(end), Some('e') | Some('E')) {
+                let sign = usize::from(matches!(self.char_at(end + 1), Some('+') | Some('-')));
+                let exponent_end = self.skip_while(end + 1 + sign, |c| c.is_ascii_digit() || c == '_');
+                let exponent = &self.result_text[(end + 1 + sign)..exponent_end];
+                if !exponent.iter().any(|c| c.as_char().is_ascii_digit()) {
+                    return Err(LexError::MissingExponent(self.location(begin, exponent_end)));
+                }
+                float = true;
+                end = exponent_end;
+            }
+        }
+
+        let suffix_end = self.identifier_end(end);
+        let suffix = self.source_span(end, suffix_end);
+        if base == Base::Decimal && !float {
+            let suffix_text = T::to_string(&self.result_text[end..suffix_end]);
+            float = suffix_text == "f32" || suffix_text == "f64";
+        }
+        let kind = if float { LiteralKind::Float } else { LiteralKind::Integer(base) };
+        Ok((TokenKind::Literal { kind, suffix }, suffix_end))
+    }
+}
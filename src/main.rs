@@ -1,21 +1,152 @@
+#[cfg(feature = "opencl")]
 extern crate ocl;
 extern crate rand;
+extern crate unicode_xid;
 
-use crate::preprocessor::preprocess;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
 
+use crate::backend::{Backend, BackendReport};
+use crate::outline::outline_indexed;
+use crate::parser::parse;
+use crate::preprocessor::stream::preprocess_reader;
+use crate::preprocessor::{LineEndings, PreprocessOptions};
+
+//Selects where the comments are searched, the OpenCL backend falls back to the CPU
+mod backend;
 #[cfg(test)]
 mod tests;
+//Without the opencl feature everything runs on the CPU
+#[cfg(feature = "opencl")]
 mod cl_part;
+//Matches the braces, parentheses and brackets of the result text of the preprocessor
+mod delimiters;
+//Turns the result text of the preprocessor into tokens
+mod lexer;
+//Lists the items of a file for symbol indexing, without parsing it fully
+mod outline;
+//Builds a lossless concrete syntax tree of a preprocessed file
+mod parser;
+//Replaces the comments and literals of the source text, keeping a map between the texts
 mod preprocessor;
 
 
 /** It is the main */
 fn main() {
-    let src = vec![';' as char; 1024];
-    let mut res = vec![';' as char; src.len()];
-    preprocess(&src, &mut res).expect("Unable to parse");
-    println!("Nothing to do :)");
-    println!("run tests instead");
+    //The backend is given as the first argument: cpu, cpu-parallel or opencl. The files after it
+    //are outlined, without files the standard input is preprocessed to the standard output.
+    //With --preserve-line-endings the result text keeps the line endings of the source
+    let mut options = PreprocessOptions::default();
+    let mut args = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--preserve-line-endings" => options.line_endings = LineEndings::Preserve,
+            _ if arg.starts_with("--") => fail(format!("Unknown option {}", arg)),
+            _ => args.push(arg)
+        }
+    }
+    let backend = match args.first() {
+        Some(name) => name.parse::<Backend>().unwrap_or_else(|error| fail(error)),
+        None => Backend::Cpu
+    };
+    #[cfg(feature = "opencl")]
+    if backend == Backend::OpenCl {
+        report_build_warnings();
+    }
+    if args.len() <= 1 {
+        preprocess_stdin(backend, &options).unwrap_or_else(|error| fail(format!("Unable to preprocess: {}", error)));
+    } else if !outline_files(backend, &args[1..], &options) {
+        process::exit(1);
+    }
 }
 
+///Prints the error and exits with a failure
+fn fail(error: impl fmt::Display) -> ! {
+    eprintln!("{}", error);
+    process::exit(1)
+}
 
+///Prints the warnings of the OpenCL compiler. A program loaded from the cache was compiled by an
+///earlier run, which already printed them
+#[cfg(feature = "opencl")]
+fn report_build_warnings() {
+    //Without a context the backend falls back to the CPU, which is reported once it does
+    if let Ok(context) = cl_part::OpenClContext::shared() {
+        let diagnostics = context.diagnostics();
+        if !diagnostics.from_cache {
+            diagnostics.warnings().for_each(|warning| eprintln!("OpenCL compiler warning: {}", warning));
+        }
+    }
+}
+
+///Prints why the requested backend was not used, if it was not
+fn report_fallback(requested: Backend, report: &BackendReport) {
+    if let Some(fallback) = &report.fallback {
+        eprintln!("Using {:?} instead of {:?}: {}", report.used, requested, fallback);
+    }
+}
+
+///Writes the result text of the standard input to the standard output. The CPU preprocesses it
+///while reading it, the other backends read it whole first
+fn preprocess_stdin(backend: Backend, options: &PreprocessOptions) -> io::Result<()> {
+    let (stdin, stdout) = (io::stdin(), io::stdout());
+    if backend == Backend::Cpu {
+        preprocess_reader(stdin.lock(), stdout.lock(), options)?;
+        return Ok(());
+    }
+    let mut src = String::new();
+    stdin.lock().read_to_string(&mut src)?;
+    //Without doc comments as attributes the result text is never longer than the source text
+    let mut res = vec![b' '; src.len()];
+    let (preprocessed, report) = backend.preprocess(&src, &mut res, options)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    report_fallback(backend, &report);
+    stdout.lock().write_all(&res[..preprocessed.source_map.result_len()])
+}
+
+///Prints the items of the files and their errors, the files are preprocessed at once. Returns
+///whether no file had errors
+fn outline_files(backend: Backend, paths: &[String], options: &PreprocessOptions) -> bool {
+    let sources = paths.iter()
+        .map(|path| fs::read_to_string(path).unwrap_or_else(|error| fail(format!("Unable to read {}: {}", path, error))))
+        .collect::<Vec<String>>();
+    let (files, report) = backend.preprocess_batch(&sources.iter().map(String::as_str).collect::<Vec<&str>>(), options);
+    report_fallback(backend, &report);
+    //The brackets fall back for the same reason as the comments, which is only reported once
+    let mut fallback_reported = report.fallback.is_some();
+    let mut succeeded = true;
+    for ((path, src), file) in paths.iter().zip(&sources).zip(files) {
+        let mut error = |error: &dyn fmt::Display| {
+            eprintln!("{}: {}", path, error);
+            succeeded = false;
+        };
+        let (res, preprocessed) = match file {
+            Ok(file) => file,
+            Err(preprocess_error) => {
+                error(&preprocess_error);
+                continue;
+            }
+        };
+        match parse(src.as_bytes(), res.as_bytes(), &preprocessed) {
+            Ok(parse) => parse.errors.iter().for_each(|parse_error| error(parse_error)),
+            Err(lex_error) => error(&lex_error)
+        }
+        let (index, report) = backend.index_delimiters(src, res.as_bytes(), &preprocessed);
+        if !fallback_reported {
+            report_fallback(backend, &report);
+            fallback_reported = report.fallback.is_some();
+        }
+        match outline_indexed(src.as_bytes(), res.as_bytes(), &preprocessed, index) {
+            Ok(items) => {
+                for item in items {
+                    let line = preprocessed.source_map.source_line_column(item.span.begin).line;
+                    println!("{}:{}: {:?} {}", path, line, item.kind, item.name.text(src.as_bytes()));
+                }
+            }
+            Err(outline_error) => error(&outline_error)
+        }
+    }
+    succeeded
+}
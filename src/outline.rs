@@ -0,0 +1,351 @@
+use std::fmt;
+
+#[cfg(test)]
+use crate::delimiters::index_delimiters;
+use crate::delimiters::{DelimiterError, DelimiterIndex};
+use crate::lexer::{lex, Keyword, LexError, Punct, Span, Token, TokenKind};
+use crate::preprocessor::{Comment, CommentType, PreprocessError, Preprocessed, TextUnit};
+#[cfg(test)]
+use crate::preprocessor::{preprocess_to_string, PreprocessOptions};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ItemKind {
+    Function,
+    Struct,
+    Enum,
+    Impl,
+    Module,
+    Trait
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Visibility {
+    //No visibility or `pub(self)`
+    Private,
+    Public,
+    //`pub(crate)` or `crate`
+    Crate,
+    //`pub(super)`
+    Super,
+    //`pub(in path)`, with the span of the path
+    InPath(Span)
+}
+
+///An item of the outline, the spans are in the source text
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct OutlineItem {
+    pub(crate) kind: ItemKind,
+    //The name, for impls the implemented trait and the type, e.g. `Display for Foo`
+    pub(crate) name: Span,
+    pub(crate) visibility: Visibility,
+    //The generic parameters with the angle brackets
+    pub(crate) generics: Option<Span>,
+    //The block with the braces, or the parentheses of a tuple struct
+    pub(crate) body: Option<Span>,
+    //The outer doc comments before the item and the inner ones at the beginning of its body
+    pub(crate) docs: Vec<Comment>,
+    //The whole item, from its first doc comment or attribute on
+    pub(crate) span: Span,
+    //The index of the item this one is nested in
+    pub(crate) parent: Option<usize>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum OutlineError {
+    Lex(LexError),
+    Delimiter(DelimiterError)
+}
+
+impl fmt::Display for OutlineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutlineError::Lex(error) => error.fmt(f),
+            OutlineError::Delimiter(error) => error.fmt(f)
+        }
+    }
+}
+
+impl std::error::Error for OutlineError {}
+
+impl From<PreprocessError> for OutlineError {
+    fn from(error: PreprocessError) -> Self {
+        OutlineError::Lex(error.into())
+    }
+}
+
+impl From<LexError> for OutlineError {
+    fn from(error: LexError) -> Self {
+        OutlineError::Lex(error)
+    }
+}
+
+impl From<DelimiterError> for OutlineError {
+    fn from(error: DelimiterError) -> Self {
+        OutlineError::Delimiter(error)
+    }
+}
+
+///Preprocesses the string and lists its items, the spans are byte offsets
+#[cfg(test)]
+pub(crate) fn outline_str(source_text: &str, options: &PreprocessOptions) -> Result<Vec<OutlineItem>, OutlineError> {
+    let (result_text, preprocessed) = preprocess_to_string(source_text, options)?;
+    outline(source_text.as_bytes(), result_text.as_bytes(), &preprocessed)
+}
+
+///Lists the fns, structs, enums, impls, mods and traits of a preprocessed text in the order
+///they begin, nested ones included. Only the item headers are looked at, the bodies are
+///skipped with the delimiter index
+#[cfg(test)]
+pub(crate) fn outline<T: TextUnit>(source_text: &[T], result_text: &[T], preprocessed: &Preprocessed)
+                                   -> Result<Vec<OutlineItem>, OutlineError> {
+    outline_indexed(source_text, result_text, preprocessed, index_delimiters(source_text, result_text, preprocessed))
+}
+
+///Like `outline`, with the delimiters already indexed, e.g. by a `Backend`. Lexing errors are
+///reported before the ones of the index
+pub(crate) fn outline_indexed<T: TextUnit>(source_text: &[T], result_text: &[T], preprocessed: &Preprocessed,
+                                           index: Result<DelimiterIndex, DelimiterError>)
+                                           -> Result<Vec<OutlineItem>, OutlineError> {
+    let tokens = lex(source_text, result_text, preprocessed)?;
+    let index = index?;
+    let mut outliner = Outliner { tokens: &tokens, index: &index, comments: &preprocessed.comments, items: Vec::new() };
+    outliner.items(0, tokens.len(), None);
+    Ok(outliner.items)
+}
+
+fn is_outer_doc(comment_type: CommentType) -> bool {
+    matches!(comment_type, CommentType::OuterLineDoc | CommentType::OuterBlockDoc)
+}
+
+fn is_inner_doc(comment_type: CommentType) -> bool {
+    matches!(comment_type, CommentType::InnerLineDoc | CommentType::InnerBlockDoc)
+}
+
+struct Outliner<'a> {
+    tokens: &'a [Token],
+    index: &'a DelimiterIndex,
+    comments: &'a [Comment],
+    items: Vec<OutlineItem>
+}
+
+impl Outliner<'_> {
+    fn kind(&self, i: usize) -> Option<TokenKind> {
+        self.tokens.get(i).map(|token| token.kind)
+    }
+
+    fn is_punct(&self, i: usize, punct: Punct) -> bool {
+        self.kind(i) == Some(TokenKind::Punctuation(punct))
+    }
+
+    fn is_keyword(&self, i: usize, keyword: Keyword) -> bool {
+        self.kind(i) == Some(TokenKind::Keyword(keyword))
+    }
+
+    fn is_open(&self, i: usize) -> bool {
+        self.is_punct(i, Punct::OpenBrace) || self.is_punct(i, Punct::OpenParen) || self.is_punct(i, Punct::OpenBracket)
+    }
+
+    ///The index of the token closing the delimiter opened by token `i`
+    fn close_of(&self, i: usize) -> usize {
+        let close = self.index.matching(self.tokens[i].span.begin).expect("The delimiters are matched");
+        self.tokens.binary_search_by_key(&close, |token| token.span.begin).expect("Delimiters are tokens")
+    }
+
+    fn comment_at(&self, begin: usize) -> Comment {
+        let i = self.comments.binary_search_by_key(&begin, |comment| comment.begin).expect("Doc comments are comments");
+        self.comments[i]
+    }
+
+    fn span(&self, first: usize, last: usize) -> Span {
+        let begin = self.tokens[first].span.begin;
+        Span { begin, length: self.tokens[last].span.end() - begin }
+    }
+
+    ///Lists the items between the tokens `begin` and `end`, looking into every delimiter which
+    ///is not the body of an item too, e.g. for items in blocks of functions
+    fn items(&mut self, begin: usize, end: usize, parent: Option<usize>) {
+        let mut docs = Vec::new();
+        //Where the doc comments and attributes of the next item begin
+        let mut item_begin = None;
+        let mut i = begin;
+        while i < end {
+            match self.tokens[i].kind {
+                TokenKind::DocComment(comment_type) => {
+                    if is_outer_doc(comment_type) {
+                        docs.push(self.comment_at(self.tokens[i].span.begin));
+                        item_begin.get_or_insert(i);
+                    }
+                    i += 1;
+                    continue;
+                }
+                TokenKind::Punctuation(Punct::Pound) if self.is_punct(i + 1, Punct::OpenBracket) => {
+                    item_begin.get_or_insert(i);
+                    i = self.close_of(i + 1) + 1;
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(next) = self.item(item_begin.unwrap_or(i), i, end, std::mem::take(&mut docs), parent) {
+                i = next;
+            } else if self.is_punct(i, Punct::Not) && i > begin && self.kind(i - 1) == Some(TokenKind::Identifier) {
+                //The tokens given to a macro, or those of a macro_rules definition, are no items
+                let group = if self.kind(i + 1) == Some(TokenKind::Identifier) { i + 2 } else { i + 1 };
+                i = if group < end && self.is_open(group) { self.close_of(group) + 1 } else { i + 1 };
+            } else if self.is_open(i) {
+                let close = self.close_of(i);
+                self.items(i + 1, close, parent);
+                i = close + 1;
+            } else {
+                i += 1;
+            }
+            docs.clear();
+            item_begin = None;
+        }
+    }
+
+    ///The visibility beginning at token `i` and the token following it
+    fn visibility(&self, i: usize) -> (Visibility, usize) {
+        if self.is_keyword(i, Keyword::Crate) && !self.is_punct(i + 1, Punct::PathSep) {
+            return (Visibility::Crate, i + 1);
+        }
+        if !self.is_keyword(i, Keyword::Pub) {
+            return (Visibility::Private, i);
+        }
+        if !self.is_punct(i + 1, Punct::OpenParen) {
+            return (Visibility::Public, i + 1);
+        }
+        let close = self.close_of(i + 1);
+        let restricted = match self.kind(i + 2) {
+            Some(TokenKind::Keyword(Keyword::Crate)) if close == i + 3 => Visibility::Crate,
+            Some(TokenKind::Keyword(Keyword::Super)) if close == i + 3 => Visibility::Super,
+            Some(TokenKind::Keyword(Keyword::SelfValue)) if close == i + 3 => Visibility::Private,
+            Some(TokenKind::Keyword(Keyword::In)) if close > i + 3 => Visibility::InPath(self.span(i + 3, close - 1)),
+            //A tuple struct field or type, e.g. in `struct S(pub (u8, u8))`
+            _ => return (Visibility::Public, i + 1)
+        };
+        (restricted, close + 1)
+    }
+
+    ///Skips the qualifiers of fns, impls and traits, e.g. `const`, `unsafe` or `extern "C"`
+    fn skip_qualifiers(&self, mut i: usize) -> usize {
+        loop {
+            match self.kind(i) {
+                Some(TokenKind::Keyword(Keyword::Const)) | Some(TokenKind::Keyword(Keyword::Async)) |
+                Some(TokenKind::Keyword(Keyword::Unsafe)) => i += 1,
+                Some(TokenKind::Keyword(Keyword::Extern)) => {
+                    i += 1;
+                    if let Some(TokenKind::Literal { .. }) = self.kind(i) {
+                        i += 1;
+                    }
+                }
+                //`default`, `auto` and `safe` are only keywords in front of other keywords
+                Some(TokenKind::Identifier) if matches!(self.kind(i + 1), Some(TokenKind::Keyword(_))) => i += 1,
+                _ => return i
+            }
+        }
+    }
+
+    ///The generic parameters beginning at token `i`, with the token following them
+    fn generics(&self, i: usize, end: usize) -> Option<(Span, usize)> {
+        if !self.is_punct(i, Punct::Lt) {
+            return None;
+        }
+        let mut depth = 0;
+        let mut j = i;
+        while j < end {
+            match self.kind(j) {
+                Some(TokenKind::Punctuation(Punct::Lt)) => depth += 1,
+                Some(TokenKind::Punctuation(Punct::Gt)) => depth -= 1,
+                Some(TokenKind::Punctuation(Punct::Shr)) => depth -= 2,
+                //E.g. `[u8; 2]` or a block as const argument
+                _ if self.is_open(j) => j = self.close_of(j),
+                _ => {}
+            }
+            if depth <= 0 {
+                return Some((self.span(i, j), j + 1));
+            }
+            j += 1;
+        }
+        None
+    }
+
+    ///The body of the item whose header continues at token `i`, with the token following the item
+    fn body(&self, kind: ItemKind, mut i: usize, end: usize) -> (Option<(usize, usize)>, usize) {
+        let header = i;
+        while i < end {
+            if self.is_punct(i, Punct::Semi) {
+                return (None, i + 1);
+            }
+            if self.is_punct(i, Punct::OpenBrace) || (kind == ItemKind::Struct && i == header && self.is_punct(i, Punct::OpenParen)) {
+                let close = self.close_of(i);
+                //Tuple structs end with a semicolon after the fields and their where clause
+                let next = if self.is_punct(i, Punct::OpenParen) {
+                    (close..end).find(|&j| self.is_punct(j, Punct::Semi)).map_or(end, |semi| semi + 1)
+                } else {
+                    close + 1
+                };
+                return (Some((i, close)), next);
+            }
+            i = if self.is_open(i) { self.close_of(i) + 1 } else { i + 1 };
+        }
+        (None, end)
+    }
+
+    ///Adds the item whose visibility or keyword is at token `i` with the items nested in it,
+    ///returning the token following it. `first` is its first doc comment or attribute
+    fn item(&mut self, first: usize, i: usize, end: usize, mut docs: Vec<Comment>, parent: Option<usize>) -> Option<usize> {
+        let (visibility, i) = self.visibility(i);
+        let i = self.skip_qualifiers(i);
+        let kind = match self.kind(i)? {
+            TokenKind::Keyword(Keyword::Fn) => ItemKind::Function,
+            TokenKind::Keyword(Keyword::Struct) => ItemKind::Struct,
+            TokenKind::Keyword(Keyword::Enum) => ItemKind::Enum,
+            TokenKind::Keyword(Keyword::Impl) => ItemKind::Impl,
+            TokenKind::Keyword(Keyword::Mod) => ItemKind::Module,
+            TokenKind::Keyword(Keyword::Trait) => ItemKind::Trait,
+            _ => return None
+        };
+        let (name, generics, header) = if kind == ItemKind::Impl {
+            let (generics, name_begin) = self.generics(i + 1, end).map_or((None, i + 1), |(span, next)| (Some(span), next));
+            let mut name_end = name_begin;
+            while name_end < end && !self.is_keyword(name_end, Keyword::Where) && !self.is_punct(name_end, Punct::OpenBrace) &&
+                !self.is_punct(name_end, Punct::Semi) {
+                name_end = if self.is_open(name_end) { self.close_of(name_end) + 1 } else { name_end + 1 };
+            }
+            if name_end == name_begin {
+                return None;
+            }
+            (self.span(name_begin, name_end - 1), generics, name_end)
+        } else {
+            if !matches!(self.kind(i + 1), Some(TokenKind::Identifier) | Some(TokenKind::RawIdentifier)) {
+                //E.g. the fn pointer type `fn(u8)`
+                return None;
+            }
+            let (generics, header) = self.generics(i + 2, end).map_or((None, i + 2), |(span, next)| (Some(span), next));
+            (self.tokens[i + 1].span, generics, header)
+        };
+        let (body, next) = self.body(kind, header, end);
+        if let Some((open, close)) = body {
+            docs.extend((open + 1..close)
+                .map_while(|j| match self.tokens[j].kind {
+                    TokenKind::DocComment(comment_type) if is_inner_doc(comment_type) => Some(self.comment_at(self.tokens[j].span.begin)),
+                    _ => None
+                }));
+        }
+        let item = self.items.len();
+        self.items.push(OutlineItem {
+            kind,
+            name,
+            visibility,
+            generics,
+            body: body.map(|(open, close)| self.span(open, close)),
+            docs,
+            span: self.span(first, next - 1),
+            parent
+        });
+        if let Some((open, close)) = body {
+            self.items(open + 1, close, Some(item));
+        }
+        Some(next)
+    }
+}
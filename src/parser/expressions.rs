@@ -0,0 +1,630 @@
+use crate::lexer::{Keyword, Punct, TokenKind};
+use crate::preprocessor::TextUnit;
+
+use super::{Element, Node, Parser, SyntaxKind};
+
+///The binding powers of the infix operators, from the left and from the right. Assignments bind
+///to the right, the others to the left
+fn infix_power(kind: TokenKind) -> Option<(u8, u8, SyntaxKind)> {
+    let punct = match kind {
+        TokenKind::Keyword(Keyword::As) => return Some((23, 24, SyntaxKind::CastExpr)),
+        TokenKind::Punctuation(punct) => punct,
+        _ => return None
+    };
+    let (left, right) = match punct {
+        Punct::Eq | Punct::PlusEq | Punct::MinusEq | Punct::StarEq | Punct::SlashEq | Punct::PercentEq |
+        Punct::CaretEq | Punct::AndEq | Punct::OrEq | Punct::ShlEq | Punct::ShrEq => (2, 1),
+        Punct::DotDot | Punct::DotDotEq => return Some((3, 4, SyntaxKind::RangeExpr)),
+        Punct::OrOr => (5, 6),
+        Punct::AndAnd => (7, 8),
+        Punct::EqEq | Punct::Ne | Punct::Lt | Punct::Gt | Punct::Le | Punct::Ge => (9, 10),
+        Punct::Or => (11, 12),
+        Punct::Caret => (13, 14),
+        Punct::And => (15, 16),
+        Punct::Shl | Punct::Shr => (17, 18),
+        Punct::Plus | Punct::Minus => (19, 20),
+        Punct::Star | Punct::Slash | Punct::Percent => (21, 22),
+        _ => return None
+    };
+    Some((left, right, SyntaxKind::BinaryExpr))
+}
+
+impl<T: TextUnit> Parser<'_, T> {
+    pub(crate) fn expect_expr(&mut self, node: &mut Node, no_struct: bool) -> bool {
+        match self.expr(no_struct) {
+            Some(expr) => {
+                node.push(expr);
+                true
+            }
+            None => {
+                self.error("expected an expression");
+                false
+            }
+        }
+    }
+
+    ///Parses an expression, returning `None` if none begins at the current token. With
+    ///`no_struct` a path followed by a brace is no struct literal, as in the condition of an `if`
+    pub(crate) fn expr(&mut self, no_struct: bool) -> Option<Node> {
+        self.expr_bp(0, no_struct)
+    }
+
+    fn at_expr(&self, no_struct: bool) -> bool {
+        match self.nth(0) {
+            Some(TokenKind::Identifier) | Some(TokenKind::RawIdentifier) | Some(TokenKind::Literal { .. }) |
+            Some(TokenKind::Lifetime) => true,
+            Some(TokenKind::Keyword(keyword)) => matches!(keyword,
+                Keyword::True | Keyword::False | Keyword::SelfValue | Keyword::SelfType | Keyword::Super | Keyword::Crate |
+                Keyword::If | Keyword::Match | Keyword::Loop | Keyword::While | Keyword::For | Keyword::Unsafe |
+                Keyword::Async | Keyword::Const | Keyword::Move | Keyword::Static | Keyword::Return | Keyword::Break |
+                Keyword::Continue | Keyword::Let),
+            Some(TokenKind::Punctuation(punct)) => match punct {
+                Punct::OpenBrace => !no_struct,
+                Punct::OpenParen | Punct::OpenBracket | Punct::Minus | Punct::Not | Punct::Star | Punct::And |
+                Punct::AndAnd | Punct::Or | Punct::OrOr | Punct::DotDot | Punct::DotDotEq | Punct::Underscore |
+                Punct::Lt | Punct::Shl | Punct::PathSep | Punct::Pound => true,
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    ///Parses the operators binding at least as strongly as `min_power` and their operands
+    fn expr_bp(&mut self, min_power: u8, no_struct: bool) -> Option<Node> {
+        let lhs = if self.at(Punct::DotDot) || self.at(Punct::DotDotEq) {
+            //A range without a start
+            let mut range = Node::new(SyntaxKind::RangeExpr);
+            self.bump(&mut range);
+            if self.at_expr(no_struct) {
+                if let Some(end) = self.expr_bp(4, no_struct) {
+                    range.push(end);
+                }
+            }
+            range
+        } else {
+            self.unary(no_struct)?
+        };
+        Some(self.infix(lhs, min_power, no_struct))
+    }
+
+    ///Parses the infix operators following `lhs`
+    fn infix(&mut self, mut lhs: Node, min_power: u8, no_struct: bool) -> Node {
+        while let Some((left, right, kind)) = self.nth(0).and_then(infix_power) {
+            if left < min_power {
+                break;
+            }
+            let mut node = lhs.wrap(kind);
+            self.bump(&mut node);
+            match kind {
+                SyntaxKind::CastExpr => {
+                    self.expect_type(&mut node);
+                }
+                SyntaxKind::RangeExpr => {
+                    if self.at_expr(no_struct) {
+                        if let Some(end) = self.expr_bp(right, no_struct) {
+                            node.push(end);
+                        }
+                    }
+                }
+                _ => match self.expr_bp(right, no_struct) {
+                    Some(rhs) => node.push(rhs),
+                    None => self.error("expected an expression")
+                }
+            }
+            lhs = node;
+        }
+        lhs
+    }
+
+    fn unary(&mut self, no_struct: bool) -> Option<Node> {
+        if self.at(Punct::Pound) && self.nth_at(1, Punct::OpenBracket) {
+            //Outer attributes like `#[cfg(x)]` belong to the operand they precede
+            let mut attributes = Node::new(SyntaxKind::Error);
+            self.attributes(&mut attributes, false);
+            match self.unary(no_struct) {
+                Some(mut operand) => {
+                    operand.children.splice(0..0, attributes.children);
+                    return Some(operand);
+                }
+                None => {
+                    self.error("expected an expression after the attributes");
+                    return Some(attributes);
+                }
+            }
+        }
+        let mut node = if self.at(Punct::Minus) || self.at(Punct::Not) || self.at(Punct::Star) {
+            let mut node = Node::new(SyntaxKind::PrefixExpr);
+            self.bump(&mut node);
+            node
+        } else if self.at_prefix(Punct::And) && !self.at(Punct::AndEq) {
+            let mut node = Node::new(SyntaxKind::RefExpr);
+            self.eat_prefix(&mut node, Punct::And);
+            if self.nth_at_contextual(0, "raw") && (self.nth_at_keyword(1, Keyword::Const) || self.nth_at_keyword(1, Keyword::Mut)) {
+                self.bump(&mut node);
+                self.bump(&mut node);
+            } else {
+                self.eat_keyword(&mut node, Keyword::Mut);
+            }
+            node
+        } else {
+            let primary = self.primary(no_struct)?;
+            return Some(self.postfix(primary));
+        };
+        match self.unary(no_struct) {
+            Some(operand) => node.push(operand),
+            None => self.error("expected an expression")
+        }
+        Some(node)
+    }
+
+    ///Parses the calls, indexing, field accesses, `?` and `.await` following `lhs`
+    fn postfix(&mut self, mut lhs: Node) -> Node {
+        loop {
+            if self.at(Punct::Question) {
+                lhs = lhs.wrap(SyntaxKind::TryExpr);
+                self.bump(&mut lhs);
+            } else if self.at(Punct::Dot) {
+                if self.nth_at_keyword(1, Keyword::Await) {
+                    lhs = lhs.wrap(SyntaxKind::AwaitExpr);
+                    self.bump(&mut lhs);
+                    self.bump(&mut lhs);
+                } else if self.nth_at_identifier(1) && (self.nth_at(2, Punct::OpenParen) || self.nth_at(2, Punct::PathSep)) {
+                    lhs = lhs.wrap(SyntaxKind::MethodCallExpr);
+                    self.bump(&mut lhs);
+                    self.name(&mut lhs);
+                    if self.eat(&mut lhs, Punct::PathSep) {
+                        if self.at_prefix(Punct::Lt) {
+                            self.generic_args(&mut lhs);
+                        } else {
+                            self.error("expected `<`");
+                        }
+                    }
+                    self.arg_list(&mut lhs);
+                } else {
+                    lhs = lhs.wrap(SyntaxKind::FieldExpr);
+                    self.bump(&mut lhs);
+                    //Named and tuple fields, `x.0.1` has the float 0.1
+                    if self.nth_at_identifier(0) {
+                        self.name(&mut lhs);
+                    } else if matches!(self.nth(0), Some(TokenKind::Literal { .. })) {
+                        self.bump(&mut lhs);
+                    } else {
+                        self.error("expected a field");
+                    }
+                }
+            } else if self.at(Punct::OpenParen) {
+                lhs = lhs.wrap(SyntaxKind::CallExpr);
+                self.arg_list(&mut lhs);
+            } else if self.at(Punct::OpenBracket) {
+                lhs = lhs.wrap(SyntaxKind::IndexExpr);
+                self.bump(&mut lhs);
+                self.expect_expr(&mut lhs, false);
+                self.expect(&mut lhs, Punct::CloseBracket);
+            } else {
+                return lhs;
+            }
+        }
+    }
+
+    fn arg_list(&mut self, node: &mut Node) {
+        let mut args = Node::new(SyntaxKind::ArgList);
+        self.delimited(&mut args, Punct::OpenParen, Punct::Comma, "an expression", |parser, list| match parser.expr(false) {
+            Some(arg) => {
+                list.push(arg);
+                true
+            }
+            None => false
+        });
+        node.push(args);
+    }
+
+    fn primary(&mut self, no_struct: bool) -> Option<Node> {
+        if self.at_literal() {
+            let mut literal = Node::new(SyntaxKind::Literal);
+            self.bump(&mut literal);
+            return Some(literal);
+        }
+        if self.at_block_like() {
+            return Some(self.block_like());
+        }
+        let expr = match self.nth(0)? {
+            TokenKind::Punctuation(Punct::OpenParen) => self.paren_or_tuple(),
+            TokenKind::Punctuation(Punct::OpenBracket) => self.array(),
+            TokenKind::Punctuation(Punct::Or) | TokenKind::Punctuation(Punct::OrOr) |
+            TokenKind::Keyword(Keyword::Move) | TokenKind::Keyword(Keyword::Static) |
+            TokenKind::Keyword(Keyword::Async) => self.closure(no_struct),
+            TokenKind::Punctuation(Punct::Underscore) => {
+                let mut expr = Node::new(SyntaxKind::UnderscoreExpr);
+                self.bump(&mut expr);
+                expr
+            }
+            TokenKind::Keyword(Keyword::Let) => {
+                let mut expr = Node::new(SyntaxKind::LetExpr);
+                self.bump(&mut expr);
+                self.expect_pattern(&mut expr);
+                if self.expect(&mut expr, Punct::Eq) {
+                    //Above `&&`, which chains the conditions
+                    match self.expr_bp(8, no_struct) {
+                        Some(value) => expr.push(value),
+                        None => self.error("expected an expression")
+                    }
+                }
+                expr
+            }
+            TokenKind::Keyword(Keyword::Return) => {
+                let mut expr = Node::new(SyntaxKind::ReturnExpr);
+                self.bump(&mut expr);
+                if self.at_expr(no_struct) {
+                    if let Some(value) = self.expr(no_struct) {
+                        expr.push(value);
+                    }
+                }
+                expr
+            }
+            TokenKind::Keyword(Keyword::Break) | TokenKind::Keyword(Keyword::Continue) => {
+                let kind = if self.at_keyword(Keyword::Break) { SyntaxKind::BreakExpr } else { SyntaxKind::ContinueExpr };
+                let mut expr = Node::new(kind);
+                self.bump(&mut expr);
+                if self.nth(0) == Some(TokenKind::Lifetime) {
+                    self.bump(&mut expr);
+                }
+                if kind == SyntaxKind::BreakExpr && self.at_expr(no_struct) {
+                    if let Some(value) = self.expr(no_struct) {
+                        expr.push(value);
+                    }
+                }
+                expr
+            }
+            _ if self.at_path_start() => {
+                let path = self.path(true);
+                if self.at(Punct::Not) && !self.nth_at(1, Punct::Eq) {
+                    let mut call = path.wrap(SyntaxKind::MacroCall);
+                    self.bump(&mut call);
+                    if self.at_open() {
+                        call.push(self.token_tree());
+                    } else {
+                        self.error("expected `(`, `[` or `{`");
+                    }
+                    call
+                } else if self.at(Punct::OpenBrace) && !no_struct {
+                    let mut expr = path.wrap(SyntaxKind::StructExpr);
+                    self.record_expr_fields(&mut expr);
+                    expr
+                } else {
+                    path.wrap(SyntaxKind::PathExpr)
+                }
+            }
+            _ => return None
+        };
+        Some(expr)
+    }
+
+    fn paren_or_tuple(&mut self) -> Node {
+        let mut expr = Node::new(SyntaxKind::TupleExpr);
+        self.bump(&mut expr);
+        let (mut elements, mut comma) = (0, false);
+        while !self.at_end() && !self.at_close() {
+            if !self.expect_expr(&mut expr, false) {
+                break;
+            }
+            elements += 1;
+            comma = self.eat(&mut expr, Punct::Comma);
+            if !comma {
+                break;
+            }
+        }
+        self.expect(&mut expr, Punct::CloseParen);
+        if elements == 1 && !comma {
+            expr.kind = SyntaxKind::ParenExpr;
+        }
+        expr
+    }
+
+    ///`[a, b, c]` or `[a; n]`
+    fn array(&mut self) -> Node {
+        let mut expr = Node::new(SyntaxKind::ArrayExpr);
+        self.bump(&mut expr);
+        if !self.at(Punct::CloseBracket) && self.expect_expr(&mut expr, false) {
+            if self.eat(&mut expr, Punct::Semi) {
+                self.expect_expr(&mut expr, false);
+            } else {
+                while self.eat(&mut expr, Punct::Comma) && !self.at(Punct::CloseBracket) {
+                    if !self.expect_expr(&mut expr, false) {
+                        break;
+                    }
+                }
+            }
+        }
+        self.expect(&mut expr, Punct::CloseBracket);
+        expr
+    }
+
+    ///The fields of a struct literal like `{ x, y: 0, ..base }`
+    fn record_expr_fields(&mut self, node: &mut Node) {
+        let mut fields = Node::new(SyntaxKind::RecordExprFieldList);
+        self.delimited(&mut fields, Punct::OpenBrace, Punct::Comma, "a field", |parser, list| {
+            let mut field = Node::new(SyntaxKind::RecordExprField);
+            parser.attributes(&mut field, false);
+            if parser.eat(&mut field, Punct::DotDot) {
+                if parser.at_expr(false) {
+                    parser.expect_expr(&mut field, false);
+                }
+            } else if (parser.nth_at_identifier(0) || matches!(parser.nth(0), Some(TokenKind::Literal { .. }))) &&
+                parser.nth_at(1, Punct::Colon) {
+                let mut name = Node::new(SyntaxKind::Name);
+                parser.bump(&mut name);
+                field.push(name);
+                parser.bump(&mut field);
+                parser.expect_expr(&mut field, false);
+            } else if parser.nth_at_identifier(0) {
+                parser.name(&mut field);
+            } else if field.children.is_empty() {
+                return false;
+            } else {
+                parser.error("expected a field");
+            }
+            list.push(field);
+            true
+        });
+        node.push(fields);
+    }
+
+    ///`|x, y: u8| x + y`, `move || {}` or `async move |x| {}`
+    fn closure(&mut self, no_struct: bool) -> Node {
+        let mut expr = Node::new(SyntaxKind::ClosureExpr);
+        self.eat_keyword(&mut expr, Keyword::Static);
+        self.eat_keyword(&mut expr, Keyword::Async);
+        self.eat_keyword(&mut expr, Keyword::Move);
+        let mut params = Node::new(SyntaxKind::ClosureParamList);
+        if !self.eat(&mut params, Punct::OrOr) && self.expect(&mut params, Punct::Or) {
+            while !self.at_end() && !self.at(Punct::Or) {
+                let mut param = Node::new(SyntaxKind::Param);
+                self.attributes(&mut param, false);
+                match self.pattern_single() {
+                    Some(pattern) => param.push(pattern),
+                    None => {
+                        self.error("expected a parameter");
+                        break;
+                    }
+                }
+                if self.eat(&mut param, Punct::Colon) {
+                    self.expect_type(&mut param);
+                }
+                params.push(param);
+                if !self.at(Punct::Or) && !self.eat(&mut params, Punct::Comma) {
+                    break;
+                }
+            }
+            self.expect(&mut params, Punct::Or);
+        }
+        expr.push(params);
+        if self.at(Punct::RArrow) {
+            self.ret_type(&mut expr);
+            if self.at(Punct::OpenBrace) {
+                expr.push(self.block_expr());
+            } else {
+                self.error("expected `{`");
+            }
+        } else {
+            self.expect_expr(&mut expr, no_struct);
+        }
+        expr
+    }
+
+    ///Whether an expression ending with a block begins here, like `if`, `match` and loops
+    fn at_block_like(&self) -> bool {
+        match self.nth(0) {
+            Some(TokenKind::Punctuation(Punct::OpenBrace)) => true,
+            Some(TokenKind::Keyword(Keyword::If)) | Some(TokenKind::Keyword(Keyword::Match)) |
+            Some(TokenKind::Keyword(Keyword::Loop)) | Some(TokenKind::Keyword(Keyword::While)) |
+            Some(TokenKind::Keyword(Keyword::For)) => true,
+            Some(TokenKind::Keyword(Keyword::Unsafe)) | Some(TokenKind::Keyword(Keyword::Const)) => self.nth_at(1, Punct::OpenBrace),
+            Some(TokenKind::Keyword(Keyword::Async)) => self.nth_at(1, Punct::OpenBrace) ||
+                (self.nth_at_keyword(1, Keyword::Move) && self.nth_at(2, Punct::OpenBrace)),
+            Some(TokenKind::Lifetime) => self.nth_at(1, Punct::Colon),
+            _ => false
+        }
+    }
+
+    fn block_like(&mut self) -> Node {
+        let label = if self.nth(0) == Some(TokenKind::Lifetime) {
+            let mut label = Node::new(SyntaxKind::Label);
+            self.bump(&mut label);
+            self.bump(&mut label);
+            Some(label)
+        } else {
+            None
+        };
+        let kind = match self.nth(0) {
+            Some(TokenKind::Keyword(Keyword::If)) => SyntaxKind::IfExpr,
+            Some(TokenKind::Keyword(Keyword::Match)) => SyntaxKind::MatchExpr,
+            Some(TokenKind::Keyword(Keyword::Loop)) => SyntaxKind::LoopExpr,
+            Some(TokenKind::Keyword(Keyword::While)) => SyntaxKind::WhileExpr,
+            Some(TokenKind::Keyword(Keyword::For)) => SyntaxKind::ForExpr,
+            _ => SyntaxKind::BlockExpr
+        };
+        let mut expr = Node::new(kind);
+        if let Some(label) = label {
+            expr.push(label);
+        }
+        match kind {
+            SyntaxKind::IfExpr => self.if_expr(&mut expr),
+            SyntaxKind::MatchExpr => self.match_expr(&mut expr),
+            SyntaxKind::LoopExpr => {
+                self.bump(&mut expr);
+                expr.push(self.block_expr());
+            }
+            SyntaxKind::WhileExpr => {
+                self.bump(&mut expr);
+                self.expect_expr(&mut expr, true);
+                expr.push(self.block_expr());
+            }
+            SyntaxKind::ForExpr => {
+                self.bump(&mut expr);
+                self.expect_pattern(&mut expr);
+                if self.expect_keyword(&mut expr, Keyword::In) {
+                    self.expect_expr(&mut expr, true);
+                }
+                expr.push(self.block_expr());
+            }
+            _ => {
+                //`unsafe`, `const`, `async` or `async move`
+                while !self.at(Punct::OpenBrace) && !self.at_end() {
+                    self.bump(&mut expr);
+                }
+                self.block_into(&mut expr);
+            }
+        }
+        expr
+    }
+
+    fn if_expr(&mut self, expr: &mut Node) {
+        self.bump(expr);
+        self.expect_expr(expr, true);
+        expr.push(self.block_expr());
+        if self.eat_keyword(expr, Keyword::Else) {
+            if self.at_keyword(Keyword::If) {
+                let mut else_if = Node::new(SyntaxKind::IfExpr);
+                self.if_expr(&mut else_if);
+                expr.push(else_if);
+            } else {
+                expr.push(self.block_expr());
+            }
+        }
+    }
+
+    fn match_expr(&mut self, expr: &mut Node) {
+        self.bump(expr);
+        self.expect_expr(expr, true);
+        let mut arms = Node::new(SyntaxKind::MatchArmList);
+        if self.expect(&mut arms, Punct::OpenBrace) {
+            self.attributes(&mut arms, true);
+            while !self.at_end() && !self.at_close() {
+                let mut arm = Node::new(SyntaxKind::MatchArm);
+                self.attributes(&mut arm, false);
+                match self.pattern() {
+                    Some(pattern) => arm.push(pattern),
+                    None => {
+                        if !arm.children.is_empty() {
+                            arms.push(arm);
+                        }
+                        self.error_and_bump(&mut arms, "expected a pattern");
+                        continue;
+                    }
+                }
+                if self.at_keyword(Keyword::If) {
+                    let mut guard = Node::new(SyntaxKind::MatchGuard);
+                    self.bump(&mut guard);
+                    self.expect_expr(&mut guard, false);
+                    arm.push(guard);
+                }
+                if self.expect(&mut arm, Punct::FatArrow) {
+                    match self.statement_expr() {
+                        Some((body, block_like)) => {
+                            arm.push(body);
+                            if !self.eat(&mut arm, Punct::Comma) && !block_like && !self.at(Punct::CloseBrace) {
+                                self.error("expected `,` or `}`");
+                            }
+                        }
+                        None => self.error("expected an expression")
+                    }
+                }
+                arms.push(arm);
+            }
+            self.expect(&mut arms, Punct::CloseBrace);
+        }
+        expr.push(arms);
+    }
+
+    pub(crate) fn block_expr(&mut self) -> Node {
+        let mut block = Node::new(SyntaxKind::BlockExpr);
+        self.block_into(&mut block);
+        block
+    }
+
+    ///Parses the statements of a block with its braces into `block`
+    fn block_into(&mut self, block: &mut Node) {
+        if !self.expect(block, Punct::OpenBrace) {
+            return;
+        }
+        self.attributes(block, true);
+        while !self.at_end() && !self.at(Punct::CloseBrace) {
+            let pos = self.pos;
+            if self.at_close() {
+                self.error_and_bump(block, "unexpected closing delimiter");
+                break;
+            }
+            if !self.eat(block, Punct::Semi) {
+                self.statement(block);
+            }
+            if self.pos == pos {
+                self.error_and_bump(block, "expected a statement");
+            }
+        }
+        self.expect(block, Punct::CloseBrace);
+    }
+
+    fn statement(&mut self, block: &mut Node) {
+        let mut statement = Node::new(SyntaxKind::ExprStmt);
+        self.attributes(&mut statement, false);
+        if self.at_keyword(Keyword::Let) {
+            statement.kind = SyntaxKind::LetStmt;
+            self.bump(&mut statement);
+            self.expect_pattern(&mut statement);
+            if self.eat(&mut statement, Punct::Colon) {
+                self.expect_type(&mut statement);
+            }
+            if self.eat(&mut statement, Punct::Eq) {
+                self.expect_expr(&mut statement, false);
+                if self.at_keyword(Keyword::Else) {
+                    let mut let_else = Node::new(SyntaxKind::LetElse);
+                    self.bump(&mut let_else);
+                    let_else.push(self.block_expr());
+                    statement.push(let_else);
+                }
+            }
+            self.expect(&mut statement, Punct::Semi);
+        } else if self.item_after_attributes(&mut statement, false) {
+            //The item gave the statement its kind
+        } else {
+            match self.statement_expr() {
+                Some((expr, block_like)) => {
+                    let braced_macro = expr.kind == SyntaxKind::MacroCall && matches!(expr.children.last(),
+                        Some(Element::Node(tree)) if tree.kind == SyntaxKind::TokenTree &&
+                            matches!(tree.children.first(), Some(Element::Token(token)) if token.kind == TokenKind::Punctuation(Punct::OpenBrace)));
+                    statement.push(expr);
+                    if block_like || braced_macro {
+                        self.eat(&mut statement, Punct::Semi);
+                    } else {
+                        self.end_statement(&mut statement);
+                    }
+                }
+                None if statement.children.is_empty() => return,
+                None => self.error("expected a statement after the attributes")
+            }
+        }
+        block.push(statement);
+    }
+
+    ///An expression statement or the body of a match arm, which ends after a block unless a
+    ///method call or `?` follows it. Returns whether it ended with the block
+    fn statement_expr(&mut self) -> Option<(Node, bool)> {
+        if !self.at_block_like() {
+            return self.expr(false).map(|expr| (expr, false));
+        }
+        let expr = self.block_like();
+        if self.at(Punct::Dot) || self.at(Punct::Question) {
+            let expr = self.postfix(expr);
+            return Some((self.infix(expr, 0, false), false));
+        }
+        Some((expr, true))
+    }
+
+    ///Eats the semicolon ending an expression statement, which the tail expression of a block
+    ///does not have
+    fn end_statement(&mut self, statement: &mut Node) {
+        if !self.at(Punct::CloseBrace) {
+            self.expect(statement, Punct::Semi);
+        }
+    }
+}
@@ -0,0 +1,682 @@
+use crate::lexer::{Keyword, Punct, TokenKind};
+use crate::preprocessor::TextUnit;
+
+use super::{Node, Parser, SyntaxKind};
+
+impl<T: TextUnit> Parser<'_, T> {
+    ///Whether an item begins at token `n`, after its attributes. Macro calls are left out, in
+    ///blocks they are expressions
+    pub(crate) fn nth_at_item(&self, n: usize) -> bool {
+        let next_is = |keywords: &[Keyword]| keywords.iter().any(|keyword| self.nth_at_keyword(n + 1, *keyword));
+        match self.nth(n) {
+            Some(TokenKind::Keyword(keyword)) => match keyword {
+                Keyword::Pub | Keyword::Fn | Keyword::Struct | Keyword::Enum | Keyword::Trait | Keyword::Impl |
+                Keyword::Mod | Keyword::Use | Keyword::Type | Keyword::Extern => true,
+                Keyword::Crate => !self.nth_at(n + 1, Punct::PathSep),
+                //Not a const block
+                Keyword::Const => self.nth_at_identifier(n + 1) || self.nth_at(n + 1, Punct::Underscore) ||
+                    next_is(&[Keyword::Fn, Keyword::Unsafe, Keyword::Async, Keyword::Extern]),
+                //Not a static closure
+                Keyword::Static => self.nth_at_identifier(n + 1) || next_is(&[Keyword::Mut]),
+                //Not an unsafe or async block, nor an async closure
+                Keyword::Unsafe => !self.nth_at(n + 1, Punct::OpenBrace),
+                Keyword::Async => next_is(&[Keyword::Fn, Keyword::Unsafe]),
+                _ => false
+            },
+            Some(TokenKind::Identifier) => {
+                (self.nth_at_contextual(n, "union") && self.nth_at_identifier(n + 1)) ||
+                    (self.nth_at_contextual(n, "auto") && next_is(&[Keyword::Trait])) ||
+                    (self.nth_at_contextual(n, "safe") && next_is(&[Keyword::Fn, Keyword::Static])) ||
+                    (self.nth_at_contextual(n, "default") &&
+                        next_is(&[Keyword::Fn, Keyword::Impl, Keyword::Unsafe, Keyword::Const, Keyword::Type, Keyword::Async])) ||
+                    (self.nth_at_contextual(n, "macro_rules") && self.nth_at(n + 1, Punct::Not) && self.nth_at_identifier(n + 2))
+            }
+            _ => false
+        }
+    }
+
+    ///Whether a macro call like `thread_local! { .. }` begins at the current token
+    fn at_macro_call(&self) -> bool {
+        let mut n = usize::from(self.at(Punct::PathSep));
+        while self.nth_at_identifier(n) || self.nth_at_keyword(n, Keyword::SelfValue) ||
+            self.nth_at_keyword(n, Keyword::Super) || self.nth_at_keyword(n, Keyword::Crate) {
+            if !self.nth_at(n + 1, Punct::PathSep) {
+                return self.nth_at(n + 1, Punct::Not);
+            }
+            n += 2;
+        }
+        false
+    }
+
+    ///Parses an item with its attributes into `node`, returning false if none begins here
+    pub(crate) fn item(&mut self, node: &mut Node) -> bool {
+        let mut item = Node::new(SyntaxKind::Error);
+        self.attributes(&mut item, false);
+        if !self.item_after_attributes(&mut item, true) {
+            if item.children.is_empty() {
+                return false;
+            }
+            self.error("expected an item after the attributes");
+        }
+        node.push(item);
+        true
+    }
+
+    ///Whether an item, its attributes or a macro call begins at the current token
+    fn at_item_start(&self) -> bool {
+        (self.at(Punct::Pound) && self.nth_at(1, Punct::OpenBracket)) || self.nth_at_item(0) || self.at_macro_call()
+    }
+
+    ///Keeps the tokens up to the next item in an `Error` node, with one error for all of them.
+    ///Closing delimiters are left to the list they close
+    pub(crate) fn error_until_item(&mut self, node: &mut Node) {
+        self.error("expected an item");
+        let mut error = Node::new(SyntaxKind::Error);
+        while !self.at_end() && !self.at_close() && !self.at_item_start() {
+            if self.at_open() {
+                error.push(self.token_tree());
+            } else {
+                self.bump(&mut error);
+            }
+        }
+        if !error.children.is_empty() {
+            node.push(error);
+        }
+    }
+
+    ///Parses the item following the attributes already in `item`, which gets the kind of the item
+    pub(crate) fn item_after_attributes(&mut self, item: &mut Node, macros: bool) -> bool {
+        if macros && !self.nth_at_item(0) && self.at_macro_call() {
+            item.kind = SyntaxKind::MacroCall;
+            self.macro_call_item(item);
+            return true;
+        }
+        if !self.nth_at_item(0) {
+            return false;
+        }
+        self.visibility(item);
+        //The qualifiers of fns, impls, traits and extern blocks come before their keyword
+        let mut n = 0;
+        loop {
+            if self.nth_at_keyword(n, Keyword::Extern) {
+                n += 1;
+                if matches!(self.nth(n), Some(TokenKind::Literal { .. })) {
+                    n += 1;
+                }
+            } else if self.nth_at_keyword(n, Keyword::Async) || self.nth_at_keyword(n, Keyword::Unsafe) ||
+                (self.nth_at_keyword(n, Keyword::Const) && !self.nth_at_identifier(n + 1) && !self.nth_at(n + 1, Punct::Underscore)) ||
+                ((self.nth_at_contextual(n, "default") || self.nth_at_contextual(n, "auto") || self.nth_at_contextual(n, "safe")) &&
+                    matches!(self.nth(n + 1), Some(TokenKind::Keyword(_)))) {
+                n += 1;
+            } else {
+                break;
+            }
+        }
+        let qualified = |kind: SyntaxKind, parser: &mut Self, item: &mut Node| {
+            item.kind = kind;
+            for _ in 0..n {
+                parser.bump(item);
+            }
+        };
+        match self.nth(n) {
+            Some(TokenKind::Keyword(Keyword::Fn)) => {
+                qualified(SyntaxKind::Function, self, item);
+                self.function(item);
+            }
+            Some(TokenKind::Keyword(Keyword::Trait)) => {
+                qualified(SyntaxKind::Trait, self, item);
+                self.trait_item(item);
+            }
+            Some(TokenKind::Keyword(Keyword::Impl)) => {
+                qualified(SyntaxKind::Impl, self, item);
+                self.impl_item(item);
+            }
+            Some(TokenKind::Keyword(Keyword::Crate)) if n > 0 && self.nth_at_keyword(n - 1, Keyword::Extern) => {
+                qualified(SyntaxKind::ExternCrate, self, item);
+                self.extern_crate(item);
+            }
+            Some(TokenKind::Punctuation(Punct::OpenBrace)) if n > 0 => {
+                qualified(SyntaxKind::ExternBlock, self, item);
+                self.item_list(item);
+            }
+            Some(TokenKind::Keyword(Keyword::Static)) if n > 0 => {
+                //E.g. `safe static` in an extern block
+                qualified(SyntaxKind::Static, self, item);
+                self.const_or_static(item);
+            }
+            _ if n > 0 => {
+                qualified(SyntaxKind::Error, self, item);
+                self.error("expected `fn`, `trait`, `impl` or an extern block");
+            }
+            Some(TokenKind::Keyword(Keyword::Struct)) => {
+                item.kind = SyntaxKind::Struct;
+                self.struct_item(item);
+            }
+            Some(TokenKind::Identifier) if self.nth_at_contextual(0, "union") => {
+                item.kind = SyntaxKind::Union;
+                self.struct_item(item);
+            }
+            Some(TokenKind::Identifier) if self.nth_at_contextual(0, "macro_rules") => {
+                item.kind = SyntaxKind::MacroRules;
+                self.bump(item);
+                self.bump(item);
+                self.name(item);
+                self.macro_body(item);
+            }
+            Some(TokenKind::Keyword(Keyword::Enum)) => {
+                item.kind = SyntaxKind::Enum;
+                self.enum_item(item);
+            }
+            Some(TokenKind::Keyword(Keyword::Mod)) => {
+                item.kind = SyntaxKind::Module;
+                self.bump(item);
+                self.name(item);
+                if self.at(Punct::OpenBrace) {
+                    self.item_list(item);
+                } else {
+                    self.expect(item, Punct::Semi);
+                }
+            }
+            Some(TokenKind::Keyword(Keyword::Use)) => {
+                item.kind = SyntaxKind::Use;
+                self.bump(item);
+                if !self.use_tree(item) {
+                    self.error("expected a path");
+                }
+                self.expect(item, Punct::Semi);
+            }
+            Some(TokenKind::Keyword(Keyword::Type)) => {
+                item.kind = SyntaxKind::TypeAlias;
+                self.type_alias(item);
+            }
+            Some(TokenKind::Keyword(Keyword::Const)) => {
+                item.kind = SyntaxKind::Const;
+                self.const_or_static(item);
+            }
+            Some(TokenKind::Keyword(Keyword::Static)) => {
+                item.kind = SyntaxKind::Static;
+                self.const_or_static(item);
+            }
+            _ => {
+                //Only a visibility
+                self.error("expected an item after the visibility");
+            }
+        }
+        true
+    }
+
+    ///`pub`, `pub(crate)`, `pub(self)`, `pub(super)`, `pub(in path)` or `crate`
+    pub(crate) fn visibility(&mut self, node: &mut Node) {
+        let mut visibility = Node::new(SyntaxKind::Visibility);
+        if self.at_keyword(Keyword::Crate) && !self.nth_at(1, Punct::PathSep) {
+            self.bump(&mut visibility);
+        } else if self.at_keyword(Keyword::Pub) {
+            self.bump(&mut visibility);
+            let restricted = self.at(Punct::OpenParen) && (self.nth_at_keyword(1, Keyword::In) ||
+                ((self.nth_at_keyword(1, Keyword::Crate) || self.nth_at_keyword(1, Keyword::SelfValue) ||
+                    self.nth_at_keyword(1, Keyword::Super)) && self.nth_at(2, Punct::CloseParen)));
+            if restricted {
+                self.bump(&mut visibility);
+                if self.eat_keyword(&mut visibility, Keyword::In) {
+                    let path = self.path(false);
+                    visibility.push(path);
+                } else {
+                    self.bump(&mut visibility);
+                }
+                self.expect(&mut visibility, Punct::CloseParen);
+            }
+        } else {
+            return;
+        }
+        node.push(visibility);
+    }
+
+    fn function(&mut self, item: &mut Node) {
+        self.bump(item);
+        self.name(item);
+        self.generic_params(item);
+        self.param_list(item);
+        self.ret_type(item);
+        self.where_clause(item);
+        if self.at(Punct::OpenBrace) {
+            let body = self.block_expr();
+            item.push(body);
+        } else {
+            self.expect(item, Punct::Semi);
+        }
+    }
+
+    fn struct_item(&mut self, item: &mut Node) {
+        self.bump(item);
+        self.name(item);
+        self.generic_params(item);
+        if self.at(Punct::OpenParen) {
+            self.tuple_field_list(item);
+            self.where_clause(item);
+            self.expect(item, Punct::Semi);
+            return;
+        }
+        self.where_clause(item);
+        if self.at(Punct::OpenBrace) {
+            self.record_field_list(item);
+        } else {
+            self.expect(item, Punct::Semi);
+        }
+    }
+
+    fn enum_item(&mut self, item: &mut Node) {
+        self.bump(item);
+        self.name(item);
+        self.generic_params(item);
+        self.where_clause(item);
+        let mut variants = Node::new(SyntaxKind::VariantList);
+        self.delimited(&mut variants, Punct::OpenBrace, Punct::Comma, "a variant", |parser, list| {
+            let mut variant = Node::new(SyntaxKind::Variant);
+            parser.attributes(&mut variant, false);
+            parser.visibility(&mut variant);
+            if !parser.nth_at_identifier(0) {
+                if variant.children.is_empty() {
+                    return false;
+                }
+                parser.error("expected a variant");
+                list.push(variant);
+                return true;
+            }
+            parser.name(&mut variant);
+            if parser.at(Punct::OpenBrace) {
+                parser.record_field_list(&mut variant);
+            } else if parser.at(Punct::OpenParen) {
+                parser.tuple_field_list(&mut variant);
+            }
+            if parser.eat(&mut variant, Punct::Eq) {
+                parser.expect_expr(&mut variant, false);
+            }
+            list.push(variant);
+            true
+        });
+        item.push(variants);
+    }
+
+    fn record_field_list(&mut self, node: &mut Node) {
+        let mut fields = Node::new(SyntaxKind::RecordFieldList);
+        self.delimited(&mut fields, Punct::OpenBrace, Punct::Comma, "a field", |parser, list| {
+            let mut field = Node::new(SyntaxKind::RecordField);
+            parser.attributes(&mut field, false);
+            parser.visibility(&mut field);
+            if !parser.nth_at_identifier(0) {
+                if field.children.is_empty() {
+                    return false;
+                }
+                parser.error("expected a field");
+                list.push(field);
+                return true;
+            }
+            parser.name(&mut field);
+            if parser.expect(&mut field, Punct::Colon) {
+                parser.expect_type(&mut field);
+            }
+            list.push(field);
+            true
+        });
+        node.push(fields);
+    }
+
+    fn tuple_field_list(&mut self, node: &mut Node) {
+        let mut fields = Node::new(SyntaxKind::TupleFieldList);
+        self.delimited(&mut fields, Punct::OpenParen, Punct::Comma, "a field", |parser, list| {
+            let mut field = Node::new(SyntaxKind::TupleField);
+            parser.attributes(&mut field, false);
+            parser.visibility(&mut field);
+            match parser.ty() {
+                Some(ty) => field.push(ty),
+                None if field.children.is_empty() => return false,
+                None => parser.error("expected a type")
+            }
+            list.push(field);
+            true
+        });
+        node.push(fields);
+    }
+
+    fn trait_item(&mut self, item: &mut Node) {
+        self.bump(item);
+        self.name(item);
+        self.generic_params(item);
+        if self.eat(item, Punct::Colon) {
+            self.type_bounds(item);
+        }
+        self.where_clause(item);
+        //A trait alias
+        if self.eat(item, Punct::Eq) {
+            self.type_bounds(item);
+            self.where_clause(item);
+            self.expect(item, Punct::Semi);
+            return;
+        }
+        self.item_list(item);
+    }
+
+    fn impl_item(&mut self, item: &mut Node) {
+        self.bump(item);
+        if self.at(Punct::Lt) {
+            self.generic_params(item);
+        }
+        self.eat_keyword(item, Keyword::Const);
+        self.eat(item, Punct::Not);
+        self.expect_type(item);
+        if self.eat_keyword(item, Keyword::For) {
+            self.expect_type(item);
+        }
+        self.where_clause(item);
+        self.item_list(item);
+    }
+
+    ///The items of a module, trait, impl or extern block with the braces
+    fn item_list(&mut self, node: &mut Node) {
+        let mut items = Node::new(SyntaxKind::ItemList);
+        if self.expect(&mut items, Punct::OpenBrace) {
+            self.attributes(&mut items, true);
+            while !self.at_end() && !self.at_close() {
+                if !self.item(&mut items) {
+                    self.error_until_item(&mut items);
+                }
+            }
+            self.expect(&mut items, Punct::CloseBrace);
+        }
+        node.push(items);
+    }
+
+    fn at_use_segment(&self, n: usize) -> bool {
+        self.nth_at_identifier(n) || self.nth_at_keyword(n, Keyword::SelfValue) || self.nth_at_keyword(n, Keyword::Super) ||
+            self.nth_at_keyword(n, Keyword::Crate) || self.nth_at_keyword(n, Keyword::SelfType)
+    }
+
+    ///A tree of paths like `std::{fmt, io::*}`, returning false if none begins here
+    fn use_tree(&mut self, node: &mut Node) -> bool {
+        let mut tree = Node::new(SyntaxKind::UseTree);
+        if self.at_use_segment(0) || (self.at(Punct::PathSep) && self.at_use_segment(1)) {
+            let mut path = Node::new(SyntaxKind::Path);
+            self.eat(&mut path, Punct::PathSep);
+            while self.at_use_segment(0) {
+                let mut segment = Node::new(SyntaxKind::PathSegment);
+                self.bump(&mut segment);
+                path.push(segment);
+                if !self.at(Punct::PathSep) || !self.at_use_segment(1) {
+                    break;
+                }
+                self.bump(&mut path);
+            }
+            tree.push(path);
+        }
+        self.eat(&mut tree, Punct::PathSep);
+        if self.at(Punct::OpenBrace) {
+            let mut list = Node::new(SyntaxKind::UseTreeList);
+            self.delimited(&mut list, Punct::OpenBrace, Punct::Comma, "a path", |parser, list| parser.use_tree(list));
+            tree.push(list);
+        } else if !self.eat(&mut tree, Punct::Star) && self.eat_keyword(&mut tree, Keyword::As) &&
+            !self.eat(&mut tree, Punct::Underscore) {
+            self.name(&mut tree);
+        }
+        if tree.children.is_empty() {
+            return false;
+        }
+        node.push(tree);
+        true
+    }
+
+    fn extern_crate(&mut self, item: &mut Node) {
+        self.bump(item);
+        if !self.eat_keyword(item, Keyword::SelfValue) {
+            self.name(item);
+        }
+        if self.eat_keyword(item, Keyword::As) && !self.eat(item, Punct::Underscore) {
+            self.name(item);
+        }
+        self.expect(item, Punct::Semi);
+    }
+
+    fn const_or_static(&mut self, item: &mut Node) {
+        self.bump(item);
+        self.eat_keyword(item, Keyword::Mut);
+        if !self.eat(item, Punct::Underscore) {
+            self.name(item);
+        }
+        if self.expect(item, Punct::Colon) {
+            self.expect_type(item);
+        }
+        if self.eat(item, Punct::Eq) {
+            self.expect_expr(item, false);
+        }
+        self.expect(item, Punct::Semi);
+    }
+
+    fn type_alias(&mut self, item: &mut Node) {
+        self.bump(item);
+        self.name(item);
+        self.generic_params(item);
+        if self.eat(item, Punct::Colon) {
+            self.type_bounds(item);
+        }
+        self.where_clause(item);
+        if self.eat(item, Punct::Eq) {
+            self.expect_type(item);
+            self.where_clause(item);
+        }
+        self.expect(item, Punct::Semi);
+    }
+
+    fn macro_call_item(&mut self, item: &mut Node) {
+        let path = self.path(true);
+        item.push(path);
+        self.bump(item);
+        self.macro_body(item);
+    }
+
+    ///The token tree given to a macro, which needs a semicolon after it unless it is in braces
+    fn macro_body(&mut self, item: &mut Node) {
+        if !self.at_open() {
+            self.error("expected `(`, `[` or `{`");
+            return;
+        }
+        let braces = self.at(Punct::OpenBrace);
+        item.push(self.token_tree());
+        if braces {
+            self.eat(item, Punct::Semi);
+        } else {
+            self.expect(item, Punct::Semi);
+        }
+    }
+
+    ///Generic parameters like `<'a, T: Clone = u8, const N: usize>`, if there are any
+    pub(crate) fn generic_params(&mut self, node: &mut Node) {
+        if !self.at(Punct::Lt) {
+            return;
+        }
+        let mut params = Node::new(SyntaxKind::GenericParamList);
+        self.bump(&mut params);
+        while !self.at_end() && !self.at_prefix(Punct::Gt) && !self.at_close() {
+            let mut param = Node::new(SyntaxKind::Error);
+            self.attributes(&mut param, false);
+            if self.nth(0) == Some(TokenKind::Lifetime) {
+                param.kind = SyntaxKind::LifetimeParam;
+                self.bump(&mut param);
+                if self.eat(&mut param, Punct::Colon) {
+                    self.lifetime_bounds(&mut param);
+                }
+            } else if self.at_keyword(Keyword::Const) {
+                param.kind = SyntaxKind::ConstParam;
+                self.bump(&mut param);
+                self.name(&mut param);
+                if self.expect(&mut param, Punct::Colon) {
+                    self.expect_type(&mut param);
+                }
+                if self.eat(&mut param, Punct::Eq) {
+                    match self.const_arg() {
+                        Some(value) => param.push(value),
+                        None => self.error("expected a constant")
+                    }
+                }
+            } else if self.nth_at_identifier(0) {
+                param.kind = SyntaxKind::TypeParam;
+                self.name(&mut param);
+                if self.eat(&mut param, Punct::Colon) {
+                    self.type_bounds(&mut param);
+                }
+                if self.eat(&mut param, Punct::Eq) {
+                    self.expect_type(&mut param);
+                }
+            } else {
+                self.error_and_bump(&mut params, "expected a generic parameter");
+                continue;
+            }
+            params.push(param);
+            if !self.at_prefix(Punct::Gt) && !self.eat(&mut params, Punct::Comma) {
+                self.error("expected `,` or `>`");
+                break;
+            }
+        }
+        if !self.eat_prefix(&mut params, Punct::Gt) {
+            self.error("expected `>`");
+        }
+        node.push(params);
+    }
+
+    ///Lifetimes separated by `+`, like the bounds of a lifetime parameter
+    fn lifetime_bounds(&mut self, node: &mut Node) {
+        while self.nth(0) == Some(TokenKind::Lifetime) {
+            self.bump(node);
+            if !self.eat(node, Punct::Plus) {
+                break;
+            }
+        }
+    }
+
+    ///A where clause like `where T: Clone, 'a: 'b`, if there is one
+    pub(crate) fn where_clause(&mut self, node: &mut Node) {
+        if !self.at_keyword(Keyword::Where) {
+            return;
+        }
+        let mut clause = Node::new(SyntaxKind::WhereClause);
+        self.bump(&mut clause);
+        loop {
+            let mut predicate = Node::new(SyntaxKind::WherePredicate);
+            if self.nth(0) == Some(TokenKind::Lifetime) {
+                self.bump(&mut predicate);
+                if self.expect(&mut predicate, Punct::Colon) {
+                    self.lifetime_bounds(&mut predicate);
+                }
+            } else {
+                if self.eat_keyword(&mut predicate, Keyword::For) {
+                    self.generic_params(&mut predicate);
+                }
+                match self.ty() {
+                    Some(ty) => predicate.push(ty),
+                    None if predicate.children.is_empty() => break,
+                    None => self.error("expected a type")
+                }
+                if self.expect(&mut predicate, Punct::Colon) {
+                    self.type_bounds(&mut predicate);
+                }
+            }
+            clause.push(predicate);
+            if !self.eat(&mut clause, Punct::Comma) {
+                break;
+            }
+        }
+        node.push(clause);
+    }
+
+    ///Bounds like `Clone + 'a + ?Sized + for<'b> Fn(&'b u8)`, there may be none
+    pub(crate) fn type_bounds(&mut self, node: &mut Node) {
+        let mut bounds = Node::new(SyntaxKind::TypeBoundList);
+        loop {
+            let mut bound = Node::new(SyntaxKind::TypeBound);
+            if self.nth(0) == Some(TokenKind::Lifetime) {
+                self.bump(&mut bound);
+            } else if self.at(Punct::OpenParen) {
+                self.bump(&mut bound);
+                self.type_bounds(&mut bound);
+                self.expect(&mut bound, Punct::CloseParen);
+            } else {
+                self.eat(&mut bound, Punct::Question);
+                if self.at(Punct::Tilde) {
+                    self.bump(&mut bound);
+                    self.expect_keyword(&mut bound, Keyword::Const);
+                }
+                self.eat_keyword(&mut bound, Keyword::Const);
+                self.eat_keyword(&mut bound, Keyword::Async);
+                if self.eat_keyword(&mut bound, Keyword::For) {
+                    self.generic_params(&mut bound);
+                }
+                if self.at_path_start() {
+                    let path = self.path(false);
+                    bound.push(path);
+                } else if self.at_keyword(Keyword::Use) && self.nth_at(1, Punct::Lt) {
+                    //Precise capturing like `use<'a, T>`
+                    self.bump(&mut bound);
+                    self.generic_args(&mut bound);
+                } else if bound.children.is_empty() {
+                    break;
+                } else {
+                    self.error("expected a trait");
+                }
+            }
+            bounds.push(bound);
+            if !self.eat(&mut bounds, Punct::Plus) {
+                break;
+            }
+        }
+        node.push(bounds);
+    }
+
+    ///The parameters of a fn, `self` included
+    pub(crate) fn param_list(&mut self, node: &mut Node) {
+        let mut params = Node::new(SyntaxKind::ParamList);
+        self.delimited(&mut params, Punct::OpenParen, Punct::Comma, "a parameter", |parser, list| {
+            let mut param = Node::new(SyntaxKind::Param);
+            parser.attributes(&mut param, false);
+            //`self`, `mut self`, `&self`, `&'a mut self` and so on
+            let mut n = 0;
+            if parser.nth_at(n, Punct::And) {
+                n += 1;
+                if parser.nth(n) == Some(TokenKind::Lifetime) {
+                    n += 1;
+                }
+            }
+            if parser.nth_at_keyword(n, Keyword::Mut) {
+                n += 1;
+            }
+            if parser.nth_at_keyword(n, Keyword::SelfValue) && !parser.nth_at(n + 1, Punct::PathSep) {
+                param.kind = SyntaxKind::SelfParam;
+                for _ in 0..=n {
+                    parser.bump(&mut param);
+                }
+                if parser.eat(&mut param, Punct::Colon) {
+                    parser.expect_type(&mut param);
+                }
+            } else if !parser.eat(&mut param, Punct::DotDotDot) {
+                match parser.pattern_single() {
+                    Some(pattern) => param.push(pattern),
+                    None if param.children.is_empty() => return false,
+                    None => parser.error("expected a pattern")
+                }
+                if parser.expect(&mut param, Punct::Colon) && !parser.eat(&mut param, Punct::DotDotDot) {
+                    parser.expect_type(&mut param);
+                }
+            }
+            list.push(param);
+            true
+        });
+        node.push(params);
+    }
+
+    ///`-> Type`, if there is one
+    pub(crate) fn ret_type(&mut self, node: &mut Node) {
+        if self.at(Punct::RArrow) {
+            let mut ret_type = Node::new(SyntaxKind::RetType);
+            self.bump(&mut ret_type);
+            self.expect_type(&mut ret_type);
+            node.push(ret_type);
+        }
+    }
+}
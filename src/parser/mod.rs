@@ -0,0 +1,471 @@
+use std::fmt;
+
+use crate::lexer::{lex, Keyword, LexError, Punct, Span, TokenKind};
+use crate::preprocessor::{Comment, CommentType, ErrorLocation, Preprocessed, TextUnit};
+#[cfg(test)]
+use crate::preprocessor::{preprocess_to_string, PreprocessOptions};
+
+mod expressions;
+mod items;
+mod patterns;
+mod types;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SyntaxKind {
+    SourceFile,
+    //Tokens which do not fit the grammar, kept so that no text is lost
+    Error,
+    //A delimited group of any tokens, e.g. the input of a macro or an attribute
+    TokenTree,
+    Attribute,
+    Visibility,
+    Name,
+    Label,
+
+    //Items
+    Function, Struct, Union, Enum, Trait, Impl, Module, Use, ExternCrate, ExternBlock, Const, Static, TypeAlias,
+    MacroCall, MacroRules,
+    ItemList, UseTree, UseTreeList, VariantList, Variant, RecordFieldList, RecordField, TupleFieldList, TupleField,
+    GenericParamList, LifetimeParam, TypeParam, ConstParam, WhereClause, WherePredicate, TypeBoundList, TypeBound,
+    ParamList, SelfParam, Param, RetType,
+
+    //Statements
+    LetStmt, LetElse, ExprStmt,
+
+    //Expressions
+    Literal, PathExpr, StructExpr, RecordExprFieldList, RecordExprField, TupleExpr, ParenExpr, ArrayExpr, BlockExpr,
+    IfExpr, LetExpr, WhileExpr, LoopExpr, ForExpr, MatchExpr, MatchArmList, MatchArm, MatchGuard, ClosureExpr,
+    ClosureParamList, ReturnExpr, BreakExpr, ContinueExpr, RangeExpr, BinaryExpr, PrefixExpr, RefExpr, CastExpr,
+    TryExpr, AwaitExpr, FieldExpr, MethodCallExpr, CallExpr, IndexExpr, ArgList, UnderscoreExpr,
+
+    //Paths
+    Path, PathSegment, GenericArgList, TypeArg, LifetimeArg, ConstArg, AssocTypeArg,
+
+    //Patterns
+    IdentPat, WildcardPat, RestPat, LiteralPat, RangePat, PathPat, TupleStructPat, RecordPat, RecordPatField,
+    TuplePat, SlicePat, RefPat, OrPat,
+
+    //Types
+    PathType, RefType, PtrType, TupleType, ParenType, ArrayType, SliceType, FnPtrType, ForType, ImplTraitType,
+    DynTraitType, NeverType, InferType
+}
+
+///A token of the tree, the span is in the source text
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CstToken {
+    pub(crate) kind: TokenKind,
+    pub(crate) span: Span,
+    //The comments, doc comments included, and the whitespace between the previous token and this one
+    pub(crate) leading_trivia: Vec<Comment>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Element {
+    Node(Node),
+    Token(CstToken)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Node {
+    pub(crate) kind: SyntaxKind,
+    pub(crate) children: Vec<Element>
+}
+
+impl Node {
+    fn new(kind: SyntaxKind) -> Node {
+        Node { kind, children: Vec::new() }
+    }
+
+    fn push(&mut self, node: Node) {
+        self.children.push(Element::Node(node));
+    }
+
+    ///Wraps the node into a new one of the given kind, e.g. the left operand into a binary expression
+    fn wrap(self, kind: SyntaxKind) -> Node {
+        Node { kind, children: vec![Element::Node(self)] }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn nodes(&self) -> impl Iterator<Item=&Node> {
+        self.children.iter().filter_map(|child| match child {
+            Element::Node(node) => Some(node),
+            Element::Token(_) => None
+        })
+    }
+
+    ///Calls `f` with every token of the node in order
+    #[cfg(test)]
+    pub(crate) fn for_each_token<'a>(&'a self, f: &mut impl FnMut(&'a CstToken)) {
+        for child in &self.children {
+            match child {
+                Element::Node(node) => node.for_each_token(f),
+                Element::Token(token) => f(token)
+            }
+        }
+    }
+
+    ///Writes the text of the node with the trivia of its tokens
+    #[cfg(test)]
+    pub(crate) fn write_text<T: TextUnit>(&self, source_text: &[T], text: &mut Vec<T>) {
+        self.for_each_token(&mut |token| {
+            for trivia in &token.leading_trivia {
+                text.extend_from_slice(&source_text[trivia.begin..(trivia.begin + trivia.length)]);
+            }
+            text.extend_from_slice(&source_text[token.span.begin..token.span.end()]);
+        });
+    }
+
+    ///The tree as an s-expression of the kinds and the token texts without trivia,
+    ///e.g. `(Name "f")`
+    #[cfg(test)]
+    pub(crate) fn to_sexp<T: TextUnit>(&self, source_text: &[T]) -> String {
+        let mut sexp = format!("({:?}", self.kind);
+        for child in &self.children {
+            sexp.push(' ');
+            match child {
+                Element::Node(node) => sexp.push_str(&node.to_sexp(source_text)),
+                Element::Token(token) => sexp.push_str(&format!("{:?}", token.span.text(source_text)))
+            }
+        }
+        sexp.push(')');
+        sexp
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ParseError {
+    pub(crate) message: String,
+    pub(crate) location: ErrorLocation
+}
+
+impl fmt::Display for ParseError {
+    ///Formats the error like rustc does, with a snippet marking the unexpected token
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.location.fmt_error(f, &self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+///The concrete syntax tree of a file, which holds every part of the text
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Parse {
+    pub(crate) root: Node,
+    //The comments and whitespace after the last token
+    pub(crate) trailing_trivia: Vec<Comment>,
+    //The syntax errors, the parser goes on after each of them
+    pub(crate) errors: Vec<ParseError>
+}
+
+impl Parse {
+    ///The text the tree was parsed from
+    #[cfg(test)]
+    pub(crate) fn text<T: TextUnit>(&self, source_text: &[T]) -> String {
+        let mut text = Vec::with_capacity(source_text.len());
+        self.root.write_text(source_text, &mut text);
+        for trivia in &self.trailing_trivia {
+            text.extend_from_slice(&source_text[trivia.begin..(trivia.begin + trivia.length)]);
+        }
+        T::to_string(&text)
+    }
+}
+
+///Preprocesses the string and parses it, the spans are byte offsets
+#[cfg(test)]
+pub(crate) fn parse_str(source_text: &str, options: &PreprocessOptions) -> Result<Parse, LexError> {
+    let (result_text, preprocessed) = preprocess_to_string(source_text, options)?;
+    parse(source_text.as_bytes(), result_text.as_bytes(), &preprocessed)
+}
+
+///Parses a preprocessed text into a concrete syntax tree. Only lexing errors are fatal, syntax
+///errors are recorded and the tokens which do not fit are kept in `Error` nodes
+pub(crate) fn parse<T: TextUnit>(source_text: &[T], result_text: &[T], preprocessed: &Preprocessed)
+                                 -> Result<Parse, LexError> {
+    let mut trivia = preprocessed.comments.iter()
+        .filter(|comment| is_trivia(comment.comment_type))
+        .peekable();
+    let tokens = lex(source_text, result_text, preprocessed)?.into_iter()
+        .filter(|token| !matches!(token.kind, TokenKind::DocComment(_)))
+        .map(|token| {
+            let mut leading_trivia = Vec::new();
+            while let Some(comment) = trivia.next_if(|comment| comment.begin < token.span.begin) {
+                leading_trivia.push(*comment);
+            }
+            CstToken { kind: token.kind, span: token.span, leading_trivia }
+        })
+        .collect();
+    let mut parser = Parser { source_text, tokens, pos: 0, errors: Vec::new() };
+    let root = parser.source_file();
+    Ok(Parse { root, trailing_trivia: trivia.copied().collect(), errors: parser.errors })
+}
+
+fn is_trivia(comment_type: CommentType) -> bool {
+    comment_type.is_doc_comment() ||
+        matches!(comment_type, CommentType::DoubleSlash | CommentType::SlashStar | CommentType::Whitespace | CommentType::Shebang)
+}
+
+fn closing(open: Punct) -> Punct {
+    match open {
+        Punct::OpenParen => Punct::CloseParen,
+        Punct::OpenBracket => Punct::CloseBracket,
+        _ => Punct::CloseBrace
+    }
+}
+
+struct Parser<'a, T: TextUnit> {
+    source_text: &'a [T],
+    tokens: Vec<CstToken>,
+    pos: usize,
+    errors: Vec<ParseError>
+}
+
+impl<T: TextUnit> Parser<'_, T> {
+    fn nth(&self, n: usize) -> Option<TokenKind> {
+        self.tokens.get(self.pos + n).map(|token| token.kind)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn nth_at(&self, n: usize, punct: Punct) -> bool {
+        self.nth(n) == Some(TokenKind::Punctuation(punct))
+    }
+
+    fn at(&self, punct: Punct) -> bool {
+        self.nth_at(0, punct)
+    }
+
+    fn nth_at_keyword(&self, n: usize, keyword: Keyword) -> bool {
+        self.nth(n) == Some(TokenKind::Keyword(keyword))
+    }
+
+    fn at_keyword(&self, keyword: Keyword) -> bool {
+        self.nth_at_keyword(0, keyword)
+    }
+
+    fn nth_at_identifier(&self, n: usize) -> bool {
+        matches!(self.nth(n), Some(TokenKind::Identifier) | Some(TokenKind::RawIdentifier))
+    }
+
+    ///Whether token `n` is an identifier with the given text, like the contextual keyword `union`
+    fn nth_at_contextual(&self, n: usize, name: &str) -> bool {
+        self.nth(n) == Some(TokenKind::Identifier) && self.tokens[self.pos + n].span.text(self.source_text) == name
+    }
+
+    fn at_open(&self) -> bool {
+        self.at(Punct::OpenParen) || self.at(Punct::OpenBracket) || self.at(Punct::OpenBrace)
+    }
+
+    fn at_close(&self) -> bool {
+        self.at(Punct::CloseParen) || self.at(Punct::CloseBracket) || self.at(Punct::CloseBrace)
+    }
+
+    fn at_literal(&self) -> bool {
+        matches!(self.nth(0), Some(TokenKind::Literal { .. })) || self.at_keyword(Keyword::True) || self.at_keyword(Keyword::False)
+    }
+
+    fn bump(&mut self, node: &mut Node) {
+        let token = &mut self.tokens[self.pos];
+        let leading_trivia = std::mem::take(&mut token.leading_trivia);
+        node.children.push(Element::Token(CstToken { kind: token.kind, span: token.span, leading_trivia }));
+        self.pos += 1;
+    }
+
+    fn eat(&mut self, node: &mut Node, punct: Punct) -> bool {
+        let at = self.at(punct);
+        if at {
+            self.bump(node);
+        }
+        at
+    }
+
+    fn eat_keyword(&mut self, node: &mut Node, keyword: Keyword) -> bool {
+        let at = self.at_keyword(keyword);
+        if at {
+            self.bump(node);
+        }
+        at
+    }
+
+    fn expect(&mut self, node: &mut Node, punct: Punct) -> bool {
+        let eaten = self.eat(node, punct);
+        if !eaten {
+            self.error(format!("expected `{}`", punct.as_str()));
+        }
+        eaten
+    }
+
+    fn expect_keyword(&mut self, node: &mut Node, keyword: Keyword) -> bool {
+        let eaten = self.eat_keyword(node, keyword);
+        if !eaten {
+            self.error(format!("expected `{}`", keyword.as_str()));
+        }
+        eaten
+    }
+
+    ///Records an error at the current token
+    fn error(&mut self, message: impl Into<String>) {
+        let location = match self.tokens.get(self.pos) {
+            Some(token) => ErrorLocation::new(self.source_text, token.span.begin, &self.source_text[token.span.begin..token.span.end()]),
+            None => ErrorLocation::new(self.source_text, self.source_text.len(), &[])
+        };
+        self.errors.push(ParseError { message: message.into(), location });
+    }
+
+    ///Records an error and keeps the current token, or the group it opens, in an `Error` node.
+    ///Closing delimiters are left to the list they close
+    fn error_and_bump(&mut self, node: &mut Node, message: &str) {
+        self.error(message);
+        let mut error = Node::new(SyntaxKind::Error);
+        if self.at_open() {
+            error.push(self.token_tree());
+        } else if !self.at_end() && !self.at_close() {
+            self.bump(&mut error);
+        }
+        if !error.children.is_empty() {
+            node.push(error);
+        }
+    }
+
+    ///Splits the current punctuation after its first character, e.g. `>>` closing two lists of
+    ///generic arguments into two `>`
+    fn split(&mut self) {
+        let token = &self.tokens[self.pos];
+        let text = match token.kind {
+            TokenKind::Punctuation(punct) => punct.as_str(),
+            _ => return
+        };
+        let (first, rest) = match (Punct::from_text(&text[..1]), Punct::from_text(&text[1..])) {
+            (Some(first), Some(rest)) => (first, rest),
+            _ => return
+        };
+        let span = token.span;
+        let rest = CstToken {
+            kind: TokenKind::Punctuation(rest),
+            span: Span { begin: span.begin + 1, length: span.length - 1 },
+            leading_trivia: Vec::new()
+        };
+        self.tokens[self.pos].kind = TokenKind::Punctuation(first);
+        self.tokens[self.pos].span.length = 1;
+        self.tokens.insert(self.pos + 1, rest);
+    }
+
+    ///Whether the current punctuation begins with `punct`, e.g. `>>` with `>`
+    fn at_prefix(&self, punct: Punct) -> bool {
+        match self.nth(0) {
+            Some(TokenKind::Punctuation(current)) => current.as_str().starts_with(punct.as_str()),
+            _ => false
+        }
+    }
+
+    ///Eats `punct`, splitting it off the current punctuation if that is longer
+    fn eat_prefix(&mut self, node: &mut Node, punct: Punct) -> bool {
+        if !self.at_prefix(punct) {
+            return false;
+        }
+        if !self.at(punct) {
+            self.split();
+        }
+        self.bump(node);
+        true
+    }
+
+    ///A delimited group of any tokens, the current token opens it
+    fn token_tree(&mut self) -> Node {
+        let mut tree = Node::new(SyntaxKind::TokenTree);
+        let open = match self.nth(0) {
+            Some(TokenKind::Punctuation(open)) => open,
+            _ => unreachable!("Token trees begin with a delimiter")
+        };
+        self.bump(&mut tree);
+        while !self.at_end() && !self.at_close() {
+            if self.at_open() {
+                tree.push(self.token_tree());
+            } else {
+                self.bump(&mut tree);
+            }
+        }
+        if !self.at(closing(open)) {
+            self.error(format!("expected `{}`", closing(open).as_str()));
+        }
+        if self.at_close() {
+            self.bump(&mut tree);
+        }
+        tree
+    }
+
+    ///Parses the elements of a list delimited by `open` and its closing delimiter and separated
+    ///by `separator`. `element` parses an element and returns false if none begins at the
+    ///current token, which is then kept as an error
+    fn delimited(&mut self, node: &mut Node, open: Punct, separator: Punct, what: &str,
+                 mut element: impl FnMut(&mut Self, &mut Node) -> bool) {
+        let close = closing(open);
+        if !self.expect(node, open) {
+            return;
+        }
+        while !self.at_end() && !self.at(close) {
+            if self.at_close() {
+                //The list is not closed, the closing delimiter belongs to an outer one
+                break;
+            }
+            if !element(self, node) {
+                if self.nth_at_item(0) {
+                    //The list is most likely missing its closing delimiter
+                    self.error(format!("expected {} or `{}`", what, close.as_str()));
+                    break;
+                }
+                self.error_and_bump(node, &format!("expected {}", what));
+                continue;
+            }
+            if !self.at(close) && !self.eat(node, separator) {
+                self.error(format!("expected `{}` or `{}`", separator.as_str(), close.as_str()));
+            }
+        }
+        self.expect(node, close);
+    }
+
+    ///Outer attributes like `#[derive(Debug)]`, or inner ones like `#![allow(x)]` if `inner` is set
+    fn attributes(&mut self, node: &mut Node, inner: bool) {
+        while self.at(Punct::Pound) && (if inner {
+            self.nth_at(1, Punct::Not) && self.nth_at(2, Punct::OpenBracket)
+        } else {
+            self.nth_at(1, Punct::OpenBracket)
+        }) {
+            let mut attribute = Node::new(SyntaxKind::Attribute);
+            self.bump(&mut attribute);
+            self.eat(&mut attribute, Punct::Not);
+            attribute.push(self.token_tree());
+            node.push(attribute);
+        }
+    }
+
+    fn name(&mut self, node: &mut Node) -> bool {
+        if !self.nth_at_identifier(0) {
+            self.error("expected a name");
+            return false;
+        }
+        let mut name = Node::new(SyntaxKind::Name);
+        self.bump(&mut name);
+        node.push(name);
+        true
+    }
+
+    fn source_file(&mut self) -> Node {
+        let mut file = Node::new(SyntaxKind::SourceFile);
+        self.attributes(&mut file, true);
+        while !self.at_end() {
+            let pos = self.pos;
+            if !self.item(&mut file) {
+                self.error_until_item(&mut file);
+            }
+            if self.pos == pos {
+                //A stray closing delimiter
+                let mut error = Node::new(SyntaxKind::Error);
+                self.bump(&mut error);
+                file.push(error);
+            }
+        }
+        file
+    }
+}
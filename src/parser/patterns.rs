@@ -0,0 +1,215 @@
+use crate::lexer::{Keyword, Punct, TokenKind};
+use crate::preprocessor::TextUnit;
+
+use super::{Node, Parser, SyntaxKind};
+
+impl<T: TextUnit> Parser<'_, T> {
+    pub(crate) fn expect_pattern(&mut self, node: &mut Node) -> bool {
+        match self.pattern() {
+            Some(pattern) => {
+                node.push(pattern);
+                true
+            }
+            None => {
+                self.error("expected a pattern");
+                false
+            }
+        }
+    }
+
+    ///A pattern which may have alternatives, like `Some(1) | None` in a match arm, returning
+    ///`None` if none begins at the current token
+    pub(crate) fn pattern(&mut self) -> Option<Node> {
+        let mut or = Node::new(SyntaxKind::OrPat);
+        let leading = self.eat(&mut or, Punct::Or);
+        let first = match self.pattern_single() {
+            Some(first) => first,
+            None if leading => {
+                self.error("expected a pattern");
+                return Some(or);
+            }
+            None => return None
+        };
+        if !leading && !self.at(Punct::Or) {
+            return Some(first);
+        }
+        or.push(first);
+        while self.eat(&mut or, Punct::Or) {
+            match self.pattern_single() {
+                Some(pattern) => or.push(pattern),
+                None => self.error("expected a pattern")
+            }
+        }
+        Some(or)
+    }
+
+    ///A pattern without alternatives at the top, like the parameters of fns and closures
+    pub(crate) fn pattern_single(&mut self) -> Option<Node> {
+        let pattern = match self.nth(0)? {
+            TokenKind::Punctuation(Punct::Underscore) => {
+                let mut pattern = Node::new(SyntaxKind::WildcardPat);
+                self.bump(&mut pattern);
+                pattern
+            }
+            TokenKind::Punctuation(Punct::DotDot) => {
+                let mut pattern = Node::new(SyntaxKind::RestPat);
+                self.bump(&mut pattern);
+                pattern
+            }
+            TokenKind::Punctuation(Punct::DotDotEq) => {
+                let mut pattern = Node::new(SyntaxKind::RangePat);
+                self.bump(&mut pattern);
+                if !self.range_end(&mut pattern) {
+                    self.error("expected the end of the range");
+                }
+                pattern
+            }
+            TokenKind::Punctuation(Punct::And) | TokenKind::Punctuation(Punct::AndAnd) => {
+                let mut pattern = Node::new(SyntaxKind::RefPat);
+                self.eat_prefix(&mut pattern, Punct::And);
+                self.eat_keyword(&mut pattern, Keyword::Mut);
+                match self.pattern_single() {
+                    Some(inner) => pattern.push(inner),
+                    None => self.error("expected a pattern")
+                }
+                pattern
+            }
+            TokenKind::Punctuation(Punct::OpenParen) => {
+                let mut pattern = Node::new(SyntaxKind::TuplePat);
+                self.pattern_list(&mut pattern, Punct::OpenParen);
+                pattern
+            }
+            TokenKind::Punctuation(Punct::OpenBracket) => {
+                let mut pattern = Node::new(SyntaxKind::SlicePat);
+                self.pattern_list(&mut pattern, Punct::OpenBracket);
+                pattern
+            }
+            TokenKind::Keyword(Keyword::Ref) | TokenKind::Keyword(Keyword::Mut) => self.ident_pattern(),
+            _ if self.at_literal() || (self.at(Punct::Minus) && matches!(self.nth(1), Some(TokenKind::Literal { .. }))) => {
+                let literal = self.literal();
+                self.range_tail(literal.wrap(SyntaxKind::LiteralPat))
+            }
+            //A binding, unless it is followed by what only paths are
+            _ if self.nth_at_identifier(0) && !(self.nth_at(1, Punct::PathSep) || self.nth_at(1, Punct::OpenParen) ||
+                self.nth_at(1, Punct::OpenBrace) || self.nth_at(1, Punct::Not) || self.nth_at(1, Punct::DotDotEq) ||
+                self.nth_at(1, Punct::DotDotDot) || self.nth_at(1, Punct::DotDot)) => self.ident_pattern(),
+            _ if self.at_path_start() => {
+                let path = self.path(true);
+                if self.at(Punct::Not) {
+                    let mut call = path.wrap(SyntaxKind::MacroCall);
+                    self.bump(&mut call);
+                    if self.at_open() {
+                        call.push(self.token_tree());
+                    } else {
+                        self.error("expected `(`, `[` or `{`");
+                    }
+                    call
+                } else if self.at(Punct::OpenParen) {
+                    let mut pattern = path.wrap(SyntaxKind::TupleStructPat);
+                    self.pattern_list(&mut pattern, Punct::OpenParen);
+                    pattern
+                } else if self.at(Punct::OpenBrace) {
+                    let mut pattern = path.wrap(SyntaxKind::RecordPat);
+                    self.record_pattern_fields(&mut pattern);
+                    pattern
+                } else {
+                    self.range_tail(path.wrap(SyntaxKind::PathPat))
+                }
+            }
+            _ => return None
+        };
+        Some(pattern)
+    }
+
+    fn pattern_list(&mut self, node: &mut Node, open: Punct) {
+        self.delimited(node, open, Punct::Comma, "a pattern", |parser, list| match parser.pattern() {
+            Some(pattern) => {
+                list.push(pattern);
+                true
+            }
+            None => false
+        });
+    }
+
+    ///A binding like `x`, `ref mut x` or `x @ 1..=9`
+    fn ident_pattern(&mut self) -> Node {
+        let mut pattern = Node::new(SyntaxKind::IdentPat);
+        self.eat_keyword(&mut pattern, Keyword::Ref);
+        self.eat_keyword(&mut pattern, Keyword::Mut);
+        self.name(&mut pattern);
+        if self.eat(&mut pattern, Punct::At) {
+            match self.pattern_single() {
+                Some(inner) => pattern.push(inner),
+                None => self.error("expected a pattern")
+            }
+        }
+        pattern
+    }
+
+    ///A literal as in patterns and constant generic arguments, where it may be negative
+    pub(crate) fn literal(&mut self) -> Node {
+        let mut literal = Node::new(SyntaxKind::Literal);
+        self.eat(&mut literal, Punct::Minus);
+        self.bump(&mut literal);
+        literal
+    }
+
+    ///Makes a range pattern of `start` if a range operator follows it
+    fn range_tail(&mut self, start: Node) -> Node {
+        if !self.at(Punct::DotDotEq) && !self.at(Punct::DotDotDot) && !self.at(Punct::DotDot) {
+            return start;
+        }
+        let mut pattern = start.wrap(SyntaxKind::RangePat);
+        let inclusive = !self.at(Punct::DotDot);
+        self.bump(&mut pattern);
+        if !self.range_end(&mut pattern) && inclusive {
+            self.error("expected the end of the range");
+        }
+        pattern
+    }
+
+    fn range_end(&mut self, pattern: &mut Node) -> bool {
+        if self.at_literal() || (self.at(Punct::Minus) && matches!(self.nth(1), Some(TokenKind::Literal { .. }))) {
+            let literal = self.literal();
+            pattern.push(literal.wrap(SyntaxKind::LiteralPat));
+        } else if self.at_path_start() {
+            let path = self.path(true);
+            pattern.push(path.wrap(SyntaxKind::PathPat));
+        } else {
+            return false;
+        }
+        true
+    }
+
+    ///The fields of a record pattern like `{ x, y: 0, ref z, .. }`
+    fn record_pattern_fields(&mut self, node: &mut Node) {
+        self.delimited(node, Punct::OpenBrace, Punct::Comma, "a field", |parser, list| {
+            let mut field = Node::new(SyntaxKind::RecordPatField);
+            parser.attributes(&mut field, false);
+            if parser.at(Punct::DotDot) {
+                let mut rest = Node::new(SyntaxKind::RestPat);
+                parser.bump(&mut rest);
+                field.push(rest);
+            } else if (parser.nth_at_identifier(0) || matches!(parser.nth(0), Some(TokenKind::Literal { .. }))) &&
+                parser.nth_at(1, Punct::Colon) {
+                let mut name = Node::new(SyntaxKind::Name);
+                parser.bump(&mut name);
+                field.push(name);
+                parser.bump(&mut field);
+                match parser.pattern() {
+                    Some(pattern) => field.push(pattern),
+                    None => parser.error("expected a pattern")
+                }
+            } else if parser.nth_at_identifier(0) || parser.at_keyword(Keyword::Ref) || parser.at_keyword(Keyword::Mut) {
+                let pattern = parser.ident_pattern();
+                field.push(pattern);
+            } else if field.children.is_empty() {
+                return false;
+            } else {
+                parser.error("expected a field");
+            }
+            list.push(field);
+            true
+        });
+    }
+}
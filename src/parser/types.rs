@@ -0,0 +1,257 @@
+use crate::lexer::{Keyword, Punct, TokenKind};
+use crate::preprocessor::TextUnit;
+
+use super::{Node, Parser, SyntaxKind};
+
+impl<T: TextUnit> Parser<'_, T> {
+    fn nth_at_segment(&self, n: usize) -> bool {
+        self.nth_at_identifier(n) || self.nth_at_keyword(n, Keyword::SelfValue) || self.nth_at_keyword(n, Keyword::SelfType) ||
+            self.nth_at_keyword(n, Keyword::Super) || self.nth_at_keyword(n, Keyword::Crate)
+    }
+
+    pub(crate) fn at_path_start(&self) -> bool {
+        self.nth_at_segment(0) || self.at_prefix(Punct::Lt) || (self.at(Punct::PathSep) && self.nth_at_segment(1))
+    }
+
+    ///A path like `std::fmt::Display`, `Vec<u8>` or `<T as Trait>::Item`. In `expression` paths
+    ///the generic arguments follow a `::`, as in `iter.collect::<Vec<_>>()`
+    pub(crate) fn path(&mut self, expression: bool) -> Node {
+        let mut path = Node::new(SyntaxKind::Path);
+        self.eat(&mut path, Punct::PathSep);
+        loop {
+            let mut segment = Node::new(SyntaxKind::PathSegment);
+            if path.children.is_empty() && self.eat_prefix(&mut segment, Punct::Lt) {
+                //A qualified path
+                self.expect_type(&mut segment);
+                if self.eat_keyword(&mut segment, Keyword::As) {
+                    self.expect_type(&mut segment);
+                }
+                if !self.eat_prefix(&mut segment, Punct::Gt) {
+                    self.error("expected `>`");
+                }
+            } else if self.nth_at_segment(0) {
+                self.bump(&mut segment);
+            } else {
+                self.error("expected a path segment");
+                break;
+            }
+            if self.at(Punct::PathSep) && (self.nth(1) == Some(TokenKind::Punctuation(Punct::Lt)) ||
+                self.nth(1) == Some(TokenKind::Punctuation(Punct::Shl))) {
+                self.bump(&mut segment);
+                self.generic_args(&mut segment);
+            } else if !expression && self.at_prefix(Punct::Lt) && !self.at(Punct::Le) && !self.at(Punct::ShlEq) {
+                self.generic_args(&mut segment);
+            } else if !expression && self.at(Punct::OpenParen) {
+                //The sugar of the Fn traits, `Fn(u8) -> u8`
+                let mut params = Node::new(SyntaxKind::ParamList);
+                self.delimited(&mut params, Punct::OpenParen, Punct::Comma, "a type", |parser, list| {
+                    match parser.ty() {
+                        Some(ty) => list.push(ty),
+                        None => return false
+                    }
+                    true
+                });
+                segment.push(params);
+                self.ret_type(&mut segment);
+            }
+            path.push(segment);
+            if !self.at(Punct::PathSep) || !self.nth_at_segment(1) {
+                break;
+            }
+            self.bump(&mut path);
+        }
+        path
+    }
+
+    ///Generic arguments like `<'a, T, 3, Item = u8>`, the current token begins with `<`
+    pub(crate) fn generic_args(&mut self, node: &mut Node) {
+        let mut args = Node::new(SyntaxKind::GenericArgList);
+        self.eat_prefix(&mut args, Punct::Lt);
+        while !self.at_end() && !self.at_prefix(Punct::Gt) && !self.at_close() {
+            let mut arg = Node::new(SyntaxKind::TypeArg);
+            if self.nth(0) == Some(TokenKind::Lifetime) {
+                arg.kind = SyntaxKind::LifetimeArg;
+                self.bump(&mut arg);
+            } else if self.nth_at_identifier(0) && (self.nth_at(1, Punct::Eq) || self.nth_at(1, Punct::Colon)) {
+                arg.kind = SyntaxKind::AssocTypeArg;
+                self.name(&mut arg);
+                if self.eat(&mut arg, Punct::Eq) {
+                    match self.const_arg().or_else(|| self.ty()) {
+                        Some(value) => arg.push(value),
+                        None => self.error("expected a type")
+                    }
+                } else {
+                    self.bump(&mut arg);
+                    self.type_bounds(&mut arg);
+                }
+            } else if let Some(value) = self.const_arg() {
+                arg = value;
+            } else if let Some(ty) = self.ty() {
+                arg.push(ty);
+            } else {
+                self.error_and_bump(&mut args, "expected a generic argument");
+                continue;
+            }
+            args.push(arg);
+            if !self.at_prefix(Punct::Gt) && !self.eat(&mut args, Punct::Comma) {
+                self.error("expected `,` or `>`");
+                break;
+            }
+        }
+        if !self.eat_prefix(&mut args, Punct::Gt) {
+            self.error("expected `>`");
+        }
+        node.push(args);
+    }
+
+    ///A constant generic argument which is not a path, like `3`, `-1` or `{ N + 1 }`
+    pub(crate) fn const_arg(&mut self) -> Option<Node> {
+        let mut arg = Node::new(SyntaxKind::ConstArg);
+        if self.at(Punct::OpenBrace) {
+            arg.push(self.block_expr());
+        } else if self.at_literal() || (self.at(Punct::Minus) && matches!(self.nth(1), Some(TokenKind::Literal { .. }))) {
+            let literal = self.literal();
+            arg.push(literal);
+        } else {
+            return None;
+        }
+        Some(arg)
+    }
+
+    pub(crate) fn expect_type(&mut self, node: &mut Node) -> bool {
+        match self.ty() {
+            Some(ty) => {
+                node.push(ty);
+                true
+            }
+            None => {
+                self.error("expected a type");
+                false
+            }
+        }
+    }
+
+    ///Parses a type, returning `None` if none begins at the current token
+    pub(crate) fn ty(&mut self) -> Option<Node> {
+        let kind = match self.nth(0)? {
+            TokenKind::Punctuation(Punct::OpenParen) => {
+                let mut ty = Node::new(SyntaxKind::TupleType);
+                self.bump(&mut ty);
+                let (mut types, mut comma) = (0, false);
+                while !self.at_end() && !self.at_close() {
+                    if !self.expect_type(&mut ty) {
+                        break;
+                    }
+                    types += 1;
+                    comma = self.eat(&mut ty, Punct::Comma);
+                    if !comma {
+                        break;
+                    }
+                }
+                self.expect(&mut ty, Punct::CloseParen);
+                if types == 1 && !comma {
+                    ty.kind = SyntaxKind::ParenType;
+                }
+                return Some(ty);
+            }
+            TokenKind::Punctuation(Punct::Not) => SyntaxKind::NeverType,
+            TokenKind::Punctuation(Punct::Underscore) => SyntaxKind::InferType,
+            TokenKind::Punctuation(Punct::And) | TokenKind::Punctuation(Punct::AndAnd) => {
+                let mut ty = Node::new(SyntaxKind::RefType);
+                self.eat_prefix(&mut ty, Punct::And);
+                if self.nth(0) == Some(TokenKind::Lifetime) {
+                    self.bump(&mut ty);
+                }
+                self.eat_keyword(&mut ty, Keyword::Mut);
+                self.expect_type(&mut ty);
+                return Some(ty);
+            }
+            TokenKind::Punctuation(Punct::Star) => {
+                let mut ty = Node::new(SyntaxKind::PtrType);
+                self.bump(&mut ty);
+                if !self.eat_keyword(&mut ty, Keyword::Const) && !self.eat_keyword(&mut ty, Keyword::Mut) {
+                    self.error("expected `mut` or `const`");
+                }
+                self.expect_type(&mut ty);
+                return Some(ty);
+            }
+            TokenKind::Punctuation(Punct::OpenBracket) => {
+                let mut ty = Node::new(SyntaxKind::SliceType);
+                self.bump(&mut ty);
+                self.expect_type(&mut ty);
+                if self.eat(&mut ty, Punct::Semi) {
+                    ty.kind = SyntaxKind::ArrayType;
+                    self.expect_expr(&mut ty, false);
+                }
+                self.expect(&mut ty, Punct::CloseBracket);
+                return Some(ty);
+            }
+            TokenKind::Keyword(Keyword::Fn) | TokenKind::Keyword(Keyword::Unsafe) | TokenKind::Keyword(Keyword::Extern) => {
+                return Some(self.fn_ptr_type());
+            }
+            TokenKind::Keyword(Keyword::For) => {
+                let mut ty = Node::new(SyntaxKind::ForType);
+                self.bump(&mut ty);
+                self.generic_params(&mut ty);
+                self.expect_type(&mut ty);
+                return Some(ty);
+            }
+            TokenKind::Keyword(Keyword::Impl) | TokenKind::Keyword(Keyword::Dyn) => {
+                let kind = if self.at_keyword(Keyword::Impl) { SyntaxKind::ImplTraitType } else { SyntaxKind::DynTraitType };
+                let mut ty = Node::new(kind);
+                self.bump(&mut ty);
+                self.type_bounds(&mut ty);
+                return Some(ty);
+            }
+            _ if self.at_path_start() => {
+                let path = self.path(false);
+                if self.at(Punct::Not) && (self.nth_at(1, Punct::OpenParen) || self.nth_at(1, Punct::OpenBracket) ||
+                    self.nth_at(1, Punct::OpenBrace)) {
+                    let mut call = path.wrap(SyntaxKind::MacroCall);
+                    self.bump(&mut call);
+                    call.push(self.token_tree());
+                    return Some(call);
+                }
+                return Some(path.wrap(SyntaxKind::PathType));
+            }
+            _ => return None
+        };
+        let mut ty = Node::new(kind);
+        self.bump(&mut ty);
+        Some(ty)
+    }
+
+    ///A fn pointer like `unsafe extern "C" fn(u8, name: u8) -> u8`
+    fn fn_ptr_type(&mut self) -> Node {
+        let mut ty = Node::new(SyntaxKind::FnPtrType);
+        self.eat_keyword(&mut ty, Keyword::Unsafe);
+        if self.eat_keyword(&mut ty, Keyword::Extern) && matches!(self.nth(0), Some(TokenKind::Literal { .. })) {
+            self.bump(&mut ty);
+        }
+        self.expect_keyword(&mut ty, Keyword::Fn);
+        let mut params = Node::new(SyntaxKind::ParamList);
+        self.delimited(&mut params, Punct::OpenParen, Punct::Comma, "a parameter", |parser, list| {
+            let mut param = Node::new(SyntaxKind::Param);
+            parser.attributes(&mut param, false);
+            if (parser.nth_at_identifier(0) || parser.at(Punct::Underscore)) && parser.nth_at(1, Punct::Colon) {
+                match parser.pattern_single() {
+                    Some(pattern) => param.push(pattern),
+                    None => parser.error("expected a pattern")
+                }
+                parser.bump(&mut param);
+            }
+            if !parser.eat(&mut param, Punct::DotDotDot) {
+                match parser.ty() {
+                    Some(ty) => param.push(ty),
+                    None if param.children.is_empty() => return false,
+                    None => parser.error("expected a type")
+                }
+            }
+            list.push(param);
+            true
+        });
+        ty.push(params);
+        self.ret_type(&mut ty);
+        ty
+    }
+}
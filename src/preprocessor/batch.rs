@@ -0,0 +1,116 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+#[cfg(test)]
+use super::parallel::available_threads;
+use super::{into_string, line_ending, Output, PreprocessError, PreprocessOptions, Preprocessed, Scanner};
+
+///The result text and comments of one file of a batch
+pub(crate) type PreprocessedFile = Result<(String, Preprocessed), PreprocessError>;
+
+///Source files concatenated into one text, e.g. to copy them to a device at once
+pub(crate) struct PackedSources {
+    text: Vec<u8>,
+    //File `i` spans `boundaries[i]..boundaries[i + 1]` of the text
+    boundaries: Vec<usize>
+}
+
+impl PackedSources {
+    pub(crate) fn pack(sources: &[&str]) -> PackedSources {
+        let mut text = Vec::with_capacity(sources.iter().map(|source| source.len()).sum());
+        let mut boundaries = Vec::with_capacity(sources.len() + 1);
+        boundaries.push(0);
+        for source in sources {
+            text.extend_from_slice(source.as_bytes());
+            boundaries.push(text.len());
+        }
+        PackedSources { text, boundaries }
+    }
+
+    #[cfg_attr(not(feature = "opencl"), allow(dead_code))]
+    pub(crate) fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    ///The offsets the files begin at, followed by the length of the text
+    #[cfg_attr(not(feature = "opencl"), allow(dead_code))]
+    pub(crate) fn boundaries(&self) -> &[usize] {
+        &self.boundaries
+    }
+
+    pub(crate) fn files(&self) -> usize {
+        self.boundaries.len() - 1
+    }
+
+    pub(crate) fn file_range(&self, file: usize) -> Range<usize> {
+        self.boundaries[file]..self.boundaries[file + 1]
+    }
+
+    pub(crate) fn file(&self, file: usize) -> &[u8] {
+        &self.text[self.file_range(file)]
+    }
+}
+
+///Preprocesses every source on its own, without keeping the result texts. The files are
+///scanned on as many threads as the machine has
+#[cfg(test)]
+pub(crate) fn preprocess_batch(sources: &[&str]) -> Vec<Result<Preprocessed, PreprocessError>> {
+    preprocess_batch_to_strings(sources, &PreprocessOptions::default())
+        .into_iter()
+        .map(|preprocessed| preprocessed.map(|(_, preprocessed)| preprocessed))
+        .collect()
+}
+
+///Like `preprocess_to_string` for every source, an error in one source does not affect the others
+#[cfg(test)]
+pub(crate) fn preprocess_batch_to_strings(sources: &[&str], options: &PreprocessOptions)
+                                          -> Vec<PreprocessedFile> {
+    let packed = PackedSources::pack(sources);
+    preprocess_packed(&packed, options, available_threads())
+}
+
+///Preprocesses the files of the packed text on up to `threads` threads. Every file is scanned
+///from its beginning in code by a scanner of its own, so nothing crosses a boundary
+pub(crate) fn preprocess_packed(packed: &PackedSources, options: &PreprocessOptions, threads: usize)
+                                -> Vec<PreprocessedFile> {
+    for_each_file(packed, threads, |file| {
+        let source_text = packed.file(file);
+        let mut scanner = Scanner::new(Output::growing(), options);
+        scanner.output.line_ending = Some(line_ending(source_text, options).unwrap_or("\n"));
+        scanner.scan(source_text, true)?;
+        let result_text = scanner.output.take();
+        scanner.source_map.finish(source_text, &result_text);
+        let preprocessed = Preprocessed {
+            comments: scanner.comments,
+            source_map: scanner.source_map
+        };
+        Ok((into_string(result_text), preprocessed))
+    })
+}
+
+///Runs `f` for every file on up to `threads` threads and returns the results in the order of
+///the files. The threads take the next file once they are done, as files differ a lot in length
+pub(crate) fn for_each_file<R, F>(packed: &PackedSources, threads: usize, f: F) -> Vec<R>
+    where R: Send, F: Fn(usize) -> R + Sync {
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let handles = (0..threads.clamp(1, packed.files().max(1)))
+            .map(|_| scope.spawn(|| {
+                let mut results = Vec::new();
+                loop {
+                    let file = next.fetch_add(1, Ordering::Relaxed);
+                    if file >= packed.files() {
+                        return results;
+                    }
+                    results.push((file, f(file)));
+                }
+            }))
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .flat_map(|handle| handle.join().expect("Preprocessing a file panicked"))
+            .collect::<Vec<(usize, R)>>()
+    });
+    results.sort_by_key(|(file, _)| *file);
+    results.into_iter().map(|(_, result)| result).collect()
+}
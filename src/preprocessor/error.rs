@@ -0,0 +1,134 @@
+use std::fmt;
+
+use super::TextUnit;
+
+///Where an error was found in the source text
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ErrorLocation {
+    //Offset of the opening delimiter in the source text
+    pub(crate) offset: usize,
+    //Line and column are counted from 1, the column in characters
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    //The opening delimiter, e.g. /* or br##"
+    pub(crate) delimiter: String,
+    //The text of the line containing the delimiter, used for the snippet
+    pub(crate) source_line: String,
+    //The amount of characters at the beginning of the line which are left out of `source_line`,
+    //see `Preprocessor`
+    pub(crate) elided: usize
+}
+
+impl ErrorLocation {
+    pub(crate) fn new<T: TextUnit>(src_text: &[T], offset: usize, delimiter: &[T]) -> ErrorLocation {
+        let line_begin = src_text[..offset].iter()
+            .rposition(|c| c.as_char() == '\n')
+            .map_or(0, |i| i + 1);
+        let line_end = src_text[offset..].iter()
+            .position(|c| c.as_char() == '\n')
+            .map_or(src_text.len(), |i| offset + i);
+        ErrorLocation {
+            offset,
+            line: src_text[..offset].iter().filter(|c| c.as_char() == '\n').count() + 1,
+            //The column is counted in characters, even for UTF-8 encoded text
+            column: T::to_string(&src_text[line_begin..offset]).chars().count() + 1,
+            delimiter: T::to_string(delimiter),
+            source_line: T::to_string(&src_text[line_begin..line_end])
+                .trim_end_matches('\r')
+                .to_string(),
+            elided: 0
+        }
+    }
+}
+
+impl ErrorLocation {
+    ///Formats an error with the message like rustc does, with a snippet marking the delimiter
+    pub(crate) fn fmt_error(&self, f: &mut fmt::Formatter<'_>, message: &str) -> fmt::Result {
+        self.fmt_labeled(f, "error", message)
+    }
+
+    ///Like `fmt_error`, but labeled as a note on a preceding error
+    pub(crate) fn fmt_note(&self, f: &mut fmt::Formatter<'_>, message: &str) -> fmt::Result {
+        self.fmt_labeled(f, "note", message)
+    }
+
+    fn fmt_labeled(&self, f: &mut fmt::Formatter<'_>, label: &str, message: &str) -> fmt::Result {
+        let line_number = self.line.to_string();
+        let padding = " ".repeat(line_number.len());
+        let ellipsis = if self.elided > 0 { "..." } else { "" };
+        let marker_offset = self.source_line.chars().take(self.column - 1 - self.elided)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+
+        writeln!(f, "{}: {}", label, message)?;
+        writeln!(f, "{}--> {}:{}", padding, self.line, self.column)?;
+        writeln!(f, "{} |", padding)?;
+        writeln!(f, "{} | {}{}", line_number, ellipsis, self.source_line)?;
+        write!(f, "{} | {}{}{}", padding, " ".repeat(ellipsis.len()), marker_offset,
+               "^".repeat(self.delimiter.chars().count().max(1)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PreprocessError {
+    //Strings, byte strings and C strings
+    UnterminatedString(ErrorLocation),
+    UnterminatedBlockComment(ErrorLocation),
+    UnterminatedRawString {
+        location: ErrorLocation,
+        hashes: usize
+    },
+    UnterminatedChar(ErrorLocation),
+    //The result text cannot hold the output of the source text beginning at the location
+    ResultBufferTooSmall {
+        location: ErrorLocation,
+        capacity: usize,
+        //The capacity needed for the complete result text
+        required: usize
+    }
+}
+
+impl PreprocessError {
+    pub(crate) fn location(&self) -> &ErrorLocation {
+        match self {
+            PreprocessError::UnterminatedString(location) |
+            PreprocessError::UnterminatedBlockComment(location) |
+            PreprocessError::UnterminatedRawString { location, .. } |
+            PreprocessError::UnterminatedChar(location) |
+            PreprocessError::ResultBufferTooSmall { location, .. } => location
+        }
+    }
+
+    pub(crate) fn location_mut(&mut self) -> &mut ErrorLocation {
+        match self {
+            PreprocessError::UnterminatedString(location) |
+            PreprocessError::UnterminatedBlockComment(location) |
+            PreprocessError::UnterminatedRawString { location, .. } |
+            PreprocessError::UnterminatedChar(location) |
+            PreprocessError::ResultBufferTooSmall { location, .. } => location
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            PreprocessError::UnterminatedString(_) => "unterminated double quote string".to_string(),
+            PreprocessError::UnterminatedBlockComment(_) => "unterminated block comment".to_string(),
+            PreprocessError::UnterminatedRawString { hashes, .. } => {
+                format!("unterminated raw string, expected \"{}", "#".repeat(*hashes))
+            }
+            PreprocessError::UnterminatedChar(_) => "unterminated character literal".to_string(),
+            PreprocessError::ResultBufferTooSmall { capacity, required, .. } => {
+                format!("result buffer with a capacity of {} is too small, {} are required", capacity, required)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PreprocessError {
+    ///Formats the error like rustc does, with a snippet marking the opening delimiter
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.location().fmt_error(f, &self.message())
+    }
+}
+
+impl std::error::Error for PreprocessError {}
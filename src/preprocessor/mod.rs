@@ -1,240 +1,981 @@
 use crate::preprocessor::CommentType::{Whitespace, SlashStar, DoubleSlash};
+use crate::preprocessor::CommentType::{OuterLineDoc, InnerLineDoc, OuterBlockDoc, InnerBlockDoc};
+
+pub(crate) mod batch;
+mod error;
+pub(crate) mod parallel;
+pub(crate) mod source_map;
+pub(crate) mod stream;
+mod text;
+
+pub(crate) use self::error::{ErrorLocation, PreprocessError};
+pub(crate) use self::source_map::SourceMap;
+pub(crate) use self::text::TextUnit;
+use self::text::char_at;
+use unicode_xid::UnicodeXID;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum CommentType {
     DoubleSlash,
     SlashStar,
+    //Doc comments are attributes, /// and /** */ document the following item
+    OuterLineDoc,
+    OuterBlockDoc,
+    //Doc comments starting with //! and /*! document the enclosing item
+    InnerLineDoc,
+    InnerBlockDoc,
     String,
     ByteString,
     RawString {
         preceding_hashes: usize
     },
-    //Whitespace also includes newline '\n' and '\t'
-    Whitespace
+    ByteRawString {
+        preceding_hashes: usize
+    },
+    //C string literals c"..." and cr#"..."#
+    CString,
+    RawCString {
+        preceding_hashes: usize
+    },
+    //A char literal such as 'a' or '\'', lifetimes and labels are not matched
+    CharLiteral,
+    ByteCharLiteral,
+    //Whitespace is any of Rust's whitespace characters, e.g. ' ', '\t', '\n', '\r' and U+2028
+    Whitespace,
+    //A line like #!/usr/bin/env run-cargo-script at the beginning of the file, without its line ending
+    Shebang
 }
 
 impl CommentType {
-    fn any_comment(src_text: &[char], index: usize) -> Option<CommentType> {
-        match src_text[index] {
+    fn any_comment<T: TextUnit>(src_text: &[T], index: usize) -> Option<CommentType> {
+        match src_text[index].as_char() {
+            //Prefixed literals only start at a token boundary, e.g. not in `bar"` or `abc"`
+            'r' | 'b' | 'c' if !at_token_boundary(src_text, index) => None,
             'r' => {
-                if index + 1 >= src_text.len() {
-                    return None;
-                }
-
-                let mut hashes = 0;
-                let mut tmp_index = index + 1;
-                loop {
-                    if src_text[tmp_index] == '#' {
-                        hashes += 1;
-                    } else {
-                        break;
-                    }
-                    tmp_index += 1;
-                    if tmp_index >= src_text.len() {
-                        return None;
+                raw_string_hashes(src_text, index)
+                    .map(|hashes| CommentType::RawString { preceding_hashes: hashes })
+            },
+            'b' => {
+                if index + 1 < src_text.len() {
+                    match src_text[index + 1].as_char() {
+                        '"' => Some(CommentType::ByteString),
+                        '\'' => Some(CommentType::ByteCharLiteral),
+                        'r' => raw_string_hashes(src_text, index + 1)
+                            .map(|hashes| CommentType::ByteRawString { preceding_hashes: hashes }),
+                        _ => None
                     }
-                }
-                if src_text[tmp_index] == '"' {
-                    Some(CommentType::RawString {preceding_hashes: hashes})
                 } else {
                     None
                 }
-            },
-            'b' => {
-                if index + 1 < src_text.len() && src_text[index + 1] == '"' {
-                    Some(CommentType::ByteString)
+            }
+            'c' => {
+                if index + 1 < src_text.len() {
+                    match src_text[index + 1].as_char() {
+                        '"' => Some(CommentType::CString),
+                        'r' => raw_string_hashes(src_text, index + 1)
+                            .map(|hashes| CommentType::RawCString { preceding_hashes: hashes }),
+                        _ => None
+                    }
                 } else {
                     None
                 }
             }
+            '\'' => {
+                //'a' and '\n' are char literals, while 'a and 'static are lifetimes or labels
+                match T::decode(src_text, index + 1) {
+                    Some(('\\', _)) => Some(CommentType::CharLiteral),
+                    Some((c, width)) if c != '\'' && char_at(src_text, index + 1 + width) == Some('\'') => {
+                        Some(CommentType::CharLiteral)
+                    }
+                    _ => None
+                }
+            }
             '"' => {
                 Some(CommentType::String)
             },
             '/' => {
                 if index != src_text.len() - 1 {
-                    match src_text[index + 1] {
-                        '*' => Some(SlashStar),
-                        '/' => Some(DoubleSlash),
+                    //The third and fourth character decide whether it is a doc comment,
+                    ////// and /*** are ordinary comments again, just like /**/
+                    let third = char_at(src_text, index + 2);
+                    let fourth = char_at(src_text, index + 3);
+                    match (src_text[index + 1].as_char(), third) {
+                        ('*', Some('!')) => Some(InnerBlockDoc),
+                        ('*', Some('*')) if fourth != Some('*') && fourth != Some('/') => Some(OuterBlockDoc),
+                        ('*', _) => Some(SlashStar),
+                        ('/', Some('!')) => Some(InnerLineDoc),
+                        ('/', Some('/')) if fourth != Some('/') => Some(OuterLineDoc),
+                        ('/', _) => Some(DoubleSlash),
                         _ => None
                     }
                 } else {
                     None
                 }
             },
-            ' ' | '\n' | '\t' => Some(Whitespace),
+            _ if whitespace_at(src_text, index).is_some() => Some(Whitespace),
             _ => None
         }
     }
 
-    fn comment_length(&self, src_text: &[char], comment_begin: usize) -> Result<usize, ()> {
-        let mut tmp_index = comment_begin;
-        let mut length;
+    ///Where scanning the comment beginning at `comment_begin` starts, after the opening delimiter
+    fn start_progress(&self, comment_begin: usize) -> SpanProgress {
+        let index = match self {
+            //Char literals are short, so they are scanned from the opening ' every time
+            Whitespace | CommentType::CharLiteral | CommentType::ByteCharLiteral => comment_begin,
+            _ => comment_begin + self.delimiter_length()
+        };
+        SpanProgress {
+            index,
+            depth: 1,
+            has_escapes: false
+        }
+    }
+
+    ///Continues scanning the comment beginning at `comment_begin` from `progress`. The end of
+    ///`src_text` is not taken as the end of the source, if the comment does not end before it
+    ///the progress is returned so that scanning can continue once more text is available
+    fn scan_span<T: TextUnit>(&self, src_text: &[T], comment_begin: usize, mut progress: SpanProgress) -> Scanned {
         match self {
-            CommentType::String => {
-                length = 2; //Account for the starting and ending "
-                tmp_index += 1;
+            CommentType::String | CommentType::ByteString | CommentType::CString => {
                 loop {
-                    if tmp_index == src_text.len() {
-                        return Result::Err(());
+                    if progress.index >= src_text.len() {
+                        return Scanned::Incomplete(progress);
                     }
-                    if src_text[tmp_index] == '"' && src_text[tmp_index - 1] != '\\' {
-                        break;
+                    match src_text[progress.index].as_char() {
+                        '\\' => {
+                            let length = escape_length(src_text, progress.index);
+                            //The escape sequence may continue in the text following
+                            if progress.index + length >= src_text.len() {
+                                return Scanned::Incomplete(progress);
+                            }
+                            progress.has_escapes = true;
+                            progress.index += length;
+                        }
+                        '"' => {
+                            return Scanned::Complete {
+                                length: progress.index + 1 - comment_begin,
+                                has_escapes: progress.has_escapes
+                            };
+                        }
+                        _ => {
+                            progress.index += 1;
+                        }
                     }
-                    length += 1;
-                    tmp_index += 1;
                 }
-                Ok(length)
             },
-            CommentType::DoubleSlash => {
-                length = 3; //Account for the starting // and ending newline
-                tmp_index += 2;
-                loop {
-                    if tmp_index == src_text.len() {
-                        return Result::Err(());
-                    }
-                    if src_text[tmp_index] == '\n' {
-                        break;
+            CommentType::Shebang => {
+                //Unlike line comments, the line ending is not part of the shebang
+                match src_text[progress.index..].iter().position(|c| c.as_char() == '\n') {
+                    Some(newline) => {
+                        let end = progress.index + newline;
+                        let carriage_return = src_text[end - 1].as_char() == '\r';
+                        Scanned::Complete {
+                            length: end - usize::from(carriage_return) - comment_begin,
+                            has_escapes: false
+                        }
                     }
-                    length += 1;
-                    tmp_index += 1;
+                    None => Scanned::Incomplete(SpanProgress { index: src_text.len(), ..progress })
                 }
-                Ok(length)
             }
-            CommentType::SlashStar => {
-                length = 4; //Account for the starting /* and ending */
-                tmp_index += 3;
-                loop {
-                    if tmp_index == src_text.len() {
-                        return Result::Err(());
-                    }
-                    if src_text[tmp_index] == '/' && src_text[tmp_index - 1] == '*' {
-                        break;
+            CommentType::DoubleSlash | CommentType::OuterLineDoc | CommentType::InnerLineDoc => {
+                //The comment includes the ending newline
+                match src_text[progress.index..].iter().position(|c| c.as_char() == '\n') {
+                    Some(newline) => Scanned::Complete {
+                        length: progress.index + newline + 1 - comment_begin,
+                        has_escapes: false
+                    },
+                    None => {
+                        progress.index = src_text.len();
+                        Scanned::Incomplete(progress)
                     }
-                    length += 1;
-                    tmp_index += 1;
                 }
-                Ok(length)
             }
-            CommentType::ByteString => {
-                length = 4; //Account for the starting and ending ", as well as the b
-                tmp_index += 1;
+            CommentType::SlashStar | CommentType::OuterBlockDoc | CommentType::InnerBlockDoc => {
+                //Block comments nest, so every /* has to be closed by its own */
                 loop {
-                    if tmp_index == src_text.len() {
-                        return Result::Err(());
+                    if progress.index + 1 >= src_text.len() {
+                        return Scanned::Incomplete(progress);
                     }
-                    if src_text[tmp_index] == '"' && src_text[tmp_index - 1] != '\\' {
-                        break;
+                    match (src_text[progress.index].as_char(), src_text[progress.index + 1].as_char()) {
+                        ('/', '*') => {
+                            progress.depth += 1;
+                            progress.index += 2;
+                        }
+                        ('*', '/') => {
+                            progress.depth -= 1;
+                            progress.index += 2;
+                            if progress.depth == 0 {
+                                return Scanned::Complete {
+                                    length: progress.index - comment_begin,
+                                    has_escapes: false
+                                };
+                            }
+                        }
+                        _ => {
+                            progress.index += 1;
+                        }
                     }
-                    length += 1;
-                    tmp_index += 1;
                 }
-                Ok(length)
             }
-            CommentType::RawString { preceding_hashes } => {
-                length = 4 + preceding_hashes * 2; //Account for the starting and ending ", as well as the r and the hashes
-                tmp_index += preceding_hashes + 2;
+            CommentType::RawString { preceding_hashes } |
+            CommentType::ByteRawString { preceding_hashes } |
+            CommentType::RawCString { preceding_hashes } => {
+                //Only a " followed by the same amount of hashes as the opening one ends the string
                 loop {
-                    if tmp_index + preceding_hashes + 1 >= src_text.len() {
-                        return Result::Err(());
+                    if progress.index >= src_text.len() {
+                        return Scanned::Incomplete(progress);
                     }
-
-                    let mut finished = true;
-                    'inner: for i in tmp_index..(tmp_index + preceding_hashes) {
-                        if src_text[i] != '#' {
-                            finished = false;
-                            break 'inner;
+                    if src_text[progress.index].as_char() == '"' {
+                        let hashes = src_text[(progress.index + 1)..].iter()
+                            .take(*preceding_hashes)
+                            .take_while(|c| c.as_char() == '#')
+                            .count();
+                        if hashes == *preceding_hashes {
+                            return Scanned::Complete {
+                                length: progress.index + 1 + preceding_hashes - comment_begin,
+                                has_escapes: false
+                            };
+                        }
+                        //The missing hashes may follow the end of the text
+                        if progress.index + 1 + hashes >= src_text.len() {
+                            return Scanned::Incomplete(progress);
                         }
                     }
-                    if finished {
-                        break;
+                    progress.index += 1;
+                }
+            }
+            CommentType::CharLiteral | CommentType::ByteCharLiteral => {
+                //Skip the opening ', as well as the b
+                let mut tmp_index = comment_begin + if *self == CommentType::ByteCharLiteral { 2 } else { 1 };
+                if tmp_index >= src_text.len() {
+                    return Scanned::Incomplete(progress);
+                }
+                let has_escapes = src_text[tmp_index].as_char() == '\\';
+                tmp_index += if has_escapes {
+                    escape_length(src_text, tmp_index)
+                } else {
+                    T::decode(src_text, tmp_index).map_or(1, |(_, width)| width)
+                };
+                if tmp_index >= src_text.len() {
+                    Scanned::Incomplete(progress)
+                } else if src_text[tmp_index].as_char() != '\'' {
+                    Scanned::Unterminated
+                } else {
+                    Scanned::Complete {
+                        length: tmp_index + 1 - comment_begin,
+                        has_escapes
                     }
-
-                    length += 1;
-                    tmp_index += 1;
                 }
-                Ok(length)
             }
             CommentType::Whitespace => {
-                length = 0;
-                while tmp_index < src_text.len() &&
-                    (src_text[tmp_index] == ' ' || src_text[tmp_index] == '\n' ||src_text[tmp_index] == '\t') {
-                    tmp_index += 1;
-                    length += 1;
+                while let Some(width) = whitespace_at(src_text, progress.index) {
+                    progress.index += width;
+                }
+                //A whitespace character encoded in several units may be cut off by the end of the text
+                let cut_off = T::decode(src_text, progress.index).is_none() && src_text.len() - progress.index < 4;
+                if progress.index == src_text.len() || cut_off {
+                    Scanned::Incomplete(progress)
+                } else {
+                    Scanned::Complete {
+                        length: progress.index - comment_begin,
+                        has_escapes: false
+                    }
                 }
-                Ok(length)
             }
         }
     }
 
-    ///Returns the amount of inserted characters
-    fn replace_in_source(&self, index: usize, source: &mut [char]) -> usize {
+    pub(crate) fn is_doc_comment(&self) -> bool {
+        matches!(self, OuterLineDoc | OuterBlockDoc | InnerLineDoc | InnerBlockDoc)
+    }
+
+    ///Returns the length of the opening delimiter, e.g. 2 for /* and 4 for br#"
+    fn delimiter_length(&self) -> usize {
         match self {
-            CommentType::DoubleSlash | SlashStar | Whitespace => {
-                if index != 0 && source[index - 1] != '\n' {
-                    match source[index - 1] {
-                        'a'..='z' | 'A'..='Z' => {
-                            source[index] = '\n';
-                            1
-                        }
-                        _ => {
-                            0
-                        }
+            CommentType::String | CommentType::CharLiteral => 1,
+            DoubleSlash | SlashStar | CommentType::ByteString | CommentType::CString |
+            CommentType::ByteCharLiteral => 2,
+            OuterLineDoc | OuterBlockDoc | InnerLineDoc | InnerBlockDoc => 3,
+            CommentType::RawString { preceding_hashes } => 2 + preceding_hashes,
+            CommentType::ByteRawString { preceding_hashes } |
+            CommentType::RawCString { preceding_hashes } => 3 + preceding_hashes,
+            Whitespace => 1,
+            CommentType::Shebang => 2
+        }
+    }
+
+    ///Whether the comment may end with the text, like line comments and whitespace do
+    fn may_end_with_text(&self) -> bool {
+        matches!(self, DoubleSlash | OuterLineDoc | InnerLineDoc | Whitespace | CommentType::Shebang)
+    }
+
+    ///Returns the error for this comment beginning at `comment_begin` and never ending
+    fn unterminated<T: TextUnit>(&self, src_text: &[T], comment_begin: usize) -> PreprocessError {
+        let delimiter_end = (comment_begin + self.delimiter_length()).min(src_text.len());
+        let location = ErrorLocation::new(src_text, comment_begin, &src_text[comment_begin..delimiter_end]);
+        match self {
+            CommentType::String | CommentType::ByteString | CommentType::CString => {
+                PreprocessError::UnterminatedString(location)
+            }
+            CommentType::RawString { preceding_hashes } |
+            CommentType::ByteRawString { preceding_hashes } |
+            CommentType::RawCString { preceding_hashes } => {
+                PreprocessError::UnterminatedRawString { location, hashes: *preceding_hashes }
+            }
+            CommentType::CharLiteral | CommentType::ByteCharLiteral => PreprocessError::UnterminatedChar(location),
+            SlashStar | OuterBlockDoc | InnerBlockDoc => PreprocessError::UnterminatedBlockComment(location),
+            DoubleSlash | OuterLineDoc | InnerLineDoc | Whitespace | CommentType::Shebang => {
+                unreachable!("{:?} ends with the text", self)
+            }
+        }
+    }
+
+    ///The character the replacement of the comment begins with, None if it is removed
+    fn first_replaced(&self, options: &PreprocessOptions) -> Option<char> {
+        match self {
+            OuterLineDoc | OuterBlockDoc | InnerLineDoc | InnerBlockDoc => match options.doc_comments {
+                DocComments::Strip => None,
+                DocComments::Keep => Some('/'),
+                DocComments::Attribute => Some('#')
+            },
+            CommentType::DoubleSlash | SlashStar | Whitespace | CommentType::Shebang => None,
+            CommentType::CharLiteral | CommentType::ByteCharLiteral => Some('\''),
+            _ => Some('"')
+        }
+    }
+
+    ///Writes the replacement of the comment to the output. `next` is the character written
+    ///after it, see `first_written`
+    fn replace_in_source<T: TextUnit>(&self, comment_text: &[T], output: &mut Output<T>,
+                                      options: &PreprocessOptions, next: Option<char>) {
+        match self {
+            OuterLineDoc | OuterBlockDoc | InnerLineDoc | InnerBlockDoc
+                if options.doc_comments == DocComments::Keep => {
+                output.extend_lines(comment_text, options)
+            }
+            OuterLineDoc | OuterBlockDoc | InnerLineDoc | InnerBlockDoc
+                if options.doc_comments == DocComments::Attribute => {
+                self.write_doc_attribute(comment_text, output, options)
+            }
+            CommentType::DoubleSlash | SlashStar | Whitespace | CommentType::Shebang |
+            OuterLineDoc | OuterBlockDoc | InnerLineDoc | InnerBlockDoc => {
+                //The tokens before and after are kept apart, if written next to each other they
+                //would become one, like in `x _y`, `1 2`, `a/**/b`, `- -x` and `& &x`
+                match (output.last(), next) {
+                    (Some(before), Some(after)) if tokens_merge(before, after) => {
+                        let line_ending = output.line_ending.expect("The scanner waits for the line ending");
+                        output.extend_ascii(line_ending)
                     }
-                } else {
-                    0
+                    _ => ()
+                }
+            }
+            CommentType::String | CommentType::ByteString | CommentType::RawString { .. } |
+            CommentType::ByteRawString { .. } | CommentType::CString | CommentType::RawCString { .. } => {
+                output.extend_ascii("\"\"")
+            }
+            CommentType::CharLiteral | CommentType::ByteCharLiteral => {
+                output.extend_ascii("''")
+            }
+        }
+    }
+
+    ///Writes the doc comment as the #[doc = r"..."] attribute rustc turns it into
+    fn write_doc_attribute<T: TextUnit>(&self, comment_text: &[T], output: &mut Output<T>,
+                                        options: &PreprocessOptions) {
+        let content = match self {
+            OuterLineDoc | InnerLineDoc => {
+                //The line ending, \n or \r\n, is not part of the documentation
+                let mut end = comment_text.len();
+                for ending in ['\n', '\r'] {
+                    if end > 3 && comment_text[end - 1].as_char() == ending {
+                        end -= 1;
+                    }
+                }
+                &comment_text[3..end]
+            }
+            _ => &comment_text[3..(comment_text.len() - 2)]
+        };
+
+        //The raw string needs more hashes than any "### sequence in the content
+        let mut hashes = 0;
+        for (i, c) in content.iter().enumerate() {
+            if c.as_char() == '"' {
+                let following = content[(i + 1)..].iter().take_while(|c| c.as_char() == '#').count();
+                hashes = hashes.max(following + 1);
+            }
+        }
+        let hashes = "#".repeat(hashes);
+
+        output.push_ascii('#');
+        if *self == InnerLineDoc || *self == InnerBlockDoc {
+            output.push_ascii('!');
+        }
+        output.extend_ascii("[doc = r");
+        output.extend_ascii(&hashes);
+        output.push_ascii('"');
+        output.extend_lines(content, options);
+        output.push_ascii('"');
+        output.extend_ascii(&hashes);
+        output.push_ascii(']');
+    }
+}
+
+///Where the result text is written to
+enum Storage<'a, T: TextUnit> {
+    //A result text of fixed capacity
+    Fixed(&'a mut [T]),
+    //A growing result text, which is taken out piece by piece when preprocessing in chunks
+    Growing(Vec<T>),
+    //Nothing is written, only the length is counted
+    Discarded
+}
+
+///The part of the result text written so far. Once a fixed text is full, the output keeps
+///counting without writing, so that the required capacity is known in the end
+struct Output<'a, T: TextUnit> {
+    text: Storage<'a, T>,
+    length: usize,
+    last: Option<char>,
+    //What separators are written as, see `line_ending`. While it is None the scanner stops before
+    //the first separator
+    line_ending: Option<&'static str>
+}
+
+impl<'a, T: TextUnit> Output<'a, T> {
+    fn new(text: &'a mut [T]) -> Output<'a, T> {
+        Output {
+            text: Storage::Fixed(text),
+            length: 0,
+            last: None,
+            line_ending: Some("\n")
+        }
+    }
+
+    fn growing() -> Output<'a, T> {
+        Output {
+            text: Storage::Growing(Vec::new()),
+            length: 0,
+            last: None,
+            line_ending: Some("\n")
+        }
+    }
+
+    fn discarded() -> Output<'a, T> {
+        Output {
+            text: Storage::Discarded,
+            length: 0,
+            last: None,
+            line_ending: Some("\n")
+        }
+    }
+
+    fn last(&self) -> Option<char> {
+        self.last
+    }
+
+    fn overflowed(&self) -> bool {
+        match &self.text {
+            Storage::Fixed(text) => self.length > text.len(),
+            Storage::Growing(_) | Storage::Discarded => false
+        }
+    }
+
+    ///Takes the text written to a growing output since the last call
+    fn take(&mut self) -> Vec<T> {
+        match &mut self.text {
+            Storage::Growing(text) => std::mem::take(text),
+            Storage::Fixed(_) | Storage::Discarded => Vec::new()
+        }
+    }
+
+    fn push(&mut self, unit: T) {
+        match &mut self.text {
+            Storage::Fixed(text) => {
+                if let Some(slot) = text.get_mut(self.length) {
+                    *slot = unit;
                 }
             }
-            CommentType::String | CommentType::ByteString | CommentType::RawString { .. } => {
-                source[index] = '"';
-                source[index + 1] = '"';
-                2
+            Storage::Growing(text) => text.push(unit),
+            Storage::Discarded => ()
+        }
+        self.length += 1;
+        self.last = Some(unit.as_char());
+    }
+
+    fn push_ascii(&mut self, c: char) {
+        self.push(T::from_ascii(c))
+    }
+
+    fn extend(&mut self, units: &[T]) {
+        units.iter().for_each(|unit| self.push(*unit))
+    }
+
+    fn extend_ascii(&mut self, text: &str) {
+        text.chars().for_each(|c| self.push_ascii(c))
+    }
+
+    ///Copies text which may span several lines, writing \r\n as \n unless line endings are preserved
+    fn extend_lines(&mut self, units: &[T], options: &PreprocessOptions) {
+        match options.line_endings {
+            LineEndings::Preserve => self.extend(units),
+            LineEndings::Normalize => {
+                for (i, unit) in units.iter().enumerate() {
+                    if unit.as_char() != '\r' || char_at(units, i + 1) != Some('\n') {
+                        self.push(*unit);
+                    }
+                }
             }
         }
     }
 }
 
+///How far a comment was scanned, so that scanning can continue once more text is available
 #[derive(Clone, Copy, Debug)]
-pub(crate)  struct Comment {
-    pub(crate) begin: usize,
-    pub(crate) length: usize,
-    pub(crate) comment_type: CommentType
+struct SpanProgress {
+    //The next index to look at
+    index: usize,
+    //The nesting depth of block comments
+    depth: usize,
+    has_escapes: bool
 }
 
-pub(crate) fn preprocess(source_text: &[char], result_text: &mut [char]) -> Result<Vec<Comment>, usize> {
-    let mut ret = Vec::new();
-    let mut index_in_src = 0;
-    let mut index_in_result = 0;
-    while index_in_src < source_text.len() {
-        let comment = CommentType::any_comment(source_text, index_in_src);
-        match comment {
+enum Scanned {
+    Complete {
+        length: usize,
+        has_escapes: bool
+    },
+    //The comment does not end before the end of the text
+    Incomplete(SpanProgress),
+    //The comment can never be ended, e.g. 'ab
+    Unterminated
+}
+
+///The state of preprocessing a source text, which can be fed to the scanner in several parts
+struct Scanner<'a, T: TextUnit> {
+    output: Output<'a, T>,
+    comments: Vec<Comment>,
+    source_map: SourceMap,
+    options: PreprocessOptions,
+    //Offset of the text passed to `scan` in the source text
+    offset: usize,
+    //Index of the next unit to scan in the text passed to `scan`
+    index: usize,
+    //The comment beginning at `index`, which did not end before the end of the text
+    open: Option<(CommentType, SpanProgress)>,
+    //Where in the source the result text was full first
+    overflow_location: Option<ErrorLocation>
+}
+
+impl<'a, T: TextUnit> Scanner<'a, T> {
+    fn new(output: Output<'a, T>, options: &PreprocessOptions) -> Scanner<'a, T> {
+        Scanner {
+            output,
+            comments: Vec::new(),
+            source_map: SourceMap::new(),
+            options: *options,
+            offset: 0,
+            index: 0,
+            open: None,
+            overflow_location: None
+        }
+    }
+
+    ///Scans `src_text` from `self.index` on. Unless `at_end`, scanning stops at the first
+    ///comment or character which could turn out differently once more text follows
+    fn scan(&mut self, src_text: &[T], at_end: bool) -> Result<(), PreprocessError> {
+        while self.index < src_text.len() && self.step(src_text, at_end)? {}
+        Ok(())
+    }
+
+    ///Scans the comment or character at `self.index`. Unless `at_end`, returns false without
+    ///scanning if it could turn out differently once more text follows
+    fn step(&mut self, src_text: &[T], at_end: bool) -> Result<bool, PreprocessError> {
+        let index_in_src = self.index;
+        let comment = match self.open {
+            Some((c, _)) => Some(c),
+            None if !at_end && needs_more_text(src_text, index_in_src) => return Ok(false),
+            None if self.offset + index_in_src == 0 => match shebang(src_text, at_end) {
+                Some(true) => Some(CommentType::Shebang),
+                Some(false) => CommentType::any_comment(src_text, index_in_src),
+                None => return Ok(false)
+            },
+            None => CommentType::any_comment(src_text, index_in_src)
+        };
+        let comment = match comment {
             Some(c) => {
-                match c.comment_length(source_text, index_in_src) {
-                    Ok(length) => {
-                        ret.push(Comment {
-                            begin: index_in_src,
-                            length,
-                            comment_type: c
-                        });
-
-                        index_in_src += length;
-                        index_in_result += c.replace_in_source(index_in_result, result_text);
-                    },
-                    Err(()) => {
-                        return Err(index_in_src);
+                let progress = match self.open.take() {
+                    Some((_, progress)) => progress,
+                    None => c.start_progress(index_in_src)
+                };
+                let (length, has_escapes) = match c.scan_span(src_text, index_in_src, progress) {
+                    //Whether a removed comment is replaced by a separator depends on what follows
+                    Scanned::Complete { length, .. } if !at_end && c.first_replaced(&self.options).is_none() &&
+                        needs_more_text(src_text, index_in_src + length) => {
+                        self.open = Some((c, progress));
+                        return Ok(false);
                     }
-                }
+                    //The separator cannot be written before the line ending is known
+                    Scanned::Complete { length, .. } if self.output.line_ending.is_none() &&
+                        c.first_replaced(&self.options).is_none() && self.separates(src_text, index_in_src + length) => {
+                        self.open = Some((c, progress));
+                        return Ok(false);
+                    }
+                    Scanned::Complete { length, has_escapes } => (length, has_escapes),
+                    Scanned::Incomplete(progress) if !at_end => {
+                        self.open = Some((c, progress));
+                        return Ok(false);
+                    }
+                    //Line comments and whitespace end with the text as well
+                    Scanned::Incomplete(progress) if c.may_end_with_text() => (progress.index - index_in_src, false),
+                    _ => return Err(c.unterminated(src_text, index_in_src))
+                };
+                Some(Comment {
+                    begin: self.offset + index_in_src,
+                    length,
+                    comment_type: c,
+                    has_escapes
+                })
+            }
+            None => None
+        };
+        self.record(src_text, comment);
+        Ok(true)
+    }
 
+    ///Records the comment beginning at `self.index` or, without one, the character there,
+    ///and writes its replacement to the output
+    fn record(&mut self, src_text: &[T], comment: Option<Comment>) {
+        let index_in_src = self.index;
+        let index_in_result = self.output.length;
+        let length = match comment {
+            Some(comment) => {
+                self.comments.push(comment);
+                let comment_text = &src_text[index_in_src..(index_in_src + comment.length)];
+                let next = first_written(src_text, index_in_src + comment.length, &self.options);
+                comment.comment_type.replace_in_source(comment_text, &mut self.output, &self.options, next);
+                comment.length
             }
             None => {
-                //TODO count semicolons, blocks etc.
-                result_text[index_in_result] = source_text[index_in_src];
-                index_in_src += 1;
-                index_in_result += 1;
+                let unit = src_text[index_in_src];
+                self.output.push(unit);
+                //Units of UTF-8 encoded characters are written one by one, the last completes it
+                if !unit.as_char().is_ascii() {
+                    self.output.last = T::decode_before(src_text, index_in_src + 1);
+                }
+                1
+            }
+        };
+        let comment = comment.map(|c| c.comment_type);
+        if self.output.overflowed() && self.overflow_location.is_none() {
+            let delimiter_length = comment.map_or(1, |c| c.delimiter_length());
+            let delimiter = &src_text[index_in_src..(index_in_src + delimiter_length.min(length))];
+            self.overflow_location = Some(ErrorLocation::new(src_text, index_in_src, delimiter));
+        }
+
+        let written = self.output.length - index_in_result;
+        //Kept doc comments are only copied unchanged if no line endings were normalized
+        let copied = comment.is_none() || (comment.is_some_and(|c| c.is_doc_comment()) &&
+            self.options.doc_comments == DocComments::Keep && written == length);
+        if copied {
+            self.source_map.copied(self.offset + index_in_src, index_in_result, length);
+        } else {
+            self.source_map.replaced(self.offset + index_in_src, length, index_in_result, written);
+        }
+        self.index += length;
+    }
+
+    ///Whether a removed comment ending at `index` is replaced by a separator, see `replace_in_source`
+    fn separates(&self, src_text: &[T], index: usize) -> bool {
+        match (self.output.last(), first_written(src_text, index, &self.options)) {
+            (Some(before), Some(after)) => tokens_merge(before, after),
+            _ => false
+        }
+    }
+
+    ///Drops the first `amount` units of the text passed to `scan`
+    fn skip(&mut self, amount: usize) {
+        self.offset += amount;
+        self.index -= amount;
+        if let Some((_, progress)) = &mut self.open {
+            progress.index -= amount;
+        }
+    }
+}
+
+///Whether the type of comment beginning at `index` could change once more text follows
+fn needs_more_text<T: TextUnit>(src_text: &[T], index: usize) -> bool {
+    //Apart from the hashes of raw strings, at most a char with 4 bytes between quotes is looked at
+    const LOOKAHEAD: usize = 8;
+    if src_text.len() - index < LOOKAHEAD {
+        return true;
+    }
+    let hashes_begin = match (char_at(src_text, index), char_at(src_text, index + 1)) {
+        (Some('r'), _) => index + 1,
+        (Some('b'), Some('r')) | (Some('c'), Some('r')) => index + 2,
+        _ => return false
+    };
+    src_text[hashes_begin..].iter().all(|c| c.as_char() == '#')
+}
+
+///Whether the text begins with a shebang, which rustc skips. A `#!` followed by `[`, even
+///after whitespace and comments, begins an inner attribute like `#![allow(unused)]` instead.
+///None if deciding needs more text
+fn shebang<T: TextUnit>(src_text: &[T], at_end: bool) -> Option<bool> {
+    if char_at(src_text, 0) != Some('#') || char_at(src_text, 1) != Some('!') {
+        return Some(false);
+    }
+    let mut index = 2;
+    loop {
+        if index >= src_text.len() {
+            return if at_end { Some(true) } else { None };
+        }
+        if !at_end && needs_more_text(src_text, index) {
+            return None;
+        }
+        //Doc comments are attributes, so they end the search just like any other token
+        match CommentType::any_comment(src_text, index) {
+            Some(c @ (Whitespace | DoubleSlash | SlashStar)) => {
+                match c.scan_span(src_text, index, c.start_progress(index)) {
+                    Scanned::Complete { length, .. } => index += length,
+                    //An unterminated comment is reported once the shebang is skipped
+                    _ if at_end => return Some(true),
+                    _ => return None
+                }
+            }
+            _ => return Some(char_at(src_text, index) != Some('['))
+        }
+    }
+}
+
+///Whether the character is whitespace in Rust source, which is Unicode's Pattern_White_Space
+pub(crate) fn is_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\u{0B}' | '\u{0C}' | '\r' | ' ' |
+        '\u{85}' | '\u{200E}' | '\u{200F}' | '\u{2028}' | '\u{2029}')
+}
+
+///The length in units of the whitespace character at `index`, None if there is none there
+fn whitespace_at<T: TextUnit>(src_text: &[T], index: usize) -> Option<usize> {
+    let c = src_text.get(index)?.as_char();
+    if c.is_ascii() {
+        return if is_whitespace(c) { Some(1) } else { None };
+    }
+    T::decode(src_text, index)
+        .filter(|(c, _)| is_whitespace(*c))
+        .map(|(_, width)| width)
+}
+
+///What separators are written as. With preserved line endings it is the line ending of the
+///first line, which is None if no line of the text has ended yet. Otherwise it is always \n
+fn line_ending<T: TextUnit>(src_text: &[T], options: &PreprocessOptions) -> Option<&'static str> {
+    if options.line_endings == LineEndings::Normalize {
+        return Some("\n");
+    }
+    let newline = src_text.iter().position(|c| c.as_char() == '\n')?;
+    if newline > 0 && src_text[newline - 1].as_char() == '\r' {
+        Some("\r\n")
+    } else {
+        Some("\n")
+    }
+}
+
+///Whether the character at `index` can begin a token, which is not the case
+///directly after an identifier or number
+fn at_token_boundary<T: TextUnit>(src_text: &[T], index: usize) -> bool {
+    !T::decode_before(src_text, index).is_some_and(|c| c.is_alphanumeric() || c == '_')
+}
+
+///The first character written for the text at `index`, which a removed comment before may
+///have to be kept apart from. None if the text ends there, or if the comment there is removed
+///as well, so that the last of the removed comments decides
+fn first_written<T: TextUnit>(src_text: &[T], index: usize, options: &PreprocessOptions) -> Option<char> {
+    if index >= src_text.len() {
+        return None;
+    }
+    match CommentType::any_comment(src_text, index) {
+        Some(comment) => comment.first_replaced(options),
+        None => T::decode(src_text, index).map(|(c, _)| c)
+    }
+}
+
+///Whether the token ending with `before` and the one beginning with `after` would become
+///different tokens if nothing separated them
+fn tokens_merge(before: char, after: char) -> bool {
+    let continues_identifier = |c: char| c.is_xid_continue();
+    match (before, after) {
+        //Identifiers, keywords and numbers, e.g. `x _y` and `1 2`, as well as prefixed and
+        //reserved literals like `r "s"` and `x #y`, and floats like `1 .5`
+        (before, after) if continues_identifier(before) => {
+            continues_identifier(after) || matches!(after, '"' | '\'' | '#') ||
+                (before.is_ascii_digit() && after == '.')
+        }
+        //Suffixes of literals, e.g. `"s" x`
+        ('"' | '\'', after) => continues_identifier(after),
+        ('.', after) => after.is_ascii_digit() || after == '.' || after == '=',
+        //Compound operators like `- -x`, `& &x` and `a < = b`, and comments like `/ /x`
+        (before, '=') => "=!<>+-*/%^&|".contains(before),
+        (before, after) if before == after => ":&|<>+-/".contains(before),
+        ('-', '>') | ('=', '>') | ('<', '-') | ('/', '*') => true,
+        _ => false
+    }
+}
+
+///Returns the amount of hashes if a raw string begins with the r at `r_index`, e.g. 2 for r##"
+fn raw_string_hashes<T: TextUnit>(src_text: &[T], r_index: usize) -> Option<usize> {
+    let hashes = src_text[(r_index + 1)..].iter().take_while(|c| c.as_char() == '#').count();
+    if char_at(src_text, r_index + 1 + hashes) == Some('"') {
+        Some(hashes)
+    } else {
+        None
+    }
+}
+
+///Returns the length of the escape sequence beginning with the backslash at `index`,
+///e.g. 2 for \n, 4 for \x7f and 10 for \u{1F600}. Malformed escapes end at the first
+///character which does not fit, so that a closing quote is never swallowed.
+fn escape_length<T: TextUnit>(src_text: &[T], index: usize) -> usize {
+    let is_hex = |i: usize| char_at(src_text, i).is_some_and(|c| c.is_ascii_hexdigit());
+    match char_at(src_text, index + 1) {
+        None => 1,
+        Some('x') => {
+            let mut tmp_index = index + 2;
+            while tmp_index < index + 4 && is_hex(tmp_index) {
+                tmp_index += 1;
+            }
+            tmp_index - index
+        }
+        Some('u') => {
+            let mut tmp_index = index + 2;
+            if char_at(src_text, tmp_index) != Some('{') {
+                return 2;
+            }
+            tmp_index += 1;
+            while is_hex(tmp_index) || char_at(src_text, tmp_index) == Some('_') {
+                tmp_index += 1;
             }
+            if char_at(src_text, tmp_index) == Some('}') {
+                tmp_index += 1;
+            }
+            tmp_index - index
         }
+        //Line continuation, the following whitespace is skipped as regular content
+        Some('\r') if char_at(src_text, index + 2) == Some('\n') => 3,
+        _ => 1 + T::decode(src_text, index + 1).map_or(1, |(_, width)| width)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate)  struct Comment {
+    pub(crate) begin: usize,
+    pub(crate) length: usize,
+    pub(crate) comment_type: CommentType,
+    //Whether the literal contains escape sequences, always false for comments and raw strings
+    pub(crate) has_escapes: bool
+}
+
+///How doc comments are written to the resulting text
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub(crate) enum DocComments {
+    //Doc comments are removed just like ordinary comments
+    #[default]
+    Strip,
+    //Doc comments are copied unchanged
+    Keep,
+    //Doc comments are rewritten to #[doc = r"..."] attributes, like rustc does
+    Attribute
+}
+
+///How line endings are written to the resulting text
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub(crate) enum LineEndings {
+    //\r\n is written as \n, as rustc reads it, and separators are \n
+    #[default]
+    Normalize,
+    //Kept text is copied with its line endings, and separators are written with the line
+    //ending of the source's first line
+    Preserve
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PreprocessOptions {
+    pub(crate) doc_comments: DocComments,
+    pub(crate) line_endings: LineEndings
+}
+
+///The comments found by the preprocessor, and how the result text relates to the source
+#[derive(Clone, Debug)]
+pub(crate) struct Preprocessed {
+    pub(crate) comments: Vec<Comment>,
+    pub(crate) source_map: SourceMap
+}
+
+///Preprocesses either a text of chars or UTF-8 encoded bytes, for which the offsets of the
+///comments are byte offsets
+#[cfg(test)]
+pub(crate) fn preprocess<T: TextUnit>(source_text: &[T], result_text: &mut [T]) -> Result<Vec<Comment>, PreprocessError> {
+    preprocess_with_options(source_text, result_text, &PreprocessOptions::default())
+        .map(|preprocessed| preprocessed.comments)
+}
+
+///Preprocesses a string without converting it to chars first, the offsets are byte offsets
+#[cfg(test)]
+pub(crate) fn preprocess_utf8(source_text: &str, result_text: &mut [u8]) -> Result<Vec<Comment>, PreprocessError> {
+    preprocess(source_text.as_bytes(), result_text)
+}
+
+///Preprocesses the text into a newly allocated result text
+#[cfg(test)]
+pub(crate) fn preprocess_owned<T: TextUnit>(source_text: &[T], options: &PreprocessOptions)
+                                            -> Result<(Vec<T>, Preprocessed), PreprocessError> {
+    //The result is only longer than the source if doc comments are rewritten
+    let mut result_text = vec![T::from_ascii(' '); source_text.len()];
+    let preprocessed = match preprocess_with_options(source_text, &mut result_text, options) {
+        Err(PreprocessError::ResultBufferTooSmall { required, .. }) => {
+            result_text.resize(required, T::from_ascii(' '));
+            preprocess_with_options(source_text, &mut result_text, options)?
+        }
+        preprocessed => preprocessed?
+    };
+    result_text.truncate(preprocessed.source_map.result_len());
+    Ok((result_text, preprocessed))
+}
+
+///Preprocesses a string into a newly allocated string, the offsets are byte offsets
+#[cfg(test)]
+pub(crate) fn preprocess_to_string(source_text: &str, options: &PreprocessOptions)
+                                   -> Result<(String, Preprocessed), PreprocessError> {
+    let (result_text, preprocessed) = preprocess_owned(source_text.as_bytes(), options)?;
+    Ok((into_string(result_text), preprocessed))
+}
+
+///Only whole characters of the source and ASCII characters are written to the result
+pub(crate) fn into_string(result_text: Vec<u8>) -> String {
+    String::from_utf8(result_text).expect("Result text is not valid UTF-8")
+}
+
+///Like `preprocess`, but keeping or rewriting doc comments depending on the options and
+///returning a source map as well. Rewritten doc comments can be longer than the source,
+///if `result_text` is too small, the error reports the required capacity
+pub(crate) fn preprocess_with_options<T: TextUnit>(source_text: &[T], result_text: &mut [T],
+                                                   options: &PreprocessOptions) -> Result<Preprocessed, PreprocessError> {
+    let capacity = result_text.len();
+    let mut scanner = Scanner::new(Output::new(result_text), options);
+    scanner.output.line_ending = Some(line_ending(source_text, options).unwrap_or("\n"));
+    scanner.scan(source_text, true)?;
+    if let Some(location) = scanner.overflow_location {
+        return Err(PreprocessError::ResultBufferTooSmall {
+            location,
+            capacity,
+            required: scanner.output.length
+        });
+    }
+    if let Storage::Fixed(result_text) = &scanner.output.text {
+        scanner.source_map.finish(source_text, result_text);
     }
 
-    Ok(ret)
+    Ok(Preprocessed {
+        comments: scanner.comments,
+        source_map: scanner.source_map
+    })
 }
 
 /*
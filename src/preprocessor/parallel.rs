@@ -0,0 +1,427 @@
+use std::thread;
+
+use super::{Comment, CommentType, Output, PreprocessError, PreprocessOptions, Preprocessed, Scanned, Scanner};
+use super::{SourceMap, SpanProgress, TextUnit, first_written, line_ending, preprocess_with_options};
+
+///Segments shorter than this are not worth a thread of their own
+const MIN_SEGMENT_LENGTH: usize = 16 * 1024;
+
+///Preprocesses the text on up to `threads` threads, with the same result as `preprocess_with_options`.
+///The text is split into segments which are scanned in parallel under every state the text before
+///could leave them in, the segments are then stitched together with the actual states in order
+pub(crate) fn preprocess_parallel<T>(source_text: &[T], result_text: &mut [T], options: &PreprocessOptions,
+                                     threads: usize) -> Result<Preprocessed, PreprocessError>
+    where T: TextUnit + Send + Sync {
+    preprocess_segments(source_text, result_text, options, &segment_boundaries(source_text.len(), threads))
+}
+
+///The amount of threads worth starting on this machine
+pub(crate) fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+///Splits a text of the given length into segments for up to `threads` threads, the boundaries
+///begin with 0 and end with the length
+pub(crate) fn segment_boundaries(length: usize, threads: usize) -> Vec<usize> {
+    let segments = (length / MIN_SEGMENT_LENGTH).clamp(1, threads.max(1));
+    (0..=segments)
+        .map(|i| length * i / segments)
+        .collect()
+}
+
+///Preprocesses the text split into segments at `boundaries`, which begin with 0 and end with
+///the length of the text. Every segment is scanned on its own thread
+pub(crate) fn preprocess_segments<T>(source_text: &[T], result_text: &mut [T], options: &PreprocessOptions,
+                                     boundaries: &[usize]) -> Result<Preprocessed, PreprocessError>
+    where T: TextUnit + Send + Sync {
+    let speculations = thread::scope(|scope| {
+        let handles = boundaries.windows(2)
+            .map(|segment| {
+                let (begin, end) = (segment[0], segment[1]);
+                scope.spawn(move || Speculation::new(source_text, begin, end, options))
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().expect("Scanning a segment panicked"))
+            .collect::<Vec<Speculation>>()
+    });
+    let comments = stitch(source_text, boundaries, &speculations, options)?;
+    write_result(source_text, comments, boundaries, result_text, options)
+}
+
+///Writes the result text for comments found by any backend, every segment on its own thread
+pub(crate) fn write_result<T>(source_text: &[T], comments: Vec<Comment>, boundaries: &[usize], result_text: &mut [T],
+                              options: &PreprocessOptions) -> Result<Preprocessed, PreprocessError>
+    where T: TextUnit + Send + Sync {
+    let parts = thread::scope(|scope| {
+        let comments = &comments;
+        let handles = boundaries.windows(2)
+            .map(|segment| {
+                let (begin, end) = (segment[0], segment[1]);
+                scope.spawn(move || write_segment(source_text, comments, begin, end, options))
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().expect("Writing a segment panicked"))
+            .collect::<Vec<(Vec<T>, SourceMap)>>()
+    });
+
+    let required = parts.iter().map(|(text, _)| text.len()).sum::<usize>();
+    if required > result_text.len() {
+        //Finding where the result text was full first is left to the sequential preprocessor
+        return preprocess_with_options(source_text, result_text, options);
+    }
+    let mut source_map = SourceMap::new();
+    let mut result_offset = 0;
+    for (text, part_map) in parts.iter() {
+        result_text[result_offset..(result_offset + text.len())].copy_from_slice(text);
+        source_map.append(part_map, result_offset);
+        result_offset += text.len();
+    }
+    source_map.finish(source_text, result_text);
+
+    Ok(Preprocessed {
+        comments,
+        source_map
+    })
+}
+
+///Where a comment which was open at the beginning of a segment ends
+#[derive(Clone, Copy, Debug)]
+enum Exit {
+    //The index after the end of the comment, and whether it contains escape sequences
+    Ends(usize, bool),
+    Open(SpanProgress)
+}
+
+///Block comments entered at some index of a segment, with any nesting depth
+#[derive(Clone, Debug)]
+struct BlockExits {
+    //The index after the */ closing the comment, for every depth beginning with 1
+    exits: Vec<usize>,
+    stop: usize,
+    //How the depth changed up to `stop`
+    depth_change: isize
+}
+
+impl BlockExits {
+    fn new<T: TextUnit>(segment: &[T], start: usize) -> BlockExits {
+        let mut exits = Vec::new();
+        let mut index = start;
+        let mut depth: isize = 0;
+        while index + 1 < segment.len() {
+            match (segment[index].as_char(), segment[index + 1].as_char()) {
+                ('/', '*') => {
+                    depth += 1;
+                    index += 2;
+                }
+                ('*', '/') => {
+                    depth -= 1;
+                    index += 2;
+                    //The first time the depth sinks this low, a comment this deep is closed
+                    if -depth > exits.len() as isize {
+                        exits.push(index);
+                    }
+                }
+                _ => {
+                    index += 1;
+                }
+            }
+        }
+        BlockExits {
+            exits,
+            stop: index,
+            depth_change: depth
+        }
+    }
+
+    fn exit(&self, depth: usize) -> Exit {
+        match self.exits.get(depth - 1) {
+            Some(end) => Exit::Ends(*end, false),
+            None => Exit::Open(SpanProgress {
+                index: self.stop,
+                depth: (depth as isize + self.depth_change) as usize,
+                has_escapes: false
+            })
+        }
+    }
+}
+
+///Raw strings entered at the beginning of a segment, with any amount of hashes
+#[derive(Clone, Debug)]
+struct RawExits {
+    //Every " followed by more hashes than any " before it, with the amount of hashes
+    quotes: Vec<(usize, usize)>,
+    //The last ", if its hashes reach the end of the segment and more may follow
+    open_quote: Option<usize>,
+    end: usize
+}
+
+impl RawExits {
+    fn new<T: TextUnit>(segment: &[T], begin: usize) -> RawExits {
+        let mut quotes: Vec<(usize, usize)> = Vec::new();
+        let mut open_quote = None;
+        for index in begin..segment.len() {
+            if segment[index].as_char() != '"' {
+                continue;
+            }
+            let hashes = segment[(index + 1)..].iter().take_while(|c| c.as_char() == '#').count();
+            if quotes.last().is_none_or(|(most, _)| hashes > *most) {
+                quotes.push((hashes, index));
+            }
+            if index + 1 + hashes == segment.len() {
+                open_quote = Some(index);
+            }
+        }
+        RawExits {
+            quotes,
+            open_quote,
+            end: segment.len()
+        }
+    }
+
+    fn exit(&self, hashes: usize) -> Exit {
+        let first = self.quotes.partition_point(|(most, _)| *most < hashes);
+        match (self.quotes.get(first), self.open_quote) {
+            (Some((_, quote)), _) => Exit::Ends(quote + 1 + hashes, false),
+            (None, Some(quote)) => Exit::Open(SpanProgress { index: quote, depth: 1, has_escapes: false }),
+            (None, None) => Exit::Open(SpanProgress { index: self.end, depth: 1, has_escapes: false })
+        }
+    }
+}
+
+///Where a comment open at the beginning of a segment ends, for every type of comment
+struct SpanExits {
+    begin: usize,
+    string: Exit,
+    string_escapes: bool,
+    line_comment: Exit,
+    whitespace: Exit,
+    //Block comments are entered at the beginning, or one unit later if a */ or /* overlaps it
+    block: [BlockExits; 2],
+    raw: RawExits
+}
+
+impl SpanExits {
+    fn new<T: TextUnit>(segment: &[T], begin: usize) -> SpanExits {
+        let entered = SpanProgress {
+            index: begin,
+            depth: 1,
+            has_escapes: false
+        };
+        let exit = |comment_type: CommentType| {
+            match comment_type.scan_span(segment, begin, entered) {
+                Scanned::Complete { length, has_escapes } => (Exit::Ends(begin + length, has_escapes), has_escapes),
+                Scanned::Incomplete(progress) => (Exit::Open(progress), progress.has_escapes),
+                Scanned::Unterminated => unreachable!("Only char literals are unterminated within the text")
+            }
+        };
+        let (string, string_escapes) = exit(CommentType::String);
+        SpanExits {
+            begin,
+            string,
+            string_escapes,
+            line_comment: exit(CommentType::DoubleSlash).0,
+            whitespace: exit(CommentType::Whitespace).0,
+            block: [BlockExits::new(segment, begin), BlockExits::new(segment, (begin + 1).min(segment.len()))],
+            raw: RawExits::new(segment, begin)
+        }
+    }
+
+    ///Where the comment scanned up to `progress` ends, if scanning it stopped right at the
+    ///beginning of the segment. Char literals are scanned again from their opening ', and strings
+    ///from a backslash whose escape sequence reaches into the segment, so those are not speculated.
+    ///The literal and the escape sequence end within a few units and are scanned sequentially instead,
+    ///the scan then takes the speculation over again once both are in code at the same place
+    fn exit<T: TextUnit>(&self, source_text: &[T], comment_type: CommentType, progress: SpanProgress) -> Option<Exit> {
+        let exit = match comment_type {
+            CommentType::String | CommentType::ByteString | CommentType::CString if progress.index == self.begin => {
+                match self.string {
+                    Exit::Ends(end, _) => Exit::Ends(end, progress.has_escapes || self.string_escapes),
+                    Exit::Open(open) => Exit::Open(SpanProgress {
+                        has_escapes: progress.has_escapes || open.has_escapes,
+                        ..open
+                    })
+                }
+            }
+            CommentType::DoubleSlash | CommentType::OuterLineDoc | CommentType::InnerLineDoc
+                if progress.index == self.begin => self.line_comment,
+            CommentType::Whitespace if progress.index == self.begin => self.whitespace,
+            CommentType::SlashStar | CommentType::OuterBlockDoc | CommentType::InnerBlockDoc => {
+                if progress.index == self.begin {
+                    self.block[0].exit(progress.depth)
+                } else if progress.index + 1 == self.begin {
+                    //The last unit of the segment before may be the first of a delimiter
+                    match (source_text[progress.index].as_char(), source_text[self.begin].as_char()) {
+                        ('/', '*') => self.block[1].exit(progress.depth + 1),
+                        ('*', '/') if progress.depth == 1 => Exit::Ends(self.begin + 1, false),
+                        ('*', '/') => self.block[1].exit(progress.depth - 1),
+                        _ => self.block[0].exit(progress.depth)
+                    }
+                } else {
+                    return None;
+                }
+            }
+            CommentType::RawString { preceding_hashes } |
+            CommentType::ByteRawString { preceding_hashes } |
+            CommentType::RawCString { preceding_hashes } if progress.index == self.begin => {
+                self.raw.exit(preceding_hashes)
+            }
+            _ => return None
+        };
+        Some(exit)
+    }
+}
+
+///A segment scanned without knowing the state the text before leaves it in
+pub(crate) struct Speculation {
+    begin: usize,
+    //The comments found if the segment begins in code, i.e. not within a comment or literal
+    comments: Vec<Comment>,
+    //Where scanning from the beginning in code stopped, with the comment left open there.
+    //If scanning failed, the error is found again once the segments are stitched together
+    stop: usize,
+    open: Option<(CommentType, SpanProgress)>,
+    //Without them, comments open at the beginning are scanned sequentially
+    exits: Option<SpanExits>
+}
+
+impl Speculation {
+    fn new<T: TextUnit>(source_text: &[T], begin: usize, end: usize, options: &PreprocessOptions) -> Speculation {
+        let segment = &source_text[..end];
+        let at_end = end == source_text.len();
+        let mut scanner = Scanner::new(Output::discarded(), options);
+        scanner.index = begin;
+        let _ = scanner.scan(segment, at_end);
+
+        Speculation {
+            begin,
+            comments: scanner.comments,
+            stop: scanner.index,
+            open: scanner.open,
+            exits: Some(SpanExits::new(segment, begin))
+        }
+    }
+
+    ///A segment scanned in code from `begin` on elsewhere, e.g. on a GPU. The comments have to
+    ///be the ones found by scanning from `begin` up to `stop`, where scanning may stop early
+    ///for whatever reason, e.g. at a character the other scanner cannot decide on
+    #[cfg_attr(not(feature = "opencl"), allow(dead_code))]
+    pub(crate) fn from_comments(begin: usize, comments: Vec<Comment>, stop: usize) -> Speculation {
+        Speculation {
+            begin,
+            comments,
+            stop,
+            open: None,
+            exits: None
+        }
+    }
+
+    ///Whether scanning from the beginning of the segment in code also began a comment or
+    ///character at `index`, from there on it found the same comments as the sequential scan
+    fn synchronized(&self, index: usize) -> bool {
+        if index < self.begin || index >= self.stop {
+            return false;
+        }
+        let before = self.comments.partition_point(|c| c.begin < index);
+        before == 0 || self.comments[before - 1].begin + self.comments[before - 1].length <= index
+    }
+}
+
+///Carries the actual state from segment to segment, taking the comments of a speculation once
+///the scan is at the same place as it, and scanning sequentially where it is not
+pub(crate) fn stitch<T: TextUnit>(source_text: &[T], boundaries: &[usize], speculations: &[Speculation],
+                       options: &PreprocessOptions) -> Result<Vec<Comment>, PreprocessError> {
+    let mut scanner = Scanner::new(Output::discarded(), options);
+    for (speculation, end) in speculations.iter().zip(boundaries[1..].iter()) {
+        let segment = &source_text[..*end];
+        let at_end = *end == source_text.len();
+        //Repeated boundaries make empty segments, which have nothing to end a comment with
+        if let Some((comment_type, progress)) = scanner.open.filter(|_| speculation.begin < *end) {
+            match speculation.exits.as_ref().and_then(|exits| exits.exit(source_text, comment_type, progress)) {
+                Some(Exit::Ends(comment_end, has_escapes)) => {
+                    scanner.open = None;
+                    scanner.record(segment, Some(Comment {
+                        begin: scanner.index,
+                        length: comment_end - scanner.index,
+                        comment_type,
+                        has_escapes
+                    }));
+                }
+                Some(Exit::Open(progress)) => {
+                    scanner.open = Some((comment_type, progress));
+                }
+                None => ()
+            }
+        }
+
+        while scanner.index < segment.len() {
+            if scanner.open.is_none() && speculation.synchronized(scanner.index) {
+                let taken = speculation.comments.partition_point(|c| c.begin < scanner.index);
+                scanner.comments.extend_from_slice(&speculation.comments[taken..]);
+                scanner.index = speculation.stop;
+                scanner.open = speculation.open;
+                //Pairs scanned elsewhere may end beyond the segment
+                continue;
+            }
+            match scanner.step(segment, at_end) {
+                Ok(true) => (),
+                Ok(false) => break,
+                //The error is located within the whole text, so that its snippet is complete
+                Err(error) => return Err(scanner.step(source_text, true).err().unwrap_or(error))
+            }
+        }
+    }
+    Ok(scanner.comments)
+}
+
+///Writes the result text for the comments of the whole source text, like `preprocess_owned`
+pub(crate) fn replay<T: TextUnit>(source_text: &[T], comments: Vec<Comment>, options: &PreprocessOptions)
+                                  -> (Vec<T>, Preprocessed) {
+    let (result_text, mut source_map) = write_segment(source_text, &comments, 0, source_text.len(), options);
+    source_map.finish(source_text, &result_text);
+    (result_text, Preprocessed { comments, source_map })
+}
+
+///Writes the result text of the comments and characters beginning within the segment
+fn write_segment<T: TextUnit>(source_text: &[T], comments: &[Comment], begin: usize, end: usize,
+                              options: &PreprocessOptions) -> (Vec<T>, SourceMap) {
+    //A comment may reach into the segment from the one before
+    let first = comments.partition_point(|c| c.begin < begin);
+    let begin = match first.checked_sub(1).map(|i| &comments[i]) {
+        Some(c) if c.begin + c.length > begin => c.begin + c.length,
+        _ => begin
+    };
+
+    let mut scanner = Scanner::new(Output::growing(), options);
+    scanner.output.line_ending = Some(line_ending(source_text, options).unwrap_or("\n"));
+    scanner.output.last = last_written_before(source_text, comments, begin, options);
+    scanner.index = begin;
+    let mut comments = comments[first..].iter().peekable();
+    while scanner.index < end {
+        let comment = comments.next_if(|c| c.begin == scanner.index).copied();
+        scanner.record(source_text, comment);
+    }
+    (scanner.output.take(), scanner.source_map)
+}
+
+///The last character written before `position`. Replacing a comment can depend on the character
+///written before it, so the whole run of comments directly before `position` is replaced again
+fn last_written_before<T: TextUnit>(source_text: &[T], comments: &[Comment], position: usize,
+                                    options: &PreprocessOptions) -> Option<char> {
+    let run_end = comments.partition_point(|c| c.begin < position);
+    let mut run_begin = run_end;
+    let mut run_position = position;
+    while run_begin > 0 && comments[run_begin - 1].begin + comments[run_begin - 1].length == run_position {
+        run_begin -= 1;
+        run_position = comments[run_begin].begin;
+    }
+
+    let mut output = Output::discarded();
+    output.last = T::decode_before(source_text, run_position);
+    for c in comments[run_begin..run_end].iter() {
+        let next = first_written(source_text, c.begin + c.length, options);
+        c.comment_type.replace_in_source(&source_text[c.begin..(c.begin + c.length)], &mut output, options, next);
+    }
+    output.last
+}
@@ -0,0 +1,260 @@
+use super::TextUnit;
+
+///A position in a text, line and column are counted from 1, the column in characters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct LineColumn {
+    pub(crate) line: usize,
+    pub(crate) column: usize
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SegmentKind {
+    //The source text was copied unchanged, so offsets map one to one
+    Copied,
+    //The source text was replaced, e.g. a string by "" or whitespace by a newline or nothing
+    Replaced
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Segment {
+    source_begin: usize,
+    source_length: usize,
+    result_begin: usize,
+    result_length: usize,
+    kind: SegmentKind
+}
+
+///The lines of a text, which is indexed part by part
+#[derive(Clone, Debug, PartialEq)]
+struct LineIndex {
+    //The offsets the lines begin at
+    starts: Vec<usize>,
+    //The characters of more than one unit, as the offset of their second unit and the amount
+    //of units after the first one
+    wide_chars: Vec<(usize, usize)>
+}
+
+impl LineIndex {
+    fn new() -> LineIndex {
+        LineIndex { starts: vec![0], wide_chars: Vec::new() }
+    }
+
+    fn add<T: TextUnit>(&mut self, part: &[T], offset: usize) {
+        for (i, unit) in part.iter().enumerate() {
+            if unit.as_char() == '\n' {
+                self.starts.push(offset + i + 1);
+            } else if unit.is_continuation() {
+                //The units of a character may be in different parts
+                match self.wide_chars.last_mut() {
+                    Some((second, after_first)) if *second + *after_first == offset + i => *after_first += 1,
+                    _ => self.wide_chars.push((offset + i, 1))
+                }
+            }
+        }
+    }
+
+    ///The wide characters from the line beginning at `line_start` on
+    fn wide_chars_from(&self, line_start: usize) -> &[(usize, usize)] {
+        &self.wide_chars[self.wide_chars.partition_point(|(second, _)| *second < line_start)..]
+    }
+
+    ///The line and column of the offset, an offset inside a character gets the column of the character
+    fn line_column(&self, offset: usize) -> LineColumn {
+        let line = self.starts.partition_point(|start| *start <= offset);
+        let line_start = self.starts[line - 1];
+        let continuations = self.wide_chars_from(line_start).iter()
+            .take_while(|(second, _)| *second <= offset)
+            .map(|(second, after_first)| (*after_first).min(offset + 1 - second))
+            .sum::<usize>();
+        LineColumn {
+            line,
+            column: offset - line_start - continuations + 1
+        }
+    }
+
+    #[cfg(test)]
+    fn offset(&self, length: usize, position: LineColumn) -> Option<usize> {
+        if position.line == 0 || position.column == 0 || position.line > self.starts.len() {
+            return None;
+        }
+        let line_start = self.starts[position.line - 1];
+        let line_end = self.starts.get(position.line).copied().unwrap_or(length + 1);
+        //Every wide character before the column moves it by the units after its first one
+        let mut offset = line_start + position.column - 1;
+        for (second, after_first) in self.wide_chars_from(line_start) {
+            if *second > offset {
+                break;
+            }
+            offset += after_first;
+        }
+        //The column may point to the newline ending the line, or one past the end of the text
+        if offset < line_end {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+///Maps offsets between the source text and the result text of `preprocess`, the offsets are
+///counted in units of the text, i.e. bytes for UTF-8, and the columns in characters like the
+///ones of `ErrorLocation`.
+///Every offset inside a replaced span maps to the beginning of the span in the other text.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SourceMap {
+    //Consecutive segments covering both texts completely
+    segments: Vec<Segment>,
+    source_lines: LineIndex,
+    result_lines: LineIndex,
+    source_length: usize,
+    result_length: usize
+}
+
+impl SourceMap {
+    pub(crate) fn new() -> SourceMap {
+        SourceMap {
+            segments: Vec::new(),
+            source_lines: LineIndex::new(),
+            result_lines: LineIndex::new(),
+            source_length: 0,
+            result_length: 0
+        }
+    }
+
+    pub(crate) fn copied(&mut self, source_begin: usize, result_begin: usize, length: usize) {
+        if let Some(last) = self.segments.last_mut() {
+            if last.kind == SegmentKind::Copied &&
+                last.source_begin + last.source_length == source_begin &&
+                last.result_begin + last.result_length == result_begin {
+                last.source_length += length;
+                last.result_length += length;
+                self.source_length = source_begin + length;
+                self.result_length = result_begin + length;
+                return;
+            }
+        }
+        self.push(Segment {
+            source_begin,
+            source_length: length,
+            result_begin,
+            result_length: length,
+            kind: SegmentKind::Copied
+        });
+    }
+
+    pub(crate) fn replaced(&mut self, source_begin: usize, source_length: usize,
+                           result_begin: usize, result_length: usize) {
+        self.push(Segment {
+            source_begin,
+            source_length,
+            result_begin,
+            result_length,
+            kind: SegmentKind::Replaced
+        });
+    }
+
+    ///Appends the segments of a map whose result text begins at `result_offset` in this one,
+    ///e.g. of a part of the source preprocessed on its own
+    pub(crate) fn append(&mut self, part: &SourceMap, result_offset: usize) {
+        for segment in part.segments.iter() {
+            match segment.kind {
+                SegmentKind::Copied => {
+                    self.copied(segment.source_begin, result_offset + segment.result_begin, segment.source_length)
+                }
+                SegmentKind::Replaced => {
+                    self.replaced(segment.source_begin, segment.source_length,
+                                  result_offset + segment.result_begin, segment.result_length)
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, segment: Segment) {
+        self.source_length = segment.source_begin + segment.source_length;
+        self.result_length = segment.result_begin + segment.result_length;
+        self.segments.push(segment);
+    }
+
+    ///Indexes the lines of both texts, has to be called once all segments are added
+    pub(crate) fn finish<T: TextUnit>(&mut self, source_text: &[T], result_text: &[T]) {
+        self.index_source_lines(&source_text[..self.source_length], 0);
+        self.index_result_lines(&result_text[..self.result_length], 0);
+    }
+
+    ///Indexes the lines of a part of the source text beginning at `offset`, the parts have to
+    ///be indexed in order
+    pub(crate) fn index_source_lines<T: TextUnit>(&mut self, part: &[T], offset: usize) {
+        self.source_lines.add(part, offset);
+    }
+
+    pub(crate) fn index_result_lines<T: TextUnit>(&mut self, part: &[T], offset: usize) {
+        self.result_lines.add(part, offset);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn source_len(&self) -> usize {
+        self.source_length
+    }
+
+    ///The amount of characters written to the result text
+    pub(crate) fn result_len(&self) -> usize {
+        self.result_length
+    }
+
+    pub(crate) fn result_to_source(&self, result_offset: usize) -> usize {
+        if result_offset >= self.result_length {
+            return self.source_length;
+        }
+        //Replacements can be empty, so the last segment beginning at or before the offset is searched
+        let index = self.segments.partition_point(|s| s.result_begin + s.result_length <= result_offset);
+        let segment = &self.segments[index];
+        match segment.kind {
+            SegmentKind::Copied => segment.source_begin + (result_offset - segment.result_begin),
+            SegmentKind::Replaced => segment.source_begin
+        }
+    }
+
+    pub(crate) fn source_to_result(&self, source_offset: usize) -> usize {
+        if source_offset >= self.source_length {
+            return self.result_length;
+        }
+        let index = self.segments.partition_point(|s| s.source_begin + s.source_length <= source_offset);
+        let segment = &self.segments[index];
+        match segment.kind {
+            SegmentKind::Copied => segment.result_begin + (source_offset - segment.source_begin),
+            SegmentKind::Replaced => segment.result_begin
+        }
+    }
+
+    pub(crate) fn source_line_column(&self, source_offset: usize) -> LineColumn {
+        self.source_lines.line_column(source_offset)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn result_line_column(&self, result_offset: usize) -> LineColumn {
+        self.result_lines.line_column(result_offset)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn source_offset(&self, position: LineColumn) -> Option<usize> {
+        self.source_lines.offset(self.source_length, position)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn result_offset(&self, position: LineColumn) -> Option<usize> {
+        self.result_lines.offset(self.result_length, position)
+    }
+
+    ///Maps a position in the result text, e.g. of a diagnostic, to the source text
+    #[cfg(test)]
+    pub(crate) fn result_to_source_line_column(&self, position: LineColumn) -> Option<LineColumn> {
+        self.result_offset(position)
+            .map(|offset| self.source_line_column(self.result_to_source(offset)))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn source_to_result_line_column(&self, position: LineColumn) -> Option<LineColumn> {
+        self.source_offset(position)
+            .map(|offset| self.result_line_column(self.source_to_result(offset)))
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::{self, Read, Write};
+
+use super::{line_ending, Comment, Output, PreprocessError, PreprocessOptions, Preprocessed, Scanner, SourceMap, TextUnit};
+
+///The amount of units of the line being scanned which are kept for error snippets, the
+///beginning of longer lines is left out of them
+const SNIPPET_PREFIX: usize = 1024;
+
+///Preprocesses a source text which is fed in chunks, e.g. while reading it from a file.
+///The comments, result text and errors are the same as those of `preprocess_with_options`,
+///wherever the chunk boundaries fall. After an error no more chunks should be fed.
+///Only the text which is not scanned yet is kept, apart from a comment which has not ended
+///yet and the end of the current line for error snippets. With preserved line endings the
+///first separator waits for the first line to end, see `line_ending`
+pub(crate) struct Preprocessor<T: TextUnit> {
+    scanner: Scanner<'static, T>,
+    //The source text from the next unit to scan on, preceded by up to `SNIPPET_PREFIX` units
+    //of its line. The text before is dropped once it is scanned
+    pending: Vec<T>,
+    //The line and column of the first unit of `pending`, counted from 1 like in `ErrorLocation`
+    line: usize,
+    column: usize,
+    //The amount of units fed so far
+    source_length: usize
+}
+
+impl<T: TextUnit> Preprocessor<T> {
+    pub(crate) fn new(options: &PreprocessOptions) -> Preprocessor<T> {
+        let mut scanner = Scanner::new(Output::growing(), options);
+        //Separators are written with the line ending of the first line, see `line_ending`
+        scanner.output.line_ending = None;
+        Preprocessor {
+            scanner,
+            pending: Vec::new(),
+            line: 1,
+            column: 1,
+            source_length: 0
+        }
+    }
+
+    ///Preprocesses as much of the text fed so far as can be decided, appends the result text
+    ///to `result_text` and returns the comments which ended within it
+    pub(crate) fn feed(&mut self, chunk: &[T], result_text: &mut Vec<T>) -> Result<Vec<Comment>, PreprocessError> {
+        self.scanner.source_map.index_source_lines(chunk, self.source_length);
+        self.source_length += chunk.len();
+        self.pending.extend_from_slice(chunk);
+        self.scan(false, result_text)
+    }
+
+    ///Preprocesses the rest of the text, returning the last comments and the source map
+    pub(crate) fn finish(mut self, result_text: &mut Vec<T>) -> Result<(Vec<Comment>, SourceMap), PreprocessError> {
+        let comments = self.scan(true, result_text)?;
+        Ok((comments, self.scanner.source_map))
+    }
+
+    ///The amount of units of the source text which are kept
+    #[cfg(test)]
+    pub(crate) fn pending_length(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn scan(&mut self, at_end: bool, result_text: &mut Vec<T>) -> Result<Vec<Comment>, PreprocessError> {
+        //The text before `pending` has no line ending, otherwise it would be known
+        if self.scanner.output.line_ending.is_none() {
+            self.scanner.output.line_ending = match line_ending(&self.pending, &self.scanner.options) {
+                None if at_end => Some("\n"),
+                line_ending => line_ending
+            };
+        }
+        match self.scanner.scan(&self.pending, at_end) {
+            Ok(()) => (),
+            //The snippet shows the whole line, so the error is reported once the line is complete.
+            //Nothing is consumed on errors, the next call finds the same error again
+            Err(ref error) if !at_end && !self.pending[error.location().offset..].iter().any(|c| c.as_char() == '\n') => (),
+            Err(mut error) => {
+                let location = error.location_mut();
+                location.offset += self.scanner.offset;
+                if location.line == 1 {
+                    location.column += self.column - 1;
+                    location.elided = self.column - 1;
+                }
+                location.line += self.line - 1;
+                return Err(error);
+            }
+        }
+
+        let written = self.scanner.output.take();
+        self.scanner.source_map.index_result_lines(&written, self.scanner.output.length - written.len());
+        result_text.extend_from_slice(&written);
+
+        //The text before the next unit to scan is never looked at again, only the end of its
+        //line is kept for the snippet. It is dropped in whole characters, so that the column
+        //can be counted
+        let index = self.scanner.index;
+        let line_begin = self.pending[..index].iter().rposition(|c| c.as_char() == '\n').map_or(0, |newline| newline + 1);
+        let mut dropped = line_begin.max(index.saturating_sub(SNIPPET_PREFIX));
+        while dropped > 0 && T::decode(&self.pending, dropped).is_none() {
+            dropped -= 1;
+        }
+        if dropped > 0 {
+            let dropped_text = T::to_string(&self.pending[..dropped]);
+            match dropped_text.rfind('\n') {
+                Some(newline) => {
+                    self.line += dropped_text.matches('\n').count();
+                    self.column = dropped_text[(newline + 1)..].chars().count() + 1;
+                }
+                None => self.column += dropped_text.chars().count()
+            }
+            self.pending.drain(..dropped);
+            self.scanner.skip(dropped);
+        }
+        Ok(std::mem::take(&mut self.scanner.comments))
+    }
+}
+
+///Preprocesses UTF-8 encoded text read from `reader` in chunks, writing the result text to
+///`writer` as it is produced. Preprocessing errors are returned as `InvalidData` errors
+///wrapping the `PreprocessError`
+pub(crate) fn preprocess_reader<R: Read, W: Write>(mut reader: R, mut writer: W, options: &PreprocessOptions)
+                                                   -> io::Result<Preprocessed> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let invalid_data = |error: PreprocessError| io::Error::new(io::ErrorKind::InvalidData, error);
+
+    let mut preprocessor = Preprocessor::new(options);
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut comments = Vec::new();
+    let mut result_text = Vec::new();
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error)
+        };
+        comments.extend(preprocessor.feed(&chunk[..read], &mut result_text).map_err(invalid_data)?);
+        writer.write_all(&result_text)?;
+        result_text.clear();
+    }
+    let (last_comments, source_map) = preprocessor.finish(&mut result_text).map_err(invalid_data)?;
+    comments.extend(last_comments);
+    writer.write_all(&result_text)?;
+
+    Ok(Preprocessed {
+        comments,
+        source_map
+    })
+}
@@ -0,0 +1,100 @@
+///A unit of the text to preprocess, either a whole char or a byte of UTF-8 encoded text.
+///Everything the preprocessor looks for is ASCII, so most of the time comparing `as_char`
+///is enough, only non-ASCII characters have to be decoded.
+pub(crate) trait TextUnit: Copy + PartialEq + 'static {
+    ///The unit as char, for bytes of multi-byte characters this is never an ASCII char
+    fn as_char(self) -> char;
+
+    fn from_ascii(c: char) -> Self;
+
+    ///Decodes the character beginning at `index`, returning it with its length in units
+    fn decode(text: &[Self], index: usize) -> Option<(char, usize)>;
+
+    ///Decodes the character ending right before `index`
+    fn decode_before(text: &[Self], index: usize) -> Option<char>;
+
+    ///Whether the unit continues a character begun by the units before it
+    fn is_continuation(self) -> bool;
+
+    fn to_string(text: &[Self]) -> String;
+}
+
+impl TextUnit for char {
+    fn as_char(self) -> char {
+        self
+    }
+
+    fn from_ascii(c: char) -> Self {
+        c
+    }
+
+    fn decode(text: &[Self], index: usize) -> Option<(char, usize)> {
+        text.get(index).map(|c| (*c, 1))
+    }
+
+    fn decode_before(text: &[Self], index: usize) -> Option<char> {
+        if index == 0 {
+            None
+        } else {
+            text.get(index - 1).copied()
+        }
+    }
+
+    fn is_continuation(self) -> bool {
+        false
+    }
+
+    fn to_string(text: &[Self]) -> String {
+        text.iter().collect()
+    }
+}
+
+impl TextUnit for u8 {
+    fn as_char(self) -> char {
+        self as char
+    }
+
+    fn from_ascii(c: char) -> Self {
+        debug_assert!(c.is_ascii());
+        c as u8
+    }
+
+    fn decode(text: &[Self], index: usize) -> Option<(char, usize)> {
+        let length = match *text.get(index)? {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            //A continuation byte, which is not the beginning of a character
+            _ => return None
+        };
+        let bytes = text.get(index..(index + length))?;
+        std::str::from_utf8(bytes).ok()?
+            .chars()
+            .next()
+            .map(|c| (c, length))
+    }
+
+    fn decode_before(text: &[Self], index: usize) -> Option<char> {
+        //Characters are at most 4 bytes long, so the lead byte is within the last 4 bytes
+        let lead = (index.saturating_sub(4)..index).rev()
+            .find(|i| text[*i] & 0xc0 != 0x80)?;
+        match Self::decode(text, lead) {
+            Some((c, length)) if lead + length == index => Some(c),
+            _ => None
+        }
+    }
+
+    fn is_continuation(self) -> bool {
+        self & 0xc0 == 0x80
+    }
+
+    fn to_string(text: &[Self]) -> String {
+        String::from_utf8_lossy(text).into_owned()
+    }
+}
+
+///Returns the unit at `index` as char, if there is one
+pub(crate) fn char_at<T: TextUnit>(text: &[T], index: usize) -> Option<char> {
+    text.get(index).map(|c| c.as_char())
+}
@@ -1,6 +1,56 @@
 use super::*;
-use preprocessor::preprocess;
+use preprocessor::{preprocess, preprocess_utf8, preprocess_with_options, Comment, CommentType, DocComments, PreprocessOptions};
+use preprocessor::{preprocess_owned, preprocess_to_string, ErrorLocation, LineEndings, PreprocessError};
+use preprocessor::batch::{preprocess_batch, preprocess_batch_to_strings, PreprocessedFile};
+use preprocessor::parallel::{preprocess_parallel, preprocess_segments};
+use preprocessor::stream::{preprocess_reader, Preprocessor};
+use preprocessor::TextUnit;
+use backend::{Backend, BackendReport};
+use delimiters::{index_delimiters, index_str, mask_delimiters, match_brackets, match_brackets_segments, Brackets};
+use delimiters::{Delimiter, DelimiterError, DelimiterKind, Semicolon};
+use outline::{outline, outline_str, ItemKind, OutlineItem, Visibility};
+use parser::{parse, parse_str, Node, SyntaxKind};
+use lexer::{lex, tokenize, Base, Keyword, LexError, LiteralKind, Punct, TokenKind};
+use preprocessor::source_map::LineColumn;
 use std::iter::FromIterator;
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+
+///Runs the preprocessor over `src` and returns the found comments as well as the resulting text
+fn preprocess_str(src: &str) -> (Vec<Comment>, String) {
+    let src_code = src.chars().collect::<Vec<char>>();
+    let mut result_text = vec!['\0'; src_code.len()];
+    let comments = preprocess(&src_code, &mut result_text).unwrap();
+    let result = String::from_iter(&result_text);
+    (comments, result.trim_end_matches('\0').to_string())
+}
+
+///Like `preprocess_str`, but with the given handling of doc comments
+fn preprocess_docs(src: &str, doc_comments: DocComments) -> (Vec<Comment>, String) {
+    let src_code = src.chars().collect::<Vec<char>>();
+    //Doc attributes are longer than the comments they replace
+    let mut result_text = vec!['\0'; src_code.len() * 4];
+    let options = PreprocessOptions { doc_comments, ..PreprocessOptions::default() };
+    let comments = preprocess_with_options(&src_code, &mut result_text, &options).unwrap().comments;
+    let result = String::from_iter(&result_text);
+    (comments, result.trim_end_matches('\0').to_string())
+}
+
+///Runs the preprocessor over `src`, which is expected to fail
+fn preprocess_err(src: &str) -> PreprocessError {
+    let src_code = src.chars().collect::<Vec<char>>();
+    let mut result_text = vec![' '; src_code.len()];
+    preprocess(&src_code, &mut result_text).unwrap_err()
+}
+
+///Returns the source text of every comment of the given type
+fn spans_of(src: &str, comments: &[Comment], comment_type: CommentType) -> Vec<String> {
+    let chars = src.chars().collect::<Vec<char>>();
+    comments.iter()
+        .filter(|c| c.comment_type == comment_type)
+        .map(|c| String::from_iter(&chars[c.begin..(c.begin + c.length)]))
+        .collect()
+}
 
 #[test]
 fn asdfasdf() {
@@ -20,10 +70,7 @@ println!("{}", k); k}"####;
 
     let src_code = src_as_string.chars().collect::<Vec<char>>();
 
-    let mut result_text = Vec::<char>::with_capacity(src_code.len());
-    for _ in 0..src_code.len() {
-        result_text.push(' ');
-    }
+    let mut result_text = vec![' '; src_code.len()];
 
     let comments = preprocess(&src_code,result_text.as_mut_slice()).unwrap();
     println!("{}", comments.len());
@@ -34,4 +81,2239 @@ println!("{}", k); k}"####;
 
     println!("{:?}", result_text);
     println!("{}", String::from_iter(&result_text));
-}
\ No newline at end of file
+}
+
+#[test]
+fn char_literal_quotes() {
+    let src = "let a = '\"'; let b = \"x\";";
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::CharLiteral), vec!["'\"'"]);
+    assert_eq!(spans_of(src, &comments, CommentType::String), vec!["\"x\""]);
+    assert_eq!(result, "let\na='';let\nb=\"\";");
+}
+
+#[test]
+fn char_literal_slash() {
+    let src = "let a = '/'; let b = 1;";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::CharLiteral), vec!["'/'"]);
+    assert!(comments.iter().all(|c| c.comment_type != CommentType::DoubleSlash));
+}
+
+#[test]
+fn char_literal_escaped_quote() {
+    let src = "let a = '\\''; let b = '\\\\';";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::CharLiteral), vec!["'\\''", "'\\\\'"]);
+}
+
+#[test]
+fn char_literal_unicode_escape() {
+    let src = "let a = '\\u{1F600}';";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::CharLiteral), vec!["'\\u{1F600}'"]);
+}
+
+#[test]
+fn char_literal_hex_escape() {
+    let src = "let a = '\\x7f';";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::CharLiteral), vec!["'\\x7f'"]);
+}
+
+#[test]
+fn char_literal_unicode() {
+    let src = "let a = '\u{e9}';";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::CharLiteral), vec!["'\u{e9}'"]);
+}
+
+#[test]
+fn byte_char_literal() {
+    let src = "let a = b'x'; let b = b'\\'';";
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::ByteCharLiteral), vec!["b'x'", "b'\\''"]);
+    assert_eq!(result, "let\na='';let\nb='';");
+}
+
+#[test]
+fn lifetimes_are_not_char_literals() {
+    let src = "fn f<'a, 'b>(x: &'a str, y: &'static str) -> &'b str { x }";
+    let (comments, result) = preprocess_str(src);
+    assert!(comments.iter().all(|c| c.comment_type == CommentType::Whitespace));
+    assert!(result.contains("&'a\nstr"));
+    assert!(result.contains("&'static\nstr"));
+}
+
+#[test]
+fn labels_are_not_char_literals() {
+    let src = "'outer: loop { 'inner: loop { break 'outer; } }";
+    let (comments, result) = preprocess_str(src);
+    assert!(comments.iter().all(|c| c.comment_type == CommentType::Whitespace));
+    assert_eq!(result, "'outer:loop{'inner:loop{break\n'outer;}}");
+}
+
+#[test]
+fn lifetime_next_to_char_literal() {
+    let src = "fn f<'a>(c: &'a char) -> bool { *c == 'a' }";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::CharLiteral), vec!["'a'"]);
+}
+
+#[test]
+fn string_escaped_backslash() {
+    let src = r#"let a = "\\"; let b = "x";"#;
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::String), vec![r#""\\""#, r#""x""#]);
+    assert_eq!(result, "let\na=\"\";let\nb=\"\";");
+}
+
+#[test]
+fn string_escaped_quote() {
+    let src = r#"f("\"", "a\\\"b");"#;
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::String), vec![r#""\"""#, r#""a\\\"b""#]);
+}
+
+#[test]
+fn string_escapes() {
+    let src = r#"f("\n\t\0", "\x41\x7f", "\u{1F600}\u{1_F6_00}");"#;
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::String),
+               vec![r#""\n\t\0""#, r#""\x41\x7f""#, r#""\u{1F600}\u{1_F6_00}""#]);
+    assert!(comments.iter()
+        .filter(|c| c.comment_type == CommentType::String)
+        .all(|c| c.has_escapes));
+}
+
+#[test]
+fn string_malformed_escapes_keep_closing_quote() {
+    let src = r#"f("\x", "\u{12");"#;
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::String), vec![r#""\x""#, r#""\u{12""#]);
+}
+
+#[test]
+fn string_line_continuation() {
+    let src = "f(\"a\\\n    b\", \"c\\\r\n    d\");";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::String),
+               vec!["\"a\\\n    b\"", "\"c\\\r\n    d\""]);
+}
+
+#[test]
+fn string_at_start_of_text() {
+    let src = r#""\\""#;
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(comments.len(), 1);
+    assert_eq!(comments[0].begin, 0);
+    assert_eq!(comments[0].length, src.chars().count());
+    assert_eq!(result, "\"\"");
+}
+
+#[test]
+fn byte_string_escapes() {
+    let src = r#"f(b"a\"b", b"\\", b"plain");"#;
+    let (comments, _) = preprocess_str(src);
+    let byte_strings = comments.iter()
+        .filter(|c| c.comment_type == CommentType::ByteString)
+        .collect::<Vec<_>>();
+    assert_eq!(spans_of(src, &comments, CommentType::ByteString),
+               vec![r#"b"a\"b""#, r#"b"\\""#, r#"b"plain""#]);
+    assert_eq!(byte_strings.iter().map(|c| c.has_escapes).collect::<Vec<_>>(), vec![true, true, false]);
+}
+
+#[test]
+fn literals_without_escapes() {
+    let src = r#"f("plain", 'c', r"raw\n"); // not \n"#;
+    let src = format!("{}\n", src);
+    let (comments, _) = preprocess_str(&src);
+    assert!(comments.iter().all(|c| !c.has_escapes));
+}
+
+#[test]
+fn nested_block_comment() {
+    let src = "a /* a /* b */ c */ b";
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::SlashStar), vec!["/* a /* b */ c */"]);
+    assert_eq!(result, "a\nb");
+}
+
+#[test]
+fn deeply_nested_block_comment() {
+    let src = "x/* 1 /* 2 /* 3 */ 2 */ 1 /**/ */;";
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::SlashStar), vec!["/* 1 /* 2 /* 3 */ 2 */ 1 /**/ */"]);
+    assert_eq!(result, "x;");
+}
+
+#[test]
+fn empty_block_comment() {
+    let src = "a/**/b";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::SlashStar), vec!["/**/"]);
+}
+
+#[test]
+fn block_comment_three_stars() {
+    let src = "a/***/b";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::SlashStar), vec!["/***/"]);
+}
+
+#[test]
+fn block_comment_opening_slash_is_not_closing() {
+    let src = "a/*/ still a comment */b";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::SlashStar), vec!["/*/ still a comment */"]);
+}
+
+#[test]
+fn unterminated_nested_block_comment() {
+    let src = "/* a /* b */".chars().collect::<Vec<char>>();
+    let mut result_text = vec![' '; src.len()];
+    assert!(preprocess(&src, &mut result_text).is_err());
+
+    let src = "/*/".chars().collect::<Vec<char>>();
+    let mut result_text = vec![' '; src.len()];
+    assert!(preprocess(&src, &mut result_text).is_err());
+}
+
+#[test]
+fn nested_block_comment_with_doc_comment() {
+    let src = "/** outer /** inner */ still outer */ fn f() {}";
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(comments[0].length, "/** outer /** inner */ still outer */".len());
+    assert_eq!(result, "fn\nf(){}");
+}
+
+#[test]
+fn doc_comment_types() {
+    let src = "//! inner\n/*! inner block */\n/// outer\n/** outer block */\n// plain\n/* plain */\n";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::InnerLineDoc), vec!["//! inner\n"]);
+    assert_eq!(spans_of(src, &comments, CommentType::InnerBlockDoc), vec!["/*! inner block */"]);
+    assert_eq!(spans_of(src, &comments, CommentType::OuterLineDoc), vec!["/// outer\n"]);
+    assert_eq!(spans_of(src, &comments, CommentType::OuterBlockDoc), vec!["/** outer block */"]);
+    assert_eq!(spans_of(src, &comments, CommentType::DoubleSlash), vec!["// plain\n"]);
+    assert_eq!(spans_of(src, &comments, CommentType::SlashStar), vec!["/* plain */"]);
+}
+
+#[test]
+fn comments_looking_like_doc_comments() {
+    let src = "//// four slashes\n/*** three stars */\n/**/\n/***/\n";
+    let (comments, _) = preprocess_str(src);
+    assert!(comments.iter().all(|c| !c.comment_type.is_doc_comment()));
+    assert_eq!(spans_of(src, &comments, CommentType::DoubleSlash), vec!["//// four slashes\n"]);
+    assert_eq!(spans_of(src, &comments, CommentType::SlashStar), vec!["/*** three stars */", "/**/", "/***/"]);
+}
+
+#[test]
+fn doc_comments_are_stripped_by_default() {
+    let src = "/// docs\nfn f() {}";
+    let (_, result) = preprocess_str(src);
+    assert_eq!(result, "fn\nf(){}");
+}
+
+#[test]
+fn doc_comments_kept() {
+    let src = "/// docs\nfn f() {\n    //! inner\n    // plain\n}";
+    let (_, result) = preprocess_docs(src, DocComments::Keep);
+    assert_eq!(result, "/// docs\nfn\nf(){//! inner\n}");
+}
+
+#[test]
+fn doc_comments_as_attributes() {
+    let src = "//! crate docs\n/// docs\n/** block */\nfn f() {\n    /*! inner */\n}";
+    let (_, result) = preprocess_docs(src, DocComments::Attribute);
+    assert_eq!(result, "#![doc = r\" crate docs\"]#[doc = r\" docs\"]#[doc = r\" block \"]fn\nf(){#![doc = r\" inner \"]}");
+}
+
+#[test]
+fn doc_attribute_with_quotes_and_hashes() {
+    let src = "/// a \"quote\" and \"# hash\nstruct A;";
+    let (_, result) = preprocess_docs(src, DocComments::Attribute);
+    assert_eq!(result, "#[doc = r##\" a \"quote\" and \"# hash\"##]struct\nA;");
+}
+
+#[test]
+fn byte_raw_strings() {
+    let src = r###"f(br#"a"#);"###;
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(comments[0].begin, 2);
+    assert_eq!(comments[0].comment_type, CommentType::ByteRawString { preceding_hashes: 1 });
+
+    let src = r###"f(br##"b"##);"###;
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(comments[0].comment_type, CommentType::ByteRawString { preceding_hashes: 2 });
+}
+
+#[test]
+fn c_strings() {
+    let src = r#"f(c"a\"b", c"");"#;
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::CString), vec![r#"c"a\"b""#, r#"c"""#]);
+    assert!(comments[0].has_escapes);
+    assert_eq!(result, "f(\"\",\"\");");
+
+    let src = r###"f(cr#"c"#);"###;
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(comments[0].begin, 2);
+    assert_eq!(comments[0].comment_type, CommentType::RawCString { preceding_hashes: 1 });
+}
+
+#[test]
+fn prefixes_only_at_token_boundary() {
+    let src = r#"m!(bar"x" ab"y" abc'z' _c"w");"#;
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::String), vec![r#""x""#, r#""y""#, r#""w""#]);
+    assert_eq!(spans_of(src, &comments, CommentType::CharLiteral), vec!["'z'"]);
+    assert!(comments.iter().all(|c| c.comment_type != CommentType::ByteString &&
+        c.comment_type != CommentType::CString));
+}
+
+#[test]
+fn raw_identifiers_are_not_raw_strings() {
+    let src = "let r#match = r#type;";
+    let (comments, result) = preprocess_str(src);
+    assert!(comments.iter().all(|c| c.comment_type == CommentType::Whitespace));
+    assert_eq!(result, "let\nr#match=r#type;");
+}
+
+#[test]
+fn raw_strings_without_hashes() {
+    let src = r#"f(r"abc", br"\", cr"");"#;
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::RawString { preceding_hashes: 0 }), vec![r#"r"abc""#]);
+    assert_eq!(spans_of(src, &comments, CommentType::ByteRawString { preceding_hashes: 0 }), vec![r#"br"\""#]);
+    assert_eq!(spans_of(src, &comments, CommentType::RawCString { preceding_hashes: 0 }), vec![r#"cr"""#]);
+    assert_eq!(result, "f(\"\",\"\",\"\");");
+}
+
+#[test]
+fn raw_string_hashes_without_quote_do_not_terminate() {
+    let src = r###"f(r#"a ## b "" c"#, br##"x "# y"##);"###;
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::RawString { preceding_hashes: 1 }), vec![r###"r#"a ## b "" c"#"###]);
+    assert_eq!(spans_of(src, &comments, CommentType::ByteRawString { preceding_hashes: 2 }), vec![r###"br##"x "# y"##"###]);
+    assert_eq!(result, "f(\"\",\"\");");
+}
+
+#[test]
+fn unterminated_raw_strings() {
+    for src in &[r#"r""#, r##"r#"abc"##, r###"r##"abc"#"###, "br\"", "cr#\"\""] {
+        let src_code = src.chars().collect::<Vec<char>>();
+        let mut result_text = vec![' '; src_code.len()];
+        assert!(preprocess(&src_code, &mut result_text).is_err(), "{} should be unterminated", src);
+    }
+}
+
+///Generates the content of a raw string with the given amount of hashes which never contains
+///the terminating sequence, but is full of almost-terminators
+fn random_raw_content(rng: &mut StdRng, hashes: usize) -> String {
+    const ALPHABET: &[char] = &['"', '#', '#', 'a', 'r', 'b', '\\', '/', '*', ' ', '\n', '\''];
+    let terminator = format!("\"{}", "#".repeat(hashes));
+    loop {
+        let length = rng.random_range(0..40);
+        let content = (0..length)
+            .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())])
+            .collect::<String>();
+        if !content.contains(&terminator) {
+            return content;
+        }
+    }
+}
+
+#[test]
+fn random_raw_strings() {
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    for _ in 0..2000 {
+        //Zero hashes can only be generated without any quote in the content
+        let hashes = rng.random_range(0..5);
+        let content = random_raw_content(&mut rng, hashes);
+        let (prefix, comment_type) = match rng.random_range(0..3) {
+            0 => ("r", CommentType::RawString { preceding_hashes: hashes }),
+            1 => ("br", CommentType::ByteRawString { preceding_hashes: hashes }),
+            _ => ("cr", CommentType::RawCString { preceding_hashes: hashes })
+        };
+        let literal = format!("{}{}\"{}\"{}", prefix, "#".repeat(hashes), content, "#".repeat(hashes));
+        let src = format!("let x = {}; y", literal);
+
+        let (comments, result) = preprocess_str(&src);
+        assert_eq!(spans_of(&src, &comments, comment_type), vec![literal.clone()], "{}", src);
+        assert_eq!(result, "let\nx=\"\";y", "{}", src);
+    }
+}
+
+#[test]
+fn random_adjacent_raw_strings() {
+    let mut rng = StdRng::seed_from_u64(0xbadc0de);
+    for _ in 0..500 {
+        let literals = (0..rng.random_range(1..5))
+            .map(|_| {
+                let hashes = rng.random_range(0..4);
+                let content = random_raw_content(&mut rng, hashes);
+                format!("r{}\"{}\"{}", "#".repeat(hashes), content, "#".repeat(hashes))
+            })
+            .collect::<Vec<String>>();
+        let src = format!("[{}]", literals.join(","));
+
+        let (comments, result) = preprocess_str(&src);
+        let found = comments.iter()
+            .filter(|c| c.comment_type != CommentType::Whitespace)
+            .map(|c| src.chars().skip(c.begin).take(c.length).collect::<String>())
+            .collect::<Vec<String>>();
+        assert_eq!(found, literals, "{}", src);
+        assert_eq!(result, format!("[{}]", vec!["\"\""; literals.len()].join(",")));
+    }
+}
+
+#[test]
+fn line_comments_end_with_the_text() {
+    let (comments, result) = preprocess_str("let x; // end");
+    assert_eq!(result, "let\nx;");
+    assert_eq!(comments.last().unwrap().comment_type, CommentType::DoubleSlash);
+    assert_eq!(comments.last().unwrap().length, 6);
+    let (_, result) = preprocess_docs("struct A; //! end", DocComments::Attribute);
+    assert_eq!(result, "struct\nA;#![doc = r\" end\"]");
+    let (comments, result) = preprocess_str("x; \t\n  ");
+    assert_eq!(result, "x;");
+    assert_eq!(spans_of("x; \t\n  ", &comments, CommentType::Whitespace), vec![" \t\n  "]);
+
+    let src = "fn f() {} // end".chars().collect::<Vec<char>>();
+    for split in 0..=src.len() {
+        check_chunks(&src, &[split], &PreprocessOptions::default());
+        check_segments(&src, &[0, split, src.len()], &PreprocessOptions::default());
+    }
+}
+
+#[test]
+fn shebang() {
+    let src = "#!/usr/bin/env run-cargo-script\nfn main() {}";
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::Shebang), vec!["#!/usr/bin/env run-cargo-script"]);
+    assert_eq!(result, "fn\nmain(){}");
+    //The line ending is whitespace, just like after line comments
+    let src = "#!/bin/x\r\nfn";
+    let (comments, _) = preprocess_str(src);
+    assert_eq!(spans_of(src, &comments, CommentType::Shebang), vec!["#!/bin/x"]);
+    assert_eq!(spans_of(src, &comments, CommentType::Whitespace), vec!["\r\n"]);
+    let (comments, result) = preprocess_str("#!");
+    assert_eq!(comments[0].comment_type, CommentType::Shebang);
+    assert_eq!(result, "");
+}
+
+#[test]
+fn inner_attributes_are_not_shebangs() {
+    let samples = ["#![allow(unused)]\nfn f() {}", "#! [x]", "#!\n// c\n/* d */ [x]", "fn f() {}\n#!/bin/x\n"];
+    for src in samples.iter() {
+        let (comments, _) = preprocess_str(src);
+        assert!(comments.iter().all(|c| c.comment_type != CommentType::Shebang), "{:?}", src);
+    }
+    //Doc comments are attributes, so a shebang may not be followed by them
+    let (comments, _) = preprocess_str("#!/// x\n[y]");
+    assert_eq!(comments[0].comment_type, CommentType::Shebang);
+
+    let src = "#! /* a */ // b\n [attr] x".chars().collect::<Vec<char>>();
+    for split in 0..=src.len() {
+        check_chunks(&src, &[split], &PreprocessOptions::default());
+        check_segments(&src, &[0, split, src.len()], &PreprocessOptions::default());
+    }
+}
+
+#[test]
+fn error_unterminated_string() {
+    match preprocess_err("let a = 1;\nlet b = b\"abc;\n") {
+        PreprocessError::UnterminatedString(location) => {
+            assert_eq!(location.offset, 19);
+            assert_eq!((location.line, location.column), (2, 9));
+            assert_eq!(location.delimiter, "b\"");
+        }
+        e => panic!("Unexpected error {:?}", e)
+    }
+}
+
+#[test]
+fn error_unterminated_block_comment() {
+    match preprocess_err("fn f() {}\n  /* a /* b */\n") {
+        PreprocessError::UnterminatedBlockComment(location) => {
+            assert_eq!((location.offset, location.line, location.column), (12, 2, 3));
+            assert_eq!(location.delimiter, "/*");
+        }
+        e => panic!("Unexpected error {:?}", e)
+    }
+}
+
+#[test]
+fn error_unterminated_raw_string() {
+    match preprocess_err("x = br##\"abc\"#;") {
+        PreprocessError::UnterminatedRawString { location, hashes } => {
+            assert_eq!(hashes, 2);
+            assert_eq!((location.offset, location.line, location.column), (4, 1, 5));
+            assert_eq!(location.delimiter, "br##\"");
+        }
+        e => panic!("Unexpected error {:?}", e)
+    }
+}
+
+#[test]
+fn error_unterminated_char() {
+    match preprocess_err("let c = '\\n;") {
+        PreprocessError::UnterminatedChar(location) => {
+            assert_eq!((location.offset, location.delimiter.as_str()), (8, "'"));
+        }
+        e => panic!("Unexpected error {:?}", e)
+    }
+}
+
+#[test]
+fn error_result_buffer_too_small() {
+    let src = "let a = 1;".chars().collect::<Vec<char>>();
+    let mut result_text = vec![' '; 5];
+    match preprocess(&src, &mut result_text).unwrap_err() {
+        PreprocessError::ResultBufferTooSmall { location, capacity, required } => {
+            assert_eq!(capacity, 5);
+            assert_eq!(required, 8);
+            assert_eq!(location.offset, 6);
+        }
+        e => panic!("Unexpected error {:?}", e)
+    }
+}
+
+#[test]
+fn error_display_snippet() {
+    let error = preprocess_err("fn f() {}\n\tlet s = \"abc;\n");
+    assert_eq!(error.to_string(), "error: unterminated double quote string\n \
+                                   --> 2:10\n  \
+                                   |\n\
+                                   2 | \tlet s = \"abc;\n  \
+                                   | \t        ^");
+}
+
+#[test]
+fn separators_keep_tokens_apart() {
+    let cases = [
+        ("1 2", "1\n2"),
+        ("x _y", "x\n_y"),
+        ("a/**/b", "a\nb"),
+        ("- -x", "-\n-x"),
+        ("& &x", "&\n&x"),
+        ("é x", "é\nx"),
+        ("x é", "x\né"),
+        ("1 .5", "1\n.5"),
+        ("x . 5", "x.\n5"),
+        ("/ /x", "/\n/x"),
+        ("/ *x", "/\n*x"),
+        ("a < = b", "a<\n=b"),
+        ("a - > b", "a-\n>b"),
+        ("a = > b", "a=\n>b"),
+        ("a : : b", "a:\n:b"),
+        ("r \"s\"", "r\n\"\""),
+        ("\"s\" x", "\"\"\nx"),
+        ("'c' x", "''\nx"),
+        ("x # [a]", "x\n#[a]")
+    ];
+    for (src, expected) in cases.iter() {
+        assert_eq!(preprocess_str(src).1, *expected, "{:?}", src);
+    }
+}
+
+#[test]
+fn separators_dropped_between_tokens_that_cannot_merge() {
+    let (_, result) = preprocess_str("fn f ( a : & str ) -> u8 { a . len ( ) as u8 + 1 }");
+    assert_eq!(result, "fn\nf(a:&str)->u8{a.len()as\nu8+1}");
+    let (_, result) = preprocess_str("x = y /* c */ ; // d\n z ;");
+    assert_eq!(result, "x=y;z;");
+}
+
+#[test]
+fn separators_at_chunk_and_segment_boundaries() {
+    let src = "let /* a */ x = 1 // b\n.5 / /c\n/ d - -e".chars().collect::<Vec<char>>();
+    let options = PreprocessOptions::default();
+    for split in 0..=src.len() {
+        check_chunks(&src, &[split], &options);
+        check_segments(&src, &[0, split, src.len()], &options);
+    }
+}
+
+#[test]
+fn unicode_whitespace() {
+    let src = "let\tx\u{0B}=\u{0C}1;\r\ny\u{85}\u{200E}+\u{200F}z\u{2028}\u{2029};";
+    let (comments, result) = preprocess_str(src);
+    assert_eq!(result, "let\nx=1;y+z;");
+    assert_eq!(spans_of(src, &comments, CommentType::Whitespace),
+               vec!["\t", "\u{0B}", "\u{0C}", "\r\n", "\u{85}\u{200E}", "\u{200F}", "\u{2028}\u{2029}"]);
+    cross_check_utf8(src, DocComments::Strip);
+    //Other separators of Unicode are not whitespace to Rust
+    let (comments, _) = preprocess_str("a\u{A0}b\u{3000}c");
+    assert!(comments.is_empty());
+}
+
+#[test]
+fn unicode_whitespace_at_chunk_boundaries() {
+    let src = "x\u{2028}y\u{85}\r\n z \u{2029}";
+    for options in [PreprocessOptions::default(), PreprocessOptions { line_endings: LineEndings::Preserve, ..PreprocessOptions::default() }] {
+        for split in 0..=src.len() {
+            check_chunks(src.as_bytes(), &[split], &options);
+            check_segments(src.as_bytes(), &[0, split, src.len()], &options);
+        }
+    }
+}
+
+#[test]
+fn crlf_line_comments() {
+    let (comments, result) = preprocess_str("x // c\r\ny //\r\n");
+    assert_eq!(result, "x\ny");
+    assert_eq!(comments[1].comment_type, CommentType::DoubleSlash);
+    assert_eq!(comments[1].length, 6);
+
+    let src = "/// a\r\n//! b\r\nfn f() {}";
+    let (_, result) = preprocess_docs(src, DocComments::Attribute);
+    assert_eq!(result, "#[doc = r\" a\"]#![doc = r\" b\"]fn\nf(){}");
+    let (_, result) = preprocess_docs(src, DocComments::Keep);
+    assert_eq!(result, "/// a\n//! b\nfn\nf(){}");
+    let (_, result) = preprocess_docs("/** a\r\n b */x", DocComments::Attribute);
+    assert_eq!(result, "#[doc = r\" a\n b \"]x");
+}
+
+#[test]
+fn preserved_line_endings() {
+    let options = PreprocessOptions { line_endings: LineEndings::Preserve, ..PreprocessOptions::default() };
+    let preprocess_preserving = |src: &str, doc_comments| {
+        let options = PreprocessOptions { doc_comments, ..options };
+        preprocess_to_string(src, &options).unwrap().0
+    };
+    assert_eq!(preprocess_preserving("let x = 1;\r\n// c\r\nlet y", DocComments::Strip), "let\r\nx=1;let\r\ny");
+    assert_eq!(preprocess_preserving("let x = 1;\nlet y", DocComments::Strip), "let\nx=1;let\ny");
+    assert_eq!(preprocess_preserving("let x", DocComments::Strip), "let\nx");
+    assert_eq!(preprocess_preserving("/// a\r\n/** b\r\n*/fn f", DocComments::Keep), "/// a\r\n/** b\r\n*/fn\r\nf");
+    assert_eq!(preprocess_preserving("/// a\r\n/** b\r\n*/fn f", DocComments::Attribute),
+               "#[doc = r\" a\"]#[doc = r\" b\r\n\"]fn\r\nf");
+
+    //Kept doc comments are only mapped unit by unit if they are written unchanged
+    let keep = PreprocessOptions { doc_comments: DocComments::Keep, ..options };
+    let (_, preprocessed) = preprocess_to_string("/// a\r\nx", &keep).unwrap();
+    assert_eq!(preprocessed.source_map.result_to_source(4), 4);
+    let keep = PreprocessOptions { line_endings: LineEndings::Normalize, ..keep };
+    let (result, preprocessed) = preprocess_to_string("/// a\r\nx", &keep).unwrap();
+    assert_eq!(result, "/// a\nx");
+    assert_eq!(preprocessed.source_map.source_to_result(7), 6);
+
+    let src = "let a = 'x';\r\nlet  b = 2; // c\r\n\r\nb".chars().collect::<Vec<char>>();
+    for split in 0..=src.len() {
+        check_chunks(&src, &[split], &options);
+        check_segments(&src, &[0, split, src.len()], &options);
+    }
+}
+
+///Preprocesses `src` and returns the result text and source map
+fn preprocess_map(src: &str) -> (String, preprocessor::SourceMap) {
+    let src_code = src.chars().collect::<Vec<char>>();
+    let mut result_text = vec![' '; src_code.len() * 4];
+    let options = PreprocessOptions { doc_comments: DocComments::Attribute, ..PreprocessOptions::default() };
+    let preprocessed = preprocess_with_options(&src_code, &mut result_text, &options).unwrap();
+    let length = preprocessed.source_map.result_len();
+    (String::from_iter(&result_text[..length]), preprocessed.source_map)
+}
+
+#[test]
+fn source_map_offsets() {
+    let src = "let  a = \"str\"; // c\nfoo(a);";
+    let (result, map) = preprocess_map(src);
+    assert_eq!(result, "let\na=\"\";foo(a);");
+    assert_eq!(map.source_len(), src.len());
+
+    //let is copied
+    assert_eq!(map.result_to_source(1), 1);
+    assert_eq!(map.source_to_result(1), 1);
+    //a
+    assert_eq!(map.result_to_source(4), 5);
+    assert_eq!(map.source_to_result(5), 4);
+    //Both quotes of "" map to the beginning of the string
+    assert_eq!(map.result_to_source(6), 9);
+    assert_eq!(map.result_to_source(7), 9);
+    assert_eq!(map.source_to_result(11), 6);
+    //The comment was removed, foo follows ;
+    assert_eq!(map.source_to_result(16), 9);
+    assert_eq!(map.result_to_source(8), 14);
+    assert_eq!(map.result_to_source(9), 21);
+    //The ends of both texts
+    assert_eq!(map.result_to_source(result.len()), src.len());
+    assert_eq!(map.source_to_result(src.len()), result.len());
+}
+
+#[test]
+fn source_map_every_copied_char() {
+    let src = "fn main() {\n    /* a */ let x = 'c'; // b\n    x\n}\n";
+    let (result, map) = preprocess_map(src);
+    let src_chars = src.chars().collect::<Vec<char>>();
+    let result_chars = result.chars().collect::<Vec<char>>();
+    for (i, c) in result_chars.iter().enumerate() {
+        let source_offset = map.result_to_source(i);
+        if c.is_alphanumeric() || "(){};=".contains(*c) {
+            assert_eq!(src_chars[source_offset], *c, "at {} in {:?}", i, result);
+            assert_eq!(map.source_to_result(source_offset), i);
+        }
+    }
+}
+
+#[test]
+fn source_map_line_columns() {
+    let src = "fn f() {\n    // comment\n    g(\"a\nb\");\n}";
+    let (result, map) = preprocess_map(src);
+    assert_eq!(result, "fn\nf(){g(\"\");}");
+
+    assert_eq!(map.source_line_column(0), LineColumn { line: 1, column: 1 });
+    assert_eq!(map.source_line_column(9), LineColumn { line: 2, column: 1 });
+    assert_eq!(map.result_line_column(3), LineColumn { line: 2, column: 1 });
+    assert_eq!(map.source_offset(LineColumn { line: 3, column: 5 }), Some(28));
+    assert_eq!(map.result_offset(LineColumn { line: 2, column: 5 }), Some(7));
+    assert_eq!(map.result_offset(LineColumn { line: 3, column: 1 }), None);
+    assert_eq!(map.source_offset(LineColumn { line: 1, column: 100 }), None);
+
+    //g( in the result is on line 3 of the source
+    assert_eq!(map.result_to_source_line_column(LineColumn { line: 2, column: 5 }),
+               Some(LineColumn { line: 3, column: 5 }));
+    //The closing brace is on the last line of the source
+    assert_eq!(map.result_to_source_line_column(LineColumn { line: 2, column: 11 }),
+               Some(LineColumn { line: 5, column: 1 }));
+    assert_eq!(map.source_to_result_line_column(LineColumn { line: 5, column: 1 }),
+               Some(LineColumn { line: 2, column: 11 }));
+    //Inside the string, mapped to its beginning
+    assert_eq!(map.source_to_result_line_column(LineColumn { line: 4, column: 1 }),
+               Some(LineColumn { line: 2, column: 7 }));
+}
+
+#[test]
+fn source_map_columns_count_characters() {
+    let src = "fn é() { \"日本\" }\n\t日x /* ü */ 😀\n";
+    let (result, preprocessed) = preprocess_to_string(src, &PreprocessOptions::default()).unwrap();
+    let map = preprocessed.source_map;
+    assert_eq!(map.source_line_column(src.find('x').unwrap()), LineColumn { line: 2, column: 3 });
+    assert_eq!(map.source_offset(LineColumn { line: 1, column: 5 }), Some(src.find('(').unwrap()));
+    //The columns are the ones of errors
+    for (offset, _) in src.char_indices() {
+        let location = ErrorLocation::new(src.as_bytes(), offset, &src.as_bytes()[offset..offset]);
+        let position = LineColumn { line: location.line, column: location.column };
+        assert_eq!(map.source_line_column(offset), position, "{}", offset);
+        assert_eq!(map.source_offset(position), Some(offset), "{}", offset);
+    }
+    for (offset, _) in result.char_indices() {
+        assert_eq!(map.result_offset(map.result_line_column(offset)), Some(offset), "{}", offset);
+    }
+    //Inside a character, mapped to the character
+    assert_eq!(map.source_line_column(src.find('日').unwrap() + 2), LineColumn { line: 1, column: 11 });
+    assert_eq!(map.source_offset(LineColumn { line: 2, column: 15 }), None);
+}
+
+#[test]
+fn source_map_doc_attributes() {
+    let src = "/// docs\nstruct A;";
+    let (result, map) = preprocess_map(src);
+    assert_eq!(result, "#[doc = r\" docs\"]struct\nA;");
+    assert_eq!(map.result_to_source(5), 0);
+    assert_eq!(map.result_to_source(17), 9);
+    assert_eq!(map.source_to_result(9), 17);
+}
+
+///Preprocesses `src` as chars and as UTF-8 bytes and checks that both find the same comments
+///at the same places, as well as producing the same result text
+fn cross_check_utf8(src: &str, doc_comments: DocComments) {
+    let chars = src.chars().collect::<Vec<char>>();
+    let byte_offsets = src.char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(src.len()))
+        .collect::<Vec<usize>>();
+    let options = PreprocessOptions { doc_comments, ..PreprocessOptions::default() };
+
+    let mut char_result = vec![' '; chars.len() * 4];
+    let mut byte_result = vec![b' '; src.len() * 4];
+    let char_preprocessed = preprocess_with_options(&chars, &mut char_result, &options);
+    let byte_preprocessed = preprocess_with_options(src.as_bytes(), &mut byte_result, &options);
+    match (char_preprocessed, byte_preprocessed) {
+        (Ok(char_preprocessed), Ok(byte_preprocessed)) => {
+            assert_eq!(char_preprocessed.comments.len(), byte_preprocessed.comments.len(), "{:?}", src);
+            for (c, b) in char_preprocessed.comments.iter().zip(byte_preprocessed.comments.iter()) {
+                assert_eq!(c.comment_type, b.comment_type, "{:?}", src);
+                assert_eq!(c.has_escapes, b.has_escapes, "{:?}", src);
+                assert_eq!(byte_offsets[c.begin], b.begin, "{:?}", src);
+                assert_eq!(byte_offsets[c.begin + c.length], b.begin + b.length, "{:?}", src);
+            }
+            let char_text = String::from_iter(&char_result[..char_preprocessed.source_map.result_len()]);
+            let byte_text = std::str::from_utf8(&byte_result[..byte_preprocessed.source_map.result_len()]).unwrap();
+            assert_eq!(char_text, byte_text, "{:?}", src);
+        }
+        (Err(c), Err(b)) => {
+            assert_eq!(std::mem::discriminant(&c), std::mem::discriminant(&b), "{:?}", src);
+            assert_eq!(byte_offsets[c.location().offset], b.location().offset, "{:?}", src);
+            assert_eq!((c.location().line, c.location().column), (b.location().line, b.location().column), "{:?}", src);
+            assert_eq!(c.to_string(), b.to_string());
+        }
+        (c, b) => panic!("{:?} was preprocessed differently: {:?} {:?}", src, c, b)
+    }
+}
+
+#[test]
+fn utf8_matches_chars() {
+    let samples = [
+        "fn naïve() -> char { 'é' }",
+        "let s = \"日本語\"; // コメント\nlet c = '日';",
+        "/* ünïcödé /* nested ü */ */ x",
+        "/// Dokumentation über \"Dinge\"#\nstruct Straße;",
+        "let x = b'a'; let y = '\\u{1F600}'; let z = '😀';",
+        "fn f<'a>(x: &'a str) -> &'a str { x } 'äußere: loop {}",
+        "ä\"x\" é'y' ß r#\"ö\"#",
+        "let r = br##\"π \"# ∑\"##; let c = c\"ç\";",
+        "'\\'' '\"' '/' '\\x7f'",
+        "let s = \"unterminated ü",
+        "ä /* unterminated",
+    ];
+    for sample in samples.iter() {
+        cross_check_utf8(sample, DocComments::Strip);
+        cross_check_utf8(sample, DocComments::Keep);
+        cross_check_utf8(sample, DocComments::Attribute);
+    }
+}
+
+#[test]
+fn random_utf8_matches_chars() {
+    const ALPHABET: &[char] = &['\'', '"', '/', '*', '\\', 'r', 'b', 'c', '#', '!', 'é', '日', '😀', 'a', '_', ' ', '\n', '\r', '\u{2028}', 'x', '{', '}'];
+    let mut rng = StdRng::seed_from_u64(0xf00d);
+    for _ in 0..3000 {
+        let length = rng.random_range(0..30);
+        let src = (0..length)
+            .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())])
+            .collect::<String>();
+        cross_check_utf8(&src, DocComments::Attribute);
+    }
+}
+
+#[test]
+fn preprocess_utf8_byte_offsets() {
+    let src = "let é = \"ü\"; /* ß */";
+    let mut result_text = vec![0u8; src.len()];
+    let comments = preprocess_utf8(src, &mut result_text).unwrap();
+    let strings = comments.iter()
+        .filter(|c| c.comment_type == CommentType::String)
+        .collect::<Vec<_>>();
+    assert_eq!(strings.len(), 1);
+    assert_eq!(&src[strings[0].begin..(strings[0].begin + strings[0].length)], "\"ü\"");
+    let block = comments.iter().find(|c| c.comment_type == CommentType::SlashStar).unwrap();
+    assert_eq!(&src[block.begin..(block.begin + block.length)], "/* ß */");
+}
+
+#[test]
+fn result_buffer_required_capacity() {
+    let src = "/// \"docs\"\nstruct A; //! x\n".chars().collect::<Vec<char>>();
+    let options = PreprocessOptions { doc_comments: DocComments::Attribute, ..PreprocessOptions::default() };
+    let mut result_text = vec![' '; src.len()];
+    let required = match preprocess_with_options(&src, &mut result_text, &options).unwrap_err() {
+        PreprocessError::ResultBufferTooSmall { required, .. } => required,
+        e => panic!("Unexpected error {:?}", e)
+    };
+
+    let mut result_text = vec![' '; required];
+    let preprocessed = preprocess_with_options(&src, &mut result_text, &options).unwrap();
+    assert_eq!(preprocessed.source_map.result_len(), required);
+    assert_eq!(String::from_iter(&result_text), "#[doc = r#\" \"docs\"\"#]struct\nA;#![doc = r\" x\"]");
+}
+
+#[test]
+fn result_buffer_smaller_than_literal_replacement() {
+    for (src, required) in &[("'a'", 2), ("\"\"", 2), ("x", 1), ("a b", 3)] {
+        let src_code = src.chars().collect::<Vec<char>>();
+        for capacity in 0..*required {
+            let mut result_text = vec![' '; capacity];
+            match preprocess(&src_code, &mut result_text).unwrap_err() {
+                PreprocessError::ResultBufferTooSmall { required: r, capacity: c, .. } => {
+                    assert_eq!((r, c), (*required, capacity), "{}", src);
+                }
+                e => panic!("Unexpected error {:?}", e)
+            }
+        }
+    }
+}
+
+#[test]
+fn preprocess_owned_allocates_enough() {
+    let src = "/// a doc comment which is much longer as an attribute\nfn f() {}".chars().collect::<Vec<char>>();
+    let options = PreprocessOptions { doc_comments: DocComments::Attribute, ..PreprocessOptions::default() };
+    let (result_text, preprocessed) = preprocess_owned(&src, &options).unwrap();
+    assert_eq!(String::from_iter(&result_text), "#[doc = r\" a doc comment which is much longer as an attribute\"]fn\nf(){}");
+    assert_eq!(result_text.len(), preprocessed.source_map.result_len());
+    assert_eq!(preprocessed.comments.len(), 3);
+}
+
+#[test]
+fn preprocess_to_string_utf8() {
+    let (result_text, preprocessed) = preprocess_to_string("let ä = \"ö\"; // ü\n", &PreprocessOptions::default()).unwrap();
+    assert_eq!(result_text, "let\nä=\"\";");
+    assert_eq!(preprocessed.source_map.result_len(), result_text.len());
+    assert!(preprocess_to_string("\"open", &PreprocessOptions::default()).is_err());
+}
+
+///Preprocesses `src` fed in chunks of the given sizes, the rest is fed as the last chunk
+fn preprocess_chunks<T: TextUnit>(src: &[T], chunk_sizes: &[usize], options: &PreprocessOptions)
+                                  -> Result<(Vec<T>, preprocessor::Preprocessed), PreprocessError> {
+    let mut preprocessor = Preprocessor::new(options);
+    let mut result_text = Vec::new();
+    let mut comments = Vec::new();
+    let mut begin = 0;
+    for size in chunk_sizes {
+        let end = (begin + size).min(src.len());
+        comments.extend(preprocessor.feed(&src[begin..end], &mut result_text)?);
+        begin = end;
+    }
+    comments.extend(preprocessor.feed(&src[begin..], &mut result_text)?);
+    let (last_comments, source_map) = preprocessor.finish(&mut result_text)?;
+    comments.extend(last_comments);
+    Ok((result_text, preprocessor::Preprocessed { comments, source_map }))
+}
+
+fn check_chunks<T: TextUnit + std::fmt::Debug>(src: &[T], chunk_sizes: &[usize], options: &PreprocessOptions) {
+    match (preprocess_owned(src, options), preprocess_chunks(src, chunk_sizes, options)) {
+        (Ok((text, preprocessed)), Ok((chunked_text, chunked))) => {
+            assert_eq!(text, chunked_text, "{:?} {:?}", T::to_string(src), chunk_sizes);
+            assert_eq!(preprocessed.comments, chunked.comments, "{:?} {:?}", T::to_string(src), chunk_sizes);
+            assert_eq!(preprocessed.source_map, chunked.source_map, "{:?} {:?}", T::to_string(src), chunk_sizes);
+        }
+        (Err(error), Err(chunked_error)) => {
+            assert_eq!(error, chunked_error, "{:?} {:?}", T::to_string(src), chunk_sizes);
+        }
+        (one_shot, chunked) => panic!("{:?} was preprocessed differently in chunks {:?}: {:?} {:?}",
+                                      T::to_string(src), chunk_sizes, one_shot, chunked)
+    }
+}
+
+#[test]
+fn chunks_match_one_shot_at_every_split() {
+    let samples = [
+        "fn f() { /* a /* nested */ comment */ let x = r##\"raw \"# still\"##; }\n",
+        "//! inner\n/// outer\nfn f<'a>(c: &'a char) -> bool { *c == '\\u{1F600}' || *c == 'é' }",
+        "let s = \"escaped \\\" quote\"; let b = b'\\x7f'; // ü line\n  \t x",
+        "let c = c\"ç\"; let r = cr#\"x\"#; let br = br###\"\"## \"###;",
+        "/** block doc */ struct S; /*! inner block */ '\\'' '日' r#ident",
+        "let s = \"unterminated\n second line",
+        "ä /* unterminated\nline",
+        "'\\n and more\nx",
+    ];
+    for sample in samples.iter() {
+        let chars = sample.chars().collect::<Vec<char>>();
+        for doc_comments in [DocComments::Strip, DocComments::Keep, DocComments::Attribute].iter() {
+            let options = PreprocessOptions { doc_comments: *doc_comments, ..PreprocessOptions::default() };
+            for split in 0..=sample.len() {
+                check_chunks(sample.as_bytes(), &[split], &options);
+            }
+            for split in 0..=chars.len() {
+                check_chunks(&chars, &[split], &options);
+            }
+            check_chunks(sample.as_bytes(), &vec![1; sample.len()], &options);
+            check_chunks(&chars, &vec![1; chars.len()], &options);
+        }
+    }
+}
+
+#[test]
+fn random_chunks_match_one_shot() {
+    const ALPHABET: &[char] = &['\'', '"', '/', '*', '\\', 'r', 'b', 'c', '#', '!', 'é', '日', '😀', 'a', '_', ' ', '\n', '\r', '\u{2028}', 'x', '{', '}'];
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    for _ in 0..3000 {
+        let length = rng.random_range(0..40);
+        let src = (0..length)
+            .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())])
+            .collect::<String>();
+        let chunk_sizes = (0..rng.random_range(0..8))
+            .map(|_| rng.random_range(0..6))
+            .collect::<Vec<usize>>();
+        let options = PreprocessOptions { doc_comments: DocComments::Attribute, ..PreprocessOptions::default() };
+        check_chunks(src.as_bytes(), &chunk_sizes, &options);
+        check_chunks(&src.chars().collect::<Vec<char>>(), &chunk_sizes, &options);
+    }
+}
+
+#[test]
+fn chunks_of_a_long_line() {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let line = "let x = a /* b */ + c; ".repeat(100_000);
+    //With preserved line endings separators wait for the first line to end, this line has none
+    let without_separators = "x=a/*b*/+c;".repeat(200_000);
+    for (line, line_endings) in [(&line, LineEndings::Normalize), (&without_separators, LineEndings::Preserve)] {
+        let options = PreprocessOptions { line_endings, ..PreprocessOptions::default() };
+        let mut preprocessor = Preprocessor::new(&options);
+        let mut result_text = Vec::new();
+        for chunk in line.as_bytes().chunks(CHUNK_SIZE) {
+            preprocessor.feed(chunk, &mut result_text).unwrap();
+            assert!(preprocessor.pending_length() < 2 * CHUNK_SIZE, "{}", preprocessor.pending_length());
+        }
+        preprocessor.finish(&mut result_text).unwrap();
+        assert_eq!(String::from_utf8(result_text).unwrap(), preprocess_to_string(line, &options).unwrap().0);
+    }
+
+    //The beginning of the line is left out of the snippet, the location is the same
+    let src = format!("fn f() {{}}\n{}ü \"open", line);
+    let expected = preprocess_to_string(&src, &PreprocessOptions::default()).unwrap_err();
+    let error = preprocess_reader(src.as_bytes(), Vec::new(), &PreprocessOptions::default()).unwrap_err();
+    match error.into_inner().unwrap().downcast::<PreprocessError>().map(|e| *e) {
+        Ok(PreprocessError::UnterminatedString(location)) => {
+            let expected = expected.location();
+            assert_eq!((location.offset, location.line, location.column), (expected.offset, expected.line, expected.column));
+            assert!(location.elided > 0 && expected.source_line.ends_with(&location.source_line));
+            //The marker is below the quote
+            let message = PreprocessError::UnterminatedString(location).to_string();
+            let lines = message.lines().collect::<Vec<&str>>();
+            assert!(lines[3].contains(" | ...") && lines[3].ends_with("ü \"open"), "{}", message);
+            assert_eq!(lines[4].chars().position(|c| c == '^'), lines[3].chars().position(|c| c == '"'), "{}", message);
+        }
+        other => panic!("Unexpected error {:?}", other)
+    }
+}
+
+#[test]
+fn chunks_wait_for_the_line_ending_of_separators() {
+    let options = PreprocessOptions { line_endings: LineEndings::Preserve, ..PreprocessOptions::default() };
+    let src = "a/**/b c/**/d\r\ne/**/f";
+    for split in 0..=src.len() {
+        check_chunks(src.as_bytes(), &[split], &options);
+    }
+    check_chunks(src.as_bytes(), &vec![1; src.len()], &options);
+    check_chunks("a/**/b".as_bytes(), &[1, 1, 1], &options);
+}
+
+///Hands out the text a few bytes at a time, like a slow pipe
+struct TrickleReader<'a> {
+    text: &'a [u8],
+    step: usize
+}
+
+impl<'a> std::io::Read for TrickleReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.step = self.step % 3 + 1;
+        let length = self.step.min(buf.len()).min(self.text.len());
+        buf[..length].copy_from_slice(&self.text[..length]);
+        self.text = &self.text[length..];
+        Ok(length)
+    }
+}
+
+#[test]
+fn preprocess_from_reader() {
+    let src = "fn main() {\n    /* a */ let s = r#\"ü\"#; // b\n    s\n}\n";
+    let options = PreprocessOptions::default();
+    let mut result_text = Vec::new();
+    let preprocessed = preprocess_reader(TrickleReader { text: src.as_bytes(), step: 0 }, &mut result_text, &options).unwrap();
+    let (expected_text, expected) = preprocess_to_string(src, &options).unwrap();
+    assert_eq!(String::from_utf8(result_text).unwrap(), expected_text);
+    assert_eq!(preprocessed.comments, expected.comments);
+    assert_eq!(preprocessed.source_map, expected.source_map);
+
+    let error = preprocess_reader(TrickleReader { text: b"fn f() {}\n/* open", step: 0 }, Vec::new(), &options).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    match error.into_inner().unwrap().downcast::<PreprocessError>().map(|e| *e) {
+        Ok(PreprocessError::UnterminatedBlockComment(location)) => assert_eq!((location.offset, location.line), (10, 2)),
+        other => panic!("Unexpected error {:?}", other)
+    }
+}
+
+fn check_segments<T: TextUnit + std::fmt::Debug + Send + Sync>(src: &[T], boundaries: &[usize], options: &PreprocessOptions) {
+    let mut result_text = vec![T::from_ascii(' '); src.len() * 3];
+    let mut segments_result_text = result_text.clone();
+    let one_shot = preprocess_with_options(src, &mut result_text, options);
+    let segments = preprocess_segments(src, &mut segments_result_text, options, boundaries);
+    match (one_shot, segments) {
+        (Ok(preprocessed), Ok(segments_preprocessed)) => {
+            assert_eq!(result_text, segments_result_text, "{:?} {:?}", T::to_string(src), boundaries);
+            assert_eq!(preprocessed.comments, segments_preprocessed.comments, "{:?} {:?}", T::to_string(src), boundaries);
+            assert_eq!(preprocessed.source_map, segments_preprocessed.source_map, "{:?} {:?}", T::to_string(src), boundaries);
+        }
+        (Err(error), Err(segments_error)) => {
+            assert_eq!(error, segments_error, "{:?} {:?}", T::to_string(src), boundaries);
+        }
+        (one_shot, segments) => panic!("{:?} was preprocessed differently in segments {:?}: {:?} {:?}",
+                                       T::to_string(src), boundaries, one_shot, segments)
+    }
+}
+
+#[test]
+fn segments_match_one_shot_at_every_split() {
+    let samples = [
+        "fn f() { /* a /* nested /* deeper */ */ comment */ let x = r##\"raw \"# still\"##; }\n",
+        "/**/*/ x /*/**/*/ y /* */*/",
+        "//! inner\n/// outer\nfn f<'a>(c: &'a char) -> bool { *c == '\\u{1F600}' || *c == 'é' }",
+        "let s = \"escaped \\\" quote \\u{1F600} \\\\\"; let b = b'\\x7f'; // ü line\n  \t x",
+        "let c = c\"ç\"; let r = cr#\"x\"#; let br = br###\"\"## \"# \"###;",
+        "/** block doc */ struct S; /*! inner block */ '\\'' '日' r#ident",
+        "let s = \"unterminated\n second line",
+        "ä /* unterminated /* nested */\nline",
+        "'\\n and more\nx",
+        "a  \n\t b // c\n// d\n  e",
+    ];
+    for sample in samples.iter() {
+        let chars = sample.chars().collect::<Vec<char>>();
+        for doc_comments in [DocComments::Strip, DocComments::Keep, DocComments::Attribute].iter() {
+            let options = PreprocessOptions { doc_comments: *doc_comments, ..PreprocessOptions::default() };
+            for split in 0..=sample.len() {
+                check_segments(sample.as_bytes(), &[0, split, sample.len()], &options);
+            }
+            for split in 0..=chars.len() {
+                check_segments(&chars, &[0, split, chars.len()], &options);
+            }
+            check_segments(sample.as_bytes(), &(0..=sample.len()).collect::<Vec<usize>>(), &options);
+        }
+    }
+}
+
+#[test]
+fn segments_beginning_in_char_literals_and_escapes() {
+    //Those are scanned sequentially up to where the speculation of the segment is in code as well
+    let src = "let q = '\"'; /* \" */ let a = '/'; let s = \"\\\"/*\\u{1F600}\"; let b = b'\\''; // '\n let c = '\\u{2F}';\n";
+    let options = PreprocessOptions::default();
+    let literals = ["'\"'", "'/'", "\\\"", "\\u{1F600}", "b'\\''", "'\\u{2F}'"];
+    for literal in literals.iter() {
+        let begin = src.find(literal).unwrap();
+        for split in (begin + 1)..(begin + literal.len()) {
+            check_segments(src.as_bytes(), &[0, split, src.len()], &options);
+            check_segments(src.as_bytes(), &[0, split - 1, split, src.len()], &options);
+        }
+    }
+}
+
+#[test]
+fn empty_segments() {
+    //The */ is split across the empty segment between the repeated boundaries
+    let src = "/*\r\n日ba!{x*/'r#\"";
+    let options = PreprocessOptions::default();
+    check_segments(src.as_bytes(), &[0, 13, 13, 18], &options);
+    check_segments(src.as_bytes(), &[0, 0, 13, 13, 18, 18], &options);
+}
+
+#[test]
+fn random_segments_match_one_shot() {
+    const ALPHABET: &[char] = &['\'', '"', '/', '*', '\\', 'r', 'b', 'c', '#', '!', 'é', '日', 'a', '_', ' ', '\n', '\r', '\u{85}', 'x', '{', '}'];
+    let mut rng = StdRng::seed_from_u64(0x5e6);
+    for _ in 0..3000 {
+        let length = rng.random_range(0..60);
+        let src = (0..length)
+            .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())])
+            .collect::<String>();
+        let mut boundaries = (0..rng.random_range(0..6))
+            .map(|_| rng.random_range(0..=src.len()))
+            .collect::<Vec<usize>>();
+        boundaries.push(0);
+        boundaries.push(src.len());
+        boundaries.sort_unstable();
+        let options = PreprocessOptions { doc_comments: DocComments::Attribute, ..PreprocessOptions::default() };
+        check_segments(src.as_bytes(), &boundaries, &options);
+    }
+}
+
+#[test]
+fn parallel_large_text() {
+    let sample = "fn f() {\n    /* block /* nested */ */ let s = r#\"raw \"# \"#; // line\n    let c = 'c'; g(\"s\\\"\", 'a);\n}\n";
+    let src = sample.repeat(4000);
+    let options = PreprocessOptions::default();
+    let mut result_text = vec![0u8; src.len()];
+    let preprocessed = preprocess_parallel(src.as_bytes(), &mut result_text, &options, 8).unwrap();
+    let (expected_text, expected) = preprocess_to_string(&src, &options).unwrap();
+    assert_eq!(&result_text[..preprocessed.source_map.result_len()], expected_text.as_bytes());
+    assert_eq!(preprocessed.comments, expected.comments);
+
+    let unterminated = src.clone() + "/* open";
+    let mut result_text = vec![0u8; unterminated.len()];
+    assert_eq!(preprocess_parallel(unterminated.as_bytes(), &mut result_text, &options, 8).unwrap_err(),
+               preprocess_err(&unterminated));
+}
+
+///Files whose ends would change the state of the next file, if the state was not reset
+const BATCH_FILES: [&str; 10] = [
+    "fn f() { /* a */ let s = \"x\"; }\n",
+    "",
+    "let s = \"unterminated",
+    "\"not in a string\" // line without newline",
+    "/* open /* nested */",
+    "x */ let a = r",
+    "#\"no raw string\"# /// doc\n  ",
+    "\t 'c' b'\\x7f' é",
+    "'",
+    "#!/usr/bin/env run-cargo-script\r\n#![allow(unused)]"
+];
+
+fn check_batch(sources: &[&str], batch: &[PreprocessedFile], options: &PreprocessOptions) {
+    assert_eq!(batch.len(), sources.len());
+    for (source, preprocessed) in sources.iter().zip(batch) {
+        match (preprocess_to_string(source, options), preprocessed) {
+            (Ok((expected_text, expected)), Ok((result_text, preprocessed))) => {
+                assert_eq!(result_text, &expected_text, "{:?}", source);
+                assert_eq!(preprocessed.comments, expected.comments, "{:?}", source);
+                assert_eq!(preprocessed.source_map, expected.source_map, "{:?}", source);
+            }
+            (Err(expected), Err(error)) => assert_eq!(error, &expected, "{:?}", source),
+            (expected, preprocessed) => panic!("{:?} was preprocessed differently in a batch: {:?} {:?}",
+                                               source, expected, preprocessed)
+        }
+    }
+}
+
+#[test]
+fn batch_matches_single_files() {
+    let options = PreprocessOptions { doc_comments: DocComments::Attribute, ..PreprocessOptions::default() };
+    check_batch(&BATCH_FILES, &preprocess_batch_to_strings(&BATCH_FILES, &options), &options);
+    let preprocessed = preprocess_batch(&BATCH_FILES);
+    assert_eq!(preprocessed[0].as_ref().unwrap().comments, preprocess_str(BATCH_FILES[0]).0);
+    assert_eq!(preprocessed[2].as_ref().unwrap_err(), &preprocess_err(BATCH_FILES[2]));
+    assert!(preprocess_batch(&[]).is_empty());
+}
+
+#[test]
+fn batch_of_many_files() {
+    let mut rng = StdRng::seed_from_u64(0xba7c);
+    let sources = (0..500)
+        .map(|_| {
+            let length = rng.random_range(0..8);
+            (0..length).map(|_| BATCH_FILES[rng.random_range(0..BATCH_FILES.len())]).collect::<String>()
+        })
+        .collect::<Vec<String>>();
+    let sources = sources.iter().map(String::as_str).collect::<Vec<&str>>();
+    let options = PreprocessOptions::default();
+    check_batch(&sources, &preprocess_batch_to_strings(&sources, &options), &options);
+}
+
+#[test]
+fn backend_batches() {
+    let options = PreprocessOptions { doc_comments: DocComments::Keep, ..PreprocessOptions::default() };
+    for backend in [Backend::Cpu, Backend::CpuParallel { threads: 3 }, Backend::OpenCl].iter() {
+        let (batch, report) = backend.preprocess_batch(&BATCH_FILES, &options);
+        check_batch(&BATCH_FILES, &batch, &options);
+        assert!(report.fallback.is_some() || report.used == *backend, "{:?}", backend);
+    }
+}
+
+#[test]
+fn backends_agree() {
+    let mut src = String::new();
+    for i in 0..2000 {
+        src.push_str(&format!("/// doc {}\nfn f{}() {{ let s = r#\"x\"#; /* a /* b */ */ 'c' }} // é\n", i, i));
+    }
+    let options = PreprocessOptions { doc_comments: DocComments::Attribute, ..PreprocessOptions::default() };
+    let mut expected_text = vec![0u8; src.len() * 2];
+    let (expected, report) = Backend::Cpu.preprocess(&src, &mut expected_text, &options).unwrap();
+    assert_eq!(report, BackendReport { used: Backend::Cpu, fallback: None });
+    for backend in [Backend::CpuParallel { threads: 1 }, Backend::CpuParallel { threads: 4 }, Backend::OpenCl].iter() {
+        let mut result_text = vec![0u8; src.len() * 2];
+        let (preprocessed, report) = backend.preprocess(&src, &mut result_text, &options).unwrap();
+        assert_eq!(preprocessed.comments, expected.comments, "{:?}", backend);
+        assert_eq!(preprocessed.source_map, expected.source_map, "{:?}", backend);
+        assert_eq!(result_text, expected_text, "{:?}", backend);
+        if report.fallback.is_none() {
+            assert_eq!(report.used, *backend);
+        } else {
+            assert_eq!(report.used, Backend::Cpu);
+        }
+    }
+}
+
+#[test]
+fn backend_errors() {
+    let mut result_text = vec![0u8; 64];
+    let expected = preprocess_utf8("let s = \"open", &mut result_text.clone()).unwrap_err();
+    for backend in [Backend::Cpu, Backend::CpuParallel { threads: 2 }, Backend::OpenCl].iter() {
+        let error = backend.preprocess("let s = \"open", &mut result_text, &PreprocessOptions::default()).unwrap_err();
+        assert_eq!(error, expected, "{:?}", backend);
+    }
+}
+
+#[test]
+fn backend_selection() {
+    assert_eq!("cpu".parse(), Ok(Backend::Cpu));
+    assert_eq!(" OpenCL ".parse(), Ok(Backend::OpenCl));
+    assert_eq!("cpu-parallel=3".parse(), Ok(Backend::CpuParallel { threads: 3 }));
+    assert!(matches!("cpu-parallel".parse(), Ok(Backend::CpuParallel { threads }) if threads > 0));
+    assert!("cpu-parallel=0".parse::<Backend>().is_err());
+    assert!("gpu".parse::<Backend>().is_err());
+}
+
+///Lexes `src` and returns the tokens with their source text
+fn tokens_of(src: &str) -> Vec<(TokenKind, String)> {
+    tokenize(src, &PreprocessOptions::default())
+        .unwrap()
+        .iter()
+        .map(|token| (token.kind, token.span.text(src.as_bytes())))
+        .collect()
+}
+
+fn texts_of(tokens: &[(TokenKind, String)]) -> Vec<&str> {
+    tokens.iter().map(|(_, text)| text.as_str()).collect()
+}
+
+#[test]
+fn lex_identifiers_keywords_and_lifetimes() {
+    let tokens = tokens_of("pub fn r#match<'a>(_x: &'a Self, _: é) { 'outer: loop { break 'outer; } }");
+    assert_eq!(texts_of(&tokens), vec!["pub", "fn", "r#match", "<", "'a", ">", "(", "_x", ":", "&", "'a", "Self", ",",
+                                       "_", ":", "é", ")", "{", "'outer", ":", "loop", "{", "break", "'outer", ";", "}", "}"]);
+    assert_eq!(tokens[0].0, TokenKind::Keyword(Keyword::Pub));
+    assert_eq!(tokens[2].0, TokenKind::RawIdentifier);
+    assert_eq!(tokens[4].0, TokenKind::Lifetime);
+    assert_eq!(tokens[7].0, TokenKind::Identifier);
+    assert_eq!(tokens[11].0, TokenKind::Keyword(Keyword::SelfType));
+    assert_eq!(tokens[13].0, TokenKind::Punctuation(Punct::Underscore));
+    assert_eq!(tokens[15].0, TokenKind::Identifier);
+    assert_eq!(tokens[18].0, TokenKind::Lifetime);
+    //Weak keywords are identifiers
+    assert_eq!(tokens_of("union")[0].0, TokenKind::Identifier);
+    assert_eq!(Keyword::Match.as_str(), "match");
+}
+
+#[test]
+fn lex_punctuation() {
+    let tokens = tokens_of("a <<= b >>= c ..= d ... e::f -> g => h && i || j != k; x.y..z?~@$#");
+    assert_eq!(texts_of(&tokens), vec!["a", "<<=", "b", ">>=", "c", "..=", "d", "...", "e", "::", "f", "->", "g",
+                                       "=>", "h", "&&", "i", "||", "j", "!=", "k", ";", "x", ".", "y", "..", "z",
+                                       "?", "~", "@", "$", "#"]);
+    assert_eq!(tokens[1].0, TokenKind::Punctuation(Punct::ShlEq));
+    assert_eq!(tokens[9].0, TokenKind::Punctuation(Punct::PathSep));
+    assert_eq!(Punct::FatArrow.as_str(), "=>");
+    //Separators keep apart what the source kept apart
+    assert_eq!(texts_of(&tokens_of("a < = b - > c & &d")), vec!["a", "<", "=", "b", "-", ">", "c", "&", "&", "d"]);
+}
+
+#[test]
+fn lex_numbers() {
+    let tokens = tokens_of("1 1_000u32 0x1F_u8 0o17 0b1010i8 1.5 1e10 2.5E-3f64 1f32 2. 1..2 1.foo() x.0 1._a");
+    assert_eq!(texts_of(&tokens), vec!["1", "1_000u32", "0x1F_u8", "0o17", "0b1010i8", "1.5", "1e10", "2.5E-3f64",
+                                       "1f32", "2.", "1", "..", "2", "1", ".", "foo", "(", ")", "x", ".", "0", "1", ".",
+                                       "_a"]);
+    let integer = |base, suffix: Option<(usize, usize)>| TokenKind::Literal {
+        kind: LiteralKind::Integer(base),
+        suffix: suffix.map(|(begin, length)| lexer::Span { begin, length })
+    };
+    assert_eq!(tokens[0].0, integer(Base::Decimal, None));
+    assert_eq!(tokens[1].0, integer(Base::Decimal, Some((7, 3))));
+    assert_eq!(tokens[2].0, integer(Base::Hexadecimal, Some((16, 2))));
+    assert_eq!(tokens[3].0, integer(Base::Octal, None));
+    assert_eq!(tokens[4].0, integer(Base::Binary, Some((30, 2))));
+    for float in [5, 6, 7, 8, 9] {
+        assert!(matches!(tokens[float].0, TokenKind::Literal { kind: LiteralKind::Float, .. }), "{:?}", tokens[float]);
+    }
+}
+
+#[test]
+fn lex_quoted_literals() {
+    let src = "f(\"s\", b'a', r#\"raw\"#, c\"c\", '\\n', \"x\"suffix, br\"b\");";
+    let tokens = tokens_of(src);
+    assert_eq!(texts_of(&tokens), vec!["f", "(", "\"s\"", ",", "b'a'", ",", "r#\"raw\"#", ",", "c\"c\"", ",", "'\\n'",
+                                       ",", "\"x\"suffix", ",", "br\"b\"", ")", ";"]);
+    let quoted = |comment_type| TokenKind::Literal { kind: LiteralKind::Quoted(comment_type), suffix: None };
+    assert_eq!(tokens[2].0, quoted(CommentType::String));
+    assert_eq!(tokens[4].0, quoted(CommentType::ByteCharLiteral));
+    assert_eq!(tokens[6].0, quoted(CommentType::RawString { preceding_hashes: 1 }));
+    assert_eq!(tokens[10].0, quoted(CommentType::CharLiteral));
+    match tokens[12].0 {
+        TokenKind::Literal { suffix: Some(suffix), .. } => assert_eq!(suffix.text(src.as_bytes()), "suffix"),
+        kind => panic!("Unexpected token {:?}", kind)
+    }
+}
+
+#[test]
+fn lex_doc_comments_in_every_mode() {
+    let src = "/// a\r\n//! b\n/** c */ fn f() {} // d\n/* e */";
+    let mut token_streams = Vec::new();
+    for doc_comments in [DocComments::Strip, DocComments::Keep, DocComments::Attribute] {
+        for line_endings in [LineEndings::Normalize, LineEndings::Preserve] {
+            let options = PreprocessOptions { doc_comments, line_endings };
+            token_streams.push(tokenize(src, &options).unwrap());
+        }
+    }
+    assert!(token_streams.windows(2).all(|streams| streams[0] == streams[1]));
+    let texts = token_streams[0].iter().map(|token| token.span.text(src.as_bytes())).collect::<Vec<String>>();
+    assert_eq!(texts, vec!["/// a\r\n", "//! b\n", "/** c */", "fn", "f", "(", ")", "{", "}"]);
+    assert_eq!(token_streams[0][1].kind, TokenKind::DocComment(CommentType::InnerLineDoc));
+}
+
+#[test]
+fn lex_spans_map_to_source() {
+    let src = "#!/bin/x\nfn  main ( ) { /* c */ let  s = \"日本\" ; // d\n\tlet\u{2028}ü = 'é' ; }";
+    let tokens = tokenize(src, &PreprocessOptions::default()).unwrap();
+    let texts = tokens.iter().map(|token| token.span.text(src.as_bytes())).collect::<Vec<String>>();
+    assert_eq!(texts, vec!["fn", "main", "(", ")", "{", "let", "s", "=", "\"日本\"", ";", "let", "ü", "=", "'é'", ";", "}"]);
+
+    //Lexing chars finds the same tokens at the corresponding offsets
+    let chars = src.chars().collect::<Vec<char>>();
+    let (result_text, preprocessed) = preprocess_owned(&chars, &PreprocessOptions::default()).unwrap();
+    let char_tokens = lex(&chars, &result_text, &preprocessed).unwrap();
+    let char_texts = char_tokens.iter().map(|token| token.span.text(&chars)).collect::<Vec<String>>();
+    assert_eq!(char_texts, texts);
+}
+
+#[test]
+fn lex_random_token_sequences() {
+    const TOKENS: &[&str] = &["x", "_y", "r#z", "fn", "'a", "1", "2.5", "0x1f", "1u8", "\"s\"", "'c'", "b\"b\"",
+                              "r#\"r\"#", "+", "-", "=", "==", "<", "<=", ">", ">>", "&", "&&", "|", ".", "..", ":",
+                              "::", "/", "*", "!", "#", "(", ")", "é"];
+    const SEPARATORS: &[&str] = &[" ", "\n", "/**/", "/* c */", "// c\n", "\t\r\n", "\u{2028}"];
+    let mut rng = StdRng::seed_from_u64(0x7e57);
+    for _ in 0..500 {
+        let tokens = (0..rng.random_range(1..20))
+            .map(|_| TOKENS[rng.random_range(0..TOKENS.len())])
+            .collect::<Vec<&str>>();
+        let mut src = String::new();
+        for token in tokens.iter() {
+            src.push_str(token);
+            //A / followed by a comment would begin another comment
+            let separators = if token.ends_with('/') { &SEPARATORS[..2] } else { SEPARATORS };
+            src.push_str(separators[rng.random_range(0..separators.len())]);
+        }
+        let lexed = tokenize(&src, &PreprocessOptions::default()).unwrap_or_else(|error| panic!("{:?}: {}", src, error));
+        let texts = lexed.iter().map(|token| token.span.text(src.as_bytes())).collect::<Vec<String>>();
+        assert_eq!(texts, tokens, "{:?}", src);
+    }
+}
+
+#[test]
+fn lex_errors() {
+    let error = |src: &str| tokenize(src, &PreprocessOptions::default()).unwrap_err();
+    assert!(matches!(error("let € = 1;"), LexError::UnknownCharacter(location) if location.offset == 4));
+    assert!(matches!(error("0x;"), LexError::MissingDigits(_)));
+    assert!(matches!(error("0b102"), LexError::InvalidDigit(location) if location.offset == 4));
+    assert!(matches!(error("1e+;"), LexError::MissingExponent(_)));
+    assert!(matches!(error("' x"), LexError::UnknownCharacter(_)));
+    assert!(matches!(error("\"open"), LexError::Preprocess(PreprocessError::UnterminatedString(_))));
+    assert_eq!(error("fn f() {\n  0b2;\n}").to_string(), "error: invalid digit for the base of the literal\n \
+                                                           --> 2:5\n  \
+                                                           |\n\
+                                                           2 |   0b2;\n  \
+                                                           |     ^");
+}
+
+#[test]
+fn delimiters_nest() {
+    let index = index_str("fn f(a: [u8; 2]) {\n    g(a);\n}", &PreprocessOptions::default()).unwrap();
+    assert_eq!(index.delimiters, vec![
+        Delimiter { kind: DelimiterKind::Parenthesis, open: 4, close: 15, depth: 0, parent: None },
+        Delimiter { kind: DelimiterKind::Bracket, open: 8, close: 14, depth: 1, parent: Some(0) },
+        Delimiter { kind: DelimiterKind::Brace, open: 17, close: 29, depth: 0, parent: None },
+        Delimiter { kind: DelimiterKind::Parenthesis, open: 24, close: 26, depth: 1, parent: Some(2) }
+    ]);
+    assert_eq!(index.semicolons, vec![
+        Semicolon { offset: 11, depth: 2, parent: Some(1) },
+        Semicolon { offset: 27, depth: 1, parent: Some(2) }
+    ]);
+    assert_eq!(index.children(None).collect::<Vec<_>>(), vec![0, 2]);
+    assert_eq!(index.children(Some(2)).collect::<Vec<_>>(), vec![3]);
+    assert_eq!(index.matching(17), Some(29));
+    assert_eq!(index.matching(14), Some(8));
+    assert_eq!(index.matching(5), None);
+    assert_eq!(index.enclosing(25), Some(3));
+    assert_eq!(index.enclosing(16), None);
+}
+
+#[test]
+fn delimiters_skip_strings_and_comments() {
+    let src = "f(\"(\", ')', /* { */ b'[', r#\"]\"#); // }\n/// (\n//! ]\n/** { */\nx;";
+    for doc_comments in [DocComments::Strip, DocComments::Keep, DocComments::Attribute] {
+        let options = PreprocessOptions { doc_comments, ..PreprocessOptions::default() };
+        let index = index_str(src, &options).unwrap();
+        assert_eq!(index.delimiters, vec![Delimiter { kind: DelimiterKind::Parenthesis, open: 1, close: 32, depth: 0, parent: None }]);
+        assert_eq!(index.semicolons.iter().map(|s| s.offset).collect::<Vec<_>>(), vec![33, src.len() - 1]);
+    }
+}
+
+#[test]
+fn delimiters_map_to_source() {
+    let src: Vec<char> = "/* é */ {\r\n  a;\r\n}".chars().collect();
+    let mut result_text = vec![' '; src.len()];
+    let preprocessed = preprocess_with_options(&src, &mut result_text, &PreprocessOptions::default()).unwrap();
+    let index = index_delimiters(&src, &result_text, &preprocessed).unwrap();
+    assert_eq!(index.delimiters[0].open, 8);
+    assert_eq!(index.delimiters[0].close, 17);
+    assert_eq!(index.semicolons[0].offset, 14);
+    assert_eq!(index.fold_ranges(&preprocessed.source_map), vec![1..=3]);
+}
+
+#[test]
+fn delimiters_fold_ranges() {
+    let src = "mod m {\n    fn f() { g(1,\n        2) }\n}\n[0];";
+    let (result_text, preprocessed) = preprocess_to_string(src, &PreprocessOptions::default()).unwrap();
+    let index = index_delimiters(src.as_bytes(), result_text.as_bytes(), &preprocessed).unwrap();
+    assert_eq!(index.fold_ranges(&preprocessed.source_map), vec![1..=4, 2..=3, 2..=3]);
+}
+
+#[test]
+fn delimiters_random_nesting() {
+    let mut rng = StdRng::seed_from_u64(22);
+    for _ in 0..200 {
+        let mut src = String::new();
+        let mut expected = Vec::new();
+        let mut open = Vec::new();
+        while src.len() < 200 || !open.is_empty() {
+            let depth = open.len();
+            match rng.random_range(0..6) {
+                0..=2 if src.len() < 200 => {
+                    let kind = [DelimiterKind::Brace, DelimiterKind::Parenthesis, DelimiterKind::Bracket][rng.random_range(0..3)];
+                    open.push(expected.len());
+                    expected.push(Delimiter { kind, open: src.len(), close: 0, depth, parent: open.iter().rev().nth(1).copied() });
+                    src.push(kind.open());
+                }
+                3 => src.push_str(["a", " ", ";", "\"}\"", "/*)*/", "//]\n"][rng.random_range(0..6)]),
+                _ => if let Some(i) = open.pop() {
+                    expected[i].close = src.len();
+                    src.push(expected[i].kind.close());
+                }
+            }
+        }
+        let index = index_str(&src, &PreprocessOptions::default()).unwrap();
+        assert_eq!(index.delimiters, expected, "{:?}", src);
+        for delimiter in &expected {
+            assert_eq!(index.matching(delimiter.open), Some(delimiter.close));
+            assert_eq!(index.matching(delimiter.close), Some(delimiter.open));
+        }
+    }
+}
+
+#[test]
+fn delimiter_errors() {
+    let error = |src: &str| index_str(src, &PreprocessOptions::default()).unwrap_err();
+    assert!(matches!(error("f(a]"), DelimiterError::Mismatched { open, close } if open.offset == 1 && close.offset == 3));
+    assert!(matches!(error("a) /* ( */"), DelimiterError::Unopened(location) if location.offset == 1));
+    assert!(matches!(error("{ [ ] ( )"), DelimiterError::Unclosed(location) if location.offset == 0));
+    assert!(matches!(error("{ \"}"), DelimiterError::Preprocess(PreprocessError::UnterminatedString(_))));
+    assert_eq!(error("fn f() {\n  g(1];\n}").to_string(), "error: mismatched closing delimiter: `]`\n \
+                                                          --> 2:6\n  \
+                                                          |\n\
+                                                          2 |   g(1];\n  \
+                                                          |      ^\n\
+                                                          note: the delimiter was opened here\n \
+                                                          --> 2:4\n  \
+                                                          |\n\
+                                                          2 |   g(1];\n  \
+                                                          |    ^");
+}
+
+#[test]
+fn brackets_depths_and_matches() {
+    let brackets = match_brackets(b"{(};[]]{[");
+    assert_eq!(brackets.depths, vec![0, 1, 1, 1, 1, 1, 0, 0, 1]);
+    assert_eq!(brackets.matches, vec![Some(6), Some(2), Some(1), None, Some(5), Some(4), Some(0), None, None]);
+}
+
+///A masked text of random delimiters, unbalanced ones included
+fn random_masked(rng: &mut StdRng, length: usize) -> Vec<u8> {
+    (0..length).map(|_| b"{}()[]; "[rng.random_range(0..8)]).collect()
+}
+
+#[test]
+fn brackets_segments_match_sequentially() {
+    let mut rng = StdRng::seed_from_u64(23);
+    for _ in 0..300 {
+        let length = rng.random_range(0..64);
+        let masked = random_masked(&mut rng, length);
+        let mut boundaries = (0..rng.random_range(0..6)).map(|_| rng.random_range(0..=masked.len())).collect::<Vec<usize>>();
+        boundaries.extend_from_slice(&[0, masked.len()]);
+        boundaries.sort_unstable();
+        assert_eq!(match_brackets_segments(&masked, &boundaries), match_brackets(&masked), "{:?} {:?}", masked, boundaries);
+    }
+    //Deep nesting leaves most delimiters unmatched within the segments
+    let masked = [vec![b'('; 1000], vec![b')'; 1000]].concat();
+    let boundaries = (0..=masked.len()).step_by(7).chain(std::iter::once(masked.len())).collect::<Vec<usize>>();
+    assert_eq!(match_brackets_segments(&masked, &boundaries), match_brackets(&masked));
+}
+
+#[test]
+fn backend_index_delimiters() {
+    let sources = ["fn f(a: [u8; 2]) {\n    g(a);\n} /* ) */", "f(a]", "a) \"(\"", "{ [ ] ( )", ""];
+    for source in sources.iter() {
+        let (result_text, preprocessed) = preprocess_to_string(source, &PreprocessOptions::default()).unwrap();
+        let expected = index_delimiters(source.as_bytes(), result_text.as_bytes(), &preprocessed);
+        for backend in [Backend::Cpu, Backend::CpuParallel { threads: 3 }] {
+            let (index, report) = backend.index_delimiters(source, result_text.as_bytes(), &preprocessed);
+            assert_eq!(index, expected, "{:?}", source);
+            assert_eq!(report, BackendReport { used: backend, fallback: None });
+        }
+    }
+    let options = PreprocessOptions { doc_comments: DocComments::Keep, ..PreprocessOptions::default() };
+    let (result_text, preprocessed) = preprocess_to_string("{ /// (\n}", &options).unwrap();
+    assert_eq!(result_text, "{/// (\n}");
+    assert_eq!(mask_delimiters(result_text.as_bytes(), &preprocessed), b"{      }");
+    assert_eq!(Backend::Cpu.match_brackets(&[]).0, Brackets::default());
+}
+
+///The kind, name, generics and body of an item, with the index of its parent
+type OutlineEntry<'a> = (ItemKind, &'a str, Option<&'a str>, Option<&'a str>, Option<usize>);
+
+fn outline_of(src: &str) -> Vec<OutlineEntry<'_>> {
+    let text = |span: lexer::Span| &src[span.begin..span.end()];
+    outline_str(src, &PreprocessOptions::default()).unwrap().into_iter()
+        .map(|item| (item.kind, text(item.name), item.generics.map(text), item.body.map(text), item.parent))
+        .collect()
+}
+
+#[test]
+fn outline_of_the_test_function() {
+    let src = "pub fn test<'a, B>( &self, u: usize) {let k = 3;\nprintln!(\"{}\", k); k}";
+    let items = outline_str(src, &PreprocessOptions::default()).unwrap();
+    assert_eq!(items.len(), 1);
+    let item = &items[0];
+    assert_eq!((item.kind, item.visibility, item.parent), (ItemKind::Function, Visibility::Public, None));
+    assert_eq!(item.name.text(src.as_bytes()), "test");
+    assert_eq!(item.generics.unwrap().text(src.as_bytes()), "<'a, B>");
+    assert_eq!(item.body.unwrap().text(src.as_bytes()), "{let k = 3;\nprintln!(\"{}\", k); k}");
+    assert_eq!(item.span.text(src.as_bytes()), src);
+}
+
+#[test]
+fn outline_items() {
+    let src = "mod m {\n    struct Unit;\n    struct Tuple<T>(T, [u8; 2]) where T: Copy;\n    enum E { A { x: u8 }, B }\n}\n\
+               trait Shape: Clone { fn area(&self) -> f64; fn scaled(&self) -> Self { self.clone() } }\n\
+               impl<T: Into<Vec<u8>>> Shape for Wrapper<T> where T: Clone {}\n\
+               unsafe impl Send for S {}\nconst fn c() {}\nextern \"C\" fn e() {}\nmod file;\n\
+               fn outer() -> impl Fn(u8) { fn inner() {} let f: fn(u8) = g; println!(\"{}\", 1); m! { fn not_an_item() {} } }\n\
+               macro_rules! r { () => { fn neither() {} } }";
+    assert_eq!(outline_of(src), vec![
+        (ItemKind::Module, "m", None, Some("{\n    struct Unit;\n    struct Tuple<T>(T, [u8; 2]) where T: Copy;\n    enum E { A { x: u8 }, B }\n}"), None),
+        (ItemKind::Struct, "Unit", None, None, Some(0)),
+        (ItemKind::Struct, "Tuple", Some("<T>"), Some("(T, [u8; 2])"), Some(0)),
+        (ItemKind::Enum, "E", None, Some("{ A { x: u8 }, B }"), Some(0)),
+        (ItemKind::Trait, "Shape", None, Some("{ fn area(&self) -> f64; fn scaled(&self) -> Self { self.clone() } }"), None),
+        (ItemKind::Function, "area", None, None, Some(4)),
+        (ItemKind::Function, "scaled", None, Some("{ self.clone() }"), Some(4)),
+        (ItemKind::Impl, "Shape for Wrapper<T>", Some("<T: Into<Vec<u8>>>"), Some("{}"), None),
+        (ItemKind::Impl, "Send for S", None, Some("{}"), None),
+        (ItemKind::Function, "c", None, Some("{}"), None),
+        (ItemKind::Function, "e", None, Some("{}"), None),
+        (ItemKind::Module, "file", None, None, None),
+        (ItemKind::Function, "outer", None,
+         Some("{ fn inner() {} let f: fn(u8) = g; println!(\"{}\", 1); m! { fn not_an_item() {} } }"), None),
+        (ItemKind::Function, "inner", None, Some("{}"), Some(12))
+    ]);
+}
+
+#[test]
+fn outline_visibility() {
+    let src = "pub struct A; pub(crate) struct B; pub(super) struct C; pub(self) struct D; \
+               pub(in crate::m) struct E; crate struct F; struct G(pub (u8, u8));";
+    let visibilities = outline_str(src, &PreprocessOptions::default()).unwrap().into_iter()
+        .map(|item| item.visibility)
+        .collect::<Vec<Visibility>>();
+    let path = lexer::Span { begin: src.find("crate::m").unwrap(), length: "crate::m".len() };
+    assert_eq!(visibilities, vec![Visibility::Public, Visibility::Crate, Visibility::Super, Visibility::Private,
+                                  Visibility::InPath(path), Visibility::Crate, Visibility::Private]);
+}
+
+#[test]
+fn outline_doc_comments() {
+    let src = "//! crate docs\n/// first\n#[derive(Debug)]\n/** second */\npub struct S;\n\
+               // not a doc\nmod m {\n    //! inner\n    /*! block */\n    fn f() {}\n}\n/// dropped\nlet x = 1;\nfn g() {}";
+    for doc_comments in [DocComments::Strip, DocComments::Keep, DocComments::Attribute] {
+        let options = PreprocessOptions { doc_comments, ..PreprocessOptions::default() };
+        let (result_text, preprocessed) = preprocess_to_string(src, &options).unwrap();
+        let items = outline(src.as_bytes(), result_text.as_bytes(), &preprocessed).unwrap();
+        let docs = |item: &OutlineItem| item.docs.iter()
+            .map(|c| &src[c.begin..(c.begin + c.length)])
+            .collect::<Vec<&str>>();
+        assert_eq!(docs(&items[0]), vec!["/// first\n", "/** second */"]);
+        assert_eq!(items[0].span.text(src.as_bytes()), "/// first\n#[derive(Debug)]\n/** second */\npub struct S;");
+        assert_eq!(docs(&items[1]), vec!["//! inner\n", "/*! block */"]);
+        assert_eq!(items[1].docs[0].comment_type, CommentType::InnerLineDoc);
+        assert!(items[2].docs.is_empty() && items[3].docs.is_empty());
+    }
+}
+
+#[test]
+fn outline_errors() {
+    let error = |src: &str| outline_str(src, &PreprocessOptions::default()).unwrap_err();
+    assert!(matches!(error("fn f() { €"), outline::OutlineError::Lex(LexError::UnknownCharacter(_))));
+    assert!(matches!(error("fn f() { ]"), outline::OutlineError::Delimiter(DelimiterError::Mismatched { .. })));
+    assert!(matches!(error("fn f() { \""), outline::OutlineError::Lex(LexError::Preprocess(_))));
+}
+
+//The sources of the crate itself, which cover most of the grammar
+const CRATE_SOURCES: [&str; 21] = [
+    include_str!("../backend.rs"), include_str!("../cl_part/device.rs"), include_str!("../cl_part/mod.rs"),
+    include_str!("../cl_part/program.rs"), include_str!("../delimiters.rs"), include_str!("../lexer.rs"),
+    include_str!("../main.rs"), include_str!("../outline.rs"), include_str!("../parser/expressions.rs"),
+    include_str!("../parser/items.rs"), include_str!("../parser/mod.rs"), include_str!("../parser/patterns.rs"),
+    include_str!("../parser/types.rs"), include_str!("../preprocessor/batch.rs"), include_str!("../preprocessor/error.rs"),
+    include_str!("../preprocessor/mod.rs"), include_str!("../preprocessor/parallel.rs"),
+    include_str!("../preprocessor/source_map.rs"), include_str!("../preprocessor/stream.rs"), include_str!("../preprocessor/text.rs"),
+    include_str!("mod.rs")
+];
+
+#[test]
+fn parse_crate_sources() {
+    for src in CRATE_SOURCES.iter() {
+        let parse = parse_str(src, &PreprocessOptions::default()).unwrap();
+        let errors: Vec<String> = parse.errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(errors, Vec::<String>::new());
+        assert_eq!(parse.text(src.as_bytes()), *src);
+    }
+}
+
+//The nodes of a source without errors
+fn parse_nodes(src: &str) -> Node {
+    let parse = parse_str(src, &PreprocessOptions::default()).unwrap();
+    assert_eq!(parse.errors, Vec::new(), "{}", src);
+    parse.root
+}
+
+fn sexps<'a>(src: &str, nodes: impl Iterator<Item=&'a Node>) -> String {
+    nodes.map(|node| node.to_sexp(src.as_bytes())).collect::<Vec<String>>().join(" ")
+}
+
+//The first node of the kind in the tree, depth first
+fn find_node(node: &Node, kind: SyntaxKind) -> Option<&Node> {
+    if node.kind == kind {
+        return Some(node);
+    }
+    node.nodes().find_map(|child| find_node(child, kind))
+}
+
+#[test]
+fn parse_items() {
+    for (src, expected) in [
+        ("pub(crate) fn f<'a, T: Clone>(&self, x: &'a T) -> u8 where T: Copy;",
+         "(Function (Visibility \"pub\" \"(\" \"crate\" \")\") \"fn\" (Name \"f\") (GenericParamList \
+          \"<\" (LifetimeParam \"'a\") \",\" (TypeParam (Name \"T\") \":\" (TypeBoundList (TypeBound \
+          (Path (PathSegment \"Clone\"))))) \">\") (ParamList \"(\" (SelfParam \"&\" \"self\") \",\" \
+          (Param (IdentPat (Name \"x\")) \":\" (RefType \"&\" \"'a\" (PathType (Path (PathSegment \
+          \"T\"))))) \")\") (RetType \"->\" (PathType (Path (PathSegment \"u8\")))) (WhereClause \
+          \"where\" (WherePredicate (PathType (Path (PathSegment \"T\"))) \":\" (TypeBoundList (TypeBound \
+          (Path (PathSegment \"Copy\")))))) \";\")"),
+        ("struct Unit; struct Tuple(pub u8); union U { a: u8 }",
+         "(Struct \"struct\" (Name \"Unit\") \";\") (Struct \"struct\" (Name \"Tuple\") (TupleFieldList \
+          \"(\" (TupleField (Visibility \"pub\") (PathType (Path (PathSegment \"u8\")))) \")\") \";\") \
+          (Union \"union\" (Name \"U\") (RecordFieldList \"{\" (RecordField (Name \"a\") \":\" (PathType \
+          (Path (PathSegment \"u8\")))) \"}\"))"),
+        ("enum E { A, B(u8), C { x: u8 } = 2 }",
+         "(Enum \"enum\" (Name \"E\") (VariantList \"{\" (Variant (Name \"A\")) \",\" (Variant (Name \
+          \"B\") (TupleFieldList \"(\" (TupleField (PathType (Path (PathSegment \"u8\")))) \")\")) \",\" \
+          (Variant (Name \"C\") (RecordFieldList \"{\" (RecordField (Name \"x\") \":\" (PathType (Path \
+          (PathSegment \"u8\")))) \"}\") \"=\" (Literal \"2\")) \"}\"))"),
+        ("use ::a::{b, c::*, d as _};",
+         "(Use \"use\" (UseTree (Path \"::\" (PathSegment \"a\")) \"::\" (UseTreeList \"{\" (UseTree \
+          (Path (PathSegment \"b\"))) \",\" (UseTree (Path (PathSegment \"c\")) \"::\" \"*\") \",\" \
+          (UseTree (Path (PathSegment \"d\")) \"as\" \"_\") \"}\")) \";\")"),
+        ("unsafe impl<T> !Send for S<T> {} const fn c() {}",
+         "(Impl \"unsafe\" \"impl\" (GenericParamList \"<\" (TypeParam (Name \"T\")) \">\") \"!\" \
+          (PathType (Path (PathSegment \"Send\"))) \"for\" (PathType (Path (PathSegment \"S\" \
+          (GenericArgList \"<\" (TypeArg (PathType (Path (PathSegment \"T\")))) \">\")))) (ItemList \"{\" \
+          \"}\")) (Function \"const\" \"fn\" (Name \"c\") (ParamList \"(\" \")\") (BlockExpr \"{\" \
+          \"}\"))"),
+        ("extern \"C\" { static X: u8; } extern crate ocl as cl; macro_rules! m { () => {} } thread_local! { static Y: u8 = 1; }",
+         "(ExternBlock \"extern\" \"\\\"C\\\"\" (ItemList \"{\" (Static \"static\" (Name \"X\") \":\" \
+          (PathType (Path (PathSegment \"u8\"))) \";\") \"}\")) (ExternCrate \"extern\" \"crate\" (Name \
+          \"ocl\") \"as\" (Name \"cl\") \";\") (MacroRules \"macro_rules\" \"!\" (Name \"m\") (TokenTree \
+          \"{\" (TokenTree \"(\" \")\") \"=>\" (TokenTree \"{\" \"}\") \"}\")) (MacroCall (Path \
+          (PathSegment \"thread_local\")) \"!\" (TokenTree \"{\" \"static\" \"Y\" \":\" \"u8\" \"=\" \
+          \"1\" \";\" \"}\"))"),
+        ("#[cfg(test)] mod tests { #![allow(x)] type T<'a> = &'a u8; }",
+         "(Module (Attribute \"#\" (TokenTree \"[\" \"cfg\" (TokenTree \"(\" \"test\" \")\") \"]\")) \
+          \"mod\" (Name \"tests\") (ItemList \"{\" (Attribute \"#\" \"!\" (TokenTree \"[\" \"allow\" \
+          (TokenTree \"(\" \"x\" \")\") \"]\")) (TypeAlias \"type\" (Name \"T\") (GenericParamList \"<\" \
+          (LifetimeParam \"'a\") \">\") \"=\" (RefType \"&\" \"'a\" (PathType (Path (PathSegment \
+          \"u8\")))) \";\") \"}\"))")
+    ].iter() {
+        let root = parse_nodes(src);
+        assert_eq!(sexps(src, root.nodes()), *expected, "{}", src);
+    }
+}
+
+#[test]
+fn parse_expressions() {
+    let body = |src: &str| {
+        let src = format!("fn f() {{ {} }}", src);
+        let root = parse_nodes(&src);
+        sexps(&src, find_node(&root, SyntaxKind::BlockExpr).unwrap().nodes())
+    };
+    for (src, expected) in [
+        ("a = b + c * d - e as u8 == f || !g && -h;",
+         "(ExprStmt (BinaryExpr (PathExpr (Path (PathSegment \"a\"))) \"=\" (BinaryExpr (BinaryExpr \
+          (BinaryExpr (BinaryExpr (PathExpr (Path (PathSegment \"b\"))) \"+\" (BinaryExpr (PathExpr (Path \
+          (PathSegment \"c\"))) \"*\" (PathExpr (Path (PathSegment \"d\"))))) \"-\" (CastExpr (PathExpr \
+          (Path (PathSegment \"e\"))) \"as\" (PathType (Path (PathSegment \"u8\"))))) \"==\" (PathExpr \
+          (Path (PathSegment \"f\")))) \"||\" (BinaryExpr (PrefixExpr \"!\" (PathExpr (Path (PathSegment \
+          \"g\")))) \"&&\" (PrefixExpr \"-\" (PathExpr (Path (PathSegment \"h\"))))))) \";\")"),
+        ("a..b; ..=c; d..;",
+         "(ExprStmt (RangeExpr (PathExpr (Path (PathSegment \"a\"))) \"..\" (PathExpr (Path (PathSegment \
+          \"b\")))) \";\") (ExprStmt (RangeExpr \"..=\" (PathExpr (Path (PathSegment \"c\")))) \";\") \
+          (ExprStmt (RangeExpr (PathExpr (Path (PathSegment \"d\"))) \"..\") \";\")"),
+        ("x.y.0.1.f::<u8>(1, 2)?.await[0];",
+         "(ExprStmt (IndexExpr (AwaitExpr (TryExpr (MethodCallExpr (FieldExpr (FieldExpr (PathExpr (Path \
+          (PathSegment \"x\"))) \".\" (Name \"y\")) \".\" \"0.1\") \".\" (Name \"f\") \"::\" \
+          (GenericArgList \"<\" (TypeArg (PathType (Path (PathSegment \"u8\")))) \">\") (ArgList \"(\" \
+          (Literal \"1\") \",\" (Literal \"2\") \")\")) \"?\") \".\" \"await\") \"[\" (Literal \"0\") \
+          \"]\") \";\")"),
+        ("if let Some(x) = y && z == S {} else if a {} else { b }",
+         "(ExprStmt (IfExpr \"if\" (BinaryExpr (LetExpr \"let\" (TupleStructPat (Path (PathSegment \
+          \"Some\")) \"(\" (IdentPat (Name \"x\")) \")\") \"=\" (PathExpr (Path (PathSegment \"y\")))) \
+          \"&&\" (BinaryExpr (PathExpr (Path (PathSegment \"z\"))) \"==\" (PathExpr (Path (PathSegment \
+          \"S\"))))) (BlockExpr \"{\" \"}\") \"else\" (IfExpr \"if\" (PathExpr (Path (PathSegment \
+          \"a\"))) (BlockExpr \"{\" \"}\") \"else\" (BlockExpr \"{\" (ExprStmt (PathExpr (Path \
+          (PathSegment \"b\")))) \"}\"))))"),
+        ("match x { 1 => {} _ => 2 } - 1",
+         "(ExprStmt (MatchExpr \"match\" (PathExpr (Path (PathSegment \"x\"))) (MatchArmList \"{\" \
+          (MatchArm (LiteralPat (Literal \"1\")) \"=>\" (BlockExpr \"{\" \"}\")) (MatchArm (WildcardPat \
+          \"_\") \"=>\" (Literal \"2\")) \"}\"))) (ExprStmt (PrefixExpr \"-\" (Literal \"1\")))"),
+        ("S { a, b: 1, ..c }.d; let v = [0; 4]; let w = [(), (1,), (2)];",
+         "(ExprStmt (FieldExpr (StructExpr (Path (PathSegment \"S\")) (RecordExprFieldList \"{\" \
+          (RecordExprField (Name \"a\")) \",\" (RecordExprField (Name \"b\") \":\" (Literal \"1\")) \",\" \
+          (RecordExprField \"..\" (PathExpr (Path (PathSegment \"c\")))) \"}\")) \".\" (Name \"d\")) \
+          \";\") (LetStmt \"let\" (IdentPat (Name \"v\")) \"=\" (ArrayExpr \"[\" (Literal \"0\") \";\" \
+          (Literal \"4\") \"]\") \";\") (LetStmt \"let\" (IdentPat (Name \"w\")) \"=\" (ArrayExpr \"[\" \
+          (TupleExpr \"(\" \")\") \",\" (TupleExpr \"(\" (Literal \"1\") \",\" \")\") \",\" (ParenExpr \
+          \"(\" (Literal \"2\") \")\") \"]\") \";\")"),
+        ("'a: for i in 0.. { break 'a x; } while x { continue; } loop {}",
+         "(ExprStmt (ForExpr (Label \"'a\" \":\") \"for\" (IdentPat (Name \"i\")) \"in\" (RangeExpr \
+          (Literal \"0\") \"..\") (BlockExpr \"{\" (ExprStmt (BreakExpr \"break\" \"'a\" (PathExpr (Path \
+          (PathSegment \"x\")))) \";\") \"}\"))) (ExprStmt (WhileExpr \"while\" (PathExpr (Path \
+          (PathSegment \"x\"))) (BlockExpr \"{\" (ExprStmt (ContinueExpr \"continue\") \";\") \"}\"))) \
+          (ExprStmt (LoopExpr \"loop\" (BlockExpr \"{\" \"}\")))"),
+        ("let f = move |x: u8, (y, _)| -> u8 { x }; let g = || return; async move {}.await",
+         "(LetStmt \"let\" (IdentPat (Name \"f\")) \"=\" (ClosureExpr \"move\" (ClosureParamList \"|\" \
+          (Param (IdentPat (Name \"x\")) \":\" (PathType (Path (PathSegment \"u8\")))) \",\" (Param \
+          (TuplePat \"(\" (IdentPat (Name \"y\")) \",\" (WildcardPat \"_\") \")\")) \"|\") (RetType \
+          \"->\" (PathType (Path (PathSegment \"u8\")))) (BlockExpr \"{\" (ExprStmt (PathExpr (Path \
+          (PathSegment \"x\")))) \"}\")) \";\") (LetStmt \"let\" (IdentPat (Name \"g\")) \"=\" \
+          (ClosureExpr (ClosureParamList \"||\") (ReturnExpr \"return\")) \";\") (ExprStmt (AwaitExpr \
+          (BlockExpr \"async\" \"move\" \"{\" \"}\") \".\" \"await\"))"),
+        ("<<A as B>::C as D>::e();",
+         "(ExprStmt (CallExpr (PathExpr (Path (PathSegment \"<\" (PathType (Path (PathSegment \"<\" (PathType \
+          (Path (PathSegment \"A\"))) \"as\" (PathType (Path (PathSegment \"B\"))) \">\") \"::\" (PathSegment \
+          \"C\"))) \"as\" (PathType (Path (PathSegment \"D\"))) \">\") \"::\" (PathSegment \"e\"))) (ArgList \
+          \"(\" \")\")) \";\")"),
+        ("g(#[cfg(x)] 1, 2); x = #[a] [#[b] 1];",
+         "(ExprStmt (CallExpr (PathExpr (Path (PathSegment \"g\"))) (ArgList \"(\" (Literal (Attribute \"#\" \
+          (TokenTree \"[\" \"cfg\" (TokenTree \"(\" \"x\" \")\") \"]\")) \"1\") \",\" (Literal \"2\") \")\")) \
+          \";\") (ExprStmt (BinaryExpr (PathExpr (Path (PathSegment \"x\"))) \"=\" (ArrayExpr (Attribute \"#\" \
+          (TokenTree \"[\" \"a\" \"]\")) \"[\" (Literal (Attribute \"#\" (TokenTree \"[\" \"b\" \"]\")) \"1\") \
+          \"]\")) \";\")"),
+        ("let Some(x) = y else { return }; unsafe { g() }.h(); println!(\"{}\", x); m! {} x",
+         "(LetStmt \"let\" (TupleStructPat (Path (PathSegment \"Some\")) \"(\" (IdentPat (Name \"x\")) \
+          \")\") \"=\" (PathExpr (Path (PathSegment \"y\"))) (LetElse \"else\" (BlockExpr \"{\" (ExprStmt \
+          (ReturnExpr \"return\")) \"}\")) \";\") (ExprStmt (MethodCallExpr (BlockExpr \"unsafe\" \"{\" \
+          (ExprStmt (CallExpr (PathExpr (Path (PathSegment \"g\"))) (ArgList \"(\" \")\"))) \"}\") \".\" \
+          (Name \"h\") (ArgList \"(\" \")\")) \";\") (ExprStmt (MacroCall (Path (PathSegment \
+          \"println\")) \"!\" (TokenTree \"(\" \"\\\"{}\\\"\" \",\" \"x\" \")\")) \";\") (ExprStmt \
+          (MacroCall (Path (PathSegment \"m\")) \"!\" (TokenTree \"{\" \"}\"))) (ExprStmt (PathExpr (Path \
+          (PathSegment \"x\"))))")
+    ].iter() {
+        assert_eq!(body(src), *expected, "{}", src);
+    }
+}
+
+#[test]
+fn parse_patterns() {
+    let arm = |src: &str| {
+        let src = format!("fn f() {{ match x {{ {} => {{}} }} }}", src);
+        let root = parse_nodes(&src);
+        sexps(&src, find_node(&root, SyntaxKind::MatchArm).unwrap().nodes().take(1))
+    };
+    for (src, expected) in [
+        ("| A | B(.., x) | C { x, y: 1, ref mut z, .. }",
+         "(OrPat \"|\" (IdentPat (Name \"A\")) \"|\" (TupleStructPat (Path (PathSegment \"B\")) \"(\" \
+          (RestPat \"..\") \",\" (IdentPat (Name \"x\")) \")\") \"|\" (RecordPat (Path (PathSegment \
+          \"C\")) \"{\" (RecordPatField (IdentPat (Name \"x\"))) \",\" (RecordPatField (Name \"y\") \":\" \
+          (LiteralPat (Literal \"1\"))) \",\" (RecordPatField (IdentPat \"ref\" \"mut\" (Name \"z\"))) \
+          \",\" (RecordPatField (RestPat \"..\")) \"}\"))"),
+        ("-1..=5 | 'a'..='z' | X..",
+         "(OrPat (RangePat (LiteralPat (Literal \"-\" \"1\")) \"..=\" (LiteralPat (Literal \"5\"))) \"|\" \
+          (RangePat (LiteralPat (Literal \"'a'\")) \"..=\" (LiteralPat (Literal \"'z'\"))) \"|\" \
+          (RangePat (PathPat (Path (PathSegment \"X\"))) \"..\"))"),
+        ("&(a, [first, rest @ ..]) | &&mut _",
+         "(OrPat (RefPat \"&\" (TuplePat \"(\" (IdentPat (Name \"a\")) \",\" (SlicePat \"[\" (IdentPat \
+          (Name \"first\")) \",\" (IdentPat (Name \"rest\") \"@\" (RestPat \"..\")) \"]\") \")\")) \"|\" \
+          (RefPat \"&\" (RefPat \"&\" \"mut\" (WildcardPat \"_\"))))"),
+        ("m::N | <T as U>::V(_) | m!(x)",
+         "(OrPat (PathPat (Path (PathSegment \"m\") \"::\" (PathSegment \"N\"))) \"|\" (TupleStructPat \
+          (Path (PathSegment \"<\" (PathType (Path (PathSegment \"T\"))) \"as\" (PathType (Path \
+          (PathSegment \"U\"))) \">\") \"::\" (PathSegment \"V\")) \"(\" (WildcardPat \"_\") \")\") \"|\" \
+          (MacroCall (Path (PathSegment \"m\")) \"!\" (TokenTree \"(\" \"x\" \")\")))")
+    ].iter() {
+        assert_eq!(arm(src), *expected, "{}", src);
+    }
+}
+
+#[test]
+fn parse_types() {
+    let alias = |src: &str| {
+        let src = format!("type T = {};", src);
+        let root = parse_nodes(&src);
+        sexps(&src, find_node(&root, SyntaxKind::TypeAlias).unwrap().nodes().skip(1))
+    };
+    for (src, expected) in [
+        ("Vec<Vec<u8>>",
+         "(PathType (Path (PathSegment \"Vec\" (GenericArgList \"<\" (TypeArg (PathType (Path \
+          (PathSegment \"Vec\" (GenericArgList \"<\" (TypeArg (PathType (Path (PathSegment \"u8\")))) \
+          \">\"))))) \">\"))))"),
+        ("<T as Iterator>::Item",
+         "(PathType (Path (PathSegment \"<\" (PathType (Path (PathSegment \"T\"))) \"as\" (PathType (Path \
+          (PathSegment \"Iterator\"))) \">\") \"::\" (PathSegment \"Item\")))"),
+        ("<<A as B>::C as D>::E",
+         "(PathType (Path (PathSegment \"<\" (PathType (Path (PathSegment \"<\" (PathType (Path (PathSegment \
+          \"A\"))) \"as\" (PathType (Path (PathSegment \"B\"))) \">\") \"::\" (PathSegment \"C\"))) \"as\" \
+          (PathType (Path (PathSegment \"D\"))) \">\") \"::\" (PathSegment \"E\")))"),
+        ("&'a mut [u8; 4]",
+         "(RefType \"&\" \"'a\" \"mut\" (ArrayType \"[\" (PathType (Path (PathSegment \"u8\"))) \";\" \
+          (Literal \"4\") \"]\"))"),
+        ("(u8,)", "(TupleType \"(\" (PathType (Path (PathSegment \"u8\"))) \",\" \")\")"),
+        ("*const dyn Fn(u8) -> u8 + Send",
+         "(PtrType \"*\" \"const\" (DynTraitType \"dyn\" (TypeBoundList (TypeBound (Path (PathSegment \
+          \"Fn\" (ParamList \"(\" (PathType (Path (PathSegment \"u8\"))) \")\") (RetType \"->\" (PathType \
+          (Path (PathSegment \"u8\"))))))) \"+\" (TypeBound (Path (PathSegment \"Send\"))))))"),
+        ("unsafe extern \"C\" fn(x: u8, ...) -> !",
+         "(FnPtrType \"unsafe\" \"extern\" \"\\\"C\\\"\" \"fn\" (ParamList \"(\" (Param (IdentPat (Name \
+          \"x\")) \":\" (PathType (Path (PathSegment \"u8\")))) \",\" (Param \"...\") \")\") (RetType \
+          \"->\" (NeverType \"!\")))"),
+        ("for<'a> fn(&'a u8)",
+         "(ForType \"for\" (GenericParamList \"<\" (LifetimeParam \"'a\") \">\") (FnPtrType \"fn\" \
+          (ParamList \"(\" (Param (RefType \"&\" \"'a\" (PathType (Path (PathSegment \"u8\"))))) \")\")))"),
+        ("impl Iterator<Item = u8> + '_",
+         "(ImplTraitType \"impl\" (TypeBoundList (TypeBound (Path (PathSegment \"Iterator\" \
+          (GenericArgList \"<\" (AssocTypeArg (Name \"Item\") \"=\" (PathType (Path (PathSegment \
+          \"u8\")))) \">\")))) \"+\" (TypeBound \"'_\")))"),
+        ("HashMap<K, V, { N }, -1>",
+         "(PathType (Path (PathSegment \"HashMap\" (GenericArgList \"<\" (TypeArg (PathType (Path \
+          (PathSegment \"K\")))) \",\" (TypeArg (PathType (Path (PathSegment \"V\")))) \",\" (ConstArg \
+          (BlockExpr \"{\" (ExprStmt (PathExpr (Path (PathSegment \"N\")))) \"}\")) \",\" (ConstArg \
+          (Literal \"-\" \"1\")) \">\"))))")
+    ].iter() {
+        assert_eq!(alias(src), *expected, "{}", src);
+    }
+}
+
+#[test]
+fn parse_trivia() {
+    let src = "#!/bin/x\r\n//! crate\r\n/// f\r\n#[inline] /* c */ fn f() {\r\n\tlet s = \"日本\"; // d\r\n}\r\n// end\r\n";
+    for doc_comments in [DocComments::Strip, DocComments::Keep, DocComments::Attribute] {
+        for line_endings in [LineEndings::Normalize, LineEndings::Preserve] {
+            let parse = parse_str(src, &PreprocessOptions { doc_comments, line_endings }).unwrap();
+            assert!(parse.errors.is_empty());
+            assert_eq!(parse.text(src.as_bytes()), src);
+        }
+    }
+    let parsed = parse_str(src, &PreprocessOptions::default()).unwrap();
+    let function = parsed.root.nodes().next().unwrap();
+    let mut trivia = Vec::new();
+    function.for_each_token(&mut |token| {
+        let texts = token.leading_trivia.iter().map(|c| &src[c.begin..(c.begin + c.length)]).collect::<String>();
+        trivia.push((texts, token.span.text(src.as_bytes())));
+    });
+    assert_eq!(trivia[0], ("#!/bin/x\r\n//! crate\r\n/// f\r\n".to_string(), "#".to_string()));
+    assert_eq!(trivia[4], (" /* c */ ".to_string(), "fn".to_string()));
+    assert_eq!(trivia[14], (" // d\r\n".to_string(), "}".to_string()));
+    let trailing = parsed.trailing_trivia.iter().map(|c| c.comment_type).collect::<Vec<CommentType>>();
+    assert_eq!(trailing, vec![CommentType::Whitespace, CommentType::DoubleSlash]);
+
+    //Parsing chars gives the same tree
+    let chars = src.chars().collect::<Vec<char>>();
+    let (result_text, preprocessed) = preprocess_owned(&chars, &PreprocessOptions::default()).unwrap();
+    let char_parse = parse(&chars, &result_text, &preprocessed).unwrap();
+    assert_eq!(char_parse.root.to_sexp(&chars), parsed.root.to_sexp(src.as_bytes()));
+    assert_eq!(char_parse.text(&chars), src);
+}
+
+#[test]
+fn parse_error_recovery() {
+    let parsed = |src: &str| {
+        let parse = parse_str(src, &PreprocessOptions::default()).unwrap();
+        assert_eq!(parse.text(src.as_bytes()), src);
+        let kinds = parse.root.nodes().map(|node| node.kind).collect::<Vec<SyntaxKind>>();
+        let errors = parse.errors.iter()
+            .map(|error| format!("{}: {}", error.location.offset, error.message))
+            .collect::<Vec<String>>();
+        (kinds, errors)
+    };
+    let (kinds, errors) = parsed("fn f() { let x = 1 let y = 2; } fn g() {}");
+    assert_eq!(kinds, vec![SyntaxKind::Function, SyntaxKind::Function]);
+    assert_eq!(errors, vec!["19: expected `;`"]);
+    let (kinds, errors) = parsed("fn f( { } struct S { a: u8 b: u8 }");
+    assert_eq!(kinds, vec![SyntaxKind::Function, SyntaxKind::Struct]);
+    assert_eq!(errors, vec!["6: expected a parameter", "10: expected a parameter or `)`", "10: expected `)`", "10: expected `;`",
+                            "27: expected `,` or `}`"]);
+    let (kinds, errors) = parsed("impl X { fn a() {} ) fn b() {} }");
+    assert_eq!(kinds, vec![SyntaxKind::Impl, SyntaxKind::Error, SyntaxKind::Function, SyntaxKind::Error]);
+    assert_eq!(errors, vec!["19: expected `}`", "19: expected an item", "31: expected an item"]);
+    let (kinds, errors) = parsed("fn f() { foo(1, , 2) + } #[test]");
+    assert_eq!(kinds, vec![SyntaxKind::Function, SyntaxKind::Error]);
+    assert_eq!(errors, vec!["16: expected an expression", "23: expected an expression", "32: expected an item after the attributes"]);
+    let (kinds, errors) = parsed("let x = (1, 2); # x mod m { 1 + 2 fn g() {} }");
+    assert_eq!(kinds, vec![SyntaxKind::Error, SyntaxKind::Module]);
+    assert_eq!(errors, vec!["0: expected an item", "28: expected an item"]);
+    let (kinds, errors) = parsed("fn f() { g(#[a]); }");
+    assert_eq!(kinds, vec![SyntaxKind::Function]);
+    assert_eq!(errors, vec!["15: expected an expression after the attributes"]);
+
+    let parse = parse_str("fn main() {\n    x = ;\n}", &PreprocessOptions::default()).unwrap();
+    assert_eq!(parse.errors[0].to_string(), "error: expected an expression\n --> 2:9\n  |\n2 |     x = ;\n  |         ^");
+}
+
+#[test]
+fn parse_random_cuts() {
+    //Every prefix of a file is parsed back to its text, whatever errors it has
+    let mut rng = StdRng::seed_from_u64(25);
+    for src in CRATE_SOURCES.iter() {
+        for _ in 0..20 {
+            let mut end = rng.random_range(0..=src.len());
+            while !src.is_char_boundary(end) {
+                end -= 1;
+            }
+            let prefix = &src[..end];
+            if let Ok(parse) = parse_str(prefix, &PreprocessOptions::default()) {
+                assert_eq!(parse.text(prefix.as_bytes()), prefix);
+            }
+        }
+    }
+}
+
+#[test]
+fn parse_random_token_sequences() {
+    const TOKENS: &[&str] = &["x", "S", "fn", "struct", "impl", "let", "if", "else", "match", "for", "in", "mut", "pub",
+                              "'a", "1", "\"s\"", "+", "-", "=", "==", "<", ">", ">>", "&", "&&", "|", "||", ".", "..",
+                              ":", "::", "*", "!", "?", "#", "@", ",", ";", "=>", "->", "_", "(", ")", "[", "]", "{", "}"];
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..300 {
+        let length = rng.random_range(0..40);
+        let src = (0..length).map(|_| TOKENS[rng.random_range(0..TOKENS.len())]).collect::<Vec<&str>>().join(" ");
+        let parse = parse_str(&src, &PreprocessOptions::default()).unwrap();
+        assert_eq!(parse.text(src.as_bytes()), src);
+    }
+}
+
+#[cfg(not(feature = "opencl"))]
+#[test]
+fn backend_opencl_disabled() {
+    let mut result_text = vec![0u8; 16];
+    let (preprocessed, report) = Backend::OpenCl.preprocess("a // b\n", &mut result_text, &PreprocessOptions::default()).unwrap();
+    assert_eq!(report, BackendReport { used: Backend::Cpu, fallback: Some(backend::Fallback::FeatureDisabled) });
+    assert_eq!(preprocessed.comments.len(), 2);
+}
+
+#[cfg(feature = "opencl")]
+mod opencl {
+    use super::*;
+    use cl_part::{find_comments, find_comments_opencl, OpenClError};
+    use cl_part::{DeviceSelector, OpenClContext, ProgramCache};
+    use preprocessor::batch::PackedSources;
+    use cl_part::program::{parse_build_log, BuildMessage, Severity};
+    use ocl::DeviceType;
+    use std::ffi::OsStr;
+
+    ///Machines without an OpenCL platform or device skip the kernel tests, unless
+    ///`WATERBOTTLE_REQUIRE_OPENCL` is set like on CI. Looking for a platform takes a while if
+    ///there is none, so it is only done once
+    fn opencl_available() -> bool {
+        static AVAILABLE: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+        let available = *AVAILABLE.get_or_init(|| match find_comments(b"x") {
+            Err(error @ OpenClError::NoPlatform(_)) | Err(error @ OpenClError::NoDevice) => {
+                if std::env::var_os("WATERBOTTLE_REQUIRE_OPENCL").is_some_and(|require| !require.is_empty()) {
+                    panic!("WATERBOTTLE_REQUIRE_OPENCL is set, but there is no OpenCL device: {}", error);
+                }
+                println!("No OpenCL device: {}", error);
+                false
+            }
+            _ => true
+        });
+        if !available {
+            println!("Skipping the OpenCL test");
+        }
+        available
+    }
+
+    ///Checks that the kernel finds the same comments as `preprocess`, with tiny segments so
+    ///that comments and literals cross them, and room for `capacity` pairs per segment
+    fn check_opencl(src: &str, segment_length: usize, capacity: usize) {
+        let mut result_text = vec![0u8; src.len() * 3];
+        let expected = preprocess_utf8(src, &mut result_text);
+        match (expected, find_comments_opencl(src.as_bytes(), segment_length, capacity)) {
+            (Ok(expected), Ok(comments)) => assert_eq!(expected, comments, "{:?} {}", src, segment_length),
+            (Err(expected), Err(cl_part::OpenClError::Preprocess(error))) => assert_eq!(expected, error, "{:?}", src),
+            (expected, comments) => panic!("{:?} was preprocessed differently: {:?} {:?}", src, expected, comments)
+        }
+    }
+
+    #[test]
+    fn opencl_matches_preprocess() {
+        if !opencl_available() {
+            return;
+        }
+        let samples = [
+            "fn f() { /* a /* nested /* deeper */ */ comment */ let x = r##\"raw \"# still\"##; }\n",
+            "/**/*/ x /*/**/*/ y /* */*/",
+            "//! inner\n/// outer\nfn f<'a>(c: &'a char) -> bool { *c == '\\u{1F600}' || *c == 'é' }",
+            "let s = \"escaped \\\" quote \\u{1F600} \\\\\"; let b = b'\\x7f'; // ü line\n  \t x",
+            "let c = c\"ç\"; let r = cr#\"x\"#; let br = br###\"\"## \"# \"###; éb\"x\" 😀r\"y\"",
+            "/** block doc */ struct S; /*! inner block */ '\\'' '日' r#ident",
+            "let s = \"unterminated\n second line",
+            "ä /* unterminated /* nested */\nline",
+            "'\\n and more\nx",
+        ];
+        for sample in samples.iter() {
+            for segment_length in [1, 2, 3, 5, 8, 64, 4096].iter() {
+                check_opencl(sample, *segment_length, *segment_length);
+            }
+        }
+    }
+
+    #[test]
+    fn opencl_random_texts() {
+        if !opencl_available() {
+            return;
+        }
+        const ALPHABET: &[char] = &['\'', '"', '/', '*', '\\', 'r', 'b', 'c', '#', '!', 'é', '日', 'a', '_', ' ', '\n', '\r', '\u{85}', 'x', '{', '}'];
+        let mut rng = StdRng::seed_from_u64(0xc1);
+        for _ in 0..200 {
+            let length = rng.random_range(1..200);
+            let src = (0..length)
+                .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())])
+                .collect::<String>();
+            check_opencl(&src, rng.random_range(1..16), rng.random_range(1..4));
+        }
+    }
+
+    #[test]
+    fn opencl_more_pairs_than_buffers() {
+        if !opencl_available() {
+            return;
+        }
+        //Every line has 4 pairs, so a segment of 4096 bytes has far more than the first buffers
+        let src = (0..3000).map(|i| format!("f(\"{}\", 'x');\n", i)).collect::<String>();
+        for (segment_length, capacity) in [(4096, 1), (4096, 16), (100, 3), (src.len(), 1000)].iter() {
+            check_opencl(&src, *segment_length, *capacity);
+        }
+        let mut result_text = vec![0u8; src.len()];
+        assert_eq!(find_comments(src.as_bytes()).unwrap(), preprocess_utf8(&src, &mut result_text).unwrap());
+    }
+
+    #[test]
+    fn opencl_comment_pairs() {
+        if !opencl_available() {
+            return;
+        }
+        let src = "let s = \"a\\n\"; // c\n";
+        let comments = find_comments(src.as_bytes()).unwrap();
+        let strings = comments.iter().filter(|c| c.comment_type == CommentType::String).collect::<Vec<_>>();
+        assert_eq!(strings.len(), 1);
+        assert_eq!(&src[strings[0].begin..(strings[0].begin + strings[0].length)], "\"a\\n\"");
+        assert!(strings[0].has_escapes);
+        assert!(comments.iter().any(|c| c.comment_type == CommentType::DoubleSlash &&
+            &src[c.begin..(c.begin + c.length)] == "// c\n"));
+    }
+
+    #[test]
+    fn opencl_batch() {
+        if !opencl_available() {
+            return;
+        }
+        let packed = PackedSources::pack(&BATCH_FILES);
+        let context = OpenClContext::from_env().unwrap();
+        for (segment_length, capacity) in [(1, 1), (3, 2), (4096, 1024)].iter() {
+            let files = context.find_comments_in_files(packed.text(), packed.boundaries(), *segment_length, *capacity)
+                .unwrap();
+            for (source, comments) in BATCH_FILES.iter().zip(files) {
+                let mut result_text = vec![0u8; source.len()];
+                assert_eq!(comments, preprocess_utf8(source, &mut result_text), "{:?}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn opencl_brackets() {
+        if !opencl_available() {
+            return;
+        }
+        let context = OpenClContext::from_env().unwrap();
+        let mut rng = StdRng::seed_from_u64(23);
+        let mut texts = (0..50)
+            .map(|_| {
+                let length = rng.random_range(0..200);
+                random_masked(&mut rng, length)
+            })
+            .collect::<Vec<Vec<u8>>>();
+        texts.push([vec![b'['; 300], vec![b']'; 300]].concat());
+        for masked in texts.iter() {
+            for (segment_length, capacity) in [(1, 1), (3, 2), (7, 1), (4096, 1024)].iter() {
+                let brackets = context.match_brackets(masked, *segment_length, *capacity).unwrap();
+                assert_eq!(brackets, match_brackets(masked), "{:?} {}", masked, segment_length);
+            }
+        }
+        let (brackets, report) = Backend::OpenCl.match_brackets(b"{(})");
+        assert_eq!(report.used, Backend::OpenCl);
+        assert_eq!(brackets, match_brackets(b"{(})"));
+    }
+
+    #[test]
+    fn opencl_too_large() {
+        if !opencl_available() {
+            return;
+        }
+        let context = OpenClContext::from_env().unwrap();
+        let too_large = i32::MAX as usize + 1;
+        assert!(matches!(context.find_comments(b"//x", too_large, 1), Err(OpenClError::TooLarge(_))));
+        assert!(matches!(context.find_comments(b"//x", i32::MAX as usize - 2, 1), Err(OpenClError::TooLarge(_))));
+        assert!(matches!(context.find_comments(b"//x", 1, too_large), Err(OpenClError::TooLarge(value)) if value == too_large));
+        assert!(matches!(context.match_brackets(b"()", too_large, 1), Err(OpenClError::TooLarge(_))));
+        assert!(matches!(context.match_brackets(b"()", 1, too_large), Err(OpenClError::TooLarge(value)) if value == too_large));
+    }
+
+    #[test]
+    fn device_selector_from_str() {
+        assert_eq!("".parse(), Ok(DeviceSelector::default()));
+        assert_eq!(" platform = Portable Computing , type=GPU, index=2".parse(), Ok(DeviceSelector {
+            platform_name: Some("Portable Computing".to_string()),
+            device_type: Some(DeviceType::GPU),
+            index: 2
+        }));
+        assert_eq!("type=cpu".parse::<DeviceSelector>().unwrap().device_type, Some(DeviceType::CPU));
+        for invalid in ["gpu", "type=tpu", "index=-1", "device=0"].iter() {
+            assert!(invalid.parse::<DeviceSelector>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn build_log_messages() {
+        let log = "<kernel>:12:5: error: use of undeclared identifier 'x'\n    x = 1;\n    ^\n\
+                   /tmp/a:b.cl:3:1: warning: unused variable\nerror: invalid build option\nnote: see above";
+        assert_eq!(parse_build_log(log), vec![
+            BuildMessage { severity: Severity::Error, line: Some(12), column: Some(5),
+                message: "use of undeclared identifier 'x'".to_string() },
+            BuildMessage { severity: Severity::Warning, line: Some(3), column: Some(1),
+                message: "unused variable".to_string() },
+            BuildMessage { severity: Severity::Error, line: None, column: None,
+                message: "invalid build option".to_string() },
+            BuildMessage { severity: Severity::Note, line: None, column: None, message: "see above".to_string() }
+        ]);
+        assert!(parse_build_log("").is_empty());
+    }
+
+    #[test]
+    fn opencl_program_cache() {
+        if !opencl_available() {
+            return;
+        }
+        let directory = std::env::temp_dir().join(format!("waterbottle-cache-test-{}", std::process::id()));
+        let cache = ProgramCache { directory: directory.clone() };
+        let first = OpenClContext::new(&DeviceSelector::default(), Some(&cache)).unwrap();
+        assert!(!first.diagnostics().from_cache);
+        assert!(first.diagnostics().errors().next().is_none());
+        let second = OpenClContext::new(&DeviceSelector::default(), Some(&cache)).unwrap();
+        assert!(second.diagnostics().from_cache);
+        let src = "let s = \"a\"; // b\n";
+        assert_eq!(first.find_comments(src.as_bytes(), 4, 1).unwrap(), second.find_comments(src.as_bytes(), 4, 1).unwrap());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn opencl_program_cache_is_opt_in() {
+        assert_eq!(ProgramCache::from_value(None), None);
+        assert_eq!(ProgramCache::from_value(Some(OsStr::new(""))), None);
+        assert_eq!(ProgramCache::from_value(Some(OsStr::new("cache"))), Some(ProgramCache { directory: "cache".into() }));
+    }
+
+    #[test]
+    fn opencl_missing_device() {
+        if !opencl_available() {
+            return;
+        }
+        let selector = DeviceSelector { index: 10_000, ..DeviceSelector::default() };
+        assert!(matches!(OpenClContext::new(&selector, None), Err(OpenClError::NoDevice)));
+        let selector = DeviceSelector { platform_name: Some("no such platform".to_string()), ..DeviceSelector::default() };
+        assert!(matches!(OpenClContext::new(&selector, None), Err(OpenClError::NoPlatform(_))));
+    }
+}